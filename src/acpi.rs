@@ -1,202 +1,301 @@
-use crate::memory::{mmap_dev, unmap, UnmapGuard};
-use acpi::{fadt::Fadt, sdt::Signature, AcpiTables, PlatformInfo};
-use alloc::{boxed::Box, collections::BTreeMap, rc::Rc};
-use aml::{value::Args, AmlContext, AmlName, AmlValue};
-use spin::Mutex;
+use self::{
+    budget::BudgetError,
+    tables::{PmTimerInfo, TableError, SDT_HEADER_SIZE},
+};
+use crate::{
+    boot, device,
+    error::KError,
+    memory::{self, CacheMode},
+    taint::{self, Taint},
+    time,
+};
+use acpi::{AcpiError, AcpiTables, HpetInfo, PlatformInfo};
+use alloc::{boxed::Box, vec::Vec};
+use aml::{value::Args, AmlContext, AmlError, AmlName, AmlValue};
+use core::{fmt, mem, time::Duration};
+use spin::{Mutex, Once};
 use x86_64::{
-    structures::{
-        paging::{Page, PhysFrame, Size4KiB},
-        port::{PortRead, PortWrite},
-    },
-    PhysAddr,
+    structures::port::{PortRead, PortWrite},
+    PhysAddr, VirtAddr,
 };
 
+pub mod budget;
 mod handlers;
+pub mod tables;
 
 const SLP_EN: u16 = 1 << 13;
 
-#[derive(Clone)]
-pub struct LockedHandler {
-    inner: Rc<Mutex<Handler>>,
-}
+/// How long the firmware has to hand over control after the enable command
+const ENABLE_TIMEOUT: Duration = Duration::from_secs(3);
+/// How often the handover is checked
+const ENABLE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Only parse the DSDT at boot and load the SSDTs and initialize the aml
+/// objects when the namespace is first used, chosen at build time by setting
+/// `ACPI_LAZY_AML`
+const LAZY_AML: bool = option_env!("ACPI_LAZY_AML").is_some();
+
+/// The acpi object used by the rest of the kernel, set by [`install`]
+///
+/// The lock is held with interrupts enabled since aml methods can run for a
+/// long time, so interrupt handlers must only `try_lock` it
+pub static ACPI: Once<Mutex<Acpi>> = Once::new();
+
+/// Maps the tables and the memory used by the aml through [`memory::mmio`]
+#[derive(Clone, Default)]
+pub struct LockedHandler;
 
 impl LockedHandler {
-    /// Identity maps a frame
+    /// Maps `size` bytes at `address` and returns where they were mapped
     ///
     /// # Safety
     ///
     /// This function is unsafe because the caller must guarantee that the
-    /// frame is free and is usable
+    /// memory belongs to the firmware or to a device
     #[track_caller]
-    pub unsafe fn map(&self, frame: PhysFrame) {
-        let inner = &mut *self.inner.lock();
-
-        inner.map(frame)
+    pub unsafe fn map(&self, address: PhysAddr, size: usize) -> VirtAddr {
+        memory::mmio::acquire(address, size, CacheMode::Uncached, true)
+            .expect("Failed to map firmware memory")
     }
 
-    /// Unmaps a page
-    #[track_caller]
-    pub fn unmap(&self, page: Page<Size4KiB>) {
-        let inner = &mut *self.inner.lock();
-
-        inner.unmap(page)
-    }
+    /// Releases a mapping made by [`LockedHandler::map`], `address` can be
+    /// anywhere in it
+    pub fn unmap(&self, address: VirtAddr) { memory::mmio::release(address) }
 
     unsafe fn read<T>(&self, address: usize) -> T {
-        self.map(PhysFrame::containing_address(PhysAddr::new(address as u64)));
-        (address as *const T).read_volatile()
+        let mapping = self.map(PhysAddr::new(address as u64), mem::size_of::<T>());
+        let value = mapping.as_ptr::<T>().read_volatile();
+
+        self.unmap(mapping);
+
+        value
     }
 
     unsafe fn write<T>(&self, address: usize, value: T) {
-        self.map(PhysFrame::containing_address(PhysAddr::new(address as u64)));
-        (address as *mut T).write_volatile(value)
-    }
-}
+        let mapping = self.map(PhysAddr::new(address as u64), mem::size_of::<T>());
 
-impl Default for LockedHandler {
-    fn default() -> Self {
-        LockedHandler {
-            inner: Rc::new(Mutex::new(Handler::new())),
-        }
+        mapping.as_mut_ptr::<T>().write_volatile(value);
+        self.unmap(mapping);
     }
 }
 
-struct Handler {
-    mapping_refs: BTreeMap<u64, (usize, UnmapGuard)>,
+/// How problems with the firmware tables are handled, there's no kernel
+/// command line yet so it's chosen at build time by setting `ACPI` to `off` or
+/// `strict`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiMode {
+    /// Acpi isn't used at all
+    Off,
+    /// Broken tables are skipped
+    Normal,
+    /// Any broken table is an error
+    Strict,
 }
 
-impl Handler {
-    fn new() -> Self {
-        Handler {
-            mapping_refs: BTreeMap::new(),
-        }
-    }
-
-    #[track_caller]
-    fn map(&mut self, frame: PhysFrame) {
-        let key = frame.start_address().as_u64();
-
-        if let Some((ref mut rc, _)) = self.mapping_refs.get_mut(&key) {
-            *rc += 1;
-        } else {
-            let guard = unsafe { mmap_dev(frame, true).expect("Failed to identity map") };
-            self.mapping_refs.insert(key, (1, guard));
-        }
+pub fn mode() -> AcpiMode {
+    match option_env!("ACPI") {
+        Some("off") => AcpiMode::Off,
+        Some("strict") => AcpiMode::Strict,
+        _ => AcpiMode::Normal,
     }
+}
 
-    #[track_caller]
-    fn unmap(&mut self, page: Page<Size4KiB>) {
-        let key = page.start_address().as_u64();
-
-        let (ref mut rc, _) = self
-            .mapping_refs
-            .get_mut(&key)
-            .expect("Trying to unmap non mapped frame");
-
-        *rc -= 1;
+#[derive(Debug)]
+pub enum AcpiInitError {
+    /// Acpi was disabled with `acpi=off`
+    Disabled,
+    /// The rsdp or the root table couldn't be found or parsed
+    Tables(AcpiError),
+    /// A table was rejected in strict mode or the FADT is unusable
+    Table(TableError),
+    /// The aml in the DSDT or a SSDT couldn't be parsed or initialized in
+    /// strict mode
+    Aml(AmlError),
+}
 
-        if *rc == 0 {
-            let (_, guard) = self.mapping_refs.remove(&key).unwrap();
-            unmap(guard).expect("Failed to unmap");
+impl fmt::Display for AcpiInitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AcpiInitError::Disabled => write!(f, "acpi is disabled"),
+            AcpiInitError::Tables(e) => write!(f, "failed to read the tables: {:?}", e),
+            AcpiInitError::Table(e) => write!(f, "{}", e),
+            AcpiInitError::Aml(e) => write!(f, "failed to load the aml: {:?}", e),
         }
     }
 }
 
+impl From<TableError> for AcpiInitError {
+    fn from(e: TableError) -> Self { AcpiInitError::Table(e) }
+}
+
+/// Reads the acpi tables and loads the aml namespace
+///
+/// Outside of strict mode broken tables are skipped, if the aml can't be
+/// loaded [`Acpi::aml_usable`] returns false and only the tables can be used.
+///
+/// In lazy aml mode only the DSDT is parsed here and the rest of the namespace
+/// is loaded on the first call to [`Acpi::aml_context`].
+///
 /// # Safety
 /// The system must be using bios
-pub unsafe fn bios_get_acpi() -> Acpi {
-    fn inner() -> Acpi {
+pub unsafe fn bios_get_acpi() -> Result<Acpi, AcpiInitError> {
+    fn inner() -> Result<Acpi, AcpiInitError> {
+        let strict = mode() == AcpiMode::Strict;
+
+        if mode() == AcpiMode::Off {
+            return Err(AcpiInitError::Disabled);
+        }
+
         let handler = LockedHandler::default();
 
         log::debug!("Reading the acpi tables");
 
-        let tables = unsafe { acpi::AcpiTables::search_for_rsdp_bios(handler.clone()) }.unwrap();
+        let mut tables = unsafe { acpi::AcpiTables::search_for_rsdp_bios(handler.clone()) }
+            .map_err(AcpiInitError::Tables)?;
+
+        match tables::validate(&handler, &mut tables) {
+            Err(e) if strict => return Err(e.into()),
+            Err(_) => taint::add(Taint::FIRMWARE_BUG),
+            Ok(()) => {},
+        }
+
+        let fadt = tables::read_fadt(&tables)?;
 
         let mut aml_context =
             aml::AmlContext::new(Box::new(handler.clone()), false, aml::DebugVerbosity::All);
 
-        log::debug!("Reading the dsdt");
+        let mut aml_usable = false;
 
         if let Some(ref dsdt) = tables.dsdt {
-            let start = PhysFrame::containing_address(PhysAddr::new(dsdt.address as u64));
-            let end = PhysFrame::containing_address(PhysAddr::new(
-                dsdt.address as u64 + dsdt.length as u64,
-            ));
-
-            for frame in PhysFrame::range_inclusive(start, end) {
-                unsafe { handler.map(frame) }
+            log::debug!("Reading the dsdt");
+
+            let _stage = boot::stage("dsdt parse");
+
+            let parsed = tables::copy_aml_table(&handler, dsdt)
+                .map_err(AcpiInitError::from)
+                .and_then(|table| {
+                    aml_context
+                        .parse_table(&table[SDT_HEADER_SIZE..])
+                        .map_err(AcpiInitError::Aml)
+                });
+
+            match parsed {
+                Ok(()) => aml_usable = true,
+                Err(e) if strict => return Err(e),
+                Err(e) => {
+                    log::warn!("Rejecting the dsdt: {}", e);
+                    taint::add(Taint::FIRMWARE_BUG);
+                },
             }
-            let stream = unsafe {
-                core::slice::from_raw_parts(dsdt.address as *const _, dsdt.length as usize)
-            };
-
-            aml_context
-                .parse_table(stream)
-                .expect("Failed to parse the dsdt");
         }
 
-        for ssdt in tables.ssdts.iter() {
-            log::debug!("Reading a ssdt");
-
-            let start = PhysFrame::containing_address(PhysAddr::new(ssdt.address as u64));
-            let end = PhysFrame::containing_address(PhysAddr::new(
-                ssdt.address as u64 + ssdt.length as u64,
-            ));
+        // The ssdts are copied now since the tables might be reclaimed before
+        // they are loaded
+        let mut ssdts = Vec::with_capacity(tables.ssdts.len());
 
-            for frame in PhysFrame::range_inclusive(start, end) {
-                unsafe { handler.map(frame) }
+        for ssdt in tables.ssdts.iter() {
+            match tables::copy_aml_table(&handler, ssdt) {
+                Ok(table) => ssdts.push(table),
+                Err(e) if strict => return Err(e.into()),
+                Err(e) => {
+                    log::warn!("Rejecting a ssdt: {}", e);
+                    taint::add(Taint::FIRMWARE_BUG);
+                },
             }
+        }
 
-            let stream = unsafe {
-                core::slice::from_raw_parts(ssdt.address as *const _, ssdt.length as usize)
-            };
+        let mut acpi = Acpi {
+            tables,
+            aml_context,
+            aml_usable,
+            pending_ssdts: Some(ssdts),
 
-            aml_context
-                .parse_table(stream)
-                .expect("Failed to parse the dsdt");
+            acpi_enable: fadt.acpi_enable,
+            smi_cmd_port: fadt.smi_cmd_port,
+            pm1a_cnt: fadt.pm1a_cnt,
+            pm1b_cnt: fadt.pm1b_cnt,
+            pm_timer: fadt.pm_timer,
+            sleep_types: None,
+            reclaimed: false,
+        };
+
+        // Strict mode can't defer the loading since the errors must fail the
+        // init
+        if !LAZY_AML || strict {
+            acpi.load_aml(strict).map_err(AcpiInitError::Aml)?;
         }
 
-        log::trace!("Starting the aml objects init");
+        Ok(acpi)
+    }
 
-        aml_context
-            .initialize_objects()
-            .expect("Failed to init the aml objects");
+    inner()
+}
 
-        log::trace!("Finished the aml objects init");
+/// Makes `acpi` available through [`ACPI`] and [`with_acpi`]
+pub fn install(acpi: Acpi) { ACPI.call_once(|| Mutex::new(acpi)); }
 
-        let fadt: &Fadt = unsafe {
-            &tables
-                .get_sdt::<Fadt>(Signature::FADT)
-                .expect("Error when serching for the FADT")
-                .expect("Couldn't find the FADT")
-        };
+/// Calls `f` with the acpi object, returns `None` if acpi isn't available
+pub fn with_acpi<R>(f: impl FnOnce(&mut Acpi) -> R) -> Option<R> {
+    ACPI.get().map(|acpi| f(&mut acpi.lock()))
+}
 
-        // Todo: check for address space (we assume port space)
-        let pm1a_cnt = fadt
-            .pm1a_control_block()
-            .expect("Error when parsing pm1a control block")
-            .address as u16;
-        let pm1b_cnt = fadt
-            .pm1b_control_block()
-            .expect("Error when parsing pm1b control block")
-            .filter(|cnt| cnt.address != 0)
-            .map(|cnt| cnt.address as u16);
-
-        Acpi {
-            tables,
-            aml_context,
+#[derive(Debug)]
+pub enum InvokeError {
+    Aml(AmlError),
+    Budget(BudgetError),
+}
 
-            acpi_enable: fadt.acpi_enable,
-            smi_cmd_port: fadt.smi_cmd_port as u16,
-            pm1a_cnt,
-            pm1b_cnt,
+impl fmt::Display for InvokeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvokeError::Aml(e) => write!(f, "{:?}", e),
+            InvokeError::Budget(e) => write!(f, "{}", e),
         }
     }
+}
 
-    inner()
+/// Where the object of the first processor usually is
+pub const PROCESSOR_PATHS: &[&str] = &["\\_PR.CPU0", "\\_PR.P000", "\\_PR.CP00", "\\_SB.CPU0"];
+
+/// A generic register descriptor, found in the resource buffers of objects
+/// like `_CST` and `_PCT`
+#[derive(Debug, Clone, Copy)]
+pub struct GenericRegister {
+    pub address_space: u8,
+    /// For fixed hardware registers this is the vendor
+    pub bit_width: u8,
+    /// For fixed hardware registers this is the class
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
 }
 
-#[derive(Debug)]
+impl GenericRegister {
+    pub const FIXED_HARDWARE: u8 = 0x7F;
+    pub const SYSTEM_IO: u8 = 0x01;
+    /// Tag of the descriptor in a resource buffer
+    const TAG: u8 = 0x82;
+
+    /// Parses the descriptor at the start of a resource buffer
+    pub fn parse(buffer: &[u8]) -> Option<Self> {
+        if buffer.len() < 15 || buffer[0] != Self::TAG {
+            return None;
+        }
+
+        let mut address = [0; 8];
+        address.copy_from_slice(&buffer[7..15]);
+
+        Some(GenericRegister {
+            address_space: buffer[3],
+            bit_width: buffer[4],
+            bit_offset: buffer[5],
+            access_size: buffer[6],
+            address: u64::from_le_bytes(address),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SleepState {
     S1,
     S2,
@@ -206,6 +305,20 @@ pub enum SleepState {
 }
 
 impl SleepState {
+    pub const ALL: [SleepState; 5] = [
+        SleepState::S1,
+        SleepState::S2,
+        SleepState::S3,
+        SleepState::S4,
+        SleepState::S5,
+    ];
+
+    /// Whether the cpu state is lost and the firmware resumes through the
+    /// waking vector, which isn't set up so these states can't be entered
+    fn needs_waking_vector(self) -> bool {
+        matches!(self, SleepState::S2 | SleepState::S3 | SleepState::S4)
+    }
+
     pub fn as_aml_name(&self) -> AmlName {
         let name = match self {
             SleepState::S1 => "\\_S1",
@@ -222,13 +335,27 @@ impl SleepState {
 pub struct Acpi {
     tables: AcpiTables<LockedHandler>,
     aml_context: AmlContext,
+    /// The DSDT was parsed and the namespace initialization didn't fail
+    aml_usable: bool,
+    /// The SSDTs waiting to be loaded, `None` once the namespace is
+    /// initialized
+    pending_ssdts: Option<Vec<Vec<u8>>>,
 
     smi_cmd_port: u16,
     pm1a_cnt: u16,
     pm1b_cnt: Option<u16>,
     acpi_enable: u8,
+    pm_timer: Option<PmTimerInfo>,
+    /// The SLP_TYP values of each sleep state, `None` until they are probed
+    sleep_types: Option<[Option<(u16, u16)>; SleepState::ALL.len()]>,
+    /// The memory of the tables was given back to the frame allocator
+    reclaimed: bool,
 }
 
+// The aml context holds the handler as a `Box<dyn Handler>` which isn't `Send`
+// but the handler is always a `LockedHandler` which is
+unsafe impl Send for Acpi {}
+
 impl Acpi {
     /// Transfers control from the SMI to the OS
     ///
@@ -236,35 +363,50 @@ impl Acpi {
     ///
     /// This function is unsafe because the OS must be prepared to handle the
     /// acpi events
-    pub unsafe fn enable(&self) -> bool {
+    pub unsafe fn enable(&self) -> Result<(), KError> {
         if self.smi_cmd_port == 0 || self.acpi_enable == 0 {
-            return false;
+            return Err(KError::NotSupported);
         }
 
         u8::write_to_port(self.smi_cmd_port, self.acpi_enable);
 
-        for _ in 0..300 {
-            if u16::read_from_port(self.pm1a_cnt) & 1 == 1
-                && self
-                    .pm1b_cnt
-                    .map_or(true, |cnt| u16::read_from_port(cnt) & 1 == 1)
-            {
-                return true;
-            }
-
-            crate::sleep(10);
-        }
-
-        false
+        time::wait_until(
+            || {
+                u16::read_from_port(self.pm1a_cnt) & 1 == 1
+                    && self
+                        .pm1b_cnt
+                        .map_or(true, |cnt| u16::read_from_port(cnt) & 1 == 1)
+            },
+            ENABLE_TIMEOUT,
+            ENABLE_POLL_INTERVAL,
+        )?;
+
+        Ok(())
     }
 
+    /// Enters `state`, only S1 keeps the cpu state so it's the only sleep
+    /// state that returns here after waking up
     pub fn set_sleep_state(&mut self, state: SleepState) -> bool {
-        let (slp_typa, slp_typb) = if let Some(val) = self.get_sleep_state(state) {
-            val
-        } else {
+        if state.needs_waking_vector() {
+            log::warn!("Sleep state {:?} needs a waking vector", state);
             return false;
+        }
+
+        let (slp_typa, slp_typb) = match self.sleep_types()[state as usize] {
+            Some(val) => val,
+            None => {
+                log::warn!("Sleep state {:?} isn't supported", state);
+                return false;
+            },
         };
 
+        // Devices keep their state in S5 since it's a shutdown
+        let sleeping = !matches!(state, SleepState::S5);
+
+        if sleeping && device::suspend_all().is_err() {
+            return false;
+        }
+
         unsafe {
             u16::write_to_port(self.pm1a_cnt, SLP_EN | slp_typa << 10);
 
@@ -273,22 +415,93 @@ impl Acpi {
             }
         }
 
+        // Execution continues here after waking up from S1
+        if sleeping {
+            device::resume_all();
+        }
+
         true
     }
 
-    pub fn platform_info(&self) -> PlatformInfo {
-        self.tables
-            .platform_info()
-            .expect("Failed to get platform info")
+    pub fn hpet_info(&self) -> Option<HpetInfo> {
+        if self.reclaimed {
+            return None;
+        }
+
+        HpetInfo::new(&self.tables).ok()
+    }
+
+    pub fn platform_info(&self) -> Result<PlatformInfo, AcpiError> {
+        assert!(!self.reclaimed, "The acpi tables were already reclaimed");
+
+        self.tables.platform_info()
+    }
+
+    /// Returns where the power management timer is if there's one
+    pub fn pm_timer(&self) -> Option<PmTimerInfo> { self.pm_timer }
+
+    /// Whether the aml namespace was loaded, without it the interrupt routing
+    /// and the sleep states are unknown
+    ///
+    /// In lazy aml mode the namespace can still fail to initialize on first use
+    pub fn aml_usable(&self) -> bool { self.aml_usable }
+
+    /// Gives the `AcpiReclaimable` memory back to the frame allocator, this
+    /// must be done after all the information needed from the tables was read
+    /// since they can't be accessed afterwards
+    ///
+    /// The aml namespace doesn't need the tables so it can still be used
+    pub fn reclaim_memory(&mut self) {
+        if self.reclaimed {
+            return;
+        }
+
+        self.reclaimed = true;
+
+        let frames = unsafe { memory::reclaim_acpi() };
+
+        log::info!("Reclaimed {} KiB of ACPI memory", frames * 4);
+    }
+
+    /// Returns the sleep states the firmware supports and that can be entered
+    /// with [`Acpi::set_sleep_state`]
+    pub fn supported_sleep_states(&mut self) -> Vec<SleepState> {
+        SleepState::ALL
+            .iter()
+            .copied()
+            .filter(|state| self.supports_sleep_state(*state))
+            .collect()
+    }
+
+    pub fn supports_sleep_state(&mut self, state: SleepState) -> bool {
+        !state.needs_waking_vector() && self.sleep_types()[state as usize].is_some()
+    }
+
+    /// Returns the SLP_TYP values of the sleep states, they are evaluated on
+    /// the first call and cached
+    fn sleep_types(&mut self) -> [Option<(u16, u16)>; SleepState::ALL.len()] {
+        if let Some(types) = self.sleep_types {
+            return types;
+        }
+
+        let mut types = [None; SleepState::ALL.len()];
+
+        for state in SleepState::ALL.iter().copied() {
+            types[state as usize] = self.get_sleep_state(state);
+            log::debug!("Sleep state {:?}: {:?}", state, types[state as usize]);
+        }
+
+        self.sleep_types = Some(types);
+
+        types
     }
 
     fn get_sleep_state(&mut self, state: SleepState) -> Option<(u16, u16)> {
         if let AmlValue::Package(items) = self
-            .aml_context
             .invoke_method(&state.as_aml_name(), Args::default())
             .ok()?
         {
-            let res = items[0].as_integer(&self.aml_context).unwrap();
+            let res = items.first()?.as_integer(&self.aml_context).ok()?;
 
             return Some(((res as u16) & 0b111, (res >> 8) as u16 & 0b111));
         }
@@ -296,5 +509,76 @@ impl Acpi {
         None
     }
 
-    pub fn aml_context(&mut self) -> &mut AmlContext { &mut self.aml_context }
+    /// Invokes an aml method within the limits of [`budget`], a method that
+    /// runs out of time returns [`BudgetError::TimedOut`] even if it finished
+    /// since its result can't be trusted
+    pub fn invoke_method(&mut self, name: &AmlName, args: Args) -> Result<AmlValue, InvokeError> {
+        let guard = budget::enter().map_err(InvokeError::Budget)?;
+
+        let result = self.aml_context().invoke_method(name, args);
+
+        if guard.expired() {
+            log::warn!("The aml method {} ran out of time", name.as_string());
+            taint::add(Taint::FIRMWARE_BUG);
+            return Err(InvokeError::Budget(BudgetError::TimedOut));
+        }
+
+        result.map_err(InvokeError::Aml)
+    }
+
+    /// Returns the aml namespace, loading it first if it's still pending
+    pub fn aml_context(&mut self) -> &mut AmlContext {
+        if self.pending_ssdts.is_some() {
+            // Outside strict mode the errors are only logged
+            let _ = self.load_aml(false);
+        }
+
+        &mut self.aml_context
+    }
+
+    /// Parses the pending SSDTs and initializes the aml objects, in strict mode
+    /// any error aborts the loading
+    fn load_aml(&mut self, strict: bool) -> Result<(), AmlError> {
+        let ssdts = match self.pending_ssdts.take() {
+            Some(ssdts) => ssdts,
+            None => return Ok(()),
+        };
+
+        let start = time::now();
+        let _stage = boot::stage("aml load");
+
+        for ssdt in ssdts {
+            log::debug!("Reading a ssdt");
+
+            match self.aml_context.parse_table(&ssdt[SDT_HEADER_SIZE..]) {
+                Ok(()) => {},
+                Err(e) if strict => return Err(e),
+                Err(e) => {
+                    log::warn!("Failed to parse a ssdt, skipping it: {:?}", e);
+                    taint::add(Taint::FIRMWARE_BUG);
+                },
+            }
+        }
+
+        if !self.aml_usable {
+            log::warn!("The aml namespace is unusable");
+            return Ok(());
+        }
+
+        log::trace!("Starting the aml objects init");
+
+        match self.aml_context.initialize_objects() {
+            Ok(()) => {},
+            Err(e) if strict => return Err(e),
+            Err(e) => {
+                log::warn!("Failed to init the aml objects: {:?}", e);
+                taint::add(Taint::FIRMWARE_BUG);
+                self.aml_usable = false;
+            },
+        }
+
+        log::debug!("Loaded the aml namespace in {:?}", time::now() - start);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,92 @@
+//! Limits on the aml methods
+//!
+//! The interpreter can't be interrupted so a method that never returns would
+//! hang the caller forever. Methods get a time budget, once it runs out the
+//! hardware accesses made by the method fail (reads return all ones like a
+//! missing device and writes are dropped) which breaks the polling loops that
+//! are the usual cause of hangs.
+use crate::time;
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+/// How long a method can run before its hardware accesses start failing
+pub const BUDGET: Duration = Duration::from_millis(500);
+
+/// How many methods can be invoked from inside other methods
+pub const MAX_DEPTH: usize = 8;
+
+/// The deadline of the outermost running method in nanoseconds, 0 if no
+/// method is running
+static DEADLINE: AtomicU64 = AtomicU64::new(0);
+
+static DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BudgetError {
+    /// The method ran for longer than [`BUDGET`]
+    TimedOut,
+    /// More than [`MAX_DEPTH`] methods were nested
+    TooDeep,
+}
+
+impl fmt::Display for BudgetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BudgetError::TimedOut => write!(f, "timed out after {:?}", BUDGET),
+            BudgetError::TooDeep => write!(f, "nested more than {} methods", MAX_DEPTH),
+        }
+    }
+}
+
+/// A running method, the budget is released when dropped
+pub struct Guard {
+    deadline: u64,
+    outer_deadline: u64,
+}
+
+impl Guard {
+    /// Whether the method ran past its deadline
+    pub fn expired(&self) -> bool { now_nanos() >= self.deadline }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        DEADLINE.store(self.outer_deadline, Ordering::SeqCst);
+        DEPTH.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Starts the budget of a method, nested methods share the deadline of the
+/// outermost one
+pub fn enter() -> Result<Guard, BudgetError> {
+    if DEPTH.fetch_add(1, Ordering::SeqCst) >= MAX_DEPTH {
+        DEPTH.fetch_sub(1, Ordering::SeqCst);
+        return Err(BudgetError::TooDeep);
+    }
+
+    let outer_deadline = DEADLINE.load(Ordering::SeqCst);
+    let deadline = match outer_deadline {
+        0 => now_nanos() + BUDGET.as_nanos() as u64,
+        outer => outer,
+    };
+
+    DEADLINE.store(deadline, Ordering::SeqCst);
+
+    Ok(Guard {
+        deadline,
+        outer_deadline,
+    })
+}
+
+/// Whether the running method is out of time
+pub fn expired() -> bool {
+    match DEADLINE.load(Ordering::SeqCst) {
+        0 => false,
+        deadline => now_nanos() >= deadline,
+    }
+}
+
+fn now_nanos() -> u64 { time::now().as_nanos() as u64 }
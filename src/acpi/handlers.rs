@@ -1,4 +1,4 @@
-use super::LockedHandler;
+use super::{budget, LockedHandler};
 use crate::pci;
 use acpi::{AcpiHandler, PhysicalMapping};
 use aml::Handler as AmlHandler;
@@ -6,7 +6,7 @@ use core::ptr::NonNull;
 use pci_types::PciAddress;
 use x86_64::{
     structures::{
-        paging::{Page, PhysFrame},
+        paging::PhysFrame,
         port::{PortRead, PortWrite},
     },
     PhysAddr, VirtAddr,
@@ -23,16 +23,15 @@ impl AcpiHandler for LockedHandler {
         let start = PhysFrame::containing_address(PhysAddr::new(physical_address as u64));
         let end = PhysFrame::containing_address(PhysAddr::new((physical_address + size) as u64));
 
-        for frame in PhysFrame::range_inclusive(start, end) {
-            self.map(frame)
-        }
-
         let mapped_length =
             (end.start_address().as_u64() + 0x1000 - start.start_address().as_u64()) as usize;
 
+        let mapping = self.map(start.start_address(), mapped_length);
+        let offset = physical_address as u64 - start.start_address().as_u64();
+
         PhysicalMapping {
             physical_start: start.start_address().as_u64() as usize,
-            virtual_start: NonNull::new_unchecked(physical_address as *mut _),
+            virtual_start: NonNull::new_unchecked((mapping + offset).as_mut_ptr()),
             region_length: size,
             mapped_length,
             handler: self.clone(),
@@ -46,60 +45,72 @@ impl AcpiHandler for LockedHandler {
             region.mapped_length
         );
 
-        let start = Page::from_start_address(VirtAddr::new(region.physical_start as u64)).unwrap();
-        let end = Page::from_start_address(VirtAddr::new(
-            (region.physical_start + region.mapped_length) as u64,
-        ))
-        .unwrap();
-
-        for page in Page::range(start, end) {
-            self.unmap(page)
-        }
+        self.unmap(VirtAddr::from_ptr(region.virtual_start.as_ptr()))
     }
 }
 
 impl AmlHandler for LockedHandler {
-    fn read_u8(&self, address: usize) -> u8 { unsafe { self.read(address) } }
+    fn read_u8(&self, address: usize) -> u8 { access(|| unsafe { self.read(address) }, u8::MAX) }
 
-    fn read_u16(&self, address: usize) -> u16 { unsafe { self.read(address) } }
+    fn read_u16(&self, address: usize) -> u16 { access(|| unsafe { self.read(address) }, u16::MAX) }
 
-    fn read_u32(&self, address: usize) -> u32 { unsafe { self.read(address) } }
+    fn read_u32(&self, address: usize) -> u32 { access(|| unsafe { self.read(address) }, u32::MAX) }
 
-    fn read_u64(&self, address: usize) -> u64 { unsafe { self.read(address) } }
+    fn read_u64(&self, address: usize) -> u64 { access(|| unsafe { self.read(address) }, u64::MAX) }
 
-    fn write_u8(&mut self, address: usize, value: u8) { unsafe { self.write(address, value) } }
+    fn write_u8(&mut self, address: usize, value: u8) {
+        access(|| unsafe { self.write(address, value) }, ())
+    }
 
-    fn write_u16(&mut self, address: usize, value: u16) { unsafe { self.write(address, value) } }
+    fn write_u16(&mut self, address: usize, value: u16) {
+        access(|| unsafe { self.write(address, value) }, ())
+    }
 
-    fn write_u32(&mut self, address: usize, value: u32) { unsafe { self.write(address, value) } }
+    fn write_u32(&mut self, address: usize, value: u32) {
+        access(|| unsafe { self.write(address, value) }, ())
+    }
 
-    fn write_u64(&mut self, address: usize, value: u64) { unsafe { self.write(address, value) } }
+    fn write_u64(&mut self, address: usize, value: u64) {
+        access(|| unsafe { self.write(address, value) }, ())
+    }
 
-    fn read_io_u8(&self, port: u16) -> u8 { unsafe { u8::read_from_port(port) } }
+    fn read_io_u8(&self, port: u16) -> u8 {
+        access(|| unsafe { u8::read_from_port(port) }, u8::MAX)
+    }
 
-    fn read_io_u16(&self, port: u16) -> u16 { unsafe { u16::read_from_port(port) } }
+    fn read_io_u16(&self, port: u16) -> u16 {
+        access(|| unsafe { u16::read_from_port(port) }, u16::MAX)
+    }
 
-    fn read_io_u32(&self, port: u16) -> u32 { unsafe { u32::read_from_port(port) } }
+    fn read_io_u32(&self, port: u16) -> u32 {
+        access(|| unsafe { u32::read_from_port(port) }, u32::MAX)
+    }
 
-    fn write_io_u8(&self, port: u16, value: u8) { unsafe { u8::write_to_port(port, value) } }
+    fn write_io_u8(&self, port: u16, value: u8) {
+        access(|| unsafe { u8::write_to_port(port, value) }, ())
+    }
 
-    fn write_io_u16(&self, port: u16, value: u16) { unsafe { u16::write_to_port(port, value) } }
+    fn write_io_u16(&self, port: u16, value: u16) {
+        access(|| unsafe { u16::write_to_port(port, value) }, ())
+    }
 
-    fn write_io_u32(&self, port: u16, value: u32) { unsafe { u32::write_to_port(port, value) } }
+    fn write_io_u32(&self, port: u16, value: u32) {
+        access(|| unsafe { u32::write_to_port(port, value) }, ())
+    }
 
     fn read_pci_u8(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u8 {
         let address = PciAddress::new(segment, bus, device, function);
-        unsafe { (pci::read(address, offset & 0xFFFC) >> (offset % 4) * 8) as u8 }
+        access(|| unsafe { pci::read_u8(address, offset) }, u8::MAX)
     }
 
     fn read_pci_u16(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u16 {
         let address = PciAddress::new(segment, bus, device, function);
-        unsafe { (pci::read(address, offset & 0xFFFC) >> (offset % 2) * 16) as u16 }
+        access(|| unsafe { pci::read_u16(address, offset) }, u16::MAX)
     }
 
     fn read_pci_u32(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u32 {
         let address = PciAddress::new(segment, bus, device, function);
-        unsafe { pci::read(address, offset) }
+        access(|| unsafe { pci::read(address, offset) }, u32::MAX)
     }
 
     fn write_pci_u8(
@@ -112,7 +123,7 @@ impl AmlHandler for LockedHandler {
         value: u8,
     ) {
         let address = PciAddress::new(segment, bus, device, function);
-        unsafe { pci::write(address, offset & 0xFFFC, (value as u32) << (offset % 4) * 8) }
+        access(|| unsafe { pci::write_u8(address, offset, value) }, ())
     }
 
     fn write_pci_u16(
@@ -125,13 +136,7 @@ impl AmlHandler for LockedHandler {
         value: u16,
     ) {
         let address = PciAddress::new(segment, bus, device, function);
-        unsafe {
-            pci::write(
-                address,
-                offset & 0xFFFC,
-                (value as u32) << (offset % 2) * 16,
-            )
-        }
+        access(|| unsafe { pci::write_u16(address, offset, value) }, ())
     }
 
     fn write_pci_u32(
@@ -144,6 +149,16 @@ impl AmlHandler for LockedHandler {
         value: u32,
     ) {
         let address = PciAddress::new(segment, bus, device, function);
-        unsafe { pci::write(address, offset, value) }
+        access(|| unsafe { pci::write(address, offset, value) }, ())
+    }
+}
+
+/// Runs a hardware access for the interpreter, once the running method is out
+/// of time the access is skipped and `expired` is returned
+fn access<T>(f: impl FnOnce() -> T, expired: T) -> T {
+    if budget::expired() {
+        expired
+    } else {
+        f()
     }
 }
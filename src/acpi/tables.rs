@@ -0,0 +1,202 @@
+//! Validation of the system description tables
+//!
+//! The firmware can't be trusted so every table is checked before use, the
+//! tables that fail the checks are dropped and the rest of the kernel behaves
+//! as if they weren't present.
+use super::LockedHandler;
+use acpi::{
+    fadt::Fadt, platform::address::AddressSpace, sdt::Signature, AcpiError, AcpiHandler,
+    AcpiTables, AmlTable,
+};
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Size of the header shared by all the system description tables
+pub const SDT_HEADER_SIZE: usize = 36;
+
+/// Offset of the length field in the header
+const LENGTH_OFFSET: usize = 4;
+/// Offset of the revision field in the header
+const REVISION_OFFSET: usize = 8;
+
+/// The oldest revision of each table that can be parsed
+const MIN_REVISIONS: &[(Signature, u8)] = &[
+    (Signature::FADT, 1),
+    (Signature::MADT, 1),
+    (Signature::HPET, 1),
+    (Signature::MCFG, 1),
+];
+
+#[derive(Debug)]
+pub enum TableError {
+    /// The table isn't present or was rejected
+    Missing(Signature),
+    /// The bytes of the table don't add up to zero
+    BadChecksum,
+    /// The length in the header doesn't match the length the table was found
+    /// with or is too small to hold the header
+    BadLength { header: u32, expected: u32 },
+    /// The table is older than the revision that can be parsed
+    UnsupportedRevision { revision: u8, min: u8 },
+    /// The acpi crate failed to parse the table
+    Acpi(AcpiError),
+}
+
+impl fmt::Display for TableError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TableError::Missing(signature) => write!(f, "table {:?} not found", signature),
+            TableError::BadChecksum => write!(f, "invalid checksum"),
+            TableError::BadLength { header, expected } => write!(
+                f,
+                "header length {} doesn't match the expected {}",
+                header, expected
+            ),
+            TableError::UnsupportedRevision { revision, min } => write!(
+                f,
+                "revision {} is older than the supported {}",
+                revision, min
+            ),
+            TableError::Acpi(e) => write!(f, "{:?}", e),
+        }
+    }
+}
+
+impl From<AcpiError> for TableError {
+    fn from(e: AcpiError) -> Self { TableError::Acpi(e) }
+}
+
+/// Checks all the tables found by the acpi crate and removes the invalid ones,
+/// returns the error of the first invalid table
+pub fn validate(
+    handler: &LockedHandler,
+    tables: &mut AcpiTables<LockedHandler>,
+) -> Result<(), TableError> {
+    let mut rejected = Vec::new();
+    let mut first_error = None;
+
+    for (signature, sdt) in tables.sdts.iter() {
+        let result = copy_table(handler, sdt.physical_address, sdt.length).and_then(|table| {
+            let min = MIN_REVISIONS
+                .iter()
+                .find(|(sig, _)| sig == signature)
+                .map_or(0, |(_, min)| *min);
+
+            check_revision(&table, min)
+        });
+
+        if let Err(e) = result {
+            log::warn!("Rejecting the {:?} table: {}", signature, e);
+            rejected.push(*signature);
+            first_error.get_or_insert(e);
+        }
+    }
+
+    for signature in rejected {
+        tables.sdts.remove(&signature);
+    }
+
+    first_error.map_or(Ok(()), Err)
+}
+
+/// Copies an aml table including its header to the heap after validating it,
+/// the table is only mapped while it's being copied
+pub fn copy_aml_table(handler: &LockedHandler, table: &AmlTable) -> Result<Vec<u8>, TableError> {
+    copy_table(
+        handler,
+        table.address - SDT_HEADER_SIZE,
+        table.length + SDT_HEADER_SIZE as u32,
+    )
+}
+
+/// The fields of the FADT needed for power management
+pub struct FadtInfo {
+    pub acpi_enable: u8,
+    pub smi_cmd_port: u16,
+    pub pm1a_cnt: u16,
+    pub pm1b_cnt: Option<u16>,
+    pub pm_timer: Option<PmTimerInfo>,
+}
+
+/// Where the power management timer is
+#[derive(Debug, Clone, Copy)]
+pub struct PmTimerInfo {
+    pub address: u64,
+    /// The timer is memory mapped instead of in port space
+    pub mmio: bool,
+    /// The counter is 32 bits wide instead of 24
+    pub wide: bool,
+}
+
+pub fn read_fadt(tables: &AcpiTables<LockedHandler>) -> Result<FadtInfo, TableError> {
+    let fadt = unsafe { tables.get_sdt::<Fadt>(Signature::FADT)? }
+        .ok_or(TableError::Missing(Signature::FADT))?;
+
+    // Todo: check for address space (we assume port space)
+    let pm1a_cnt = fadt.pm1a_control_block()?.address as u16;
+    let pm1b_cnt = fadt
+        .pm1b_control_block()?
+        .filter(|cnt| cnt.address != 0)
+        .map(|cnt| cnt.address as u16);
+    let pm_timer = fadt
+        .pm_timer_block()?
+        .filter(|block| block.address != 0)
+        .map(|block| PmTimerInfo {
+            address: block.address,
+            mmio: block.address_space == AddressSpace::SystemMemory,
+            wide: fadt.flags.pm_timer_is_32_bit(),
+        });
+
+    Ok(FadtInfo {
+        acpi_enable: fadt.acpi_enable,
+        smi_cmd_port: fadt.smi_cmd_port as u16,
+        pm1a_cnt,
+        pm1b_cnt,
+        pm_timer,
+    })
+}
+
+/// Copies the `length` bytes of the table at `address` checking the length in
+/// its header and its checksum
+fn copy_table(handler: &LockedHandler, address: usize, length: u32) -> Result<Vec<u8>, TableError> {
+    if (length as usize) < SDT_HEADER_SIZE {
+        return Err(TableError::BadLength {
+            header: length,
+            expected: SDT_HEADER_SIZE as u32,
+        });
+    }
+
+    let copy = unsafe {
+        let mapping = handler.map_physical_region::<u8>(address, length as usize);
+        let bytes = core::slice::from_raw_parts(mapping.virtual_start.as_ptr(), length as usize);
+
+        bytes.to_vec()
+    };
+
+    let mut header_length = [0; 4];
+    header_length.copy_from_slice(&copy[LENGTH_OFFSET..LENGTH_OFFSET + 4]);
+    let header_length = u32::from_le_bytes(header_length);
+
+    if header_length != length {
+        return Err(TableError::BadLength {
+            header: header_length,
+            expected: length,
+        });
+    }
+
+    if copy.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+        return Err(TableError::BadChecksum);
+    }
+
+    Ok(copy)
+}
+
+fn check_revision(table: &[u8], min: u8) -> Result<(), TableError> {
+    let revision = table[REVISION_OFFSET];
+
+    if revision < min {
+        return Err(TableError::UnsupportedRevision { revision, min });
+    }
+
+    Ok(())
+}
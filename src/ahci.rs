@@ -1,8 +1,60 @@
+use crate::{
+    device::{DeviceId, Driver},
+    dma::DmaAddr,
+    pci::{self, ConfigSnapshot, PowerState},
+};
 use bitflags::bitflags;
 use core::{
     fmt::{self, Debug},
     mem::MaybeUninit,
 };
+use pci_types::PciAddress;
+use spin::Mutex;
+
+/// Saves the controller config space and powers it down while the system
+/// sleeps
+pub struct AhciDriver {
+    address: PciAddress,
+    snapshot: Mutex<Option<ConfigSnapshot>>,
+}
+
+impl AhciDriver {
+    pub fn new(address: PciAddress) -> Self {
+        AhciDriver {
+            address,
+            snapshot: Mutex::new(None),
+        }
+    }
+}
+
+impl Driver for AhciDriver {
+    fn name(&self) -> &'static str { "ahci" }
+
+    fn suspend(&self, _device: DeviceId) -> Result<(), &'static str> {
+        *self.snapshot.lock() = Some(ConfigSnapshot::save(self.address));
+
+        // Not all controllers support the power management capability, they
+        // just stay powered
+        unsafe { pci::set_power_state(self.address, PowerState::D3Hot) };
+
+        Ok(())
+    }
+
+    fn resume(&self, _device: DeviceId) -> Result<(), &'static str> {
+        let snapshot = self
+            .snapshot
+            .lock()
+            .take()
+            .ok_or("resumed without being suspended")?;
+
+        unsafe {
+            pci::set_power_state(self.address, PowerState::D0);
+            snapshot.restore();
+        }
+
+        Ok(())
+    }
+}
 
 pub const ATA_SIGNATURE: u32 = 0x00000101;
 pub const ATAPI_SIGNATURE: u32 = 0xEB140101;
@@ -108,6 +160,15 @@ impl HBACapabilities {
     pub fn number_of_cmd_slots(&self) -> u8 { ((self.bits() >> 8) & 0b11111) as u8 }
 
     pub fn if_speed(&self) -> InterfaceSpeed { InterfaceSpeed::from((self.bits() >> 20) & 0b1111) }
+
+    /// The width of the addresses the controller uses for dma
+    pub fn address_bits(&self) -> u8 {
+        if self.contains(HBACapabilities::SUPPORTS_64_ADDRESSES) {
+            64
+        } else {
+            32
+        }
+    }
 }
 
 bitflags! {
@@ -195,9 +256,10 @@ impl HBAPortRegisters {
     pub fn cmd_list_addr(&self) -> u64 { (self.clbu as u64) << 32 | self.clb as u64 }
 
     /// # Safety
-    /// The caller must assure that the address is only 64bit
-    /// if the ahci supports it and points to usable memory
-    pub unsafe fn set_cmd_list_addr(&mut self, addr: u64) {
+    /// The caller must assure that the address was mapped for the ahci, with
+    /// 64bit addresses only if it supports them
+    pub unsafe fn set_cmd_list_addr(&mut self, addr: DmaAddr) {
+        let addr = addr.as_u64();
         assert_eq!(addr & 0x3FF, 0, "Address must be 1K aligned");
 
         self.clb = addr as u32;
@@ -207,9 +269,10 @@ impl HBAPortRegisters {
     pub fn fis_addr(&self) -> u64 { (self.fbu as u64) << 32 | self.fb as u64 }
 
     /// # Safety
-    /// The caller must assure that the address is only 64bit
-    /// if the ahci supports it and points to usable memory
-    pub unsafe fn set_fb_list_addr(&mut self, addr: u64) {
+    /// The caller must assure that the address was mapped for the ahci, with
+    /// 64bit addresses only if it supports them
+    pub unsafe fn set_fb_list_addr(&mut self, addr: DmaAddr) {
+        let addr = addr.as_u64();
         assert_eq!(addr & 0x3FF, 0, "Address must be 1K aligned");
 
         self.fb = addr as u32;
@@ -1,44 +1,191 @@
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
 
 use buddy_system_allocator::LockedHeap;
 use x86_64::{
-    structures::paging::{mapper::MapToError, Page, PageTableFlags, Size4KiB},
+    structures::paging::{Page, PageTableFlags},
     VirtAddr,
 };
 
-use crate::memory;
+use crate::{
+    error::KError,
+    interrupts::irqoff,
+    memory::{self, layout, shrinker, vmm},
+};
 
-pub const HEAP_START: usize = 0x_4444_4444_0000;
+/// The initial size of the heap
 pub const HEAP_SIZE: usize = 500 * 1024; // 500 KiB
+/// The address space reserved for the heap, only the start of it is mapped
+const HEAP_RESERVED: u64 = layout::LEVEL_4_ENTRY;
+/// The heap grows, or the caches are shrunk if it can't, when the free heap
+/// memory drops below this
+pub const HEAP_LOW_WATERMARK: usize = HEAP_SIZE / 16;
+/// The least the heap grows by
+const GROW_STEP: usize = 256 * 1024;
 
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::new();
+static ALLOCATOR: Allocator = Allocator(LockedHeap::new());
+
+/// The heap allocator, under memory pressure it maps more of the reserved
+/// region and if that fails asks the caches to give back memory
+struct Allocator(LockedHeap);
+
+unsafe impl GlobalAlloc for Allocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        irqoff::might_alloc();
+
+        // The free memory is read under the same lock as the allocation
+        let (ptr, free) = {
+            let mut heap = self.0.lock();
+            let ptr = heap.alloc(layout).map_or(ptr::null_mut(), NonNull::as_ptr);
+
+            (ptr, heap.stats_total_bytes() - heap.stats_alloc_actual())
+        };
+
+        if ptr.is_null() {
+            // A block aligned to it's size always fits in twice it's size
+            let block = layout.size().max(layout.align()).next_power_of_two();
+
+            // Retry only if something was actually added or freed
+            if grow(block * 2) || shrinker::shrink(layout.size()) != 0 {
+                return self.0.alloc(layout);
+            }
+        } else if free < HEAP_LOW_WATERMARK && !grow(GROW_STEP) {
+            shrinker::shrink(HEAP_LOW_WATERMARK - free);
+        }
+
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        irqoff::might_alloc();
+
+        self.0.dealloc(ptr, layout)
+    }
+}
 
 pub static INITIALIZED: AtomicBool = AtomicBool::new(false);
 
-pub fn init_heap() -> Result<(), MapToError<Size4KiB>> {
+static HEAP_START: AtomicU64 = AtomicU64::new(0);
+/// The end of the mapped part of the heap
+static HEAP_END: AtomicU64 = AtomicU64::new(0);
+
+/// Set while the heap is growing so that the allocations made meanwhile don't
+/// grow it again
+static GROWING: AtomicBool = AtomicBool::new(false);
+
+pub fn init_heap() -> Result<(), KError> {
+    let heap_start = vmm::alloc_region("heap", HEAP_RESERVED, layout::LEVEL_4_ENTRY)?;
+
     let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
         let heap_end = heap_start + HEAP_SIZE - 1u64;
         let heap_start_page = Page::containing_address(heap_start);
         let heap_end_page = Page::containing_address(heap_end);
-        Page::range_inclusive(heap_start_page, heap_end_page)
+        Page::range(heap_start_page, heap_end_page + 1)
     };
 
     let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
 
-    memory::map_range(page_range, flags)?;
+    memory::map_range(
+        page_range,
+        flags,
+        memory::CacheMode::WriteBack,
+        memory::FrameOwner::Heap,
+    )?;
 
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        ALLOCATOR
+            .0
+            .lock()
+            .init(heap_start.as_u64() as usize, HEAP_SIZE);
     }
 
+    HEAP_START.store(heap_start.as_u64(), Ordering::Relaxed);
+    HEAP_END.store(heap_start.as_u64() + HEAP_SIZE as u64, Ordering::Relaxed);
     INITIALIZED.store(true, Ordering::SeqCst);
 
     Ok(())
 }
 
-pub fn stats() -> usize { ALLOCATOR.lock().stats_alloc_actual() }
+/// Returns where the heap starts, it's only valid after [`init_heap`]
+pub fn heap_start() -> VirtAddr { VirtAddr::new(HEAP_START.load(Ordering::Relaxed)) }
+
+/// Returns the size of the mapped part of the heap
+pub fn heap_size() -> usize {
+    (HEAP_END.load(Ordering::Relaxed) - HEAP_START.load(Ordering::Relaxed)) as usize
+}
+
+/// Maps at least `min` more bytes at the end of the heap and adds them to the
+/// allocator, returns false if it couldn't
+///
+/// The allocating code might be holding the paging context so it's only
+/// tried, which fails in that case.
+fn grow(min: usize) -> bool {
+    if !INITIALIZED.load(Ordering::Relaxed) || GROWING.swap(true, Ordering::Acquire) {
+        return false;
+    }
+
+    let size = (min.max(GROW_STEP) as u64 + 0xFFF) & !0xFFF;
+    let grown = grow_by(size);
+
+    GROWING.store(false, Ordering::Release);
+
+    grown
+}
+
+fn grow_by(size: u64) -> bool {
+    let start = HEAP_END.load(Ordering::Relaxed);
+
+    if start + size > HEAP_START.load(Ordering::Relaxed) + HEAP_RESERVED {
+        return false;
+    }
+
+    let mut ctx = match memory::PAGING_CTX.get().and_then(|ctx| ctx.try_lock()) {
+        Some(ctx) => ctx,
+        None => return false,
+    };
+
+    let first = Page::containing_address(VirtAddr::new(start));
+    let range = Page::range(first, first + size / 0x1000);
+
+    if memory::map_range_in(
+        &mut ctx,
+        range,
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+        memory::CacheMode::WriteBack,
+        memory::FrameOwner::Heap,
+    )
+    .is_err()
+    {
+        // Part of the range might have been mapped
+        memory::unmap_range_in(&mut ctx, range);
+        return false;
+    }
+
+    drop(ctx);
+
+    unsafe {
+        ALLOCATOR
+            .0
+            .lock()
+            .add_to_heap(start as usize, (start + size) as usize)
+    };
+    HEAP_END.store(start + size, Ordering::Relaxed);
+
+    true
+}
+
+pub fn stats() -> usize { ALLOCATOR.0.lock().stats_alloc_actual() }
+
+/// Returns the number of free bytes in the heap
+pub fn free() -> usize {
+    let heap = ALLOCATOR.0.lock();
+
+    heap.stats_total_bytes() - heap.stats_alloc_actual()
+}
 
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
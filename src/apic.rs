@@ -1,9 +1,15 @@
-use crate::{acpi::Acpi, interrupts, memory::mmap_dev};
+use crate::{
+    acpi::Acpi,
+    error::KError,
+    interrupts::{self, InterruptIndex},
+    memory::{map_mmio, CacheMode},
+    time,
+};
 use acpi::platform::Apic as ApicInfo;
 use alloc::vec::Vec;
 use aml::{value::Args, AmlName, AmlValue};
 use core::fmt;
-use x86_64::{structures::paging::PhysFrame, PhysAddr};
+use x86_64::PhysAddr;
 
 pub struct Apic {
     info: ApicInfo,
@@ -53,23 +59,51 @@ impl Apic {
 
         self.io_apics[idx].set_redir_entry(vector, entry)
     }
+
+    /// Routes an interrupt to the local apic with the id `apic_id`, the
+    /// entries are in physical destination mode
+    pub fn set_affinity(&mut self, vector: u8, apic_id: u8) {
+        let mut entry = self.get_entry(vector);
+
+        entry.set_logical_mode(false);
+        entry.set_destination_id(apic_id);
+
+        self.set_entry(vector, entry);
+    }
 }
 
+/// The size of the registers of the local apic, the ioapic ones are smaller
+const REGISTERS_SIZE: usize = 0x400;
+
+/// Maps the registers of a local apic or an ioapic, they stay mapped forever,
+/// and returns their address
+///
 /// # Safety
 /// The provided `base_address` must be valid
-unsafe fn lapic_handover(base_address: u64) {
-    mmap_dev(
-        PhysFrame::from_start_address(PhysAddr::new(base_address)).unwrap(),
-        false,
-    )
-    .expect("Failed to identity map");
-
-    interrupts::PICS.lock().apic_handover(base_address);
+unsafe fn map_registers(base_address: u64) -> Result<u64, KError> {
+    let registers = map_mmio(
+        PhysAddr::new(base_address),
+        REGISTERS_SIZE,
+        CacheMode::Uncached,
+    )?
+    .leak();
+
+    Ok(registers.as_u64())
 }
 
 /// Hands over control from the pic to the apic and the ioapic
-pub fn apic_init(acpi: &mut Acpi, info: ApicInfo) -> Apic {
-    x86_64::instructions::interrupts::without_interrupts(|| {
+///
+/// The registers are mapped before anything is changed so on error the pic is
+/// still in use
+pub fn apic_init(acpi: &mut Acpi, info: ApicInfo) -> Result<Apic, KError> {
+    let lapic_address = unsafe { map_registers(info.local_apic_address)? };
+    let io_apic_addresses = info
+        .io_apics
+        .iter()
+        .map(|io_apic| unsafe { map_registers(io_apic.address as u64) })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let apic = interrupts::irqoff::without_interrupts(|| {
         let args = Args {
             // 0 – PIC mode
             // 1 – APIC mode
@@ -80,30 +114,19 @@ pub fn apic_init(acpi: &mut Acpi, info: ApicInfo) -> Apic {
         };
 
         // Ignore the result since the method might not exist
-        let _ = acpi
-            .aml_context()
-            .invoke_method(&AmlName::from_str("\\_PIC").unwrap(), args);
-
-        unsafe { lapic_handover(info.local_apic_address) };
+        let _ = acpi.invoke_method(&AmlName::from_str("\\_PIC").unwrap(), args);
 
-        let mut io_apics = Vec::with_capacity(info.io_apics.len());
+        unsafe { interrupts::PICS.lock().apic_handover(lapic_address) };
 
-        for io_apic in info.io_apics.iter() {
-            let base_address = io_apic.address as u64;
-
-            unsafe {
-                mmap_dev(
-                    PhysFrame::from_start_address(PhysAddr::new(base_address)).unwrap(),
-                    false,
-                )
-                .expect("Failed to identity map");
-            }
-
-            io_apics.push(IOApic {
+        let io_apics = info
+            .io_apics
+            .iter()
+            .zip(io_apic_addresses)
+            .map(|(io_apic, base_address)| IOApic {
                 base_address,
                 base_interrupt: io_apic.global_system_interrupt_base as u8,
             })
-        }
+            .collect();
 
         let mut this = Apic { info, io_apics };
 
@@ -123,8 +146,22 @@ pub fn apic_init(acpi: &mut Acpi, info: ApicInfo) -> Apic {
 
         this.set_entry(1, entry);
 
+        // Set rtc interrupt
+        let mut entry = this.get_entry(8);
+
+        entry.set_vector(InterruptIndex::Rtc as u8);
+        entry.set_masked(false);
+
+        this.set_entry(8, entry);
+
         this
-    })
+    });
+
+    // The calibration needs the timer interrupt so it must run with
+    // interrupts enabled
+    unsafe { time::lapic::init(lapic_address) };
+
+    Ok(apic)
 }
 
 pub struct IOApic {
@@ -259,7 +296,7 @@ impl RedirEntry {
             DeliveryMode::Reserved => panic!("Cannot use a reserved mode"),
         };
 
-        self.0 ^= 0b111 << 8;
+        self.0 &= !(0b111 << 8);
         self.0 |= bits << 8;
     }
 
@@ -270,7 +307,7 @@ impl RedirEntry {
     }
 
     pub fn set_logical_mode(&mut self, mode: bool) {
-        self.0 ^= 0b1 << 11;
+        self.0 &= !(0b1 << 11);
         self.0 |= (mode as u64) << 11;
     }
 
@@ -287,7 +324,7 @@ impl RedirEntry {
 
     /// true for Low is active, false for High is active
     pub fn set_low_is_active(&mut self, mode: bool) {
-        self.0 ^= 0b1 << 13;
+        self.0 &= !(0b1 << 13);
         self.0 |= (mode as u64) << 13;
     }
 
@@ -306,7 +343,7 @@ impl RedirEntry {
 
     /// true for level sensitive, false for edge sensitive
     pub fn set_level_sensitive(&mut self, mode: bool) {
-        self.0 ^= 0b1 << 15;
+        self.0 &= !(0b1 << 15);
         self.0 |= (mode as u64) << 15;
     }
 
@@ -316,11 +353,16 @@ impl RedirEntry {
     }
 
     pub fn set_masked(&mut self, mode: bool) {
-        self.0 ^= 0b1 << 16;
+        self.0 &= !(0b1 << 16);
         self.0 |= (mode as u64) << 16;
     }
 
-    pub fn destination_id(&self) -> u8 { ((self.0 >> 56) & 0xF) as u8 }
+    pub fn destination_id(&self) -> u8 { ((self.0 >> 56) & 0xFF) as u8 }
+
+    pub fn set_destination_id(&mut self, id: u8) {
+        self.0 &= !(0xFF << 56);
+        self.0 |= (id as u64) << 56;
+    }
 }
 
 impl fmt::Debug for RedirEntry {
@@ -358,3 +400,19 @@ impl fmt::Debug for RedirEntry {
             .finish()
     }
 }
+
+#[test_case]
+fn redir_entry_fields_round_trip() {
+    let mut entry = RedirEntry(0);
+
+    entry.set_destination_id(0x10);
+    entry.set_logical_mode(false);
+    entry.set_masked(true);
+
+    assert_eq!(entry.destination_id(), 0x10);
+    assert!(!entry.logical_mode());
+    assert!(entry.masked());
+
+    entry.set_masked(false);
+    assert!(!entry.masked());
+}
@@ -0,0 +1,216 @@
+//! Boot progress reporting
+//!
+//! The init code reports when it reaches each [`Milestone`], by default this
+//! is shown as a progress bar on the screen instead of the full logs, which
+//! always go to the serial port.
+//!
+//! The init stages are also timed with the tsc, which can be read before
+//! anything else is initialized, [`profile_report`] lists the slowest ones.
+use crate::{
+    interrupts::irqoff,
+    serial_println,
+    time::tsc,
+    vga_buffer::{Color, ColorCode, WRITER},
+};
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use spin::Mutex;
+
+/// Width of the progress bar in columns
+const BAR_WIDTH: usize = 50;
+
+/// How many stages can be recorded, they're kept in a static array since
+/// most of them run before the heap is ready
+const MAX_STAGES: usize = 64;
+
+static PROFILE: Mutex<Profile> = Mutex::new(Profile {
+    stages: [None; MAX_STAGES],
+    len: 0,
+});
+
+struct Profile {
+    stages: [Option<Stage>; MAX_STAGES],
+    len: usize,
+}
+
+/// A finished init stage, in tsc cycles
+#[derive(Debug, Clone, Copy)]
+struct Stage {
+    name: &'static str,
+    start: u64,
+    end: u64,
+}
+
+/// A running init stage, it's recorded when dropped
+pub struct StageGuard {
+    name: &'static str,
+    start: u64,
+}
+
+impl Drop for StageGuard {
+    fn drop(&mut self) {
+        let stage = Stage {
+            name: self.name,
+            start: self.start,
+            end: tsc::read(),
+        };
+
+        irqoff::without_interrupts(|| {
+            let mut profile = PROFILE.lock();
+
+            if profile.len < MAX_STAGES {
+                let len = profile.len;
+
+                profile.stages[len] = Some(stage);
+                profile.len += 1;
+            }
+        })
+    }
+}
+
+/// There's no kernel command line yet so verbose mode is chosen at build
+/// time by setting `BOOT_VERBOSE`
+static VERBOSE: AtomicBool = AtomicBool::new(option_env!("BOOT_VERBOSE").is_some());
+
+/// The boot stages in the order they are reached
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Milestone {
+    Console,
+    Interrupts,
+    Timers,
+    Memory,
+    Heap,
+    Acpi,
+    InterruptController,
+    Pci,
+    Storage,
+    Done,
+}
+
+impl Milestone {
+    const COUNT: usize = Milestone::Done as usize + 1;
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Milestone::Console => "early console",
+            Milestone::Interrupts => "interrupts",
+            Milestone::Timers => "timers",
+            Milestone::Memory => "memory",
+            Milestone::Heap => "heap",
+            Milestone::Acpi => "acpi",
+            Milestone::InterruptController => "interrupt controller",
+            Milestone::Pci => "pci devices",
+            Milestone::Storage => "storage",
+            Milestone::Done => "done",
+        }
+    }
+}
+
+/// Whether the full logs are shown on the screen instead of the progress bar
+pub fn verbose() -> bool { VERBOSE.load(Ordering::Relaxed) }
+
+pub fn set_verbose(verbose: bool) { VERBOSE.store(verbose, Ordering::Relaxed) }
+
+/// Reports that the boot reached `milestone`
+pub fn milestone(milestone: Milestone) {
+    let step = milestone as usize + 1;
+
+    serial_println!(
+        "[boot] {}/{} {}",
+        step,
+        Milestone::COUNT,
+        milestone.description()
+    );
+
+    if !verbose() {
+        draw_progress(step, milestone.description());
+    }
+}
+
+/// Starts timing the init stage `name`, it ends when the guard is dropped
+pub fn stage(name: &'static str) -> StageGuard {
+    StageGuard {
+        name,
+        start: tsc::read(),
+    }
+}
+
+/// Runs `f` as the init stage `name`
+pub fn profile<R>(name: &'static str, f: impl FnOnce() -> R) -> R {
+    let _stage = stage(name);
+
+    f()
+}
+
+/// Lists the recorded stages from the slowest to the fastest, nested stages
+/// are included in the time of their parents
+pub fn profile_report() -> impl fmt::Display {
+    struct Report {
+        stages: [Option<Stage>; MAX_STAGES],
+    }
+
+    impl fmt::Display for Report {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            for stage in self.stages.iter().flatten() {
+                let cycles = stage.end - stage.start;
+
+                match tsc::cycles_to_duration(cycles) {
+                    Some(duration) => {
+                        writeln!(f, "{:>12} us     {}", duration.as_micros(), stage.name)?
+                    },
+                    None => writeln!(f, "{:>12} cycles {}", cycles, stage.name)?,
+                }
+            }
+
+            Ok(())
+        }
+    }
+
+    let mut stages = irqoff::without_interrupts(|| PROFILE.lock().stages);
+
+    // Unstable sort since the stable one allocates
+    stages.sort_unstable_by_key(|stage| {
+        core::cmp::Reverse(stage.map_or(0, |stage| stage.end - stage.start))
+    });
+
+    Report { stages }
+}
+
+/// Draws the progress bar on the first row of the screen
+fn draw_progress(step: usize, description: &str) {
+    let filled = step * BAR_WIDTH / Milestone::COUNT;
+
+    irqoff::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let color_code = writer.color_code();
+
+        writer.set_color_code(ColorCode::new(Color::White, Color::Blue));
+        writer.clear_region(0..1, 0..80);
+        writer.write_at(0, 1, "[");
+
+        for col in 0..BAR_WIDTH {
+            writer.write_at(0, 2 + col, if col < filled { "#" } else { "-" });
+        }
+
+        writer.write_at(0, 2 + BAR_WIDTH, "]");
+        writer.write_at(0, 4 + BAR_WIDTH, description);
+
+        writer.set_color_code(color_code);
+    })
+}
+
+#[test_case]
+fn progress_bar_layout() {
+    use crate::vga_buffer;
+
+    let snapshot = vga_buffer::capture(|| draw_progress(5, Milestone::Heap.description()));
+
+    let bar = ["#".repeat(25), "-".repeat(25)].concat();
+    assert_eq!(snapshot.row(0), alloc::format!(" [{}]  heap", bar));
+    assert_eq!(
+        snapshot.color_at(0, 79),
+        ColorCode::new(Color::White, Color::Blue)
+    );
+}
@@ -0,0 +1,336 @@
+//! Cpu frequency scaling
+//!
+//! The performance states are read from the `_PSS` object of the processor
+//! and switched through the registers described by `_PCT`. By default the
+//! ondemand governor picks the state from the load of the cpu, which is the
+//! fraction of time it wasn't idle, the state can also be fixed with
+//! [`set_policy`].
+//!
+//! There's no scheduler yet so the load comes from the idle time accounted by
+//! [`cpuidle`], sampled every [`SAMPLING_PERIOD`].
+use crate::{
+    acpi::{self, GenericRegister, PROCESSOR_PATHS},
+    cpuidle,
+    interrupts::irqoff,
+    time,
+};
+use alloc::vec::Vec;
+use aml::{value::Args, AmlContext, AmlName, AmlValue};
+use core::{arch::x86_64::__cpuid, fmt, time::Duration};
+use spin::Mutex;
+use x86_64::{
+    instructions::port::{PortRead, PortWrite},
+    registers::model_specific::Msr,
+};
+
+const IA32_PERF_STATUS: u32 = 0x198;
+const IA32_PERF_CTL: u32 = 0x199;
+
+/// Cpuid 1 ecx bit signaling enhanced speedstep support
+const CPUID_EIST: u32 = 1 << 7;
+
+/// Fixed hardware class of the native performance control
+const FIXED_HW_CLASS_PERF: u8 = 0;
+
+/// How often the governor samples the load
+const SAMPLING_PERIOD: Duration = Duration::from_millis(100);
+/// Above this load in percent the fastest state is used
+const UP_THRESHOLD: u64 = 80;
+
+static CPUFREQ: Mutex<Option<Cpufreq>> = Mutex::new(None);
+
+/// A performance state
+#[derive(Debug, Clone, Copy)]
+pub struct PState {
+    pub frequency_mhz: u64,
+    pub power_mw: u64,
+    /// How long the cpu is unavailable while switching to the state
+    pub latency: Duration,
+    /// The value written to the control register
+    control: u64,
+    /// The value of the status register while the cpu is in the state
+    status: u64,
+}
+
+impl fmt::Display for PState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} MHz {} mW (latency {:?})",
+            self.frequency_mhz, self.power_mw, self.latency
+        )
+    }
+}
+
+/// How the performance state is chosen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// The state follows the load
+    Ondemand,
+    /// Always use the state with this index
+    Fixed(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpufreqError {
+    /// The firmware doesn't describe any performance states
+    Unsupported,
+    /// There's no state with the index
+    InvalidState,
+}
+
+impl fmt::Display for CpufreqError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CpufreqError::Unsupported => write!(f, "frequency scaling isn't supported"),
+            CpufreqError::InvalidState => write!(f, "invalid performance state"),
+        }
+    }
+}
+
+/// How the control or the status register is accessed
+#[derive(Debug, Clone, Copy)]
+enum Control {
+    Msr,
+    Io { port: u16, width: u8 },
+}
+
+struct Cpufreq {
+    /// Ordered from the fastest to the slowest like in `_PSS`
+    states: Vec<PState>,
+    control: Control,
+    current: usize,
+    policy: Policy,
+    /// The time and the idle time when the load was last sampled
+    last_sample: (Duration, Duration),
+}
+
+impl Cpufreq {
+    fn set_state(&mut self, index: usize) {
+        if index == self.current {
+            return;
+        }
+
+        let control = self.states[index].control;
+
+        match self.control {
+            Control::Msr => unsafe {
+                let mut msr = Msr::new(IA32_PERF_CTL);
+                let value = msr.read();
+
+                msr.write((value & !0xFFFF) | (control & 0xFFFF));
+            },
+            Control::Io { port, width } => unsafe {
+                match width {
+                    8 => u8::write_to_port(port, control as u8),
+                    16 => u16::write_to_port(port, control as u16),
+                    _ => u32::write_to_port(port, control as u32),
+                }
+            },
+        }
+
+        self.current = index;
+    }
+
+    /// Picks the slowest state that can handle the load
+    fn ondemand(&mut self, load: u64) {
+        let target = if load >= UP_THRESHOLD {
+            0
+        } else {
+            let max = self.states[0].frequency_mhz;
+            let wanted = max * load / UP_THRESHOLD;
+
+            self.states
+                .iter()
+                .rposition(|state| state.frequency_mhz >= wanted)
+                .unwrap_or(0)
+        };
+
+        self.set_state(target)
+    }
+}
+
+/// Reads the performance states of the cpu and starts the governor
+pub fn init() {
+    let cpufreq = match acpi::with_acpi(read_states).flatten() {
+        Some(cpufreq) => cpufreq,
+        None => {
+            log::info!("No performance states available");
+            return;
+        },
+    };
+
+    for (i, state) in cpufreq.states.iter().enumerate() {
+        log::info!("P{}: {}", i, state);
+    }
+
+    irqoff::without_interrupts(|| *CPUFREQ.lock() = Some(cpufreq));
+
+    time::timer::after(SAMPLING_PERIOD, sample);
+}
+
+/// Returns the available states from the fastest to the slowest
+pub fn states() -> Vec<PState> {
+    irqoff::without_interrupts(|| {
+        CPUFREQ
+            .lock()
+            .as_ref()
+            .map_or(Vec::new(), |cpufreq| cpufreq.states.clone())
+    })
+}
+
+/// Returns the index of the state in use
+pub fn current_state() -> Option<usize> {
+    irqoff::without_interrupts(|| CPUFREQ.lock().as_ref().map(|cpufreq| cpufreq.current))
+}
+
+/// Changes how the performance state is chosen, a fixed state is applied
+/// immediately
+pub fn set_policy(policy: Policy) -> Result<(), CpufreqError> {
+    irqoff::without_interrupts(|| {
+        let mut cpufreq = CPUFREQ.lock();
+        let cpufreq = cpufreq.as_mut().ok_or(CpufreqError::Unsupported)?;
+
+        if let Policy::Fixed(index) = policy {
+            if index >= cpufreq.states.len() {
+                return Err(CpufreqError::InvalidState);
+            }
+
+            cpufreq.set_state(index);
+        }
+
+        cpufreq.policy = policy;
+
+        Ok(())
+    })
+}
+
+/// Runs the governor and rearms itself
+fn sample() {
+    let now = time::now();
+    let idle = cpuidle::idle_time();
+
+    irqoff::without_interrupts(|| {
+        if let Some(ref mut cpufreq) = *CPUFREQ.lock() {
+            let (last_now, last_idle) = core::mem::replace(&mut cpufreq.last_sample, (now, idle));
+
+            let elapsed = (now - last_now).as_nanos() as u64;
+            let idle = (idle - last_idle).as_nanos() as u64;
+
+            if cpufreq.policy == Policy::Ondemand && elapsed != 0 {
+                let load = 100 - idle.min(elapsed) * 100 / elapsed;
+
+                cpufreq.ondemand(load);
+            }
+        }
+    });
+
+    time::timer::after(SAMPLING_PERIOD, sample);
+}
+
+/// Reads `_PCT` and `_PSS` from the first processor
+fn read_states(acpi: &mut acpi::Acpi) -> Option<Cpufreq> {
+    if !acpi.aml_usable() {
+        return None;
+    }
+
+    let (path, pct) = PROCESSOR_PATHS.iter().find_map(|path| {
+        let name = AmlName::from_str(&alloc::format!("{}._PCT", path)).ok()?;
+
+        Some((path, acpi.invoke_method(&name, Args::default()).ok()?))
+    })?;
+
+    let (control, status) = match pct {
+        AmlValue::Package(ref registers) => match (registers.get(0), registers.get(1)) {
+            (Some(AmlValue::Buffer(control)), Some(AmlValue::Buffer(status))) => (
+                parse_control(GenericRegister::parse(control)?)?,
+                parse_control(GenericRegister::parse(status)?)?,
+            ),
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    let name = AmlName::from_str(&alloc::format!("{}._PSS", path)).ok()?;
+    let pss = match acpi.invoke_method(&name, Args::default()).ok()? {
+        AmlValue::Package(entries) => entries,
+        _ => return None,
+    };
+
+    let states = pss
+        .iter()
+        .map(|entry| parse_pss_entry(entry, acpi.aml_context()))
+        .collect::<Option<Vec<_>>>()?;
+
+    if states.is_empty() {
+        return None;
+    }
+
+    // The status register has the status value of the current state
+    let current = match status {
+        Control::Msr => {
+            let status = unsafe { Msr::new(IA32_PERF_STATUS).read() } & 0xFFFF;
+
+            states
+                .iter()
+                .position(|state| state.status & 0xFFFF == status)
+                .unwrap_or(0)
+        },
+        Control::Io { port, width } => {
+            let status = unsafe {
+                match width {
+                    8 => u8::read_from_port(port) as u64,
+                    16 => u16::read_from_port(port) as u64,
+                    _ => u32::read_from_port(port) as u64,
+                }
+            };
+
+            states
+                .iter()
+                .position(|state| state.status == status)
+                .unwrap_or(0)
+        },
+    };
+
+    Some(Cpufreq {
+        states,
+        control,
+        current,
+        policy: Policy::Ondemand,
+        last_sample: (time::now(), cpuidle::idle_time()),
+    })
+}
+
+fn parse_control(register: GenericRegister) -> Option<Control> {
+    match register.address_space {
+        GenericRegister::FIXED_HARDWARE if register.bit_offset == FIXED_HW_CLASS_PERF => {
+            if unsafe { __cpuid(1) }.ecx & CPUID_EIST == 0 {
+                return None;
+            }
+
+            Some(Control::Msr)
+        },
+        GenericRegister::SYSTEM_IO => Some(Control::Io {
+            port: register.address as u16,
+            width: register.bit_width,
+        }),
+        _ => None,
+    }
+}
+
+/// Parses a `Package { Frequency, Power, Latency, BusMasterLatency, Control,
+/// Status }` entry of `_PSS`
+fn parse_pss_entry(entry: &AmlValue, context: &AmlContext) -> Option<PState> {
+    let fields = match entry {
+        AmlValue::Package(fields) if fields.len() >= 6 => fields,
+        _ => return None,
+    };
+
+    Some(PState {
+        frequency_mhz: fields[0].as_integer(context).ok()?,
+        power_mw: fields[1].as_integer(context).ok()?,
+        latency: Duration::from_micros(fields[2].as_integer(context).ok()?),
+        control: fields[4].as_integer(context).ok()?,
+        status: fields[5].as_integer(context).ok()?,
+    })
+}
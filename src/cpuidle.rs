@@ -0,0 +1,253 @@
+//! Cpu idle states
+//!
+//! Deeper c-states save more power but take longer to wake up from, the idle
+//! loop picks the deepest state that can wake up well before the next timer
+//! interrupt. The states are read from the `_CST` object of the processor, if
+//! there isn't one and the cpu supports `mwait` they are taken from cpuid.
+use crate::{
+    acpi::{self, GenericRegister, PROCESSOR_PATHS},
+    time,
+};
+use alloc::vec::Vec;
+use aml::{value::Args, AmlName, AmlValue};
+use core::{
+    arch::x86_64::__cpuid,
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use spin::Mutex;
+use x86_64::instructions::port::PortRead;
+
+/// A state is only entered if the cpu is expected to stay in it this many
+/// times longer than it takes to wake up
+const RESIDENCY_FACTOR: u32 = 3;
+
+const CPUID_MONITOR: u32 = 1 << 3;
+/// Cpuid leaf 5 ecx bit signaling that interrupts break out of mwait even if
+/// they are masked
+const CPUID_MWAIT_INTERRUPT_BREAK: u32 = 1 << 1;
+
+/// Fixed hardware class of native c-state instructions
+const FIXED_HW_CLASS_CSTATE: u8 = 2;
+
+/// Exit latencies of C1 through C7 for the states found through cpuid, which
+/// doesn't report them, these are conservative values for recent intel cpus
+const MWAIT_LATENCIES: [u64; 7] = [1, 20, 100, 150, 200, 250, 300];
+
+/// The cache line monitored by mwait, nothing ever writes to it so only
+/// interrupts wake the cpu up
+static MONITOR: AtomicU64 = AtomicU64::new(0);
+
+/// Total time spent idle in nanoseconds
+static IDLE_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// The known states ordered from the shallowest to the deepest
+static STATES: Mutex<Vec<CState>> = Mutex::new(Vec::new());
+
+/// How a c-state is entered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Entry {
+    Halt,
+    /// `mwait` with the hint in eax
+    Mwait(u32),
+    /// Reading one of the processor's P_LVLx ports
+    Io(u16),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CState {
+    /// The c-state number, 1 for C1
+    pub level: u8,
+    pub entry: Entry,
+    /// How long it takes to wake up from the state
+    pub latency: Duration,
+}
+
+impl fmt::Display for CState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "C{} ", self.level)?;
+
+        match self.entry {
+            Entry::Halt => write!(f, "hlt")?,
+            Entry::Mwait(hint) => write!(f, "mwait {:#X}", hint)?,
+            Entry::Io(port) => write!(f, "io {:#X}", port)?,
+        }
+
+        write!(f, " ({:?})", self.latency)
+    }
+}
+
+/// Finds the c-states supported by the cpu, until this is called only C1 is
+/// used
+pub fn init() {
+    let mut states = acpi::with_acpi(read_cst)
+        .flatten()
+        .unwrap_or_else(read_cpuid);
+
+    states.sort_by_key(|state| state.latency);
+
+    for state in states.iter() {
+        log::info!("Idle state {}", state);
+    }
+
+    *STATES.lock() = states;
+}
+
+/// Returns the known c-states from the shallowest to the deepest
+pub fn states() -> Vec<CState> { STATES.lock().clone() }
+
+/// Returns the total time the cpu spent idle since boot
+pub fn idle_time() -> Duration { Duration::from_nanos(IDLE_NANOS.load(Ordering::Relaxed)) }
+
+/// Sleeps until the next interrupt in the deepest state that can wake up in
+/// time for the next timer event
+///
+/// Interrupts must be enabled
+pub fn idle() {
+    let start = time::now();
+
+    time::tick_stop();
+    let predicted = time::next_event_in();
+
+    // Don't wait for the lock since this might be called from a panic
+    let state = STATES.try_lock().and_then(|states| {
+        states
+            .iter()
+            .rev()
+            .find(|state| state.latency * RESIDENCY_FACTOR <= predicted)
+            .copied()
+    });
+
+    match state.map(|state| state.entry) {
+        None | Some(Entry::Halt) => x86_64::instructions::hlt(),
+        Some(Entry::Mwait(hint)) => unsafe {
+            asm!(
+                "monitor",
+                in("rax") &MONITOR as *const _ as u64,
+                in("ecx") 0,
+                in("edx") 0,
+                options(nostack)
+            );
+            asm!("mwait", in("eax") hint, in("ecx") 1, options(nostack));
+        },
+        Some(Entry::Io(port)) => unsafe {
+            u8::read_from_port(port);
+        },
+    }
+
+    time::tick_restart();
+
+    IDLE_NANOS.fetch_add((time::now() - start).as_nanos() as u64, Ordering::Relaxed);
+}
+
+/// Reads the states from the `_CST` object of the first processor
+fn read_cst(acpi: &mut acpi::Acpi) -> Option<Vec<CState>> {
+    if !acpi.aml_usable() {
+        return None;
+    }
+
+    let cst = PROCESSOR_PATHS.iter().find_map(|path| {
+        let name = AmlName::from_str(&alloc::format!("{}._CST", path)).ok()?;
+
+        acpi.invoke_method(&name, Args::default()).ok()
+    })?;
+
+    let entries = match cst {
+        // The first element is the number of states
+        AmlValue::Package(entries) => entries,
+        _ => return None,
+    };
+
+    let mut states = Vec::new();
+
+    for entry in entries.iter().skip(1) {
+        match parse_cst_entry(entry, acpi.aml_context()) {
+            Some(state) => states.push(state),
+            None => log::warn!("Skipping an unsupported _CST entry"),
+        }
+    }
+
+    if states.is_empty() {
+        None
+    } else {
+        Some(states)
+    }
+}
+
+/// Parses a `Package { Register, Type, Latency, Power }` entry of `_CST`
+fn parse_cst_entry(entry: &AmlValue, context: &aml::AmlContext) -> Option<CState> {
+    let fields = match entry {
+        AmlValue::Package(fields) if fields.len() >= 3 => fields,
+        _ => return None,
+    };
+
+    let register = match fields[0] {
+        AmlValue::Buffer(ref register) => GenericRegister::parse(register)?,
+        _ => return None,
+    };
+    let level = fields[1].as_integer(context).ok()? as u8;
+    let latency = Duration::from_micros(fields[2].as_integer(context).ok()?);
+
+    let entry = match register.address_space {
+        GenericRegister::FIXED_HARDWARE
+            if register.bit_offset == FIXED_HW_CLASS_CSTATE && mwait_supported() =>
+        {
+            Entry::Mwait(register.address as u32)
+        },
+        _ if level == 1 => Entry::Halt,
+        // C3 through io needs the bus master arbitration to be disabled which
+        // isn't supported
+        GenericRegister::SYSTEM_IO if level == 2 => Entry::Io(register.address as u16),
+        _ => return None,
+    };
+
+    Some(CState {
+        level,
+        entry,
+        latency,
+    })
+}
+
+/// Builds the states from the mwait sub states enumerated by cpuid
+fn read_cpuid() -> Vec<CState> {
+    let mut states = alloc::vec![CState {
+        level: 1,
+        entry: Entry::Halt,
+        latency: Duration::from_micros(MWAIT_LATENCIES[0]),
+    }];
+
+    if !mwait_supported() {
+        return states;
+    }
+
+    // Each nibble of edx has the number of sub states of a c-state starting
+    // from C0
+    let sub_states = unsafe { __cpuid(5) }.edx;
+
+    for level in 2..=MWAIT_LATENCIES.len() as u8 {
+        if (sub_states >> (level * 4)) & 0xF == 0 {
+            continue;
+        }
+
+        states.push(CState {
+            level,
+            entry: Entry::Mwait(((level as u32 - 1) & 0xF) << 4),
+            latency: Duration::from_micros(MWAIT_LATENCIES[level as usize - 1]),
+        });
+    }
+
+    states
+}
+
+/// Whether `mwait` can be used to enter c-states, interrupts must be able to
+/// break out of it since it's entered without a monitored write
+fn mwait_supported() -> bool {
+    let features = unsafe { __cpuid(1) }.ecx;
+
+    if features & CPUID_MONITOR == 0 || unsafe { __cpuid(0) }.eax < 5 {
+        return false;
+    }
+
+    unsafe { __cpuid(5) }.ecx & CPUID_MWAIT_INTERRUPT_BREAK != 0
+}
@@ -0,0 +1,392 @@
+//! The device model
+//!
+//! Devices found by enumeration (pci, acpi, legacy probing) are registered in
+//! a tree under the bus they were found on, drivers record when they bind to
+//! them so the state of the hardware can be inspected in one place.
+//!
+//! Drivers initialize their devices through [`probe`], a probe that runs past
+//! [`PROBE_BUDGET`] and fails gets it's device quarantined so it's never
+//! probed again. Probes can't be preempted without threads, so a probe that
+//! hangs still hangs the boot and one that panics still brings the kernel
+//! down.
+use crate::{interrupts::irqoff, serial_println, time};
+use alloc::{string::String, vec::Vec};
+use core::{
+    fmt,
+    ops::Range,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::Duration,
+};
+use spin::Mutex;
+
+pub type DeviceId = usize;
+
+/// The root of the tree, registered by [`init`]
+pub const ROOT: DeviceId = 0;
+/// The bus of the legacy devices that aren't enumerated, registered by
+/// [`init`]
+pub const PLATFORM: DeviceId = 1;
+
+/// How long a driver can take to probe a device
+pub const PROBE_BUDGET: Duration = Duration::from_secs(5);
+
+const NOT_PROBING: usize = usize::MAX;
+
+static DEVICES: Mutex<Vec<Device>> = Mutex::new(Vec::new());
+
+/// The device being probed, so that hangs and panics can be blamed on it
+static PROBING: AtomicUsize = AtomicUsize::new(NOT_PROBING);
+/// Set by the watchdog when the probe runs past [`PROBE_BUDGET`]
+static PROBE_EXPIRED: AtomicBool = AtomicBool::new(false);
+/// The name of the driver being probed, for the watchdog which can't capture
+/// it since it runs from an interrupt handler and mustn't free memory
+static PROBING_DRIVER: Mutex<&'static str> = Mutex::new("");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bus {
+    Root,
+    Platform,
+    Acpi,
+    Pci,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resource {
+    Memory(Range<u64>),
+    Io(Range<u16>),
+    Irq(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceState {
+    /// No driver is bound
+    Unbound,
+    Active,
+    Suspended,
+    /// The driver failed to initialize the device
+    Failed,
+    /// The probe of the device ran past the budget and failed, it's not
+    /// probed again
+    Quarantined,
+}
+
+/// A driver bound to a device
+///
+/// The power management callbacks are optional, children are suspended
+/// before their parents and resumed after them.
+pub trait Driver: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Called before the system enters a sleep state
+    fn suspend(&self, _device: DeviceId) -> Result<(), &'static str> { Ok(()) }
+
+    /// Called after the system wakes up
+    fn resume(&self, _device: DeviceId) -> Result<(), &'static str> { Ok(()) }
+
+    /// Called before the system is shutdown or rebooted to stop the device
+    fn shutdown(&self, _device: DeviceId) {}
+}
+
+/// A driver without power management callbacks
+pub struct BasicDriver(pub &'static str);
+
+impl Driver for BasicDriver {
+    fn name(&self) -> &'static str { self.0 }
+}
+
+/// A driver refused to suspend it's device
+#[derive(Debug, Clone, Copy)]
+pub struct SuspendError {
+    pub device: DeviceId,
+    pub driver: &'static str,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for SuspendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} failed to suspend device {}: {}",
+            self.driver, self.device, self.reason
+        )
+    }
+}
+
+pub struct Device {
+    pub id: DeviceId,
+    pub parent: Option<DeviceId>,
+    pub name: String,
+    pub bus: Bus,
+    pub driver: Option<&'static dyn Driver>,
+    pub resources: Vec<Resource>,
+    pub state: DeviceState,
+}
+
+/// Registers the root device and the buses that always exist
+///
+/// The heap must be initialized before calling this
+pub fn init() {
+    register(None, "system", Bus::Root, Vec::new());
+    register(Some(ROOT), "platform", Bus::Platform, Vec::new());
+}
+
+/// Registers a device under `parent` (or as a root if it's `None`) and returns
+/// it's id
+pub fn register(
+    parent: Option<DeviceId>,
+    name: impl Into<String>,
+    bus: Bus,
+    resources: Vec<Resource>,
+) -> DeviceId {
+    let name = name.into();
+
+    irqoff::without_interrupts(|| {
+        let mut devices = DEVICES.lock();
+        let id = devices.len();
+
+        devices.push(Device {
+            id,
+            parent,
+            name,
+            bus,
+            driver: None,
+            resources,
+            state: DeviceState::Unbound,
+        });
+
+        id
+    })
+}
+
+/// Returns the id of the first device with `name`
+pub fn find(name: &str) -> Option<DeviceId> {
+    irqoff::without_interrupts(|| {
+        DEVICES
+            .lock()
+            .iter()
+            .find(|device| device.name == name)
+            .map(|device| device.id)
+    })
+}
+
+/// Calls `f` with a device, panics if the id doesn't exist
+pub fn with_device<R>(id: DeviceId, f: impl FnOnce(&mut Device) -> R) -> R {
+    irqoff::without_interrupts(|| f(&mut DEVICES.lock()[id]))
+}
+
+/// Records that `driver` took over a device
+pub fn bind(id: DeviceId, driver: &'static dyn Driver) {
+    with_device(id, |device| {
+        device.driver = Some(driver);
+        device.state = DeviceState::Active;
+    })
+}
+
+pub fn set_state(id: DeviceId, state: DeviceState) {
+    with_device(id, |device| device.state = state)
+}
+
+pub fn add_resource(id: DeviceId, resource: Resource) {
+    with_device(id, |device| device.resources.push(resource))
+}
+
+/// Returns the active devices and their drivers in registration order, which
+/// is also dependency order since parents are registered before their
+/// children
+fn active_drivers(state: DeviceState) -> Vec<(DeviceId, &'static dyn Driver)> {
+    irqoff::without_interrupts(|| {
+        DEVICES
+            .lock()
+            .iter()
+            .filter(|device| device.state == state)
+            .filter_map(|device| device.driver.map(|driver| (device.id, driver)))
+            .collect()
+    })
+}
+
+/// Runs the probe `f` of `driver` for a device and binds the driver if it
+/// succeeds, returns whether the device is usable
+///
+/// If the probe is still running after [`PROBE_BUDGET`] the device is
+/// reported, a slow probe that succeeds still binds the driver but one that
+/// fails gets the device quarantined.
+pub fn probe<E: fmt::Display>(
+    id: DeviceId,
+    driver: &'static dyn Driver,
+    f: impl FnOnce() -> Result<(), E>,
+) -> bool {
+    if with_device(id, |device| device.state) == DeviceState::Quarantined {
+        log::warn!("Skipping the quarantined device {}", id);
+        return false;
+    }
+
+    irqoff::without_interrupts(|| *PROBING_DRIVER.lock() = driver.name());
+    PROBE_EXPIRED.store(false, Ordering::SeqCst);
+    PROBING.store(id, Ordering::SeqCst);
+
+    let watchdog = time::timer::after(PROBE_BUDGET, || {
+        let id = match probing() {
+            Some(id) => id,
+            None => return,
+        };
+        let driver = PROBING_DRIVER.try_lock().map_or("-", |driver| *driver);
+
+        // Runs in interrupt context so the logger can't be used
+        serial_println!("{} is taking too long to probe device {}", driver, id);
+        PROBE_EXPIRED.store(true, Ordering::SeqCst);
+    });
+
+    let result = f();
+
+    PROBING.store(NOT_PROBING, Ordering::SeqCst);
+    watchdog.cancel();
+
+    let expired = PROBE_EXPIRED.load(Ordering::SeqCst);
+
+    match result {
+        Ok(()) => {
+            if expired {
+                log::warn!(
+                    "{} ran past the probe budget for device {}",
+                    driver.name(),
+                    id
+                );
+            }
+
+            bind(id, driver);
+            true
+        },
+        Err(e) if expired => {
+            log::error!(
+                "{} ran past the probe budget and failed, quarantined device {}: {}",
+                driver.name(),
+                id,
+                e
+            );
+            set_state(id, DeviceState::Quarantined);
+            false
+        },
+        Err(e) => {
+            log::warn!("{} failed to probe device {}: {}", driver.name(), id, e);
+            set_state(id, DeviceState::Failed);
+            false
+        },
+    }
+}
+
+/// Returns the device being probed, if any
+pub fn probing() -> Option<DeviceId> {
+    match PROBING.load(Ordering::SeqCst) {
+        NOT_PROBING => None,
+        id => Some(id),
+    }
+}
+
+/// Suspends all the active devices, children before their parents
+///
+/// If a driver fails the devices already suspended are resumed again
+pub fn suspend_all() -> Result<(), SuspendError> {
+    let drivers = active_drivers(DeviceState::Active);
+
+    for (id, driver) in drivers.iter().rev() {
+        if let Err(reason) = driver.suspend(*id) {
+            let error = SuspendError {
+                device: *id,
+                driver: driver.name(),
+                reason,
+            };
+
+            log::error!("{}", error);
+            resume_all();
+
+            return Err(error);
+        }
+
+        set_state(*id, DeviceState::Suspended);
+    }
+
+    Ok(())
+}
+
+/// Resumes all the suspended devices, parents before their children
+///
+/// Devices that fail to resume are marked as failed
+pub fn resume_all() {
+    for (id, driver) in active_drivers(DeviceState::Suspended) {
+        match driver.resume(id) {
+            Ok(()) => set_state(id, DeviceState::Active),
+            Err(reason) => {
+                log::error!(
+                    "{} failed to resume device {}: {}",
+                    driver.name(),
+                    id,
+                    reason
+                );
+                set_state(id, DeviceState::Failed);
+            },
+        }
+    }
+}
+
+/// Stops all the active devices, children before their parents
+pub fn shutdown_all() {
+    for (id, driver) in active_drivers(DeviceState::Active).iter().rev() {
+        driver.shutdown(*id);
+    }
+}
+
+/// Returns a listing of the device tree with the drivers, resources and state
+/// of each device
+pub fn lsdev() -> impl fmt::Display {
+    struct Listing;
+
+    fn write_device(
+        f: &mut fmt::Formatter<'_>,
+        devices: &[Device],
+        device: &Device,
+        depth: usize,
+    ) -> fmt::Result {
+        write!(f, "{:width$}{}", "", device.name, width = depth * 2)?;
+
+        if let Some(driver) = device.driver {
+            write!(f, " [{}]", driver.name())?;
+        }
+
+        for resource in device.resources.iter() {
+            match resource {
+                Resource::Memory(range) => {
+                    write!(f, " mem {:#X}-{:#X}", range.start, range.end - 1)?
+                },
+                Resource::Io(range) => write!(f, " io {:#X}-{:#X}", range.start, range.end - 1)?,
+                Resource::Irq(irq) => write!(f, " irq {}", irq)?,
+            }
+        }
+
+        writeln!(f, " ({:?})", device.state)?;
+
+        for child in devices
+            .iter()
+            .filter(|child| child.parent == Some(device.id))
+        {
+            write_device(f, devices, child, depth + 1)?;
+        }
+
+        Ok(())
+    }
+
+    impl fmt::Display for Listing {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            irqoff::without_interrupts(|| {
+                let devices = DEVICES.lock();
+
+                for root in devices.iter().filter(|device| device.parent.is_none()) {
+                    write_device(f, &devices, root, 0)?;
+                }
+
+                Ok(())
+            })
+        }
+    }
+
+    Listing
+}
@@ -0,0 +1,223 @@
+//! Mapping of memory for device access
+//!
+//! Drivers get the addresses they give to devices from here instead of
+//! translating them themselves. There's no IOMMU and the cpu caches are
+//! coherent with dma so the device addresses are the physical addresses and
+//! syncing only orders the memory accesses, but going through [`Dma`] lets
+//! bounce buffers and an IOMMU be added without changing the drivers.
+use crate::{
+    device::DeviceId,
+    error::KError,
+    memory::{dma::DmaBuffer, CacheMode, Zone, PAGING_CTX},
+};
+use alloc::vec::Vec;
+use core::sync::atomic::{self, Ordering};
+use x86_64::{structures::paging::Translate, VirtAddr};
+
+/// An address as seen by a device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DmaAddr(u64);
+
+impl DmaAddr {
+    pub fn as_u64(self) -> u64 { self.0 }
+}
+
+/// Who accesses the buffer while it's mapped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// The device only reads the buffer
+    ToDevice,
+    /// The device only writes the buffer
+    FromDevice,
+    Bidirectional,
+}
+
+/// A contiguous part of a mapping
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    pub addr: DmaAddr,
+    pub len: usize,
+}
+
+/// A buffer mapped for a device, it's unmapped when dropped
+#[derive(Debug)]
+pub struct DmaMapping {
+    segments: Vec<Segment>,
+    direction: Direction,
+}
+
+impl DmaMapping {
+    /// The address of the start of the mapping
+    pub fn addr(&self) -> DmaAddr { self.segments[0].addr }
+
+    pub fn segments(&self) -> &[Segment] { &self.segments }
+
+    pub fn direction(&self) -> Direction { self.direction }
+
+    /// Makes the writes of the cpu visible to the device, must be called
+    /// before the device is told to access the buffer
+    pub fn sync_for_device(&self) { atomic::fence(Ordering::SeqCst) }
+
+    /// Makes the writes of the device visible to the cpu, must be called after
+    /// the device is done with the buffer and before it's read
+    pub fn sync_for_cpu(&self) { atomic::fence(Ordering::SeqCst) }
+}
+
+/// The dma state of a device, each driver keeps one per device it drives
+#[derive(Debug)]
+pub struct Dma {
+    device: DeviceId,
+    /// The highest address the device can reach
+    limit: u64,
+}
+
+impl Dma {
+    /// Creates the dma state of a device that uses `address_bits` wide
+    /// addresses, wider addresses than 64 bits are treated as 64 bits
+    pub fn new(device: DeviceId, address_bits: u8) -> Self {
+        let limit = match address_bits {
+            64..=u8::MAX => u64::MAX,
+            bits => (1 << bits) - 1,
+        };
+
+        Dma { device, limit }
+    }
+
+    /// Allocates a buffer of `len` bytes that the device can reach, for
+    /// memory that is shared with it for longer than a transfer like command
+    /// lists and descriptor rings
+    pub fn alloc_buffer(&self, len: usize, mode: CacheMode) -> Result<DmaBuffer, KError> {
+        let zone = match self.limit {
+            u64::MAX => Zone::Normal,
+            limit if limit >= u32::MAX as u64 => Zone::Dma32,
+            _ => Zone::Dma,
+        };
+
+        let buffer = DmaBuffer::new(len, zone, mode)?;
+
+        // Devices with less than 24 bit addresses can't reach all of the dma
+        // zone
+        if buffer.phys().as_u64() + (buffer.len().max(1) as u64 - 1) > self.limit {
+            return Err(KError::DeviceError("buffer isn't reachable by the device"));
+        }
+
+        Ok(buffer)
+    }
+
+    /// The address the device accesses `buffer` through
+    pub fn buffer_addr(&self, buffer: &DmaBuffer) -> DmaAddr { DmaAddr(buffer.phys().as_u64()) }
+
+    /// Maps a buffer that the device needs to see as contiguous
+    ///
+    /// # Safety
+    ///
+    /// The buffer must not be freed or reused while it's mapped
+    pub unsafe fn map_single(
+        &self,
+        buf: VirtAddr,
+        len: usize,
+        direction: Direction,
+    ) -> Result<DmaMapping, KError> {
+        let segments = self.segments(buf, len)?;
+
+        if segments.is_empty() {
+            return Err(KError::Fault);
+        }
+
+        if segments.len() > 1 {
+            log::error!(
+                "Device {} tried to map {:#X} which isn't physically contiguous",
+                self.device,
+                buf.as_u64()
+            );
+            return Err(KError::NotSupported);
+        }
+
+        Ok(DmaMapping {
+            segments,
+            direction,
+        })
+    }
+
+    /// Maps buffers that the device accesses through a scatter gather list,
+    /// every buffer might be split in multiple segments
+    ///
+    /// # Safety
+    ///
+    /// The buffers must not be freed or reused while they are mapped
+    pub unsafe fn map_sg(
+        &self,
+        bufs: &[(VirtAddr, usize)],
+        direction: Direction,
+    ) -> Result<DmaMapping, KError> {
+        let mut segments = Vec::new();
+
+        for &(buf, len) in bufs {
+            segments.extend(self.segments(buf, len)?);
+        }
+
+        if segments.is_empty() {
+            return Err(KError::Fault);
+        }
+
+        Ok(DmaMapping {
+            segments,
+            direction,
+        })
+    }
+
+    /// Splits a buffer at the pages that aren't physically contiguous
+    fn segments(&self, buf: VirtAddr, len: usize) -> Result<Vec<Segment>, KError> {
+        let ctx = PAGING_CTX.get().unwrap().lock();
+        let mut segments: Vec<Segment> = Vec::new();
+        let mut offset = 0;
+
+        while offset < len {
+            let addr = buf + offset;
+            // Up to the end of the page or of the buffer
+            let chunk = (0x1000 - addr.as_u64() as usize % 0x1000).min(len - offset);
+
+            let phys = ctx
+                .mapper
+                .translate_addr(addr)
+                .ok_or(KError::Fault)?
+                .as_u64();
+
+            if phys + (chunk as u64 - 1) > self.limit {
+                return Err(KError::DeviceError("buffer isn't reachable by the device"));
+            }
+
+            match segments.last_mut() {
+                Some(last) if last.addr.0 + last.len as u64 == phys => last.len += chunk,
+                _ => segments.push(Segment {
+                    addr: DmaAddr(phys),
+                    len: chunk,
+                }),
+            }
+
+            offset += chunk;
+        }
+
+        Ok(segments)
+    }
+}
+
+#[test_case]
+fn alloc_buffer_under_32_bit_mask() {
+    let dma = Dma::new(crate::device::ROOT, 32);
+    let buffer = dma.alloc_buffer(0x3000, CacheMode::Uncached).unwrap();
+
+    assert!(buffer.phys().as_u64() + 0x3000 - 1 <= u32::MAX as u64);
+    assert_eq!(dma.buffer_addr(&buffer).as_u64(), buffer.phys().as_u64());
+
+    assert_eq!(Dma::new(crate::device::ROOT, u8::MAX).limit, u64::MAX);
+}
+
+#[test_case]
+fn map_single_rejects_empty_buffers() {
+    let dma = Dma::new(crate::device::ROOT, 64);
+    let buffer = dma.alloc_buffer(0x1000, CacheMode::WriteBack).unwrap();
+    let mapping = unsafe { dma.map_single(buffer.virt(), 0, Direction::ToDevice) };
+
+    assert_eq!(mapping.unwrap_err(), KError::Fault);
+}
@@ -0,0 +1,4 @@
+//! Drivers for legacy platform devices
+pub mod keyboard;
+pub mod pit;
+pub mod ps2;
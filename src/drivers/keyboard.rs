@@ -0,0 +1,231 @@
+//! Keyboard input decoding
+//!
+//! `pc_keyboard` is only used to turn scancodes into key events, the events are
+//! mapped to characters with a keymap that can be switched at runtime.
+use crate::interrupts::irqoff;
+use lazy_static::lazy_static;
+use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyCode, KeyEvent, KeyState, ScancodeSet1};
+use spin::Mutex;
+
+mod keymaps;
+
+/// What a key produces with a set of modifiers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Symbol {
+    Char(char),
+    /// A dead key, it's combined with the next character
+    Dead(char),
+    None,
+}
+
+/// The symbols produced by a key
+#[derive(Debug, Clone, Copy)]
+pub struct Mapping {
+    pub normal: Symbol,
+    pub shift: Symbol,
+    pub altgr: Symbol,
+}
+
+impl Mapping {
+    /// Caps lock only affects letters
+    fn is_letter(&self) -> bool { matches!(self.normal, Symbol::Char(c) if c.is_alphabetic()) }
+}
+
+pub struct Keymap {
+    pub name: &'static str,
+    pub description: &'static str,
+    /// Keys that differ from the us keymap
+    keys: &'static [(KeyCode, Mapping)],
+}
+
+impl Keymap {
+    fn lookup(&self, code: KeyCode) -> Option<Mapping> {
+        self.keys
+            .iter()
+            .chain(keymaps::US.keys)
+            .find(|(key, _)| *key == code)
+            .map(|(_, mapping)| *mapping)
+    }
+}
+
+struct State {
+    decoder: pc_keyboard::Keyboard<layouts::Us104Key, ScancodeSet1>,
+    keymap: &'static Keymap,
+    left_shift: bool,
+    right_shift: bool,
+    altgr: bool,
+    caps_lock: bool,
+    num_lock: bool,
+    /// The dead key waiting for the next character
+    dead: Option<char>,
+}
+
+impl State {
+    fn new() -> Self {
+        State {
+            decoder: pc_keyboard::Keyboard::new(
+                layouts::Us104Key,
+                ScancodeSet1,
+                HandleControl::Ignore,
+            ),
+            keymap: &keymaps::US,
+            left_shift: false,
+            right_shift: false,
+            altgr: false,
+            caps_lock: false,
+            num_lock: true,
+            dead: None,
+        }
+    }
+
+    fn process(&mut self, event: KeyEvent, f: &mut impl FnMut(DecodedKey)) {
+        let down = matches!(event.state, KeyState::Down);
+
+        match event.code {
+            KeyCode::ShiftLeft => self.left_shift = down,
+            KeyCode::ShiftRight => self.right_shift = down,
+            KeyCode::AltRight => self.altgr = down,
+            KeyCode::CapsLock if down => self.caps_lock = !self.caps_lock,
+            KeyCode::NumpadLock if down => self.num_lock = !self.num_lock,
+            // Control characters aren't produced so ctrl and alt don't change
+            // the keys
+            KeyCode::ControlLeft | KeyCode::ControlRight | KeyCode::AltLeft => {},
+            code if down => self.press(code, f),
+            _ => {},
+        }
+    }
+
+    fn press(&mut self, code: KeyCode, f: &mut impl FnMut(DecodedKey)) {
+        if !self.num_lock {
+            if let Some(code) = numpad_navigation(code) {
+                return f(DecodedKey::RawKey(code));
+            }
+        }
+
+        let mapping = match self.keymap.lookup(code) {
+            Some(mapping) => mapping,
+            None => return f(DecodedKey::RawKey(code)),
+        };
+
+        let shift = self.left_shift || self.right_shift;
+
+        let symbol = if self.altgr {
+            mapping.altgr
+        } else if shift ^ (self.caps_lock && mapping.is_letter()) {
+            mapping.shift
+        } else {
+            mapping.normal
+        };
+
+        match (symbol, self.dead.take()) {
+            (Symbol::Char(c), None) => f(DecodedKey::Unicode(c)),
+            (Symbol::Char(c), Some(dead)) => match keymaps::compose(dead, c) {
+                Some(composed) => f(DecodedKey::Unicode(composed)),
+                // Space produces the accent on it's own
+                None if c == ' ' => f(DecodedKey::Unicode(dead)),
+                None => {
+                    f(DecodedKey::Unicode(dead));
+                    f(DecodedKey::Unicode(c));
+                },
+            },
+            // Pressing the same dead key twice produces the accent
+            (Symbol::Dead(c), Some(dead)) if c == dead => f(DecodedKey::Unicode(c)),
+            (Symbol::Dead(c), Some(dead)) => {
+                f(DecodedKey::Unicode(dead));
+                self.dead = Some(c);
+            },
+            (Symbol::Dead(c), None) => self.dead = Some(c),
+            (Symbol::None, dead) => self.dead = dead,
+        }
+    }
+}
+
+/// What the keypad keys do when num lock is off
+fn numpad_navigation(code: KeyCode) -> Option<KeyCode> {
+    Some(match code {
+        KeyCode::Numpad0 => KeyCode::Insert,
+        KeyCode::Numpad1 => KeyCode::End,
+        KeyCode::Numpad2 => KeyCode::ArrowDown,
+        KeyCode::Numpad3 => KeyCode::PageDown,
+        KeyCode::Numpad4 => KeyCode::ArrowLeft,
+        KeyCode::Numpad6 => KeyCode::ArrowRight,
+        KeyCode::Numpad7 => KeyCode::Home,
+        KeyCode::Numpad8 => KeyCode::ArrowUp,
+        KeyCode::Numpad9 => KeyCode::PageUp,
+        KeyCode::NumpadPeriod => KeyCode::Delete,
+        _ => return None,
+    })
+}
+
+lazy_static! {
+    static ref STATE: Mutex<State> = Mutex::new(State::new());
+}
+
+/// Feeds a scancode from the keyboard to the decoder and calls `f` with the
+/// resulting keys
+///
+/// Must be called with interrupts disabled (from the keyboard interrupt
+/// handler)
+pub fn handle_scancode(scancode: u8, mut f: impl FnMut(DecodedKey)) {
+    let mut state = STATE.lock();
+
+    if let Ok(Some(event)) = state.decoder.add_byte(scancode) {
+        state.process(event, &mut f)
+    }
+}
+
+/// Switches to the keymap named `name`, returns false if there's no such
+/// keymap
+pub fn set_keymap(name: &str) -> bool {
+    let keymap = match keymaps::KEYMAPS.iter().find(|keymap| keymap.name == name) {
+        Some(keymap) => *keymap,
+        None => return false,
+    };
+
+    irqoff::without_interrupts(|| {
+        let mut state = STATE.lock();
+
+        state.keymap = keymap;
+        state.dead = None;
+    });
+
+    log::info!("Keymap set to {} ({})", keymap.name, keymap.description);
+
+    true
+}
+
+/// Returns the name of the keymap in use
+pub fn keymap_name() -> &'static str { irqoff::without_interrupts(|| STATE.lock().keymap.name) }
+
+/// Returns all the available keymaps
+pub fn keymaps() -> impl Iterator<Item = &'static Keymap> { keymaps::KEYMAPS.iter().copied() }
+
+#[test_case]
+fn modifier_keys_produce_nothing() {
+    use alloc::vec::Vec;
+
+    let mut state = State::new();
+    let mut keys = Vec::new();
+    let mut push = |key: DecodedKey| keys.push(key);
+
+    for code in [
+        KeyCode::ControlLeft,
+        KeyCode::AltLeft,
+        KeyCode::Numpad8,
+        KeyCode::NumpadLock,
+        KeyCode::Numpad8,
+        KeyCode::NumpadLock,
+        KeyCode::Numpad8,
+    ]
+    .iter()
+    {
+        state.process(KeyEvent::new(*code, KeyState::Down), &mut push);
+        state.process(KeyEvent::new(*code, KeyState::Up), &mut push);
+    }
+
+    assert_eq!(keys, [
+        DecodedKey::Unicode('8'),
+        DecodedKey::RawKey(KeyCode::ArrowUp),
+        DecodedKey::Unicode('8'),
+    ]);
+}
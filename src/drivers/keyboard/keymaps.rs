@@ -0,0 +1,246 @@
+//! Keymap tables
+//!
+//! Key codes are named after the key positions on the us layout, on iso
+//! keyboards `BackSlash` is the key left of enter and `HashTilde` the extra key
+//! right of left shift.
+use super::{Keymap, Mapping, Symbol};
+use pc_keyboard::KeyCode;
+
+pub static KEYMAPS: &[&Keymap] = &[&US, &UK, &PT, &DE, &AZERTY];
+
+/// A key producing `normal` and `shift`
+const fn key(code: KeyCode, normal: char, shift: char) -> (KeyCode, Mapping) {
+    (code, Mapping {
+        normal: Symbol::Char(normal),
+        shift: Symbol::Char(shift),
+        altgr: Symbol::None,
+    })
+}
+
+/// A key producing `normal` and `shift` and `altgr` with alt gr
+const fn alt(code: KeyCode, normal: char, shift: char, altgr: char) -> (KeyCode, Mapping) {
+    (code, Mapping {
+        normal: Symbol::Char(normal),
+        shift: Symbol::Char(shift),
+        altgr: Symbol::Char(altgr),
+    })
+}
+
+/// A key with custom symbols
+const fn sym(code: KeyCode, normal: Symbol, shift: Symbol, altgr: Symbol) -> (KeyCode, Mapping) {
+    (code, Mapping {
+        normal,
+        shift,
+        altgr,
+    })
+}
+
+const fn c(c: char) -> Symbol { Symbol::Char(c) }
+
+const fn d(c: char) -> Symbol { Symbol::Dead(c) }
+
+const NONE: Symbol = Symbol::None;
+
+/// The base keymap, the other keymaps only list the keys that differ from it
+pub static US: Keymap = Keymap {
+    name: "us",
+    description: "US 104 key",
+    keys: &[
+        key(KeyCode::BackTick, '`', '~'),
+        key(KeyCode::Key1, '1', '!'),
+        key(KeyCode::Key2, '2', '@'),
+        key(KeyCode::Key3, '3', '#'),
+        key(KeyCode::Key4, '4', '$'),
+        key(KeyCode::Key5, '5', '%'),
+        key(KeyCode::Key6, '6', '^'),
+        key(KeyCode::Key7, '7', '&'),
+        key(KeyCode::Key8, '8', '*'),
+        key(KeyCode::Key9, '9', '('),
+        key(KeyCode::Key0, '0', ')'),
+        key(KeyCode::Minus, '-', '_'),
+        key(KeyCode::Equals, '=', '+'),
+        key(KeyCode::Q, 'q', 'Q'),
+        key(KeyCode::W, 'w', 'W'),
+        key(KeyCode::E, 'e', 'E'),
+        key(KeyCode::R, 'r', 'R'),
+        key(KeyCode::T, 't', 'T'),
+        key(KeyCode::Y, 'y', 'Y'),
+        key(KeyCode::U, 'u', 'U'),
+        key(KeyCode::I, 'i', 'I'),
+        key(KeyCode::O, 'o', 'O'),
+        key(KeyCode::P, 'p', 'P'),
+        key(KeyCode::BracketSquareLeft, '[', '{'),
+        key(KeyCode::BracketSquareRight, ']', '}'),
+        key(KeyCode::BackSlash, '\\', '|'),
+        key(KeyCode::A, 'a', 'A'),
+        key(KeyCode::S, 's', 'S'),
+        key(KeyCode::D, 'd', 'D'),
+        key(KeyCode::F, 'f', 'F'),
+        key(KeyCode::G, 'g', 'G'),
+        key(KeyCode::H, 'h', 'H'),
+        key(KeyCode::J, 'j', 'J'),
+        key(KeyCode::K, 'k', 'K'),
+        key(KeyCode::L, 'l', 'L'),
+        key(KeyCode::SemiColon, ';', ':'),
+        key(KeyCode::Quote, '\'', '"'),
+        key(KeyCode::Z, 'z', 'Z'),
+        key(KeyCode::X, 'x', 'X'),
+        key(KeyCode::C, 'c', 'C'),
+        key(KeyCode::V, 'v', 'V'),
+        key(KeyCode::B, 'b', 'B'),
+        key(KeyCode::N, 'n', 'N'),
+        key(KeyCode::M, 'm', 'M'),
+        key(KeyCode::Comma, ',', '<'),
+        key(KeyCode::Fullstop, '.', '>'),
+        key(KeyCode::Slash, '/', '?'),
+        key(KeyCode::Spacebar, ' ', ' '),
+        key(KeyCode::Enter, '\n', '\n'),
+        key(KeyCode::Tab, '\t', '\t'),
+        key(KeyCode::Backspace, '\u{8}', '\u{8}'),
+        key(KeyCode::Escape, '\u{1B}', '\u{1B}'),
+        key(KeyCode::Delete, '\u{7F}', '\u{7F}'),
+        key(KeyCode::Numpad0, '0', '0'),
+        key(KeyCode::Numpad1, '1', '1'),
+        key(KeyCode::Numpad2, '2', '2'),
+        key(KeyCode::Numpad3, '3', '3'),
+        key(KeyCode::Numpad4, '4', '4'),
+        key(KeyCode::Numpad5, '5', '5'),
+        key(KeyCode::Numpad6, '6', '6'),
+        key(KeyCode::Numpad7, '7', '7'),
+        key(KeyCode::Numpad8, '8', '8'),
+        key(KeyCode::Numpad9, '9', '9'),
+        key(KeyCode::NumpadSlash, '/', '/'),
+        key(KeyCode::NumpadStar, '*', '*'),
+        key(KeyCode::NumpadMinus, '-', '-'),
+        key(KeyCode::NumpadPlus, '+', '+'),
+        key(KeyCode::NumpadPeriod, '.', '.'),
+        key(KeyCode::NumpadEnter, '\n', '\n'),
+    ],
+};
+
+pub static UK: Keymap = Keymap {
+    name: "uk",
+    description: "UK 105 key",
+    keys: &[
+        alt(KeyCode::BackTick, '`', '¬', '¦'),
+        key(KeyCode::Key2, '2', '"'),
+        key(KeyCode::Key3, '3', '£'),
+        alt(KeyCode::Key4, '4', '$', '€'),
+        key(KeyCode::Quote, '\'', '@'),
+        key(KeyCode::BackSlash, '#', '~'),
+        key(KeyCode::HashTilde, '\\', '|'),
+    ],
+};
+
+pub static PT: Keymap = Keymap {
+    name: "pt",
+    description: "Portuguese",
+    keys: &[
+        key(KeyCode::BackTick, '\\', '|'),
+        alt(KeyCode::Key2, '2', '"', '@'),
+        alt(KeyCode::Key3, '3', '#', '£'),
+        alt(KeyCode::Key4, '4', '$', '§'),
+        alt(KeyCode::Key5, '5', '%', '€'),
+        key(KeyCode::Key6, '6', '&'),
+        alt(KeyCode::Key7, '7', '/', '{'),
+        alt(KeyCode::Key8, '8', '(', '['),
+        alt(KeyCode::Key9, '9', ')', ']'),
+        alt(KeyCode::Key0, '0', '=', '}'),
+        key(KeyCode::Minus, '\'', '?'),
+        key(KeyCode::Equals, '«', '»'),
+        alt(KeyCode::E, 'e', 'E', '€'),
+        sym(KeyCode::BracketSquareLeft, c('+'), c('*'), d('¨')),
+        sym(KeyCode::BracketSquareRight, d('´'), d('`'), NONE),
+        key(KeyCode::SemiColon, 'ç', 'Ç'),
+        key(KeyCode::Quote, 'º', 'ª'),
+        sym(KeyCode::BackSlash, d('~'), d('^'), NONE),
+        key(KeyCode::HashTilde, '<', '>'),
+        key(KeyCode::Comma, ',', ';'),
+        key(KeyCode::Fullstop, '.', ':'),
+        key(KeyCode::Slash, '-', '_'),
+    ],
+};
+
+pub static DE: Keymap = Keymap {
+    name: "de",
+    description: "German",
+    keys: &[
+        sym(KeyCode::BackTick, d('^'), c('°'), NONE),
+        alt(KeyCode::Key2, '2', '"', '²'),
+        alt(KeyCode::Key3, '3', '§', '³'),
+        key(KeyCode::Key6, '6', '&'),
+        alt(KeyCode::Key7, '7', '/', '{'),
+        alt(KeyCode::Key8, '8', '(', '['),
+        alt(KeyCode::Key9, '9', ')', ']'),
+        alt(KeyCode::Key0, '0', '=', '}'),
+        alt(KeyCode::Minus, 'ß', '?', '\\'),
+        sym(KeyCode::Equals, d('´'), d('`'), NONE),
+        alt(KeyCode::Q, 'q', 'Q', '@'),
+        alt(KeyCode::E, 'e', 'E', '€'),
+        key(KeyCode::Y, 'z', 'Z'),
+        key(KeyCode::BracketSquareLeft, 'ü', 'Ü'),
+        alt(KeyCode::BracketSquareRight, '+', '*', '~'),
+        key(KeyCode::SemiColon, 'ö', 'Ö'),
+        key(KeyCode::Quote, 'ä', 'Ä'),
+        key(KeyCode::BackSlash, '#', '\''),
+        alt(KeyCode::HashTilde, '<', '>', '|'),
+        key(KeyCode::Z, 'y', 'Y'),
+        alt(KeyCode::M, 'm', 'M', 'µ'),
+        key(KeyCode::Comma, ',', ';'),
+        key(KeyCode::Fullstop, '.', ':'),
+        key(KeyCode::Slash, '-', '_'),
+    ],
+};
+
+pub static AZERTY: Keymap = Keymap {
+    name: "fr",
+    description: "French AZERTY",
+    keys: &[
+        sym(KeyCode::BackTick, c('²'), NONE, NONE),
+        key(KeyCode::Key1, '&', '1'),
+        sym(KeyCode::Key2, c('é'), c('2'), d('~')),
+        alt(KeyCode::Key3, '"', '3', '#'),
+        alt(KeyCode::Key4, '\'', '4', '{'),
+        alt(KeyCode::Key5, '(', '5', '['),
+        alt(KeyCode::Key6, '-', '6', '|'),
+        sym(KeyCode::Key7, c('è'), c('7'), d('`')),
+        alt(KeyCode::Key8, '_', '8', '\\'),
+        alt(KeyCode::Key9, 'ç', '9', '^'),
+        alt(KeyCode::Key0, 'à', '0', '@'),
+        alt(KeyCode::Minus, ')', '°', ']'),
+        alt(KeyCode::Equals, '=', '+', '}'),
+        key(KeyCode::Q, 'a', 'A'),
+        key(KeyCode::W, 'z', 'Z'),
+        alt(KeyCode::E, 'e', 'E', '€'),
+        sym(KeyCode::BracketSquareLeft, d('^'), d('¨'), NONE),
+        alt(KeyCode::BracketSquareRight, '$', '£', '¤'),
+        key(KeyCode::A, 'q', 'Q'),
+        key(KeyCode::SemiColon, 'm', 'M'),
+        key(KeyCode::Quote, 'ù', '%'),
+        key(KeyCode::BackSlash, '*', 'µ'),
+        key(KeyCode::HashTilde, '<', '>'),
+        key(KeyCode::Z, 'w', 'W'),
+        key(KeyCode::M, ',', '?'),
+        key(KeyCode::Comma, ';', '.'),
+        key(KeyCode::Fullstop, ':', '/'),
+        key(KeyCode::Slash, '!', '§'),
+    ],
+};
+
+/// Dead keys and the letters they combine with, the nth letter of the base
+/// string combines into the nth letter of the result string
+const COMPOSE: &[(char, &str, &str)] = &[
+    ('´', "aeiouyAEIOUYcC", "áéíóúýÁÉÍÓÚÝćĆ"),
+    ('`', "aeiouAEIOU", "àèìòùÀÈÌÒÙ"),
+    ('^', "aeiouAEIOU", "âêîôûÂÊÎÔÛ"),
+    ('~', "aonAON", "ãõñÃÕÑ"),
+    ('¨', "aeiouyAEIOU", "äëïöüÿÄËÏÖÜ"),
+];
+
+/// Combines a dead key with a letter
+pub fn compose(dead: char, letter: char) -> Option<char> {
+    let (_, base, result) = COMPOSE.iter().find(|(key, ..)| *key == dead)?;
+    let idx = base.chars().position(|c| c == letter)?;
+
+    result.chars().nth(idx)
+}
@@ -0,0 +1,116 @@
+//! The 8254 programmable interval timer
+//!
+//! Channel 0 is wired to irq 0 and is used by the timekeeping code, channel 2
+//! drives the pc speaker.
+use crate::interrupts::irqoff;
+use x86_64::instructions::port::{PortRead, PortWrite};
+
+const CHANNEL_BASE: u16 = 0x40;
+const COMMAND: u16 = 0x43;
+/// Port B of the keyboard controller, controls the channel 2 gate and the
+/// speaker output
+const SPEAKER_CONTROL: u16 = 0x61;
+
+/// Enables counting on channel 2
+const SPEAKER_GATE: u8 = 1;
+/// Connects the channel 2 output to the speaker
+const SPEAKER_DATA: u8 = 1 << 1;
+
+/// Lobyte/hibyte access mode
+const ACCESS_LOHI: u8 = 0b11 << 4;
+
+/// The frequency of the pit oscillator in Hz
+pub const FREQUENCY: u64 = 1_193_182;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Zero = 0,
+    One = 1,
+    Two = 2,
+}
+
+impl Channel {
+    fn port(self) -> u16 { CHANNEL_BASE + self as u16 }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    InterruptOnTerminalCount = 0,
+    HardwareOneShot = 1,
+    RateGenerator = 2,
+    SquareWave = 3,
+    SoftwareStrobe = 4,
+    HardwareStrobe = 5,
+}
+
+/// Sets the mode of a channel, the channel stops counting until a divisor is
+/// written
+///
+/// # Safety
+///
+/// The channel must not be in use by anyone else
+pub unsafe fn set_mode(channel: Channel, mode: Mode) {
+    u8::write_to_port(
+        COMMAND,
+        (channel as u8) << 6 | ACCESS_LOHI | (mode as u8) << 1,
+    )
+}
+
+/// Sets the mode of a channel and starts it counting down from `divisor`, a
+/// divisor of 0 is interpreted as 65536
+///
+/// # Safety
+///
+/// The channel must not be in use by anyone else
+pub unsafe fn configure(channel: Channel, mode: Mode, divisor: u16) {
+    irqoff::without_interrupts(|| {
+        set_mode(channel, mode);
+        u8::write_to_port(channel.port(), divisor as u8);
+        u8::write_to_port(channel.port(), (divisor >> 8) as u8);
+    })
+}
+
+/// Returns the divisor that makes a channel run at `frequency` Hz
+pub fn divisor(frequency: u32) -> u16 {
+    (FREQUENCY / frequency.max(1) as u64)
+        .max(1)
+        .min(u16::MAX as u64) as u16
+}
+
+/// Reads the current count of a channel
+pub fn current_count(channel: Channel) -> u16 {
+    irqoff::without_interrupts(|| unsafe {
+        // The latch command freezes the count until it's read so both bytes
+        // belong to the same value
+        u8::write_to_port(COMMAND, (channel as u8) << 6);
+
+        let low = u8::read_from_port(channel.port()) as u16;
+        let high = u8::read_from_port(channel.port()) as u16;
+
+        high << 8 | low
+    })
+}
+
+/// Starts playing a tone at `frequency` Hz on the pc speaker
+pub fn speaker_on(frequency: u32) {
+    unsafe {
+        configure(Channel::Two, Mode::SquareWave, divisor(frequency));
+
+        let control = u8::read_from_port(SPEAKER_CONTROL);
+        u8::write_to_port(SPEAKER_CONTROL, control | SPEAKER_GATE | SPEAKER_DATA);
+    }
+}
+
+pub fn speaker_off() {
+    unsafe {
+        let control = u8::read_from_port(SPEAKER_CONTROL);
+        u8::write_to_port(SPEAKER_CONTROL, control & !(SPEAKER_GATE | SPEAKER_DATA));
+    }
+}
+
+/// Plays a tone at `frequency` Hz for `miliseconds`
+pub fn beep(frequency: u32, miliseconds: u64) {
+    speaker_on(frequency);
+    crate::sleep(miliseconds);
+    speaker_off();
+}
@@ -0,0 +1,236 @@
+//! The 8042 ps/2 controller
+//!
+//! The firmware might leave the controller in any state (usb legacy emulation,
+//! pending bytes, odd configuration) so it's reset to a known one before the
+//! keyboard interrupt is used.
+use crate::{
+    device::{DeviceId, Driver},
+    time::{self, TimedOut},
+};
+use core::{fmt, time::Duration};
+use x86_64::instructions::port::{PortRead, PortWrite};
+
+const DATA: u16 = 0x60;
+const STATUS: u16 = 0x64;
+const COMMAND: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 1;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+const CMD_READ_CONFIG: u8 = 0x20;
+const CMD_WRITE_CONFIG: u8 = 0x60;
+const CMD_DISABLE_SECOND: u8 = 0xA7;
+const CMD_ENABLE_SECOND: u8 = 0xA8;
+const CMD_TEST_SECOND: u8 = 0xA9;
+const CMD_SELF_TEST: u8 = 0xAA;
+const CMD_TEST_FIRST: u8 = 0xAB;
+const CMD_DISABLE_FIRST: u8 = 0xAD;
+const CMD_ENABLE_FIRST: u8 = 0xAE;
+/// Pulses the cpu reset line
+const CMD_RESET_CPU: u8 = 0xFE;
+
+const CONFIG_FIRST_IRQ: u8 = 1;
+const CONFIG_SECOND_IRQ: u8 = 1 << 1;
+/// Set when the clock of the second port is disabled
+const CONFIG_SECOND_CLOCK_DISABLED: u8 = 1 << 5;
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+const SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+const DEVICE_RESET: u8 = 0xFF;
+const DEVICE_SCANCODE_SET: u8 = 0xF0;
+const DEVICE_ACK: u8 = 0xFA;
+const DEVICE_RESEND: u8 = 0xFE;
+const DEVICE_RESET_PASSED: u8 = 0xAA;
+
+/// The keyboard is set to scancode set 2 which the controller translates to
+/// set 1 for the decoder
+const SCANCODE_SET: u8 = 2;
+
+/// The output buffer is a single byte but usb legacy emulation might keep
+/// feeding it
+const FLUSH_LIMIT: usize = 64;
+
+const TIMEOUT: Duration = Duration::from_millis(100);
+/// Devices can take a while to complete their self test after a reset
+const RESET_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub static DRIVER: Ps2Driver = Ps2Driver;
+
+pub struct Ps2Driver;
+
+impl Driver for Ps2Driver {
+    fn name(&self) -> &'static str { "ps2" }
+
+    /// The controller state is lost while sleeping so it's initialized again
+    fn resume(&self, _device: DeviceId) -> Result<(), &'static str> {
+        init().map_err(|_| "controller initialization failed")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Error {
+    Timeout,
+    SelfTestFailed(u8),
+    PortTestFailed(u8),
+    /// The device didn't acknowledge a command and replied with this instead
+    UnexpectedResponse(u8),
+}
+
+impl From<TimedOut> for Ps2Error {
+    fn from(_: TimedOut) -> Self { Ps2Error::Timeout }
+}
+
+impl fmt::Display for Ps2Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Ps2Error::Timeout => write!(f, "timed out"),
+            Ps2Error::SelfTestFailed(res) => write!(f, "controller self test failed ({:#X})", res),
+            Ps2Error::PortTestFailed(res) => write!(f, "port test failed ({:#X})", res),
+            Ps2Error::UnexpectedResponse(res) => write!(f, "unexpected response {:#X}", res),
+        }
+    }
+}
+
+fn status() -> u8 { unsafe { u8::read_from_port(STATUS) } }
+
+fn read(timeout: Duration) -> Result<u8, Ps2Error> {
+    time::wait_until(|| status() & STATUS_OUTPUT_FULL != 0, timeout, time::SPIN)?;
+
+    Ok(unsafe { u8::read_from_port(DATA) })
+}
+
+fn write(port: u16, val: u8) -> Result<(), Ps2Error> {
+    time::wait_until(|| status() & STATUS_INPUT_FULL == 0, TIMEOUT, time::SPIN)?;
+
+    unsafe { u8::write_to_port(port, val) };
+
+    Ok(())
+}
+
+fn command(cmd: u8) -> Result<(), Ps2Error> { write(COMMAND, cmd) }
+
+fn command_with_response(cmd: u8) -> Result<u8, Ps2Error> {
+    command(cmd)?;
+    read(TIMEOUT)
+}
+
+fn write_config(config: u8) -> Result<(), Ps2Error> {
+    command(CMD_WRITE_CONFIG)?;
+    write(DATA, config)
+}
+
+/// Sends a byte to the device on the first port and waits for it to
+/// acknowledge it
+fn device_command(val: u8) -> Result<(), Ps2Error> {
+    for _ in 0..3 {
+        write(DATA, val)?;
+
+        match read(TIMEOUT)? {
+            DEVICE_ACK => return Ok(()),
+            DEVICE_RESEND => continue,
+            res => return Err(Ps2Error::UnexpectedResponse(res)),
+        }
+    }
+
+    Err(Ps2Error::Timeout)
+}
+
+/// Discards any bytes waiting in the output buffer
+fn flush() {
+    // Without a controller the status reads as all ones so bound the loop
+    for _ in 0..FLUSH_LIMIT {
+        if try_read().is_none() {
+            break;
+        }
+    }
+}
+
+/// Resets the controller and the keyboard and enables the keyboard interrupt
+///
+/// The second port is tested but left disabled since there's no mouse driver
+pub fn init() -> Result<(), Ps2Error> {
+    command(CMD_DISABLE_FIRST)?;
+    command(CMD_DISABLE_SECOND)?;
+    flush();
+
+    let mut config = command_with_response(CMD_READ_CONFIG)?;
+    config &= !(CONFIG_FIRST_IRQ | CONFIG_SECOND_IRQ);
+    config |= CONFIG_TRANSLATION;
+    write_config(config)?;
+
+    match command_with_response(CMD_SELF_TEST)? {
+        SELF_TEST_PASSED => {},
+        res => return Err(Ps2Error::SelfTestFailed(res)),
+    }
+
+    // Some controllers reset themselves during the self test
+    write_config(config)?;
+
+    // The second clock is disabled right now, if enabling the port doesn't
+    // clear the bit there is no second port
+    let dual_channel = config & CONFIG_SECOND_CLOCK_DISABLED != 0 && {
+        command(CMD_ENABLE_SECOND)?;
+        let enabled = command_with_response(CMD_READ_CONFIG)? & CONFIG_SECOND_CLOCK_DISABLED == 0;
+        command(CMD_DISABLE_SECOND)?;
+
+        enabled
+    };
+
+    match command_with_response(CMD_TEST_FIRST)? {
+        PORT_TEST_PASSED => {},
+        res => return Err(Ps2Error::PortTestFailed(res)),
+    }
+
+    if dual_channel {
+        let res = command_with_response(CMD_TEST_SECOND)?;
+
+        if res != PORT_TEST_PASSED {
+            log::warn!("PS/2 second port test failed ({:#X})", res);
+        }
+    }
+
+    command(CMD_ENABLE_FIRST)?;
+
+    device_command(DEVICE_RESET)?;
+
+    match read(RESET_TIMEOUT)? {
+        DEVICE_RESET_PASSED => {},
+        res => return Err(Ps2Error::UnexpectedResponse(res)),
+    }
+
+    // Some keyboards also send their id after the reset
+    flush();
+
+    device_command(DEVICE_SCANCODE_SET)?;
+    device_command(SCANCODE_SET)?;
+
+    write_config(config | CONFIG_FIRST_IRQ)?;
+
+    log::info!(
+        "PS/2 controller initialized (dual channel: {})",
+        dual_channel
+    );
+
+    Ok(())
+}
+
+/// Asks the controller to reset the cpu, only returns if it failed to
+pub fn reset_cpu() -> Result<(), Ps2Error> {
+    command(CMD_RESET_CPU)?;
+
+    // Give the controller some time to pulse the line
+    time::busy_wait(TIMEOUT);
+
+    Ok(())
+}
+
+/// Reads a byte from the controller if there's one available
+pub fn try_read() -> Option<u8> {
+    if status() & STATUS_OUTPUT_FULL != 0 {
+        Some(unsafe { u8::read_from_port(DATA) })
+    } else {
+        None
+    }
+}
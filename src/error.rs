@@ -0,0 +1,53 @@
+//! Kernel wide errors
+//!
+//! Subsystems with their own error types can convert them into a [`KError`] so
+//! that the callers that only need to recover don't have to handle each one.
+use crate::time::TimedOut;
+use core::fmt;
+use x86_64::structures::paging::{
+    mapper::{MapToError, UnmapError},
+    Size4KiB,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KError {
+    /// Frames or heap memory ran out
+    NoMemory,
+    /// The hardware didn't respond in time
+    Timeout,
+    /// The device is misbehaving or missing something it needs
+    DeviceError(&'static str),
+    /// The hardware or the firmware doesn't support the operation
+    NotSupported,
+    /// An address is invalid or can't be mapped
+    Fault,
+}
+
+impl fmt::Display for KError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KError::NoMemory => write!(f, "out of memory"),
+            KError::Timeout => write!(f, "timed out"),
+            KError::DeviceError(reason) => write!(f, "device error: {}", reason),
+            KError::NotSupported => write!(f, "not supported"),
+            KError::Fault => write!(f, "bad address"),
+        }
+    }
+}
+
+impl From<MapToError<Size4KiB>> for KError {
+    fn from(e: MapToError<Size4KiB>) -> Self {
+        match e {
+            MapToError::FrameAllocationFailed => KError::NoMemory,
+            MapToError::ParentEntryHugePage | MapToError::PageAlreadyMapped(_) => KError::Fault,
+        }
+    }
+}
+
+impl From<UnmapError> for KError {
+    fn from(_: UnmapError) -> Self { KError::Fault }
+}
+
+impl From<TimedOut> for KError {
+    fn from(_: TimedOut) -> Self { KError::Timeout }
+}
@@ -0,0 +1,125 @@
+//! Formatting without the heap
+//!
+//! [`FmtBuf`] formats into a fixed buffer that lives on the stack and cuts
+//! what doesn't fit, for the code that must not allocate like the interrupt
+//! handlers, the logger and the early boot before the heap is ready.
+use core::{fmt, str};
+
+/// The capacity of a [`FmtBuf`] in bytes
+pub const FMT_BUF_SIZE: usize = 256;
+
+/// Ends the text that was cut
+const ELLIPSIS: &str = "...";
+
+/// Formats into a [`FmtBuf`], like `format!` but without allocating
+#[macro_export]
+macro_rules! format_fixed {
+    ($($arg:tt)*) => ($crate::fmtbuf::FmtBuf::format(format_args!($($arg)*)));
+}
+
+/// A string of at most [`FMT_BUF_SIZE`] bytes, longer text is cut and ends with
+/// an ellipsis
+#[derive(Clone)]
+pub struct FmtBuf {
+    data: [u8; FMT_BUF_SIZE],
+    len: usize,
+    truncated: bool,
+}
+
+impl FmtBuf {
+    pub const fn new() -> Self {
+        FmtBuf {
+            data: [0; FMT_BUF_SIZE],
+            len: 0,
+            truncated: false,
+        }
+    }
+
+    pub fn format(args: fmt::Arguments) -> Self {
+        let mut buf = FmtBuf::new();
+
+        // Only fails when the text is cut
+        let _ = fmt::Write::write_fmt(&mut buf, args);
+
+        buf
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only whole characters are ever written so this can't fail
+        str::from_utf8(&self.data[..self.len]).unwrap_or_default()
+    }
+
+    /// Whether the text didn't fit and was cut
+    pub fn is_truncated(&self) -> bool { self.truncated }
+
+    fn push(&mut self, s: &str) {
+        self.data[self.len..self.len + s.len()].copy_from_slice(s.as_bytes());
+        self.len += s.len();
+    }
+}
+
+impl Default for FmtBuf {
+    fn default() -> Self { FmtBuf::new() }
+}
+
+impl fmt::Write for FmtBuf {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.truncated {
+            return Err(fmt::Error);
+        }
+
+        if self.len + s.len() <= FMT_BUF_SIZE {
+            self.push(s);
+            return Ok(());
+        }
+
+        // Keep room for the ellipsis, cutting on a character boundary
+        let limit = FMT_BUF_SIZE - ELLIPSIS.len();
+
+        if self.len < limit {
+            let mut end = limit - self.len;
+
+            while !s.is_char_boundary(end) {
+                end -= 1;
+            }
+
+            self.push(&s[..end]);
+        } else {
+            self.len = limit;
+
+            // Continuation bytes of utf-8 start with 0b10
+            while self.data[self.len] & 0xC0 == 0x80 {
+                self.len -= 1;
+            }
+        }
+
+        self.push(ELLIPSIS);
+        self.truncated = true;
+
+        Err(fmt::Error)
+    }
+}
+
+impl fmt::Display for FmtBuf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { f.write_str(self.as_str()) }
+}
+
+impl fmt::Debug for FmtBuf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { fmt::Debug::fmt(self.as_str(), f) }
+}
+
+#[test_case]
+fn fmt_buf_truncates_on_char_boundary() {
+    let short = format_fixed!("{} {}", "hello", 42);
+
+    assert_eq!(short.as_str(), "hello 42");
+    assert!(!short.is_truncated());
+
+    // Two byte characters put the cut in the middle of one
+    let long = format_fixed!("{}", "é".repeat(FMT_BUF_SIZE));
+
+    assert!(long.is_truncated());
+    assert!(long.as_str().len() <= FMT_BUF_SIZE);
+    assert!(long.as_str().starts_with('é'));
+    assert!(long.as_str().ends_with(ELLIPSIS));
+}
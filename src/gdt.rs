@@ -1,3 +1,4 @@
+use core::{mem::size_of, ops::Range};
 use lazy_static::lazy_static;
 use x86_64::{
     structures::{
@@ -9,16 +10,36 @@ use x86_64::{
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
+/// The number of stacks in the interrupt stack table
+pub const IST_STACKS: usize = 1;
+
+/// Size of the page left unmapped below each interrupt stack
+pub const GUARD_SIZE: usize = 4096;
+
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 5;
+static mut DOUBLE_FAULT_STACK: IstStack<DOUBLE_FAULT_STACK_SIZE> = IstStack::new();
+
+/// An interrupt stack with room for a guard page below it, the guard is only
+/// unmapped once paging is set up by [`crate::memory::init`]
+#[repr(C, align(4096))]
+struct IstStack<const SIZE: usize> {
+    guard: [u8; GUARD_SIZE],
+    stack: [u8; SIZE],
+}
+
+impl<const SIZE: usize> IstStack<SIZE> {
+    const fn new() -> Self {
+        IstStack {
+            guard: [0; GUARD_SIZE],
+            stack: [0; SIZE],
+        }
+    }
+}
+
 lazy_static! {
     static ref TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            stack_start + STACK_SIZE
-        };
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = double_fault_stack().end;
         tss
     };
 }
@@ -49,3 +70,30 @@ pub fn init() {
         load_tss(GDT.1.tss_selector);
     }
 }
+
+/// Returns the range of addresses used by the double fault stack
+pub fn double_fault_stack() -> Range<VirtAddr> {
+    let stack_start = VirtAddr::from_ptr(unsafe { &DOUBLE_FAULT_STACK.stack });
+    stack_start..stack_start + DOUBLE_FAULT_STACK_SIZE
+}
+
+/// Returns the name and the range of every stack in the interrupt stack table,
+/// each one has a guard page of [`GUARD_SIZE`] right below it
+pub fn ist_stacks() -> [(&'static str, Range<VirtAddr>); IST_STACKS] {
+    [("double fault", double_fault_stack())]
+}
+
+/// Checks that the double fault entry of the interrupt stack table still points
+/// to the top of the double fault stack
+pub fn double_fault_ist_intact() -> bool {
+    TSS.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] == double_fault_stack().end
+}
+
+/// Returns true if `addr` is inside the GDT or the TSS
+pub fn descriptor_tables_contain(addr: VirtAddr) -> bool {
+    let gdt_start = VirtAddr::from_ptr(&GDT.0);
+    let tss_start = VirtAddr::from_ptr(&*TSS);
+
+    (gdt_start..gdt_start + size_of::<GlobalDescriptorTable>()).contains(&addr)
+        || (tss_start..tss_start + size_of::<TaskStateSegment>()).contains(&addr)
+}
@@ -103,12 +103,12 @@ impl InterruptController {
     }
 }
 
-unsafe fn read_apic_reg(base_address: u64, offset: usize) -> u32 {
+pub unsafe fn read_apic_reg(base_address: u64, offset: usize) -> u32 {
     let ptr = (base_address as usize + offset) as *mut u32;
     ptr.read_volatile()
 }
 
-unsafe fn write_apic_reg(base_address: u64, offset: usize, val: u32) {
+pub unsafe fn write_apic_reg(base_address: u64, offset: usize, val: u32) {
     let ptr = (base_address as usize + offset) as *mut u32;
     ptr.write_volatile(val)
 }
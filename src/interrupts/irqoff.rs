@@ -0,0 +1,138 @@
+//! Tracking of the regions that run with interrupts disabled
+//!
+//! Long regions delay the timer and the devices, [`without_interrupts`]
+//! measures them and remembers the longest one along with where it started.
+//! There's no preemption yet so interrupts being disabled is the only atomic
+//! context, and only one cpu runs so the counters are global.
+//!
+//! The interrupt handlers also take a [`no_alloc`] guard so that allocations
+//! made from them are caught in debug builds.
+use crate::time::tsc;
+use core::{
+    panic::Location,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Regions longer than this are logged when they become the longest
+const WARN_THRESHOLD: Duration = Duration::from_millis(1);
+
+/// How many tracked regions are nested
+static DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// How many interrupt handlers are running, none of them can allocate
+static NO_ALLOC: AtomicUsize = AtomicUsize::new(0);
+
+/// Length in tsc cycles of the longest region
+static LONGEST_CYCLES: AtomicU64 = AtomicU64::new(0);
+static LONGEST_LOCATION: Mutex<Option<&'static Location<'static>>> = Mutex::new(None);
+
+/// Runs `f` with interrupts disabled, like
+/// [`x86_64::instructions::interrupts::without_interrupts`] but the region is
+/// timed if it's the one disabling them
+#[track_caller]
+pub fn without_interrupts<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    if !interrupts::are_enabled() {
+        DEPTH.fetch_add(1, Ordering::Relaxed);
+        let ret = f();
+        DEPTH.fetch_sub(1, Ordering::Relaxed);
+
+        return ret;
+    }
+
+    let location = Location::caller();
+
+    interrupts::disable();
+    DEPTH.fetch_add(1, Ordering::Relaxed);
+
+    let start = tsc::read();
+    let ret = f();
+    let cycles = tsc::read() - start;
+
+    DEPTH.fetch_sub(1, Ordering::Relaxed);
+
+    let longest = cycles > LONGEST_CYCLES.fetch_max(cycles, Ordering::Relaxed);
+
+    if longest {
+        if let Some(mut longest_location) = LONGEST_LOCATION.try_lock() {
+            *longest_location = Some(location);
+        }
+    }
+
+    interrupts::enable();
+
+    if longest {
+        if let Some(duration) = tsc::cycles_to_duration(cycles) {
+            if duration > WARN_THRESHOLD {
+                log::warn!(
+                    "Interrupts were disabled for {:?} at {}",
+                    duration,
+                    location
+                );
+            }
+        }
+    }
+
+    ret
+}
+
+/// Whether the caller can't block, either because interrupts are disabled or
+/// because it's inside a tracked region
+pub fn in_atomic() -> bool { !interrupts::are_enabled() || DEPTH.load(Ordering::Relaxed) != 0 }
+
+/// Checks in debug builds that the caller is allowed to block
+#[track_caller]
+pub fn might_block() {
+    debug_assert!(
+        !in_atomic(),
+        "Blocking operation called with interrupts disabled"
+    );
+}
+
+/// Marks the running code as not allowed to allocate until it's dropped
+pub struct NoAllocGuard(());
+
+impl Drop for NoAllocGuard {
+    fn drop(&mut self) { NO_ALLOC.fetch_sub(1, Ordering::Relaxed); }
+}
+
+/// Forbids allocating until the returned guard is dropped, taken by the
+/// interrupt handlers
+pub fn no_alloc() -> NoAllocGuard {
+    NO_ALLOC.fetch_add(1, Ordering::Relaxed);
+
+    NoAllocGuard(())
+}
+
+/// Checks in debug builds that the caller is allowed to allocate or free
+#[track_caller]
+pub fn might_alloc() {
+    debug_assert!(
+        NO_ALLOC.load(Ordering::Relaxed) == 0,
+        "Allocation inside an interrupt handler"
+    );
+}
+
+/// Whether the caller runs inside an interrupt handler
+pub fn in_interrupt() -> bool { NO_ALLOC.load(Ordering::Relaxed) != 0 }
+
+/// Leaves the regions a panic didn't return from and enables interrupts, for
+/// the test runner that goes on after a test failed outside of an interrupt
+/// handler
+pub(crate) fn reset() {
+    DEPTH.store(0, Ordering::Relaxed);
+    interrupts::enable();
+}
+
+/// Returns the longest region with interrupts disabled and where it started
+pub fn longest() -> Option<(Duration, &'static Location<'static>)> {
+    let cycles = LONGEST_CYCLES.load(Ordering::Relaxed);
+    let location = interrupts::without_interrupts(|| *LONGEST_LOCATION.lock())?;
+
+    Some((tsc::cycles_to_duration(cycles)?, location))
+}
@@ -0,0 +1,199 @@
+//! Machine check architecture support
+//!
+//! The machine check exception is raised by the cpu when it detects an hardware
+//! error (bad memory, cache or bus errors), the details of the error are
+//! stored in the status registers of the reporting bank.
+use crate::taint::{self, Taint};
+use core::{arch::x86_64::__cpuid, fmt};
+use x86_64::registers::{
+    control::{Cr4, Cr4Flags},
+    model_specific::Msr,
+};
+
+const IA32_MCG_CAP: u32 = 0x179;
+const IA32_MCG_STATUS: u32 = 0x17A;
+const IA32_MCG_CTL: u32 = 0x17B;
+const IA32_MC0_CTL: u32 = 0x400;
+
+const CPUID_MCE: u32 = 1 << 7;
+const CPUID_MCA: u32 = 1 << 14;
+
+/// `IA32_MCG_CAP` bit signaling the presence of `IA32_MCG_CTL`
+const MCG_CTL_P: u64 = 1 << 8;
+
+/// `IA32_MCG_STATUS` bit signaling that the interrupted program can be
+/// restarted
+const MCG_STATUS_RIPV: u64 = 1;
+
+const STATUS_VAL: u64 = 1 << 63;
+const STATUS_OVER: u64 = 1 << 62;
+const STATUS_UC: u64 = 1 << 61;
+const STATUS_ADDRV: u64 = 1 << 58;
+const STATUS_PCC: u64 = 1 << 57;
+
+/// Enables the machine check exception and all the error reporting banks,
+/// errors left in the banks from before the reset are logged and cleared
+pub fn init() {
+    let features = unsafe { __cpuid(1) }.edx;
+
+    if features & CPUID_MCE == 0 {
+        log::warn!("Machine check exception not supported");
+        return;
+    }
+
+    if features & CPUID_MCA != 0 {
+        let cap = unsafe { Msr::new(IA32_MCG_CAP).read() };
+
+        if cap & MCG_CTL_P != 0 {
+            unsafe { Msr::new(IA32_MCG_CTL).write(u64::MAX) }
+        }
+
+        for bank in 0..bank_count() {
+            if let Some(error) = read_bank(bank) {
+                log::warn!("Machine check error from before boot: {}", error);
+                taint::add(Taint::MACHINE_CHECK);
+            }
+
+            unsafe {
+                // Bank 0 control is owned by the firmware on most intel cpus
+                if bank != 0 {
+                    bank_msr(bank, 0).write(u64::MAX);
+                }
+
+                bank_msr(bank, 1).write(0);
+            }
+        }
+    }
+
+    unsafe {
+        let mut flags = Cr4::read();
+        flags.insert(Cr4Flags::MACHINE_CHECK_EXCEPTION);
+        Cr4::write(flags);
+    }
+}
+
+/// Logs all the errors reported by the banks and returns true if the
+/// interrupted program can be restarted
+pub fn report() -> bool {
+    let status = unsafe { Msr::new(IA32_MCG_STATUS).read() };
+
+    for bank in 0..bank_count() {
+        if let Some(error) = read_bank(bank) {
+            log::error!("{}", error);
+            taint::add(Taint::MACHINE_CHECK);
+        }
+    }
+
+    status & MCG_STATUS_RIPV != 0
+}
+
+fn bank_count() -> u8 { (unsafe { Msr::new(IA32_MCG_CAP).read() } & 0xFF) as u8 }
+
+/// Returns one of the registers of a bank (0: CTL, 1: STATUS, 2: ADDR, 3: MISC)
+fn bank_msr(bank: u8, register: u32) -> Msr { Msr::new(IA32_MC0_CTL + bank as u32 * 4 + register) }
+
+fn read_bank(bank: u8) -> Option<MachineCheckError> {
+    let status = unsafe { bank_msr(bank, 1).read() };
+
+    if status & STATUS_VAL == 0 {
+        return None;
+    }
+
+    let address = if status & STATUS_ADDRV != 0 {
+        Some(unsafe { bank_msr(bank, 2).read() })
+    } else {
+        None
+    };
+
+    Some(MachineCheckError {
+        bank,
+        status,
+        address,
+    })
+}
+
+/// An error reported by one of the machine check banks
+pub struct MachineCheckError {
+    bank: u8,
+    status: u64,
+    address: Option<u64>,
+}
+
+impl MachineCheckError {
+    /// The architectural error code (lower 16 bits of the status)
+    pub fn error_code(&self) -> u16 { self.status as u16 }
+
+    /// The error wasn't corrected by the hardware
+    pub fn uncorrected(&self) -> bool { self.status & STATUS_UC != 0 }
+
+    /// The processor state might be corrupted
+    pub fn context_corrupt(&self) -> bool { self.status & STATUS_PCC != 0 }
+
+    /// Another error happened while this one was still in the bank
+    pub fn overflow(&self) -> bool { self.status & STATUS_OVER != 0 }
+
+    /// Decodes the class of the architectural error code
+    pub fn kind(&self) -> &'static str {
+        let code = self.error_code();
+        // Bit 12 only filters the reporting of corrected errors
+        let compound = code & !0x1000;
+
+        match code {
+            0x0000 => return "no error",
+            0x0001 => return "unclassified",
+            0x0002 => return "microcode rom parity error",
+            0x0003 => return "external error",
+            0x0004 => return "frc error",
+            0x0005 => return "internal parity error",
+            0x0006 => return "smm handler code access violation",
+            0x0400 => return "internal timer error",
+            0x0401..=0x07FF => return "internal unclassified error",
+            _ => {},
+        }
+
+        if compound & 0xF800 == 0x0800 {
+            "bus or interconnect error"
+        } else if compound & 0xFF00 == 0x0100 {
+            "cache hierarchy error"
+        } else if compound & 0xFF80 == 0x0080 {
+            "memory controller error"
+        } else if compound & 0xFFF0 == 0x0010 {
+            "tlb error"
+        } else if compound & 0xFFFC == 0x000C {
+            "generic cache hierarchy error"
+        } else {
+            "model specific error"
+        }
+    }
+}
+
+impl fmt::Display for MachineCheckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "bank {}: {} (code {:#X}, status {:#X})",
+            self.bank,
+            self.kind(),
+            self.error_code(),
+            self.status
+        )?;
+
+        if let Some(address) = self.address {
+            write!(f, " at {:#X}", address)?;
+        }
+
+        if self.uncorrected() {
+            write!(f, " uncorrected")?;
+        }
+
+        if self.context_corrupt() {
+            write!(f, " context corrupt")?;
+        }
+
+        if self.overflow() {
+            write!(f, " overflow")?;
+        }
+
+        Ok(())
+    }
+}
@@ -1,14 +1,28 @@
-use crate::{gdt, hlt_loop, memory, print, println};
-use core::fmt::{self, Display};
+use crate::{
+    drivers::{keyboard, ps2},
+    error_ratelimited, gdt, hlt_loop, logger, memory, print, println, serial_println,
+    taint::{self, Taint},
+    time,
+};
+use core::{
+    fmt::{self, Display},
+    mem::size_of,
+};
 use lazy_static::lazy_static;
-use x86_64::structures::{
-    idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
-    paging::Translate,
+use x86_64::{
+    structures::{
+        idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+        paging::Translate,
+    },
+    VirtAddr,
 };
 
 use self::controller::InterruptController;
+pub(crate) use self::controller::{read_apic_reg, write_apic_reg};
 
 mod controller;
+pub mod irqoff;
+pub mod mce;
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
@@ -18,6 +32,8 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    Rtc = PIC_2_OFFSET,
+    LapicTimer = PIC_2_OFFSET + 8,
 }
 
 pub static PICS: spin::Mutex<InterruptController> =
@@ -28,6 +44,8 @@ lazy_static! {
         let mut idt = InterruptDescriptorTable::new();
         idt.breakpoint.set_handler_fn(breakpoint_handler);
         idt.page_fault.set_handler_fn(page_fault_handler);
+        idt.machine_check.set_handler_fn(machine_check_handler);
+        idt.non_maskable_interrupt.set_handler_fn(nmi_handler);
         unsafe {
             idt.double_fault
                 .set_handler_fn(double_fault_handler)
@@ -35,6 +53,8 @@ lazy_static! {
         }
         idt[InterruptIndex::Timer as usize].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard as usize].set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::Rtc as usize].set_handler_fn(rtc_interrupt_handler);
+        idt[InterruptIndex::LapicTimer as usize].set_handler_fn(lapic_timer_interrupt_handler);
         idt
     };
 }
@@ -42,6 +62,8 @@ lazy_static! {
 pub fn init_idt() { IDT.load(); }
 
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut InterruptStackFrame) {
+    let _no_alloc = irqoff::no_alloc();
+
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
@@ -51,10 +73,21 @@ extern "x86-interrupt" fn page_fault_handler(
 ) {
     use x86_64::registers::control::Cr2;
 
+    let _no_alloc = irqoff::no_alloc();
+
     let addr = Cr2::read();
 
     println!("EXCEPTION: PAGE FAULT");
     println!("Accessed Address: {:?}", addr);
+
+    if let Some(region) = memory::vmm::region_of(addr) {
+        println!("Region: {}", region.name);
+    }
+
+    if let Some(stack) = memory::stack::overflowed(addr) {
+        println!("Kernel stack overflow: {} stack", stack);
+    }
+
     println!("Error Code: {:?}", error_code);
     println!("{}", stack_frame_display(stack_frame));
 
@@ -104,39 +137,166 @@ extern "x86-interrupt" fn double_fault_handler(
     stack_frame: &mut InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+    use x86_64::registers::control::{Cr2, Cr3};
+
+    let _no_alloc = irqoff::no_alloc();
+
+    let cr2 = Cr2::read();
+    let (cr3, _) = Cr3::read();
+    let cause = DoubleFaultCause::diagnose(stack_frame, cr2);
+
+    println!("EXCEPTION: DOUBLE FAULT");
+    println!("CR2: {:#X}", cr2.as_u64());
+    println!("CR3: {:#X}", cr3.start_address().as_u64());
+    println!("{}", stack_frame_display(stack_frame));
+    println!("Probable cause: {}", cause.description());
+
+    if let Some(stack) = memory::stack::overflowed(cr2) {
+        println!("Overflowed stack: {}", stack);
+    }
+
+    // The history is too long for the screen so only send it to the serial
+    serial_println!("Recent log messages:");
+    logger::recent_messages(|message| {
+        serial_println!("    {}", message);
+    });
+
+    panic!("EXCEPTION: DOUBLE FAULT ({:?})", cause);
+}
+
+/// Best guess of what caused a double fault
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DoubleFaultCause {
+    /// The faulting address is in the guard page of a stack or right below the
+    /// interrupted stack pointer
+    StackOverflow,
+    /// The faulting address is inside the IDT, GDT or TSS so the cpu couldn't
+    /// fetch the descriptors needed to deliver the first exception
+    DescriptorTable,
+    /// The double fault stack isn't the one set up at init, so the handler
+    /// might be running on garbage
+    CorruptedIst,
+    Unknown,
+}
+
+impl DoubleFaultCause {
+    fn diagnose(stack_frame: &InterruptStackFrame, cr2: VirtAddr) -> Self {
+        // Any local lives on the stack the handler is running on
+        let marker = 0u8;
+        let current_stack = VirtAddr::from_ptr(&marker);
+
+        if !gdt::double_fault_ist_intact() || !gdt::double_fault_stack().contains(&current_stack) {
+            return DoubleFaultCause::CorruptedIst;
+        }
+
+        let idt_start = VirtAddr::from_ptr(&*IDT);
+        let idt = idt_start..idt_start + size_of::<InterruptDescriptorTable>();
+
+        if idt.contains(&cr2) || gdt::descriptor_tables_contain(cr2) {
+            return DoubleFaultCause::DescriptorTable;
+        }
+
+        if memory::stack::overflowed(cr2).is_some() {
+            return DoubleFaultCause::StackOverflow;
+        }
+
+        let sp = stack_frame.stack_pointer.as_u64();
+
+        // Pushing the exception frame for the first fault failed on the page
+        // below the stack pointer
+        if cr2.as_u64() <= sp && sp - cr2.as_u64() <= 0x1000 {
+            return DoubleFaultCause::StackOverflow;
+        }
+
+        DoubleFaultCause::Unknown
+    }
+
+    fn description(&self) -> &'static str {
+        match self {
+            DoubleFaultCause::StackOverflow => "kernel stack overflow",
+            DoubleFaultCause::DescriptorTable => "page fault while accessing a descriptor table",
+            DoubleFaultCause::CorruptedIst => "corrupted interrupt stack table",
+            DoubleFaultCause::Unknown => "unknown",
+        }
+    }
+}
+
+extern "x86-interrupt" fn machine_check_handler(stack_frame: &mut InterruptStackFrame) -> ! {
+    let _no_alloc = irqoff::no_alloc();
+
+    println!("EXCEPTION: MACHINE CHECK");
+    println!("{}", stack_frame_display(stack_frame));
+
+    let restartable = mce::report();
+
+    panic!("EXCEPTION: MACHINE CHECK (restartable: {})", restartable);
+}
+
+/// The legacy nmi sources are reported in the system control port B
+const SYSTEM_CONTROL_B: u16 = 0x61;
+const NMI_SERR: u8 = 1 << 7;
+const NMI_IOCHK: u8 = 1 << 6;
+
+extern "x86-interrupt" fn nmi_handler(_stack_frame: &mut InterruptStackFrame) {
+    use x86_64::instructions::port::PortRead;
+
+    let _no_alloc = irqoff::no_alloc();
+
+    let reason = unsafe { u8::read_from_port(SYSTEM_CONTROL_B) };
+
+    if reason & NMI_SERR != 0 {
+        error_ratelimited!("NMI: system error (memory parity)");
+    } else if reason & NMI_IOCHK != 0 {
+        error_ratelimited!("NMI: io channel check");
+    } else {
+        error_ratelimited!("NMI: unknown reason {:#X}", reason);
+        taint::add(Taint::UNKNOWN_NMI);
+    }
 }
 
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
-    // print!(".");
+    let _no_alloc = irqoff::no_alloc();
+
+    time::handle_event();
+
     unsafe {
         PICS.lock()
             .notify_end_of_interrupt(InterruptIndex::Timer as u8);
     }
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
-    use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-    use spin::Mutex;
-    use x86_64::instructions::port::Port;
-
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> = Mutex::new(
-            Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore)
-        );
+extern "x86-interrupt" fn lapic_timer_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+    let _no_alloc = irqoff::no_alloc();
+
+    time::handle_event();
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::LapicTimer as u8);
     }
+}
 
-    let mut keyboard = KEYBOARD.lock();
-    let mut port = Port::new(0x60);
+extern "x86-interrupt" fn rtc_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+    let _no_alloc = irqoff::no_alloc();
 
-    let scancode: u8 = unsafe { port.read() };
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(character) => print!("{}", character),
-                DecodedKey::RawKey(key) => print!("{:?}", key),
-            }
-        }
+    time::rtc::handle_interrupt();
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Rtc as u8);
+    }
+}
+
+extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+    use pc_keyboard::DecodedKey;
+
+    let _no_alloc = irqoff::no_alloc();
+
+    if let Some(scancode) = ps2::try_read() {
+        keyboard::handle_scancode(scancode, |key| match key {
+            DecodedKey::Unicode(character) => print!("{}", character),
+            DecodedKey::RawKey(key) => print!("{:?}", key),
+        });
     }
 
     unsafe {
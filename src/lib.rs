@@ -2,6 +2,8 @@
 #![cfg_attr(test, no_main)]
 #![feature(custom_test_frameworks)]
 #![feature(abi_x86_interrupt)]
+#![feature(asm)]
+#![feature(const_panic)]
 #![feature(alloc_error_handler)]
 #![feature(const_mut_refs)]
 #![feature(const_maybe_uninit_assume_init, maybe_uninit_slice)]
@@ -11,8 +13,10 @@
 #[cfg(test)]
 use bootloader::entry_point;
 use bootloader::BootInfo;
-use core::panic::PanicInfo;
-use x86_64::{structures::port::PortWrite, VirtAddr};
+use core::{fmt, panic::PanicInfo, time::Duration};
+use spin::Mutex;
+use time::{timer::TimerHandle, tsc};
+use x86_64::VirtAddr;
 
 extern crate alloc;
 
@@ -20,51 +24,124 @@ pub mod acpi;
 pub mod ahci;
 pub mod allocator;
 pub mod apic;
+pub mod boot;
+pub mod cpufreq;
+pub mod cpuidle;
+pub mod device;
+pub mod dma;
+pub mod drivers;
+pub mod error;
+pub mod fmtbuf;
 pub mod gdt;
 pub mod interrupts;
 pub mod logger;
 pub mod memory;
 pub mod pci;
+pub mod power;
 pub mod serial;
+pub mod taint;
+pub mod time;
 pub mod vga_buffer;
+pub mod vga_graphics;
 
 pub fn init(boot_info: &'static BootInfo) {
+    let _stage = boot::stage("init");
+
+    // Setup the early console so that failures before the heap is ready
+    // are visible
+    logger::init_early();
+    boot::milestone(boot::Milestone::Console);
+
     gdt::init();
     interrupts::init_idt();
+    boot::profile("mce", interrupts::mce::init);
     unsafe { interrupts::PICS.lock().init() };
     x86_64::instructions::interrupts::enable();
+    boot::milestone(boot::Milestone::Interrupts);
 
-    // Setup the pit for 1ms tick
-    pit_init();
-
-    // Setup logger
-    log::set_logger(&logger::Logger).unwrap();
-    log::set_max_level(log::LevelFilter::Debug);
+    // Start the timer tick and calibrate the clocks
+    boot::profile("time", time::init);
+    boot::milestone(boot::Milestone::Timers);
 
     // Setup memory and heap
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
 
-    unsafe { memory::init(phys_mem_offset, &boot_info.memory_map) };
+    boot::profile("memory", || unsafe {
+        memory::init(phys_mem_offset, &boot_info.memory_map)
+    });
+    boot::milestone(boot::Milestone::Memory);
+
+    boot::profile("heap", allocator::init_heap).expect("heap initialization failed");
+
+    // Hand over from the early console to the main logger
+    logger::init();
+    boot::milestone(boot::Milestone::Heap);
 
-    allocator::init_heap().expect("heap initialization failed");
+    device::init();
+
+    let ps2 = device::register(
+        Some(device::PLATFORM),
+        "i8042",
+        device::Bus::Platform,
+        alloc::vec![
+            device::Resource::Io(0x60..0x61),
+            device::Resource::Io(0x64..0x65),
+            device::Resource::Irq(1),
+        ],
+    );
+
+    boot::profile("ps2", || {
+        device::probe(ps2, &drivers::ps2::DRIVER, drivers::ps2::init)
+    });
 }
 
-fn pit_init() {
-    const DIVISOR: u16 = 1193; // 1193182 / 1193 ≃ 1000
-    unsafe {
-        u8::write_to_port(0x43, 0b00110100);
-        u8::write_to_port(0x40, DIVISOR as u8);
-        u8::write_to_port(0x40, (DIVISOR >> 8) as u8);
-    }
+pub fn sleep(miliseconds: u64) { time::sleep(Duration::from_millis(miliseconds)) }
+
+/// How long the whole test run may take, can be changed with `TEST_TIMEOUT`
+/// in seconds at build time
+///
+/// It's shorter than the timeout of bootimage so the test that hung is
+/// reported instead of the run just being killed.
+const TEST_TIMEOUT: Duration = Duration::from_secs(240);
+
+/// The state of the test run, the panic handler uses it to report the failed
+/// test and go on with the next one
+static TEST_RUN: Mutex<Option<TestRun>> = Mutex::new(None);
+
+struct TestRun {
+    /// The tests passed to `test_runner`, the lifetimes are made up so the
+    /// pointer is only valid while the frame of `test_runner` is, which is for
+    /// the whole run since it never returns and a panic doesn't unwind it
+    tests: *const [&'static dyn Testable],
+    next: usize,
+    failed: usize,
+    /// The running test and the tsc when it started
+    current: Option<(&'static str, u64)>,
+    watchdog: Option<TimerHandle>,
 }
 
-pub fn sleep(miliseconds: u64) {
-    for _ in 0..miliseconds {
-        x86_64::instructions::hlt()
+// The tests are only run by the runner and the panic handler
+unsafe impl Send for TestRun {}
+
+impl TestRun {
+    fn len(&self) -> usize {
+        // Safety: the run is still going on, see `tests`
+        unsafe { (*self.tests).len() }
+    }
+
+    /// Returns a pointer to the test at `index`, it's valid while the run is
+    /// going on
+    fn test(&self, index: usize) -> Option<*const dyn Testable> {
+        // Safety: the run is still going on, see `tests`
+        let tests = unsafe { &*self.tests };
+
+        tests.get(index).map(|test| *test as *const dyn Testable)
     }
 }
 
 pub trait Testable {
+    fn name(&self) -> &'static str;
+
     fn run(&self);
 }
 
@@ -72,26 +149,195 @@ impl<T> Testable for T
 where
     T: Fn(),
 {
-    fn run(&self) {
-        serial_print!("{}...\t", core::any::type_name::<T>());
-        self();
-        serial_println!("[ok]");
-    }
+    fn name(&self) -> &'static str { core::any::type_name::<T>() }
+
+    fn run(&self) { self() }
 }
 
+/// Runs the tests and reports the results over serial as lines starting with
+/// `[test]`, which `tools/testharness.py` parses on the host:
+///
+/// | Line                                   | Meaning                           |
+/// |----------------------------------------|-----------------------------------|
+/// | `[test] run <count>`                   | The run started                   |
+/// | `[test] start <name>`                  | A test started                    |
+/// | `[test] ok <name> <us>`                | The test passed                   |
+/// | `[test] failed <name> <us> <message>`  | The test panicked                 |
+/// | `[test] timeout <name>`                | The watchdog stopped the run      |
+/// | `[test] panic <message>`               | The run can't go on after a panic |
+/// | `[test] done <passed> <failed>`        | All the tests ran                 |
+///
+/// Durations are in microseconds, `-` if the tsc isn't calibrated. Panic
+/// messages are escaped to a single line with `\n` and `\\`. Everything else
+/// printed between the start of a test and its result is the output of that
+/// test, the logs included.
+///
+/// A failed test doesn't stop the run, the next one is started from the panic
+/// handler. Nothing is unwound so the stack frames of every failed test are
+/// left behind, a run with many failures could overflow the stack and hit
+/// it's guard page. If the failed test held a lock the following ones might
+/// hang, the watchdog stops the run then. A panic in an interrupt handler ends
+/// the run since the interrupt can't be finished.
 pub fn test_runner(tests: &[&dyn Testable]) {
-    serial_println!("Running {} tests", tests.len());
-    for test in tests {
+    serial_println!("[test] run {}", tests.len());
+
+    // Only catches hangs with interrupts enabled, the timer can't fire
+    // otherwise
+    let timeout = option_env!("TEST_TIMEOUT")
+        .and_then(|secs| secs.parse().ok())
+        .map_or(TEST_TIMEOUT, Duration::from_secs);
+    let watchdog = time::timer::after(timeout, || {
+        let test = TEST_RUN
+            .try_lock()
+            .and_then(|run| Some(run.as_ref()?.current?.0));
+
+        // Runs in interrupt context so the logger can't be used
+        serial_println!("[test] timeout {}", test.unwrap_or("-"));
+        exit_qemu(QemuExitCode::Timeout);
+    });
+
+    interrupts::irqoff::without_interrupts(|| {
+        *TEST_RUN.lock() = Some(TestRun {
+            tests: tests as *const [&dyn Testable] as *const [&'static dyn Testable],
+            next: 0,
+            failed: 0,
+            current: None,
+            watchdog: Some(watchdog),
+        })
+    });
+
+    run_tests()
+}
+
+/// Runs the tests that are left and exits qemu
+fn run_tests() -> ! {
+    loop {
+        let test = with_run(|run| {
+            let test = run.test(run.next);
+            run.next += 1;
+            test
+        });
+
+        // Safety: this runs inside the frame of `test_runner`, which never
+        // returns, so the tests it was passed are still there
+        let test = match test {
+            Some(test) => unsafe { &*test },
+            None => break,
+        };
+
+        serial_println!("[test] start {}", test.name());
+
+        let start = tsc::read();
+        with_run(|run| run.current = Some((test.name(), start)));
+
         test.run();
+
+        with_run(|run| run.current = None);
+        serial_println!("[test] ok {} {}", test.name(), Micros(start));
+    }
+
+    let (total, failed, watchdog) = with_run(|run| (run.len(), run.failed, run.watchdog.take()));
+
+    if let Some(watchdog) = watchdog {
+        watchdog.cancel();
+    }
+
+    serial_println!("[test] done {} {}", total - failed, failed);
+
+    if failed == 0 {
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        exit_qemu(QemuExitCode::Failed);
+    }
+
+    hlt_loop();
+}
+
+fn with_run<R>(f: impl FnOnce(&mut TestRun) -> R) -> R {
+    interrupts::irqoff::without_interrupts(|| f(TEST_RUN.lock().as_mut().unwrap()))
+}
+
+/// Shows the microseconds since the tsc read `start`
+struct Micros(u64);
+
+impl fmt::Display for Micros {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match tsc::cycles_to_duration(tsc::read() - self.0) {
+            Some(duration) => write!(f, "{}", duration.as_micros()),
+            None => write!(f, "-"),
+        }
+    }
+}
+
+/// Shows a message on a single line by escaping newlines and backslashes
+struct Escaped<T>(T);
+
+impl<T: fmt::Display> fmt::Display for Escaped<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        struct Escaper<'a, 'b>(&'a mut fmt::Formatter<'b>);
+
+        impl fmt::Write for Escaper<'_, '_> {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                for c in s.chars() {
+                    match c {
+                        '\n' => self.0.write_str("\\n")?,
+                        '\\' => self.0.write_str("\\\\")?,
+                        c => fmt::Write::write_char(self.0, c)?,
+                    }
+                }
+
+                Ok(())
+            }
+        }
+
+        fmt::write(&mut Escaper(f), format_args!("{}", self.0))
+    }
+}
+
+/// Prints the panic message and the state that might explain it on the screen
+pub fn print_panic_screen(message: &dyn fmt::Display) {
+    println!("{}", message);
+    println!("{}", taint::status());
+
+    if let Some(id) = device::probing() {
+        println!("Panicked while probing device {}", id);
     }
-    exit_qemu(QemuExitCode::Success);
 }
 
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
-    serial_println!("[failed]\n");
-    serial_println!("Error: {}\n", info);
-    exit_qemu(QemuExitCode::Failed);
-    hlt_loop();
+    // Nothing that was interrupted by the panic runs again
+    unsafe {
+        vga_buffer::force_unlock();
+        serial::force_unlock();
+    }
+
+    let current = TEST_RUN.try_lock().and_then(|mut run| {
+        let run = run.as_mut()?;
+        let current = run.current.take()?;
+
+        run.failed += 1;
+        Some(current)
+    });
+
+    match current {
+        Some((name, start)) => {
+            serial_println!("[test] failed {} {} {}", name, Micros(start), Escaped(info));
+
+            if interrupts::irqoff::in_interrupt() {
+                serial_println!("[test] panic {} panicked in an interrupt handler", name);
+                exit_qemu(QemuExitCode::Failed);
+                hlt_loop()
+            }
+
+            interrupts::irqoff::reset();
+            run_tests()
+        },
+        None => {
+            serial_println!("[test] panic outside of a test: {}", Escaped(info));
+            exit_qemu(QemuExitCode::Failed);
+            hlt_loop()
+        },
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -99,6 +345,8 @@ pub fn test_panic_handler(info: &PanicInfo) -> ! {
 pub enum QemuExitCode {
     Success = 0x10,
     Failed = 0x11,
+    /// The test watchdog stopped a run that took too long
+    Timeout = 0x12,
 }
 
 pub fn exit_qemu(exit_code: QemuExitCode) {
@@ -130,3 +378,14 @@ fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
 #[cfg(test)]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! { test_panic_handler(info) }
+
+#[test_case]
+fn panic_screen_layout() {
+    use alloc::{format, string::ToString};
+
+    let snapshot = vga_buffer::capture(|| print_panic_screen(&"panicked at 'oops'"));
+
+    // No device is being probed while the tests run
+    let expected = format!("panicked at 'oops'\n{}", taint::status());
+    assert_eq!(snapshot.to_string(), expected);
+}
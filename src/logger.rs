@@ -1,5 +1,143 @@
-use crate::{serial_print, serial_println};
+use crate::{
+    boot,
+    fmtbuf::FmtBuf,
+    format_fixed,
+    memory::shrinker::{self, Shrinker},
+    serial_print, serial_println, time,
+    vga_buffer::{self, Color, ColorCode},
+};
+use alloc::collections::VecDeque;
+use core::{
+    fmt::{self, Display, Write},
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+    time::Duration,
+};
 use log::Log;
+use spin::{Mutex, Once};
+
+mod binary;
+
+/// Size of the buffer holding the messages logged before the heap is ready
+const EARLY_BUFFER_SIZE: usize = 4096;
+/// Number of messages kept in the history
+const HISTORY_LEN: usize = 64;
+/// How often [`RateLimit`] lets a new burst of messages through
+const RATELIMIT_INTERVAL: Duration = Duration::from_secs(5);
+/// How many messages [`RateLimit`] lets through every interval
+const RATELIMIT_BURST: u32 = 10;
+
+/// Set once the heap is available and the main logger took over from the
+/// early console
+static READY: AtomicBool = AtomicBool::new(false);
+
+static EARLY_BUFFER: Mutex<EarlyBuffer> = Mutex::new(EarlyBuffer::new());
+
+/// The recent messages, the space for all of them is allocated up front so
+/// logging never allocates and can be used from interrupt handlers
+///
+/// It's given back under memory pressure by [`HistoryShrinker`], the messages
+/// logged after that aren't kept.
+static HISTORY: Once<Mutex<Option<VecDeque<FmtBuf>>>> = Once::new();
+
+/// Whether log messages are shown on the screen
+static SCREEN: AtomicBool = AtomicBool::new(true);
+
+/// The last message logged, identical messages in a row are only counted so
+/// that a storm of them doesn't flood the serial port and the screen
+static REPEATS: Mutex<Repeats> = Mutex::new(Repeats {
+    last: 0,
+    count: 0,
+    on_screen: false,
+});
+
+struct Repeats {
+    /// Hash of the last message
+    last: u64,
+    count: usize,
+    /// Whether the last message was shown on the screen
+    on_screen: bool,
+}
+
+/// Logs like `log::log!` but only lets through a burst of messages every few
+/// seconds from each call site, how many were dropped is logged before the
+/// next one that goes through
+#[macro_export]
+macro_rules! log_ratelimited {
+    ($lvl:expr, $($arg:tt)+) => {{
+        static LIMIT: $crate::logger::RateLimit = $crate::logger::RateLimit::new();
+
+        if let Some(missed) = LIMIT.check() {
+            if missed != 0 {
+                ::log::log!($lvl, "{} messages suppressed", missed);
+            }
+
+            ::log::log!($lvl, $($arg)+);
+        }
+    }};
+}
+
+/// Logs an error through [`log_ratelimited!`]
+#[macro_export]
+macro_rules! error_ratelimited {
+    ($($arg:tt)+) => ($crate::log_ratelimited!(::log::Level::Error, $($arg)+));
+}
+
+/// Logs a warning through [`log_ratelimited!`]
+#[macro_export]
+macro_rules! warn_ratelimited {
+    ($($arg:tt)+) => ($crate::log_ratelimited!(::log::Level::Warn, $($arg)+));
+}
+
+/// Lets through at most [`RATELIMIT_BURST`] events every
+/// [`RATELIMIT_INTERVAL`]
+///
+/// It only uses atomics and the tick count so it can be used anywhere, even
+/// from the time code and interrupt handlers. Racing callers might let a few
+/// more through.
+pub struct RateLimit {
+    /// The tick the current interval started at
+    start: AtomicU64,
+    count: AtomicU32,
+    missed: AtomicU32,
+}
+
+impl RateLimit {
+    pub const fn new() -> Self {
+        RateLimit {
+            start: AtomicU64::new(0),
+            count: AtomicU32::new(0),
+            missed: AtomicU32::new(0),
+        }
+    }
+
+    /// Returns `None` if the event must be dropped, otherwise how many were
+    /// dropped since the last one let through
+    pub fn check(&self) -> Option<u32> {
+        let interval = (RATELIMIT_INTERVAL.as_nanos() / time::TICK_PERIOD.as_nanos()) as u64;
+        let now = time::ticks();
+        let start = self.start.load(Ordering::Relaxed);
+
+        if now.wrapping_sub(start) >= interval
+            && self
+                .start
+                .compare_exchange(start, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            self.count.store(0, Ordering::Relaxed);
+        }
+
+        if self.count.fetch_add(1, Ordering::Relaxed) < RATELIMIT_BURST {
+            Some(self.missed.swap(0, Ordering::Relaxed))
+        } else {
+            self.missed.fetch_add(1, Ordering::Relaxed);
+            None
+        }
+    }
+}
+
+impl Default for RateLimit {
+    fn default() -> Self { RateLimit::new() }
+}
 
 pub struct Logger;
 
@@ -11,18 +149,315 @@ impl Log for Logger {
             return;
         }
 
-        serial_print!("[{}][{}]", record.level(), record.target());
+        match fold_repeats(record) {
+            None => return,
+            Some((0, _)) => {},
+            Some((count, on_screen)) => log_repeats(count, on_screen),
+        }
 
-        if let Some(file) = record.file() {
-            serial_print!("[{}", file);
-            if let Some(line) = record.line() {
-                serial_print!(":{}", line);
-            }
-            serial_print!("]");
+        if binary::ENABLED {
+            binary::write_record(record);
+        } else {
+            write_serial(record);
         }
 
-        serial_println!("{}", record.args());
+        // There might not be anyone listening on the serial port so the
+        // screen gets a copy
+        write_screen(record);
+
+        if READY.load(Ordering::Acquire) {
+            push_history(format_fixed!("{}", record_display(record)));
+        } else {
+            x86_64::instructions::interrupts::without_interrupts(|| {
+                let _ = writeln!(EARLY_BUFFER.lock(), "{}", record_display(record));
+            });
+        }
     }
 
     fn flush(&self) {}
 }
+
+/// Installs the logger in early console mode, this doesn't need the heap so it
+/// can be called before anything else is initialized
+pub fn init_early() {
+    log::set_logger(&Logger).unwrap();
+    log::set_max_level(log::LevelFilter::Debug);
+}
+
+/// Switches from the early console to the main logger replaying the messages
+/// logged until now into the history
+///
+/// The heap must be initialized before calling this
+pub fn init() {
+    HISTORY.call_once(|| Mutex::new(Some(VecDeque::with_capacity(HISTORY_LEN))));
+    shrinker::register(&HistoryShrinker);
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let early = EARLY_BUFFER.lock();
+
+        for line in early.as_str().lines() {
+            push_history(format_fixed!("{}", line));
+        }
+
+        if early.dropped != 0 {
+            push_history(format_fixed!(
+                "[WARN][early] {} bytes of early messages were dropped",
+                early.dropped
+            ));
+        }
+
+        READY.store(true, Ordering::Release);
+    });
+}
+
+/// Calls `f` with the most recent messages from the oldest to the newest, does
+/// nothing if the main logger isn't initialized yet
+///
+/// Since this is meant to be used in fault handlers it doesn't wait for the
+/// history lock if it's already taken
+pub fn recent_messages(mut f: impl FnMut(&str)) {
+    if let Some(history) = HISTORY.get().and_then(|history| history.try_lock()) {
+        for message in history.iter().flatten() {
+            f(message.as_str())
+        }
+    }
+}
+
+/// Enables or disables showing log messages on the screen, they still go to
+/// the serial port
+pub fn set_screen_output(enabled: bool) { SCREEN.store(enabled, Ordering::Relaxed) }
+
+fn write_serial(record: &log::Record) {
+    serial_print!("[{}][{}]", record.level(), record.target());
+
+    if let Some(file) = record.file() {
+        serial_print!("[{}", file);
+        if let Some(line) = record.line() {
+            serial_print!(":{}", line);
+        }
+        serial_print!("]");
+    }
+
+    serial_println!("{}", record.args());
+}
+
+/// Whether `record` is shown on the screen
+fn on_screen(record: &log::Record) -> bool {
+    // Outside verbose mode only problems are shown so they don't bury the boot
+    // progress
+    SCREEN.load(Ordering::Relaxed) && (boot::verbose() || record.level() <= log::Level::Warn)
+}
+
+fn write_screen(record: &log::Record) {
+    if !on_screen(record) {
+        return;
+    }
+
+    vga_buffer::print_colored(
+        level_color(record.level()),
+        format_args!("{}\n", record_display(record)),
+    );
+}
+
+/// Counts `record` if it's the same as the last message and returns `None`,
+/// otherwise returns how many times the last message was repeated and whether
+/// it was shown on the screen
+///
+/// The count is only reported when a different message comes, a message
+/// logged while another one is being counted is never folded.
+fn fold_repeats(record: &log::Record) -> Option<(usize, bool)> {
+    let mut hasher = Fnv::new();
+    let _ = write!(hasher, "{}", record_display(record));
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut repeats = match REPEATS.try_lock() {
+            Some(repeats) => repeats,
+            None => return Some((0, false)),
+        };
+
+        if repeats.last == hasher.0 {
+            repeats.count += 1;
+            return None;
+        }
+
+        let previous = (repeats.count, repeats.on_screen);
+
+        repeats.last = hasher.0;
+        repeats.count = 0;
+        repeats.on_screen = on_screen(record);
+
+        Some(previous)
+    })
+}
+
+fn log_repeats(count: usize, on_screen: bool) {
+    serial_println!("(last message repeated {} times)", count);
+
+    if on_screen && SCREEN.load(Ordering::Relaxed) {
+        vga_buffer::print_colored(
+            ColorCode::new(Color::DarkGray, Color::Black),
+            format_args!("(last message repeated {} times)\n", count),
+        );
+    }
+
+    if READY.load(Ordering::Acquire) {
+        push_history(format_fixed!("(last message repeated {} times)", count));
+    }
+}
+
+fn level_color(level: log::Level) -> ColorCode {
+    let foreground = match level {
+        log::Level::Error => Color::LightRed,
+        log::Level::Warn => Color::Yellow,
+        log::Level::Info => Color::White,
+        log::Level::Debug => Color::LightGray,
+        log::Level::Trace => Color::DarkGray,
+    };
+
+    ColorCode::new(foreground, Color::Black)
+}
+
+/// Adds `message` to the history, it's dropped if the history lock is taken
+/// since nmis can't be masked and would wait forever for the code they
+/// interrupted
+fn push_history(message: FmtBuf) {
+    if let Some(history) = HISTORY.get() {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut history = match history.try_lock() {
+                Some(history) => history,
+                None => return,
+            };
+            let history = match history.as_mut() {
+                Some(history) => history,
+                None => return,
+            };
+
+            if history.len() == HISTORY_LEN {
+                history.pop_front();
+            }
+
+            history.push_back(message);
+        })
+    }
+}
+
+/// Frees the history, it's the only cache the logger has
+struct HistoryShrinker;
+
+impl HistoryShrinker {
+    fn size(history: &VecDeque<FmtBuf>) -> usize {
+        history.capacity() * core::mem::size_of::<FmtBuf>()
+    }
+}
+
+impl Shrinker for HistoryShrinker {
+    fn name(&self) -> &'static str { "log history" }
+
+    fn count(&self) -> usize {
+        HISTORY
+            .get()
+            .and_then(|history| history.try_lock())
+            .and_then(|history| history.as_ref().map(HistoryShrinker::size))
+            .unwrap_or(0)
+    }
+
+    fn shrink(&self, _target: usize) -> usize {
+        HISTORY
+            .get()
+            .and_then(|history| history.try_lock())
+            .and_then(|mut history| history.take())
+            .map_or(0, |history| HistoryShrinker::size(&history))
+    }
+}
+
+fn record_display<'a>(record: &'a log::Record<'a>) -> impl Display + 'a {
+    struct RecordDisplay<'a>(&'a log::Record<'a>);
+
+    impl<'a> Display for RecordDisplay<'a> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(
+                f,
+                "[{}][{}] {}",
+                self.0.level(),
+                self.0.target(),
+                self.0.args()
+            )
+        }
+    }
+
+    RecordDisplay(record)
+}
+
+/// A fixed size buffer for the messages logged before the heap is available,
+/// when full new messages are dropped
+struct EarlyBuffer {
+    data: [u8; EARLY_BUFFER_SIZE],
+    len: usize,
+    dropped: usize,
+}
+
+impl EarlyBuffer {
+    const fn new() -> Self {
+        EarlyBuffer {
+            data: [0; EARLY_BUFFER_SIZE],
+            len: 0,
+            dropped: 0,
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        // Only whole `&str`s are ever written so this can't fail
+        core::str::from_utf8(&self.data[..self.len]).unwrap_or_default()
+    }
+}
+
+impl Write for EarlyBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.len + s.len() > EARLY_BUFFER_SIZE {
+            self.dropped += s.len();
+            return Ok(());
+        }
+
+        self.data[self.len..self.len + s.len()].copy_from_slice(s.as_bytes());
+        self.len += s.len();
+
+        Ok(())
+    }
+}
+
+/// 64 bit FNV-1a hasher for formatted text
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Self { Fnv(0xcbf2_9ce4_8422_2325) }
+}
+
+impl Write for Fnv {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.0 = (self.0 ^ byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+
+        Ok(())
+    }
+}
+
+#[test_case]
+fn history_is_freed_under_pressure() {
+    use core::alloc::Layout;
+
+    assert!(HistoryShrinker.count() > 0);
+
+    // More than the heap can grow to so only the shrinkers can help
+    let layout = Layout::from_size_align(1 << 40, 8).unwrap();
+    let ptr = unsafe { alloc::alloc::alloc(layout) };
+
+    assert!(ptr.is_null());
+    assert_eq!(HistoryShrinker.count(), 0);
+
+    // Put it back for the other tests
+    let history = Some(VecDeque::with_capacity(HISTORY_LEN));
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        *HISTORY.get().unwrap().lock() = history
+    });
+}
@@ -0,0 +1,101 @@
+//! Compact binary encoding of the log records sent to the serial port
+//!
+//! Formatting every record as text is too slow for high frequency tracing, so
+//! when built with `LOG_FORMAT=binary` the records are sent as frames that
+//! `tools/logdecode.py` turns back into text on the host. Everything else
+//! printed to the serial port stays text and is passed through by the decoder.
+//!
+//! A frame starts with [`MAGIC`] followed by the length of the rest of the
+//! frame as a little endian u16, then:
+//!
+//! | Size  | Field                                     |
+//! |-------|-------------------------------------------|
+//! | 1     | [`VERSION`]                               |
+//! | 1     | Level, 1 for errors up to 5 for traces    |
+//! | 8     | Ticks since boot                          |
+//! | 4     | Line, 0 if unknown                        |
+//! | 1 + n | Target                                    |
+//! | 1 + n | File, empty if unknown                    |
+//! | rest  | Message                                   |
+//!
+//! Numbers are little endian and strings are utf-8, the ones prefixed by their
+//! length are cut to 255 bytes and the message to [`FMT_BUF_SIZE`]. Only the
+//! message is formatted, the rest is copied as is.
+use crate::{fmtbuf::FMT_BUF_SIZE, format_fixed, serial, time};
+
+/// Starts every frame, 0xFE never appears in utf-8 text
+const MAGIC: [u8; 2] = [0xFE, 0xCA];
+const VERSION: u8 = 1;
+/// Size of the magic and the length
+const HEADER_SIZE: usize = 4;
+const MAX_FRAME_SIZE: usize = HEADER_SIZE + 14 + 2 * (1 + u8::MAX as usize) + FMT_BUF_SIZE;
+
+/// Whether the records are sent in the binary format
+pub const ENABLED: bool = matches!(option_env!("LOG_FORMAT"), Some("binary"));
+
+/// Sends `record` to the serial port as a single frame
+pub fn write_record(record: &log::Record) {
+    let message = format_fixed!("{}", record.args());
+    let mut frame = Frame::new();
+
+    frame.push(&[VERSION, record.level() as u8]);
+    frame.push(&time::ticks().to_le_bytes());
+    frame.push(&record.line().unwrap_or(0).to_le_bytes());
+    frame.push_short_str(record.target());
+    frame.push_short_str(record.file().unwrap_or(""));
+    frame.push(message.as_str().as_bytes());
+
+    serial::write_bytes(frame.finish());
+}
+
+struct Frame {
+    data: [u8; MAX_FRAME_SIZE],
+    len: usize,
+}
+
+impl Frame {
+    fn new() -> Self {
+        Frame {
+            data: [0; MAX_FRAME_SIZE],
+            len: HEADER_SIZE,
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        self.data[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+
+    /// Pushes `s` prefixed by it's length
+    fn push_short_str(&mut self, s: &str) {
+        let s = cut(s, u8::MAX as usize);
+
+        self.push(&[s.len() as u8]);
+        self.push(s.as_bytes());
+    }
+
+    /// Fills in the header and returns the whole frame
+    fn finish(&mut self) -> &[u8] {
+        let len = (self.len - HEADER_SIZE) as u16;
+
+        self.data[..2].copy_from_slice(&MAGIC);
+        self.data[2..HEADER_SIZE].copy_from_slice(&len.to_le_bytes());
+
+        &self.data[..self.len]
+    }
+}
+
+/// Cuts `s` to at most `max` bytes on a character boundary
+fn cut(s: &str, max: usize) -> &str {
+    if s.len() <= max {
+        return s;
+    }
+
+    let mut end = max;
+
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    &s[..end]
+}
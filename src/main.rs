@@ -7,123 +7,291 @@
 
 extern crate alloc;
 
+use alloc::{boxed::Box, string::ToString, vec::Vec};
 use bootloader::{entry_point, BootInfo};
-use capucho_os::{acpi::SleepState, ahci::HBAMemoryRegisters, apic, memory::mmap_dev, println};
+use capucho_os::{
+    acpi::AcpiMode,
+    ahci::{AhciDriver, HBAMemoryRegisters},
+    apic,
+    boot::{self, Milestone},
+    cpufreq, cpuidle, device,
+    error::KError,
+    memory::{self, map_mmio, CacheMode},
+    pci::{ids, ConfigSpaceMechanism1},
+    println,
+};
 use core::panic::PanicInfo;
-use pci_types::{device_type::DeviceType, Bar, EndpointHeader};
-use x86_64::{structures::paging::PhysFrame, PhysAddr};
+use pci_types::{Bar, EndpointHeader, PciAddress};
+use x86_64::PhysAddr;
 
 entry_point!(kernel_main);
 
+static ACPI_DRIVER: device::BasicDriver = device::BasicDriver("acpi");
+
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
     println!("Hello World!");
 
     capucho_os::init(boot_info);
 
-    let mut acpi = unsafe { capucho_os::acpi::bios_get_acpi() };
-    let platform_info = acpi.platform_info();
+    match boot::profile("acpi tables", || unsafe {
+        capucho_os::acpi::bios_get_acpi()
+    }) {
+        Ok(acpi) => capucho_os::acpi::install(acpi),
+        Err(e) if capucho_os::acpi::mode() == AcpiMode::Strict => {
+            panic!("Failed to init the acpi: {}", e)
+        },
+        Err(e) => log::warn!("Continuing without acpi: {}", e),
+    }
+
+    let enabled = boot::profile("acpi enable", || {
+        capucho_os::acpi::with_acpi(|acpi| unsafe { acpi.enable() })
+    });
 
-    if unsafe { !acpi.enable() } {
-        panic!("Failed to init the acpi")
+    match enabled {
+        Some(Ok(())) => {
+            let acpi_device =
+                device::register(Some(device::ROOT), "acpi", device::Bus::Acpi, Vec::new());
+            device::bind(acpi_device, &ACPI_DRIVER);
+        },
+        Some(Err(e)) if capucho_os::acpi::mode() == AcpiMode::Strict => {
+            panic!("Failed to enable the acpi: {}", e)
+        },
+        Some(Err(e)) => log::warn!("Failed to enable the acpi: {}", e),
+        None => {},
     }
 
-    log::debug!("Apic handover start");
+    boot::milestone(Milestone::Acpi);
+
+    // Registered before the apic so that the lapic timer can be calibrated
+    // against it
+    if let Some(pm_timer) = capucho_os::acpi::with_acpi(|acpi| acpi.pm_timer()).flatten() {
+        unsafe { capucho_os::time::pm_timer::init(pm_timer.address, pm_timer.mmio, pm_timer.wide) };
+    }
+
+    // The apic is configured from the MADT and the interrupt routing needs
+    // the aml, without them the pic is kept
+    let apic_stage = boot::stage("apic");
+    let apic = capucho_os::acpi::with_acpi(|acpi| {
+        if !acpi.aml_usable() {
+            return None;
+        }
+
+        let info = match acpi.platform_info() {
+            Ok(info) => info,
+            Err(e) => {
+                log::warn!("Failed to get the platform info: {:?}", e);
+                return None;
+            },
+        };
+
+        match info.interrupt_model {
+            acpi::InterruptModel::Apic(info) => {
+                log::debug!("Apic handover start");
+
+                match apic::apic_init(acpi, info) {
+                    Ok(apic) => {
+                        log::debug!("Apic handover end");
+                        Some(apic)
+                    },
+                    Err(e) => {
+                        log::warn!("Apic handover failed: {}", e);
+                        None
+                    },
+                }
+            },
+            _ => None,
+        }
+    })
+    .flatten();
 
-    let _apic = match platform_info.interrupt_model {
-        acpi::InterruptModel::Unknown => panic!("We need apic"),
-        acpi::InterruptModel::Apic(apic) => apic::apic_init(&mut acpi, apic),
-        _ => unreachable!(),
+    drop(apic_stage);
+
+    let _apic = match apic {
+        Some(apic) => Some(apic),
+        None if capucho_os::acpi::mode() == AcpiMode::Strict => panic!("We need apic"),
+        None => {
+            log::warn!("No usable apic, staying on the pic");
+            None
+        },
     };
 
-    log::debug!("Apic handover end");
+    boot::milestone(Milestone::InterruptController);
+
+    if let Some(hpet) = capucho_os::acpi::with_acpi(|acpi| acpi.hpet_info()).flatten() {
+        unsafe { capucho_os::time::hpet::init(hpet.base_address as u64) };
+    }
 
-    let access = capucho_os::pci::ConfigSpaceMechanism1;
+    boot::profile("cpuidle", cpuidle::init);
+    boot::profile("cpufreq", cpufreq::init);
 
-    let devices = capucho_os::pci::brute_force_find(&access);
+    // Everything needed from the tables was read
+    capucho_os::acpi::with_acpi(|acpi| acpi.reclaim_memory());
+
+    log::info!(
+        "Using clock source {:?} and clock event {:?}",
+        capucho_os::time::clock_source_name(),
+        capucho_os::time::clock_event_name()
+    );
+
+    let access = ConfigSpaceMechanism1;
+
+    let devices = boot::profile("pci scan", || capucho_os::pci::brute_force_find(&access));
 
     let mut sata_controller = None;
 
+    let pci_bus = device::register(
+        Some(device::ROOT),
+        "pci0000:00",
+        device::Bus::Pci,
+        Vec::new(),
+    );
+
     for (address, header) in devices {
+        let (vendor, device) = header.id(&access);
         let (_, class, subclass, interface) = header.revision_and_class(&access);
 
         log::info!(
-            "{} {:?} class: {} subclass: {} interface: {} header: {:#X}",
+            "{} {}: {} {} [{:04X}:{:04X}]",
             address,
-            DeviceType::from((class, subclass)),
-            class,
-            subclass,
-            interface,
-            header.header_type(&access)
+            ids::class_name(class, subclass),
+            ids::vendor_name(vendor).unwrap_or("Unknown vendor"),
+            ids::device_name(vendor, device).unwrap_or("Unknown device"),
+            vendor,
+            device,
+        );
+
+        let id = device::register(
+            Some(pci_bus),
+            address.to_string(),
+            device::Bus::Pci,
+            Vec::new(),
         );
 
         if class == 0x01 && subclass == 0x06 && interface == 0x01 {
-            sata_controller = Some(EndpointHeader::from_header(header, &access).unwrap())
+            sata_controller = EndpointHeader::from_header(header, &access)
+                .map(|endpoint| (id, address, endpoint));
         }
     }
 
-    let sata_controller = sata_controller.expect("There's no sata controller :(");
-    let (abar_address, abar_size) = {
-        let bar = sata_controller
-            .bar(5, &access)
-            .expect("There's no ABAR -_-");
+    boot::milestone(Milestone::Pci);
 
-        log::info!("{:#X?}", bar);
+    match sata_controller {
+        Some((id, address, endpoint)) => {
+            let driver = Box::leak(Box::new(AhciDriver::new(address)));
 
-        match bar {
-            Bar::Memory32 { address, size, .. } => (address as u64, size as u64),
-            Bar::Memory64 { address, size, .. } => (address, size),
-            Bar::Io { .. } => panic!("ABAR is in port space o_O"),
-        }
-    };
+            device::probe(id, driver, || unsafe {
+                init_sata(id, address, &endpoint, &access)
+            });
+        },
+        None => log::warn!("There's no sata controller :("),
+    }
 
-    let start = PhysFrame::containing_address(PhysAddr::new(abar_address as u64));
-    let end = PhysFrame::containing_address(PhysAddr::new((abar_address + abar_size - 1) as u64));
+    boot::milestone(Milestone::Storage);
 
-    for frame in PhysFrame::range_inclusive(start, end) {
-        unsafe { mmap_dev(frame, false).expect("Failed to mmap the sata device") };
-    }
+    log::info!("Devices:\n{}", device::lsdev());
+    log::info!("Frames per owner:\n{}", memory::frame_owner_report());
+    log::info!("{}", memory::memory_map_report());
+    log::info!("Kernel health: {}", capucho_os::taint::status());
 
-    let hba_mem_reg = unsafe { &mut *(abar_address as *mut HBAMemoryRegisters) };
+    #[cfg(test)]
+    test_main();
 
-    unsafe {
+    boot::milestone(Milestone::Done);
+
+    log::info!("Slowest boot stages:\n{}", boot::profile_report());
+
+    if let Some((duration, location)) = capucho_os::interrupts::irqoff::longest() {
         log::info!(
-            "{:?} {} {} {:?}",
-            hba_mem_reg.cap,
-            hba_mem_reg.cap.number_of_ports(),
-            hba_mem_reg.cap.number_of_cmd_slots(),
-            hba_mem_reg.cap.if_speed(),
+            "Longest region with interrupts disabled: {:?} at {}",
+            duration,
+            location
         );
-
-        log::info!("{:?}", hba_mem_reg.ghc);
     }
 
-    for port in hba_mem_reg.port_slice_mut() {
-        unsafe {
-            log::info!("{:#X}", port.sig);
-            log::info!("{:?}", port.ssts);
-            log::info!("{:?}", port.int_status);
-            log::info!("{:?}\n", port.int_enable);
-        }
-    }
+    log::info!("Now perish");
 
-    #[cfg(test)]
-    test_main();
+    capucho_os::power::shutdown()
+}
 
-    log::info!("Now perish");
+/// Maps the registers of the sata controller and wakes it up
+///
+/// # Safety
+///
+/// `endpoint` must be an ahci controller
+unsafe fn init_sata(
+    id: device::DeviceId,
+    address: PciAddress,
+    endpoint: &EndpointHeader,
+    access: &ConfigSpaceMechanism1,
+) -> Result<(), KError> {
+    let bar = endpoint
+        .bar(5, access)
+        .ok_or(KError::DeviceError("there's no ABAR"))?;
+
+    log::info!("{:#X?}", bar);
+
+    let (abar_address, abar_size) = match bar {
+        Bar::Memory32 { address, size, .. } => (address as u64, size as u64),
+        Bar::Memory64 { address, size, .. } => (address, size),
+        Bar::Io { .. } => return Err(KError::DeviceError("the ABAR is in port space")),
+    };
 
-    if !acpi.set_sleep_state(SleepState::S5) {
-        panic!("Failed to shutdown")
+    // The driver uses the registers until the system is shutdown
+    let abar = map_mmio(
+        PhysAddr::new(abar_address),
+        abar_size as usize,
+        CacheMode::Uncached,
+    )?
+    .leak();
+
+    capucho_os::pci::set_power_state(address, capucho_os::pci::PowerState::D0);
+    capucho_os::pci::enable_bus_mastering(address);
+
+    device::add_resource(
+        id,
+        device::Resource::Memory(abar_address..abar_address + abar_size),
+    );
+
+    let hba_mem_reg = &mut *abar.as_mut_ptr::<HBAMemoryRegisters>();
+
+    log::info!(
+        "{:?} {} {} {:?}",
+        hba_mem_reg.cap,
+        hba_mem_reg.cap.number_of_ports(),
+        hba_mem_reg.cap.number_of_cmd_slots(),
+        hba_mem_reg.cap.if_speed(),
+    );
+
+    log::info!("{:?}", hba_mem_reg.ghc);
+
+    for port in hba_mem_reg.port_slice_mut() {
+        log::info!("{:#X}", port.sig);
+        log::info!("{:?}", port.ssts);
+        log::info!("{:?}", port.int_status);
+        log::info!("{:?}\n", port.int_enable);
     }
 
-    unreachable!()
+    Ok(())
 }
 
 /// This function is called on panic.
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("{}", info);
+    // Nothing that was interrupted by the panic runs again
+    unsafe {
+        capucho_os::vga_buffer::force_unlock();
+        capucho_os::serial::force_unlock();
+    }
+
+    capucho_os::print_panic_screen(info);
+
+    if let Some(id) = device::probing() {
+        log::error!("Panicked while probing device {}", id);
+    }
+
     log::error!("{}", info);
+    log::error!("{}", capucho_os::taint::status());
     capucho_os::hlt_loop();
 }
 
@@ -0,0 +1,234 @@
+//! Memory shared with devices
+//!
+//! A [`DmaBuffer`] is physically contiguous so a device can be given a single
+//! address for all of it, which is what command lists, receive areas and
+//! descriptor rings need. Write back buffers are accessed through the physical
+//! memory mapping, the others get their own mapping with the requested cache
+//! mode. Mapping memory with different modes is undefined so the physical
+//! memory mapping of their frames is switched to the same mode while the
+//! buffer lives, splitting the 2MiB pages it's made of.
+use super::{layout, vmap, vunmap, CacheMode, FrameOwner, PagingContext, Zone, PAGING_CTX};
+use crate::error::KError;
+use x86_64::{
+    instructions::tlb,
+    registers::control::Cr3,
+    structures::paging::{Page, PageTable, PageTableEntry, PageTableFlags, PhysFrame},
+    PhysAddr, VirtAddr,
+};
+
+/// The flags that select the cache mode of a 4KiB mapping
+const CACHE_FLAGS: PageTableFlags = PageTableFlags::from_bits_truncate(
+    PageTableFlags::NO_CACHE.bits()
+        | PageTableFlags::WRITE_THROUGH.bits()
+        | PageTableFlags::HUGE_PAGE.bits(),
+);
+
+/// A zeroed, physically contiguous buffer, it's unmapped and freed when
+/// dropped
+#[derive(Debug)]
+pub struct DmaBuffer {
+    virt: VirtAddr,
+    first: PhysFrame,
+    frames: usize,
+    len: usize,
+    mode: CacheMode,
+}
+
+impl DmaBuffer {
+    /// Allocates a buffer of `len` bytes from `zone`, or a lower zone if it's
+    /// exhausted, mapped with `mode`
+    pub fn new(len: usize, zone: Zone, mode: CacheMode) -> Result<Self, KError> {
+        let frames = ((len + 0xFFF) / 0x1000).max(1);
+
+        let first = PAGING_CTX
+            .get()
+            .unwrap()
+            .lock()
+            .allocator
+            .allocate_contiguous_for(FrameOwner::Driver, zone, frames, 0x1000)
+            .ok_or(KError::NoMemory)?;
+
+        let virt = match mode {
+            CacheMode::WriteBack => {
+                VirtAddr::new(layout::PHYSMAP.start + first.start_address().as_u64())
+            },
+            _ => {
+                let mapped = unsafe { set_physmap_mode(first, frames, mode) }
+                    .and_then(|_| vmap(first, frames as u64, PageTableFlags::WRITABLE, mode));
+
+                match mapped {
+                    Ok(virt) => virt,
+                    Err(e) => unsafe {
+                        // Part of the frames might have been switched
+                        set_physmap_mode(first, frames, CacheMode::WriteBack)
+                            .expect("split pages can always be switched back");
+                        let mut ctx = PAGING_CTX.get().unwrap().lock();
+                        ctx.allocator.deallocate_contiguous(first, frames);
+
+                        return Err(e);
+                    },
+                }
+            },
+        };
+
+        unsafe { core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, frames * 0x1000) };
+
+        Ok(DmaBuffer {
+            virt,
+            first,
+            frames,
+            len,
+            mode,
+        })
+    }
+
+    /// The address the cpu accesses the buffer through
+    pub fn virt(&self) -> VirtAddr { self.virt }
+
+    /// The physical address of the start of the buffer
+    pub fn phys(&self) -> PhysAddr { self.first.start_address() }
+
+    pub fn len(&self) -> usize { self.len }
+
+    pub fn is_empty(&self) -> bool { self.len == 0 }
+
+    pub fn mode(&self) -> CacheMode { self.mode }
+
+    pub fn as_mut_ptr<T>(&self) -> *mut T { self.virt.as_mut_ptr() }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            if self.mode != CacheMode::WriteBack {
+                vunmap(self.virt);
+                set_physmap_mode(self.first, self.frames, CacheMode::WriteBack)
+                    .expect("split pages can always be switched back");
+            }
+
+            PAGING_CTX
+                .get()
+                .unwrap()
+                .lock()
+                .allocator
+                .deallocate_contiguous(self.first, self.frames);
+        }
+    }
+}
+
+/// Switches the physical memory mapping of the frames to `mode`
+///
+/// # Safety
+///
+/// The frames must not be accessed through the physical memory mapping while
+/// it has a different mode than the other mappings of the frames
+unsafe fn set_physmap_mode(first: PhysFrame, frames: usize, mode: CacheMode) -> Result<(), KError> {
+    let ctx = &mut *PAGING_CTX.get().unwrap().lock();
+    let phys_offset = ctx.mapper.phys_offset();
+    // Pages that weren't split are still write back
+    let split = mode != CacheMode::WriteBack;
+
+    for frame in PhysFrame::range(first, first + frames as u64) {
+        let page = Page::containing_address(phys_offset + frame.start_address().as_u64());
+
+        if let Some(entry) = physmap_entry(ctx, page, split)? {
+            entry.set_flags((entry.flags() - CACHE_FLAGS) | mode.flags());
+            tlb::flush(page.start_address());
+        }
+    }
+
+    // The manuals ask for the caches to be flushed so no line cached with the
+    // old mode is written back over the memory later
+    asm!("wbinvd", options(nostack));
+
+    Ok(())
+}
+
+/// Returns the level 1 entry that maps `page` of the physical memory mapping,
+/// splitting the 2MiB page that maps it if `split` is set or returning `None`
+/// otherwise
+unsafe fn physmap_entry(
+    ctx: &mut PagingContext,
+    page: Page,
+    split: bool,
+) -> Result<Option<&'static mut PageTableEntry>, KError> {
+    let phys_offset = ctx.mapper.phys_offset();
+    let (level_4_frame, _) = Cr3::read();
+
+    let level_4 = table(phys_offset, level_4_frame.start_address());
+    let level_3 = table(phys_offset, level_4[page.p4_index()].addr());
+    let level_3_entry = &level_3[page.p3_index()];
+
+    // The bootloader maps the physical memory with 2MiB pages
+    if level_3_entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+        return if split {
+            Err(KError::NotSupported)
+        } else {
+            Ok(None)
+        };
+    }
+
+    let level_2 = table(phys_offset, level_3_entry.addr());
+    let level_2_entry = &mut level_2[page.p2_index()];
+
+    if level_2_entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+        if !split {
+            return Ok(None);
+        }
+
+        let frame = ctx
+            .allocator
+            .allocate_frame_for(FrameOwner::PageTables, Zone::Normal)
+            .ok_or(KError::NoMemory)?;
+        let level_1 = table(phys_offset, frame.start_address());
+        let flags = level_2_entry.flags() - PageTableFlags::HUGE_PAGE;
+
+        for (i, entry) in level_1.iter_mut().enumerate() {
+            entry.set_addr(level_2_entry.addr() + i as u64 * 0x1000, flags);
+        }
+
+        level_2_entry.set_addr(frame.start_address(), flags - CACHE_FLAGS);
+        tlb::flush(page.start_address());
+    }
+
+    let level_1 = table(phys_offset, level_2_entry.addr());
+
+    Ok(Some(&mut level_1[page.p1_index()]))
+}
+
+/// Returns the page table in `frame`
+unsafe fn table(phys_offset: VirtAddr, frame: PhysAddr) -> &'static mut PageTable {
+    &mut *(phys_offset + frame.as_u64()).as_mut_ptr()
+}
+
+#[test_case]
+fn physmap_alias_follows_the_buffer_mode() {
+    let mode_of = |buffer: &DmaBuffer| unsafe {
+        let ctx = &mut *PAGING_CTX.get().unwrap().lock();
+        let phys_offset = ctx.mapper.phys_offset();
+        let page = Page::containing_address(phys_offset + buffer.phys().as_u64());
+
+        physmap_entry(ctx, page, false).unwrap().unwrap().flags() & CACHE_FLAGS
+    };
+
+    let buffer = DmaBuffer::new(0x3000, Zone::Dma32, CacheMode::Uncached).unwrap();
+    let phys = buffer.phys();
+
+    assert_eq!(mode_of(&buffer), CacheMode::Uncached.flags());
+    assert!(
+        unsafe { core::slice::from_raw_parts(buffer.as_mut_ptr::<u8>(), 0x3000) }
+            .iter()
+            .all(|byte| *byte == 0)
+    );
+
+    drop(buffer);
+
+    let ctx = &mut *PAGING_CTX.get().unwrap().lock();
+    let page = Page::containing_address(ctx.mapper.phys_offset() + phys.as_u64());
+    let flags = unsafe { physmap_entry(ctx, page, false) }
+        .unwrap()
+        .unwrap()
+        .flags();
+
+    assert_eq!(flags & CACHE_FLAGS, PageTableFlags::empty());
+}
@@ -11,14 +11,19 @@ pub struct BootStrapAllocator {
 }
 
 impl BootStrapAllocator {
+    /// Maps a frame read only at `addr`, the frame allocator makes it writable
+    /// only while it's updating it
     pub fn allocate_bitmap_frame(&mut self, mapper: &mut impl Mapper<Size4KiB>, addr: u64) {
         let frame = self.allocate_frame().expect("Failed to allocate frame");
 
         unsafe {
             mapper
-                .map_to(
+                .map_to_with_table_flags(
                     Page::from_start_address_unchecked(VirtAddr::new(addr)),
                     frame,
+                    PageTableFlags::PRESENT,
+                    // The parent tables must stay writable for the other
+                    // mappings they might hold
                     PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
                     self,
                 )
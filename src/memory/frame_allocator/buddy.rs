@@ -0,0 +1,209 @@
+//! The free lists of the frame allocator
+//!
+//! Free frames are kept in blocks of `2^order` frames aligned to their size.
+//! Allocating takes a block of the smallest order that fits and splits it in
+//! halves, the buddies, until it has the requested order. Freeing a block
+//! merges it with it's buddy for as long as the buddy is free, so both only
+//! take a step per order.
+//!
+//! The links of the lists are stored in the first frame of each free block,
+//! through the physical memory mapping, and the order of the free blocks is
+//! kept in a table with a byte per frame so a buddy can be checked without
+//! touching it's memory.
+use super::Zone;
+use crate::memory::layout;
+use core::ops::Range;
+
+/// The order of the biggest blocks, 4MiB
+pub const MAX_ORDER: usize = 10;
+
+/// The end of a list
+const NONE: u64 = u64::MAX;
+
+/// Stored at the start of the first frame of a free block
+struct Link {
+    next: u64,
+    prev: u64,
+}
+
+pub struct FreeLists<'a> {
+    /// The first free block of each order in each zone
+    heads: [[u64; MAX_ORDER + 1]; 3],
+    /// The order plus one of the free block starting at each frame or zero if
+    /// no free block starts there
+    orders: &'a mut [u8],
+}
+
+impl<'a> FreeLists<'a> {
+    /// Creates empty free lists, must be called in a
+    /// [`super::write_window`]
+    pub fn new(orders: &'a mut [u8]) -> Self {
+        for order in orders.iter_mut() {
+            *order = 0;
+        }
+
+        FreeLists {
+            heads: [[NONE; MAX_ORDER + 1]; 3],
+            orders,
+        }
+    }
+
+    /// Returns the start and the order of the free blocks in a zone
+    pub fn blocks(&self, zone: Zone) -> impl Iterator<Item = (u64, usize)> + '_ {
+        self.heads[zone as usize]
+            .iter()
+            .enumerate()
+            .flat_map(move |(order, &head)| {
+                let mut next = head;
+
+                core::iter::from_fn(move || {
+                    let idx = next;
+
+                    if idx == NONE {
+                        return None;
+                    }
+
+                    next = unsafe { (*link(idx)).next };
+
+                    Some((idx, order))
+                })
+            })
+    }
+
+    /// Removes a block of `order` from a zone and returns it's first frame,
+    /// must be called in a [`super::write_window`]
+    pub fn alloc(&mut self, zone: Zone, order: usize) -> Option<u64> {
+        let (idx, found) = (order..=MAX_ORDER).find_map(|found| {
+            let head = self.heads[zone as usize][found];
+
+            (head != NONE).then(|| (head, found))
+        })?;
+
+        Some(self.take(zone, idx, found, order))
+    }
+
+    /// Removes the free block at `idx` and splits it until it's first part has
+    /// `order`, the rest is kept free, must be called in a
+    /// [`super::write_window`]
+    pub fn take(&mut self, zone: Zone, idx: u64, found: usize, order: usize) -> u64 {
+        self.remove(zone, idx, found);
+
+        for split in (order..found).rev() {
+            self.push(zone, idx + (1 << split), split);
+        }
+
+        idx
+    }
+
+    /// Removes the frames in `range` from the free lists, the parts of their
+    /// blocks outside of it are kept free, must be called in a
+    /// [`super::write_window`]
+    ///
+    /// All the frames in `range` must be free.
+    pub fn claim(&mut self, zone: Zone, range: Range<u64>) {
+        let mut idx = range.start;
+
+        while idx < range.end {
+            let (start, order) = (0..=MAX_ORDER)
+                .map(|order| (idx & !((1 << order) - 1), order))
+                .find(|&(start, order)| self.is_free(start, order))
+                .expect("Claiming a frame that isn't free");
+            let end = start + (1 << order);
+
+            self.remove(zone, start, order);
+            self.push_range(zone, start..range.start.max(start));
+            self.push_range(zone, range.end.min(end)..end);
+
+            idx = end;
+        }
+    }
+
+    /// Adds the block at `idx` and merges it with it's buddies, must be called
+    /// in a [`super::write_window`]
+    ///
+    /// Blocks never cross a zone boundary since the zones are aligned to a
+    /// bigger size than the biggest block.
+    pub fn free(&mut self, zone: Zone, mut idx: u64, mut order: usize) {
+        while order < MAX_ORDER {
+            let buddy = idx ^ (1 << order);
+
+            if !self.is_free(buddy, order) {
+                break;
+            }
+
+            self.remove(zone, buddy, order);
+            idx = idx.min(buddy);
+            order += 1;
+        }
+
+        self.push(zone, idx, order)
+    }
+
+    /// Checks if a free block of `order` starts at `idx`
+    fn is_free(&self, idx: u64, order: usize) -> bool {
+        self.orders.get(idx as usize).copied() == Some(order as u8 + 1)
+    }
+
+    fn push(&mut self, zone: Zone, idx: u64, order: usize) {
+        let head = &mut self.heads[zone as usize][order];
+
+        unsafe {
+            link(idx).write(Link {
+                next: *head,
+                prev: NONE,
+            });
+
+            if *head != NONE {
+                (*link(*head)).prev = idx;
+            }
+        }
+
+        *head = idx;
+        self.orders[idx as usize] = order as u8 + 1;
+    }
+
+    /// Adds the frames of `range` in the biggest aligned blocks that fit
+    /// without merging them, the range must be part of a block that was free
+    fn push_range(&mut self, zone: Zone, range: Range<u64>) {
+        let mut idx = range.start;
+
+        while idx < range.end {
+            let order = max_order_at(idx, range.end);
+
+            self.push(zone, idx, order);
+            idx += 1 << order;
+        }
+    }
+
+    fn remove(&mut self, zone: Zone, idx: u64, order: usize) {
+        let Link { next, prev } = unsafe { link(idx).read() };
+
+        unsafe {
+            if next != NONE {
+                (*link(next)).prev = prev;
+            }
+
+            if prev != NONE {
+                (*link(prev)).next = next;
+            }
+        }
+
+        if self.heads[zone as usize][order] == idx {
+            self.heads[zone as usize][order] = next;
+        }
+
+        self.orders[idx as usize] = 0;
+    }
+}
+
+/// Returns the order of the biggest block that starts at `idx` and ends before
+/// `end`
+pub fn max_order_at(idx: u64, end: u64) -> usize {
+    (0..=MAX_ORDER)
+        .rev()
+        .find(|&order| idx % (1 << order) == 0 && idx + (1 << order) <= end)
+        .unwrap_or(0)
+}
+
+/// Returns the link stored in the frame `idx`
+fn link(idx: u64) -> *mut Link { (layout::PHYSMAP.start + idx * 0x1000) as *mut Link }
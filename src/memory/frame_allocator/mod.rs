@@ -1,21 +1,116 @@
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::ops::Range;
 use x86_64::{
-    structures::paging::{FrameAllocator, FrameDeallocator, Mapper, PhysFrame, Size4KiB},
+    registers::control::{Cr0, Cr0Flags},
+    structures::paging::{FrameAllocator, FrameDeallocator, Mapper, PhysFrame, Size2MiB, Size4KiB},
     PhysAddr,
 };
 
-use self::bootstrap::BootStrapAllocator;
+use self::{bootstrap::BootStrapAllocator, buddy::FreeLists};
+use super::layout;
+use crate::interrupts::irqoff;
+
+pub use self::buddy::MAX_ORDER;
 
 mod bootstrap;
+mod buddy;
+
+const BITMAP_START: u64 = layout::FRAME_BITMAP.start;
+const OWNERS_START: u64 = layout::FRAME_OWNERS.start;
+const ORDERS_START: u64 = layout::FRAME_ORDERS.start;
+
+/// The order of a 2MiB block
+const HUGE_ORDER: usize = 9;
+
+/// Who allocated a frame, used to find out where the memory is going
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FrameOwner {
+    /// Frames that weren't allocated by the frame allocator (kernel image,
+    /// bootloader structures, ...)
+    Unknown = 0,
+    FrameAllocator = 1,
+    PageTables = 2,
+    Heap = 3,
+    Driver = 4,
+    Valloc = 5,
+    /// The real mode entry point of the application processors
+    Trampoline = 6,
+}
+
+impl FrameOwner {
+    pub const ALL: [FrameOwner; 7] = [
+        FrameOwner::Unknown,
+        FrameOwner::FrameAllocator,
+        FrameOwner::PageTables,
+        FrameOwner::Heap,
+        FrameOwner::Driver,
+        FrameOwner::Valloc,
+        FrameOwner::Trampoline,
+    ];
+}
+
+/// A physical memory zone, some devices can only address the lower zones
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zone {
+    /// Below 16MiB, reachable by isa dma
+    Dma = 0,
+    /// Below 4GiB, reachable by devices with 32 bit addressing
+    Dma32 = 1,
+    Normal = 2,
+}
+
+impl Zone {
+    pub const ALL: [Zone; 3] = [Zone::Dma, Zone::Dma32, Zone::Normal];
+
+    /// The range of frame indices in the zone
+    pub fn frames(self) -> Range<u64> {
+        match self {
+            Zone::Dma => 0..0x1000,
+            Zone::Dma32 => 0x1000..0x10_0000,
+            Zone::Normal => 0x10_0000..u64::MAX,
+        }
+    }
+
+    pub fn of(frame: PhysFrame) -> Zone {
+        let idx = frame.start_address().as_u64() / 0x1000;
 
-pub const BITMAP_START: u64 = 0x_6666_6666_0000;
+        Zone::ALL
+            .iter()
+            .copied()
+            .find(|zone| zone.frames().contains(&idx))
+            .unwrap_or(Zone::Normal)
+    }
+
+    /// The zones to allocate from when the zone is requested, the lower zones
+    /// are scarce so they are only used when the higher ones are exhausted
+    fn fallback(self) -> &'static [Zone] {
+        match self {
+            Zone::Dma => &[Zone::Dma],
+            Zone::Dma32 => &[Zone::Dma32, Zone::Dma],
+            Zone::Normal => &[Zone::Normal, Zone::Dma32, Zone::Dma],
+        }
+    }
+}
 
 /// A FrameAllocator that returns usable frames from the bootloader's memory
 /// map.
+///
+/// The free frames are found through the buddy free lists, the bitmap and the
+/// owners table record the state of every frame.
 pub struct GlobalFrameAllocator<'a> {
     memory_map: &'static MemoryMap,
-    next_usable: u64,
+    /// The number of free frames in each zone
+    free: [u64; 3],
     bitmap: &'a mut [u32],
+    /// The number of frames mapped for the bitmap, the slice is longer than
+    /// what's mapped
+    bitmap_frames: u64,
+    /// The owner of each frame, parallel to the bitmap
+    owners: &'a mut [u8],
+    lists: FreeLists<'a>,
+    /// The `AcpiReclaimable` regions were given back to the allocator
+    acpi_reclaimed: bool,
 }
 
 impl<'a> GlobalFrameAllocator<'a> {
@@ -49,6 +144,10 @@ impl<'a> GlobalFrameAllocator<'a> {
             used: [0; 64],
         };
 
+        // The bitmaps are mapped read only which is only enforced for the
+        // kernel with write protection
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+
         // Allocate the bitmaps and store a pointer for the root bitmap
         for i in 0..bitmap_frames {
             bootstrap.allocate_bitmap_frame(mapper, BITMAP_START + i * 0x1000);
@@ -57,40 +156,453 @@ impl<'a> GlobalFrameAllocator<'a> {
         let bitmap =
             core::slice::from_raw_parts_mut(BITMAP_START as *mut _, end_frame as usize + 1);
 
+        // The owners use a byte per frame
+        let owners_frames = (end_frame + 1 + 0x1000 - 1) / 0x1000;
+
+        for i in 0..owners_frames {
+            bootstrap.allocate_bitmap_frame(mapper, OWNERS_START + i * 0x1000);
+        }
+
+        let owners =
+            core::slice::from_raw_parts_mut(OWNERS_START as *mut u8, end_frame as usize + 1);
+
+        // The free block orders also use a byte per frame
+        for i in 0..owners_frames {
+            bootstrap.allocate_bitmap_frame(mapper, ORDERS_START + i * 0x1000);
+        }
+
+        let orders =
+            core::slice::from_raw_parts_mut(ORDERS_START as *mut u8, end_frame as usize + 1);
+
+        let lists = write_window(|| {
+            for owner in owners.iter_mut() {
+                *owner = FrameOwner::Unknown as u8;
+            }
+
+            FreeLists::new(orders)
+        });
+
         let mut this = GlobalFrameAllocator {
             memory_map,
-            next_usable: 0,
+            free: [0; 3],
             bitmap,
+            bitmap_frames,
+            owners,
+            lists,
+            acpi_reclaimed: false,
         };
 
-        // Mark the frames that were used by the bootstrap allocator
-        for (block, size) in bootstrap.used.iter().enumerate().filter(|(_, s)| **s != 0) {
-            let start = memory_map[block].range.start_frame_number;
+        write_window(|| {
+            // Mark the frames that were used by the bootstrap allocator
+            for (block, size) in bootstrap.used.iter().enumerate().filter(|(_, s)| **s != 0) {
+                let start = memory_map[block].range.start_frame_number;
+
+                for i in start..(start + size) {
+                    this.mark_used(i);
+                    this.owners[i as usize] = FrameOwner::FrameAllocator as u8;
+                }
+            }
+
+            // Mark frames that shouldn't be used as in use
+            for region in bootstrap.memory_map.into_iter() {
+                if let MemoryRegionType::Usable
+                | MemoryRegionType::Reserved
+                | MemoryRegionType::AcpiReclaimable
+                | MemoryRegionType::FrameZero = region.region_type
+                {
+                    continue;
+                }
+
+                let start = region.range.start_frame_number;
+                let end = region.range.end_frame_number;
+
+                for i in start..end {
+                    this.mark_used(i)
+                }
+            }
+        });
+
+        for zone in Zone::ALL.iter().copied() {
+            write_window(|| {
+                for i in usable_frames(memory_map, zone, false) {
+                    if !this.is_used(i) {
+                        this.lists.free(zone, i, 0);
+                        this.free[zone as usize] += 1;
+                    }
+                }
+            });
+
+            log::info!("Zone {:?}: {} free frames", zone, this.free[zone as usize]);
+        }
+
+        this
+    }
+
+    pub fn memory_map(&self) -> &'static MemoryMap { self.memory_map }
+
+    /// Returns the virtual ranges of the bitmap, the owners table and the
+    /// orders table
+    pub fn metadata_ranges(&self) -> [Range<u64>; 3] {
+        [
+            BITMAP_START..BITMAP_START + self.bitmap_frames * 0x1000,
+            OWNERS_START..OWNERS_START + self.owners.len() as u64,
+            ORDERS_START..ORDERS_START + self.owners.len() as u64,
+        ]
+    }
+
+    /// Returns how many frames in the range of frame indices are used
+    pub fn used_frames(&self, frames: Range<u64>) -> u64 {
+        frames.filter(|i| self.is_used(*i)).count() as u64
+    }
+
+    /// Returns the number of free frames in a zone
+    pub fn free_frames(&self, zone: Zone) -> u64 { self.free[zone as usize] }
+
+    /// Returns the number of used frames per owner in the order of
+    /// [`FrameOwner::ALL`]
+    pub fn frames_per_owner(&self) -> [u64; FrameOwner::ALL.len()] {
+        let mut counts = [0; FrameOwner::ALL.len()];
+
+        for zone in Zone::ALL.iter().copied() {
+            let frames = usable_frames(self.memory_map, zone, self.acpi_reclaimed);
 
-            for i in start..(start + size) {
-                this.mark_used(i)
+            for i in frames.filter(|i| self.is_used(*i)) {
+                if let Some(count) = counts.get_mut(self.owners[i as usize] as usize) {
+                    *count += 1;
+                }
             }
         }
 
-        // Mark frames that shouldn't be used as in use
-        for region in bootstrap.memory_map.into_iter() {
-            if let MemoryRegionType::Usable
-            | MemoryRegionType::Reserved
-            | MemoryRegionType::AcpiReclaimable
-            | MemoryRegionType::FrameZero = region.region_type
-            {
+        counts
+    }
+
+    /// Makes the free frames of the `AcpiReclaimable` regions usable and
+    /// returns how many were reclaimed
+    ///
+    /// # Safety
+    ///
+    /// The acpi tables must not be read after calling this since their memory
+    /// might be reused
+    pub unsafe fn reclaim_acpi(&mut self) -> u64 {
+        if self.acpi_reclaimed {
+            return 0;
+        }
+
+        self.acpi_reclaimed = true;
+
+        let mut reclaimed = 0;
+        let regions = self
+            .memory_map
+            .iter()
+            .filter(|r| r.region_type == MemoryRegionType::AcpiReclaimable);
+
+        for region in regions {
+            for i in region.range.start_frame_number..region.range.end_frame_number {
+                if !self.is_used(i) {
+                    let frame = PhysFrame::from_start_address_unchecked(PhysAddr::new(i * 0x1000));
+                    let zone = Zone::of(frame);
+
+                    write_window(|| self.lists.free(zone, i, 0));
+                    self.free[zone as usize] += 1;
+                    reclaimed += 1;
+                }
+            }
+        }
+
+        reclaimed
+    }
+
+    /// Checks that the free counts, the free lists and the owners agree with
+    /// the bitmap
+    pub fn check_invariants(&self) -> Result<(), &'static str> {
+        for zone in Zone::ALL.iter().copied() {
+            let mut free = 0;
+
+            for (start, order) in self.lists.blocks(zone) {
+                if start % (1 << order) != 0 || !zone.frames().contains(&start) {
+                    return Err("a free block is misplaced");
+                }
+
+                for i in start..start + (1 << order) {
+                    if self.is_used(i) {
+                        return Err("a free block has a used frame");
+                    }
+
+                    if self.owners[i as usize] != FrameOwner::Unknown as u8 {
+                        return Err("a free frame has an owner");
+                    }
+                }
+
+                free += 1 << order;
+            }
+
+            if free != self.free[zone as usize] {
+                return Err("the free count doesn't match the free lists");
+            }
+
+            let unused = usable_frames(self.memory_map, zone, self.acpi_reclaimed)
+                .filter(|i| !self.is_used(*i))
+                .count() as u64;
+
+            if unused != free {
+                return Err("a free frame isn't in the free lists");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Allocates a frame for `owner` from `zone` or if it's exhausted from a
+    /// lower zone
+    pub fn allocate_frame_for(&mut self, owner: FrameOwner, zone: Zone) -> Option<PhysFrame> {
+        self.allocate_block_for(owner, zone, 0)
+    }
+
+    /// Allocates `2^order` contiguous frames aligned to their size for `owner`
+    /// from `zone` or if it's exhausted from a lower zone and returns the
+    /// first frame
+    pub fn allocate_block_for(
+        &mut self,
+        owner: FrameOwner,
+        zone: Zone,
+        order: usize,
+    ) -> Option<PhysFrame> {
+        if order > MAX_ORDER {
+            return None;
+        }
+
+        for zone in zone.fallback().iter().copied() {
+            if self.free[zone as usize] < 1 << order {
                 continue;
             }
 
-            let start = region.range.start_frame_number;
-            let end = region.range.end_frame_number;
+            let start = match write_window(|| self.lists.alloc(zone, order)) {
+                Some(start) => start,
+                None => continue,
+            };
 
-            for i in start..end {
-                this.mark_used(i)
+            self.mark_block_used(start, order, owner);
+            self.free[zone as usize] -= 1 << order;
+
+            let addr = PhysAddr::new(start * 0x1000);
+            let frame = unsafe { PhysFrame::from_start_address_unchecked(addr) };
+
+            log::trace!(
+                "Allocating {} frames at {:#X} from {:?} for {:?}",
+                1 << order,
+                frame.start_address(),
+                zone,
+                owner
+            );
+
+            return Some(frame);
+        }
+
+        None
+    }
+
+    /// Frees a block allocated by [`GlobalFrameAllocator::allocate_block_for`]
+    ///
+    /// # Safety
+    ///
+    /// The frames must not be used after this
+    pub unsafe fn deallocate_block(&mut self, frame: PhysFrame, order: usize) {
+        let start = frame.start_address().as_u64() / 0x1000;
+        let frames = start..start + (1 << order);
+
+        if order > MAX_ORDER
+            || start % (1 << order) != 0
+            || !frames.clone().all(|i| self.is_used(i) && self.is_usable(i))
+        {
+            log::error!(
+                "Freeing an invalid block of {} frames at {:#X}",
+                1 << order,
+                frame.start_address()
+            );
+
+            // Free what can be freed
+            for i in frames {
+                self.deallocate_frame(PhysFrame::containing_address(PhysAddr::new(i * 0x1000)));
             }
+
+            return;
         }
 
-        this
+        let zone = Zone::of(frame);
+
+        write_window(|| {
+            for i in frames {
+                self.mark_unused(i);
+                self.owners[i as usize] = FrameOwner::Unknown as u8;
+            }
+
+            self.lists.free(zone, start, order);
+        });
+        self.free[zone as usize] += 1 << order;
+    }
+
+    /// Allocates `frames` contiguous frames aligned to `align` bytes for
+    /// drivers, below 4GiB so that devices with 32 bit addressing can use them,
+    /// and returns the first frame
+    pub fn allocate_contiguous(&mut self, frames: usize, align: u64) -> Option<PhysFrame> {
+        self.allocate_contiguous_for(FrameOwner::Driver, Zone::Dma32, frames, align)
+    }
+
+    /// Allocates `frames` contiguous frames aligned to `align` bytes for
+    /// `owner` from `zone` or if it's exhausted from a lower zone and returns
+    /// the first frame
+    ///
+    /// Runs that fit in a block are cut from one and the rest of it is given
+    /// back, bigger ones are searched for in the bitmap.
+    pub fn allocate_contiguous_for(
+        &mut self,
+        owner: FrameOwner,
+        zone: Zone,
+        frames: usize,
+        align: u64,
+    ) -> Option<PhysFrame> {
+        if frames == 0 || !align.is_power_of_two() {
+            return None;
+        }
+
+        let frames = frames as u64;
+        let align = (align / 0x1000).max(1);
+        // Blocks are aligned to their size
+        let order = frames.max(align).next_power_of_two().trailing_zeros() as usize;
+
+        if order <= MAX_ORDER {
+            let frame = self.allocate_block_for(owner, zone, order)?;
+            let start = frame.start_address().as_u64() / 0x1000;
+
+            self.release(start + frames..start + (1 << order));
+
+            return Some(frame);
+        }
+
+        for zone in zone.fallback().iter().copied() {
+            if self.free[zone as usize] < frames {
+                continue;
+            }
+
+            let start = match self.find_free_run(zone, frames, align) {
+                Some(start) => start,
+                None => continue,
+            };
+
+            write_window(|| {
+                self.lists.claim(zone, start..start + frames);
+
+                for i in start..start + frames {
+                    self.mark_used(i);
+                    self.owners[i as usize] = owner as u8;
+                }
+            });
+            self.free[zone as usize] -= frames;
+
+            let frame =
+                unsafe { PhysFrame::from_start_address_unchecked(PhysAddr::new(start * 0x1000)) };
+
+            log::trace!(
+                "Allocating {} contiguous frames at {:#X} from {:?} for {:?}",
+                frames,
+                frame.start_address(),
+                zone,
+                owner
+            );
+
+            return Some(frame);
+        }
+
+        None
+    }
+
+    /// Frees `frames` frames allocated by
+    /// [`GlobalFrameAllocator::allocate_contiguous`]
+    ///
+    /// # Safety
+    ///
+    /// The frames must not be used after this
+    pub unsafe fn deallocate_contiguous(&mut self, frame: PhysFrame, frames: usize) {
+        let start = frame.start_address().as_u64() / 0x1000;
+        let range = start..start + frames as u64;
+
+        if frames == 0
+            || Zone::of(frame)
+                != Zone::of(PhysFrame::containing_address(PhysAddr::new(
+                    (range.end - 1) * 0x1000,
+                )))
+            || !range.clone().all(|i| self.is_used(i) && self.is_usable(i))
+        {
+            log::error!(
+                "Freeing an invalid run of {} frames at {:#X}",
+                frames,
+                frame.start_address()
+            );
+
+            // Free what can be freed
+            for i in range {
+                self.deallocate_frame(PhysFrame::containing_address(PhysAddr::new(i * 0x1000)));
+            }
+
+            return;
+        }
+
+        self.release(range);
+    }
+
+    /// Allocates 2MiB of contiguous frames aligned to 2MiB for `owner` from
+    /// `zone` or if it's exhausted from a lower zone
+    pub fn allocate_huge_frame_for(
+        &mut self,
+        owner: FrameOwner,
+        zone: Zone,
+    ) -> Option<PhysFrame<Size2MiB>> {
+        let frame = self.allocate_block_for(owner, zone, HUGE_ORDER)?;
+
+        Some(PhysFrame::containing_address(frame.start_address()))
+    }
+
+    /// Frees the frames of a 2MiB frame
+    ///
+    /// # Safety
+    ///
+    /// The frames must not be used after this
+    pub unsafe fn deallocate_huge_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        let first = PhysFrame::<Size4KiB>::containing_address(frame.start_address());
+
+        self.deallocate_block(first, HUGE_ORDER)
+    }
+
+    /// Allocates the lowest free frame below `limit` for `owner`, for memory
+    /// that must be at a low address like real mode code, `limit` must be in
+    /// the dma zone
+    pub fn allocate_frame_below(
+        &mut self,
+        owner: FrameOwner,
+        limit: PhysAddr,
+    ) -> Option<PhysFrame> {
+        let limit = limit.as_u64() / 0x1000;
+
+        let (i, order) = self
+            .lists
+            .blocks(Zone::Dma)
+            .filter(|(start, _)| *start < limit)
+            .min_by_key(|(start, _)| *start)?;
+
+        write_window(|| self.lists.take(Zone::Dma, i, order, 0));
+        self.mark_block_used(i, 0, owner);
+        self.free[Zone::Dma as usize] -= 1;
+
+        let frame = unsafe { PhysFrame::from_start_address_unchecked(PhysAddr::new(i * 0x1000)) };
+
+        log::trace!(
+            "Allocating frame {:#X} below {:#X} for {:?}",
+            frame.start_address(),
+            limit * 0x1000,
+            owner
+        );
+
+        Some(frame)
     }
 
     /// Check if the frame is already in use
@@ -105,15 +617,81 @@ impl<'a> GlobalFrameAllocator<'a> {
         self.bitmap[int] & mask != 0
     }
 
-    /// Set the frame `idx` as used
+    /// Check if the frame `idx` is in a region the allocator hands out
+    fn is_usable(&self, idx: u64) -> bool {
+        Zone::ALL.iter().any(|zone| {
+            usable_ranges(self.memory_map, *zone, self.acpi_reclaimed)
+                .any(|range| range.contains(&idx))
+        })
+    }
+
+    /// Returns the first frame of the lowest run of `frames` free frames
+    /// aligned to `align` frames in `zone`
+    fn find_free_run(&self, zone: Zone, frames: u64, align: u64) -> Option<u64> {
+        usable_ranges(self.memory_map, zone, self.acpi_reclaimed).find_map(|range| {
+            let mut start = (range.start + align - 1) / align * align;
+
+            while start + frames <= range.end {
+                // Checking from the end allows skipping past the last used frame
+                match (start..start + frames).rev().find(|&i| self.is_used(i)) {
+                    Some(used) => start = (used + align) / align * align,
+                    None => return Some(start),
+                }
+            }
+
+            None
+        })
+    }
+
+    /// Gives the used frames in `range` back to the free lists in the biggest
+    /// blocks that fit, they must be usable and in a single zone
+    fn release(&mut self, range: Range<u64>) {
+        if range.start >= range.end {
+            return;
+        }
+
+        let zone = Zone::of(PhysFrame::containing_address(PhysAddr::new(
+            range.start * 0x1000,
+        )));
+        let mut idx = range.start;
+
+        write_window(|| {
+            while idx < range.end {
+                let order = buddy::max_order_at(idx, range.end);
+
+                for i in idx..idx + (1 << order) {
+                    self.mark_unused(i);
+                    self.owners[i as usize] = FrameOwner::Unknown as u8;
+                }
+
+                self.lists.free(zone, idx, order);
+                idx += 1 << order;
+            }
+        });
+        self.free[zone as usize] += range.end - range.start;
+    }
+
+    /// Marks the frames of the block at `start` as used by `owner`
+    fn mark_block_used(&mut self, start: u64, order: usize, owner: FrameOwner) {
+        write_window(|| {
+            for i in start..start + (1 << order) {
+                self.mark_used(i);
+                self.owners[i as usize] = owner as u8;
+            }
+        })
+    }
+
+    /// Set the frame `idx` as used, must be called in a [`write_window`]
     fn mark_used(&mut self, idx: u64) {
+        debug_assert!(in_write_window());
         let (int, mask) = frame_idx_to_parts(idx);
 
         self.bitmap[int] |= mask;
     }
 
-    /// Set the frame `idx` as unused
+    /// Set the frame `idx` as unused, must be called in a [`write_window`]
     fn mark_unused(&mut self, idx: u64) {
+        debug_assert!(in_write_window());
         let (int, mask) = frame_idx_to_parts(idx);
 
         self.bitmap[int] &= !mask;
@@ -130,61 +708,90 @@ impl<'a> GlobalFrameAllocator<'a> {
             })
             .map(|v| v.region_type)
     }
+}
 
-    /// Retuns true and sets `self.next_usable` to the index of the next usable
-    /// frame if ther's one available otherwise returns false
-    fn recalculate_next_usable(&mut self) -> bool {
-        /// Helper function returns an iterator of indexes of all usable frames
-        fn usable_frames(memory_map: &'static MemoryMap) -> impl Iterator<Item = u64> {
-            let regions = memory_map.iter();
-            let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
-            usable_regions.flat_map(|r| r.range.start_frame_number..r.range.end_frame_number)
-        }
-
-        // Get an iterator over the indices of all usable frames that are after
-        // the previous `self.next_usable`
-        let iter = usable_frames(self.memory_map).skip_while(|r| *r < self.next_usable);
-
-        // Try to find a frame that isn't used
-        for i in iter {
-            if !self.is_used(i) {
-                self.next_usable = i;
-                return true;
-            }
-        }
+/// Helper function returns an iterator of indexes of all usable frames in a
+/// zone, the `AcpiReclaimable` regions are included if they were reclaimed
+fn usable_frames(
+    memory_map: &'static MemoryMap,
+    zone: Zone,
+    acpi_reclaimed: bool,
+) -> impl Iterator<Item = u64> {
+    usable_ranges(memory_map, zone, acpi_reclaimed).flatten()
+}
 
-        // There are no usable frames
-        false
-    }
+/// Helper function returns an iterator of the ranges of frame indexes of the
+/// usable regions in a zone
+fn usable_ranges(
+    memory_map: &'static MemoryMap,
+    zone: Zone,
+    acpi_reclaimed: bool,
+) -> impl Iterator<Item = Range<u64>> {
+    let frames = zone.frames();
+    let regions = memory_map.iter();
+    let usable_regions = regions.filter(move |r| match r.region_type {
+        MemoryRegionType::Usable => true,
+        MemoryRegionType::AcpiReclaimable => acpi_reclaimed,
+        _ => false,
+    });
+    usable_regions.map(move |r| {
+        r.range.start_frame_number.max(frames.start)..r.range.end_frame_number.min(frames.end)
+    })
 }
 
 unsafe impl<'a> FrameAllocator<Size4KiB> for GlobalFrameAllocator<'a> {
+    /// Only the mapper uses this interface, to allocate page tables
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        // See if there are frames available
-        if self.recalculate_next_usable() {
-            let i = self.next_usable;
+        self.allocate_frame_for(FrameOwner::PageTables, Zone::Normal)
+    }
+}
 
-            //Mark the frame as used
-            self.mark_used(i);
+impl<'a> FrameDeallocator<Size4KiB> for GlobalFrameAllocator<'a> {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        let idx = frame.start_address().as_u64() / 0x1000;
+        let zone = Zone::of(frame);
 
-            let addr = PhysAddr::new(i * 0x1000);
-            let frame = unsafe { PhysFrame::from_start_address_unchecked(addr) };
+        if !self.is_used(idx) {
+            return;
+        }
 
-            log::trace!("Allocating frame {:#X}", frame.start_address());
+        // Frames outside of the usable regions are never handed out again
+        let usable = self.is_usable(idx);
 
-            Some(frame)
-        } else {
-            None
+        write_window(|| {
+            self.mark_unused(idx);
+            self.owners[idx as usize] = FrameOwner::Unknown as u8;
+
+            if usable {
+                self.lists.free(zone, idx, 0);
+            }
+        });
+
+        if usable {
+            self.free[zone as usize] += 1;
         }
     }
 }
 
-impl<'a> FrameDeallocator<Size4KiB> for GlobalFrameAllocator<'a> {
-    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
-        self.mark_unused(frame.start_address().as_u64() / 0x1000)
-    }
+/// Runs `f` with the read only bitmap and owners table writable
+///
+/// Supervisor writes ignore the page permissions while `CR0.WP` is clear, so
+/// interrupts are disabled to keep anything but the allocator from running
+/// in the window.
+fn write_window<R>(f: impl FnOnce() -> R) -> R {
+    irqoff::without_interrupts(|| {
+        let cr0 = Cr0::read();
+
+        unsafe { Cr0::write(cr0 - Cr0Flags::WRITE_PROTECT) };
+        let ret = f();
+        unsafe { Cr0::write(cr0) };
+
+        ret
+    })
 }
 
+fn in_write_window() -> bool { !Cr0::read().contains(Cr0Flags::WRITE_PROTECT) }
+
 /// Helper function translates a frame index to it's part in the bitmap
 /// (int, mask) where int is the index on the `u32` array and mask is the mask
 /// over the `u32`
@@ -0,0 +1,180 @@
+//! Live memory inspection
+//!
+//! Reads and writes arbitrary addresses after checking with the page tables
+//! that they are mapped, so registers and structures can be inspected without
+//! adding logging and rebuilding.
+use super::PAGING_CTX;
+use core::fmt;
+use x86_64::{
+    structures::paging::{mapper::TranslateResult, Page, PageTableFlags, Size4KiB, Translate},
+    PhysAddr, VirtAddr,
+};
+
+/// Number of bytes shown per hexdump line
+const LINE_LEN: u64 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Address {
+    Physical(PhysAddr),
+    Virtual(VirtAddr),
+}
+
+/// The size of an access, device registers usually must be accessed with
+/// their exact size
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Width {
+    Byte = 1,
+    Word = 2,
+    Dword = 4,
+    Qword = 8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InspectError {
+    NotMapped(VirtAddr),
+    NotWritable(VirtAddr),
+    Unaligned(VirtAddr),
+    /// The range wraps around the address space
+    Overflow,
+}
+
+impl fmt::Display for InspectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InspectError::NotMapped(addr) => write!(f, "{:#X} isn't mapped", addr.as_u64()),
+            InspectError::NotWritable(addr) => write!(f, "{:#X} isn't writable", addr.as_u64()),
+            InspectError::Unaligned(addr) => write!(f, "{:#X} isn't aligned", addr.as_u64()),
+            InspectError::Overflow => write!(f, "the range overflows"),
+        }
+    }
+}
+
+/// Returns the virtual address of `len` bytes at `addr` after checking that
+/// all of them are mapped
+fn resolve(addr: Address, len: u64, write: bool) -> Result<VirtAddr, InspectError> {
+    let ctx = PAGING_CTX.get().unwrap().lock();
+
+    let start = match addr {
+        Address::Virtual(addr) => addr,
+        Address::Physical(addr) => ctx.mapper.phys_offset() + addr.as_u64(),
+    };
+
+    let end = start
+        .as_u64()
+        .checked_add(len.max(1) - 1)
+        .ok_or(InspectError::Overflow)?;
+
+    let start_page = Page::<Size4KiB>::containing_address(start);
+    let end_page = Page::<Size4KiB>::containing_address(
+        VirtAddr::try_new(end).map_err(|_| InspectError::Overflow)?,
+    );
+
+    for page in Page::range_inclusive(start_page, end_page) {
+        let addr = page.start_address().max(start);
+
+        match ctx.mapper.translate(addr) {
+            TranslateResult::Mapped { flags, .. } => {
+                if write && !flags.contains(PageTableFlags::WRITABLE) {
+                    return Err(InspectError::NotWritable(addr));
+                }
+            },
+            _ => return Err(InspectError::NotMapped(addr)),
+        }
+    }
+
+    Ok(start)
+}
+
+/// Reads a value of `width` bytes
+pub fn peek(addr: Address, width: Width) -> Result<u64, InspectError> {
+    let ptr = resolve(addr, width as u64, false)?;
+
+    if !ptr.is_aligned(width as u64) {
+        return Err(InspectError::Unaligned(ptr));
+    }
+
+    unsafe {
+        Ok(match width {
+            Width::Byte => ptr.as_ptr::<u8>().read_volatile() as u64,
+            Width::Word => ptr.as_ptr::<u16>().read_volatile() as u64,
+            Width::Dword => ptr.as_ptr::<u32>().read_volatile() as u64,
+            Width::Qword => ptr.as_ptr::<u64>().read_volatile(),
+        })
+    }
+}
+
+/// Writes a value of `width` bytes, the value is truncated to the width
+///
+/// # Safety
+///
+/// The write can't fault but it can still break anything that uses the
+/// memory, the caller must know what is at the address
+pub unsafe fn poke(addr: Address, width: Width, val: u64) -> Result<(), InspectError> {
+    let ptr = resolve(addr, width as u64, true)?;
+
+    if !ptr.is_aligned(width as u64) {
+        return Err(InspectError::Unaligned(ptr));
+    }
+
+    match width {
+        Width::Byte => ptr.as_mut_ptr::<u8>().write_volatile(val as u8),
+        Width::Word => ptr.as_mut_ptr::<u16>().write_volatile(val as u16),
+        Width::Dword => ptr.as_mut_ptr::<u32>().write_volatile(val as u32),
+        Width::Qword => ptr.as_mut_ptr::<u64>().write_volatile(val),
+    }
+
+    Ok(())
+}
+
+/// Returns a hexdump of `len` bytes at `addr`, the memory is read when the
+/// dump is formatted
+pub fn hexdump(addr: Address, len: u64) -> Result<impl fmt::Display, InspectError> {
+    struct Hexdump {
+        start: VirtAddr,
+        len: u64,
+    }
+
+    impl fmt::Display for Hexdump {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            for line in (0..self.len).step_by(LINE_LEN as usize) {
+                let line_start = self.start + line;
+                let line_len = (self.len - line).min(LINE_LEN) as usize;
+
+                // Every byte is read once since reading device memory can
+                // have side effects
+                let mut bytes = [0; LINE_LEN as usize];
+                for (idx, byte) in bytes[..line_len].iter_mut().enumerate() {
+                    *byte = unsafe { (line_start + idx).as_ptr::<u8>().read_volatile() };
+                }
+
+                write!(f, "{:016X} ", line_start.as_u64())?;
+
+                for byte in &bytes[..line_len] {
+                    write!(f, " {:02X}", byte)?;
+                }
+
+                for _ in line_len..bytes.len() {
+                    write!(f, "   ")?;
+                }
+
+                write!(f, "  |")?;
+
+                for byte in &bytes[..line_len] {
+                    match *byte {
+                        0x20..=0x7E => write!(f, "{}", *byte as char)?,
+                        _ => write!(f, ".")?,
+                    }
+                }
+
+                writeln!(f, "|")?;
+            }
+
+            Ok(())
+        }
+    }
+
+    Ok(Hexdump {
+        start: resolve(addr, len, false)?,
+        len,
+    })
+}
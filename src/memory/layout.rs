@@ -0,0 +1,157 @@
+//! The fixed part of the kernel virtual memory layout
+//!
+//! The lower half only holds the kernel image, loaded by the bootloader at it's
+//! link address. Everything else is in the higher half, each region starts on
+//! it's own level 4 entry so the regions never share page tables.
+//!
+//! | Region          | Start                   | Size    |
+//! |-----------------|-------------------------|---------|
+//! | physical memory | `0xFFFF_8000_0000_0000` | 64 TiB  |
+//! | frame bitmap    | `0xFFFF_C000_0000_0000` | 2 GiB   |
+//! | frame owners    | `0xFFFF_C080_0000_0000` | 16 GiB  |
+//! | frame orders    | `0xFFFF_C100_0000_0000` | 16 GiB  |
+//! | boot info       | `0xFFFF_FE00_0000_0000` | 512 GiB |
+//! | kernel stack    | `0xFFFF_FF00_0000_0000` | 512 GiB |
+//!
+//! These are the regions that are needed before the heap exists or that are
+//! placed by the bootloader, the rest of the higher half is handed out by
+//! [`super::vmm`]. The bootloader creates the physical memory mapping, the
+//! boot info and the kernel stack at the addresses set in `Cargo.toml`, they
+//! must be kept in sync with the regions here.
+use bootloader::bootinfo::MemoryMap;
+use x86_64::VirtAddr;
+
+const GIB: u64 = 1 << 30;
+const TIB: u64 = 1 << 40;
+
+/// The size of the address space mapped by a level 4 entry
+pub const LEVEL_4_ENTRY: u64 = 512 * GIB;
+
+/// The start of the higher half
+pub const HIGHER_HALF: u64 = 0xFFFF_8000_0000_0000;
+
+/// A range of the virtual address space reserved for a single use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub name: &'static str,
+    pub start: u64,
+    pub size: u64,
+}
+
+impl Region {
+    pub const fn new(name: &'static str, start: u64, size: u64) -> Self {
+        Region { name, start, size }
+    }
+
+    /// The last address of the region, the region at the top of the address
+    /// space doesn't have an end that fits
+    pub const fn last(&self) -> u64 { self.start + (self.size - 1) }
+
+    pub fn start_addr(&self) -> VirtAddr { VirtAddr::new(self.start) }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        addr.as_u64() >= self.start && addr.as_u64() - self.start < self.size
+    }
+
+    pub const fn overlaps(&self, other: &Region) -> bool {
+        self.start <= other.last() && other.start <= self.last()
+    }
+}
+
+/// All of physical memory mapped at an offset, the physical memory supported is
+/// limited by it's size
+pub const PHYSMAP: Region = Region::new("physical memory", HIGHER_HALF, 64 * TIB);
+
+/// The frame allocator bitmap, a bit for every frame of [`PHYSMAP`]
+pub const FRAME_BITMAP: Region = Region::new(
+    "frame bitmap",
+    0xFFFF_C000_0000_0000,
+    PHYSMAP.size / 0x1000 / 8,
+);
+
+/// The owner of every frame of [`PHYSMAP`], a byte for each
+pub const FRAME_OWNERS: Region =
+    Region::new("frame owners", 0xFFFF_C080_0000_0000, PHYSMAP.size / 0x1000);
+
+/// The order of the free blocks of the frame allocator, a byte for every frame
+/// of [`PHYSMAP`]
+pub const FRAME_ORDERS: Region =
+    Region::new("frame orders", 0xFFFF_C100_0000_0000, PHYSMAP.size / 0x1000);
+
+/// The boot information passed by the bootloader
+pub const BOOT_INFO: Region = Region::new("boot info", 0xFFFF_FE00_0000_0000, 512 * GIB);
+
+/// The stack the kernel is entered with
+pub const KERNEL_STACK: Region = Region::new("kernel stack", 0xFFFF_FF00_0000_0000, 512 * GIB);
+
+/// Every region sorted by address
+pub const REGIONS: [Region; 6] = [
+    PHYSMAP,
+    FRAME_BITMAP,
+    FRAME_OWNERS,
+    FRAME_ORDERS,
+    BOOT_INFO,
+    KERNEL_STACK,
+];
+
+const _: () = assert!(is_valid(&REGIONS), "The virtual memory layout is invalid");
+
+/// Checks that the regions are in the higher half, sorted, start on a level 4
+/// entry and don't overlap
+const fn is_valid(regions: &[Region]) -> bool {
+    let mut i = 0;
+
+    while i < regions.len() {
+        let region = &regions[i];
+
+        if region.start < HIGHER_HALF || region.start % LEVEL_4_ENTRY != 0 {
+            return false;
+        }
+
+        if region.size == 0 || region.start.checked_add(region.size - 1).is_none() {
+            return false;
+        }
+
+        if i + 1 < regions.len()
+            && (regions[i + 1].start < region.start || region.overlaps(&regions[i + 1]))
+        {
+            return false;
+        }
+
+        i += 1;
+    }
+
+    true
+}
+
+/// Checks that the bootloader honored the layout
+///
+/// # Panics
+///
+/// If the bootloader placed a region somewhere else or the physical memory
+/// doesn't fit in [`PHYSMAP`]
+pub fn check(physical_memory_offset: VirtAddr, memory_map: &MemoryMap, stack: VirtAddr) {
+    assert_eq!(
+        physical_memory_offset,
+        PHYSMAP.start_addr(),
+        "The physical memory is mapped outside of it's region"
+    );
+
+    let phys_end = memory_map
+        .iter()
+        .map(|region| region.range.end_addr())
+        .max()
+        .unwrap_or(0);
+
+    assert!(
+        phys_end <= PHYSMAP.size,
+        "The physical memory ends at {:#X} which doesn't fit in it's region",
+        phys_end
+    );
+
+    assert!(
+        KERNEL_STACK.contains(stack),
+        "The kernel stack is at {:#X} outside of it's region",
+        stack.as_u64()
+    );
+}
@@ -0,0 +1,465 @@
+//! Memory mapped device regions
+//!
+//! Device memory is mapped in a window of the address space reserved from the
+//! [`vmm`](super::vmm), every mapping gets it's own range followed by an
+//! unmapped guard page. A region that is inside an existing mapping with the
+//! same cache mode, like firmware tables in the same page or a device that is
+//! mapped by two subsystems, shares it and takes a reference to it. Regions
+//! that only partially overlap get their own mapping, so the cache mode of
+//! every mapped frame is tracked to keep the aliases consistent. Parts of a
+//! mapping that cover a whole aligned 2MiB page are mapped with a huge page.
+use super::{device_frame_flags, layout, valloc::Areas, PagingContext, PAGING_CTX};
+use crate::error::KError;
+use alloc::collections::BTreeMap;
+use core::arch::x86_64::__cpuid;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::{
+    instructions::tlb,
+    registers::model_specific::Msr,
+    structures::paging::{
+        mapper::{MapToError, UnmapError},
+        Mapper, Page, PageSize, PageTableFlags, PhysFrame, Size2MiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+const IA32_PAT: u32 = 0x277;
+
+const CPUID_PAT: u32 = 1 << 16;
+
+/// The PAT bit of a 4KiB page table entry, it's in the same place as the huge
+/// page bit of the upper levels
+const PAT: PageTableFlags = PageTableFlags::HUGE_PAGE;
+
+/// Memory type encodings used in the PAT
+const UC: u64 = 0x00;
+const WC: u64 = 0x01;
+const WT: u64 = 0x04;
+const WB: u64 = 0x06;
+const UC_MINUS: u64 = 0x07;
+
+/// The PAT entries, indexed by the PAT, NO_CACHE and WRITE_THROUGH bits of a
+/// mapping
+///
+/// The first four entries are the reset values so mappings that don't set the
+/// PAT bit behave the same with and without the PAT, the upper half repeats
+/// them except for write combining in place of write back.
+const PAT_LAYOUT: [u64; 8] = [WB, WT, UC_MINUS, UC, WC, WT, UC_MINUS, UC];
+
+/// The size of the window device memory is mapped in
+const WINDOW_SIZE: u64 = layout::LEVEL_4_ENTRY;
+
+lazy_static! {
+    /// The device mappings, the lock must be taken before the paging context
+    static ref MAPPINGS: Mutex<Mappings> = Mutex::new(Mappings {
+        window: Areas::new("mmio", WINDOW_SIZE),
+        regions: BTreeMap::new(),
+        frames: BTreeMap::new(),
+    });
+}
+
+/// Frames in a 2MiB page
+const HUGE_FRAMES: u64 = 512;
+
+struct Mappings {
+    /// The free and used ranges of the window
+    window: Areas,
+    /// The mappings keyed by their first page
+    regions: BTreeMap<Page, Mapping>,
+    /// The cache mode of every mapped frame and the number of mappings that
+    /// contain it
+    frames: BTreeMap<PhysFrame, (CacheMode, usize)>,
+}
+
+/// The pages of one or more regions
+#[derive(Debug)]
+struct Mapping {
+    first: PhysFrame,
+    frames: u64,
+    mode: CacheMode,
+    /// The start of the range of the window, it's before the first page when
+    /// the region was aligned for huge pages
+    area: u64,
+    /// How many regions use the mapping
+    refs: usize,
+}
+
+impl Mappings {
+    /// Returns the first page of a mapping with `mode` that contains the range
+    fn find(&self, first: PhysFrame, frames: u64, mode: CacheMode) -> Option<Page> {
+        self.regions
+            .iter()
+            .find(|(_, mapping)| {
+                mapping.mode == mode
+                    && mapping.first <= first
+                    && first + frames <= mapping.first + mapping.frames
+            })
+            .map(|(first_page, _)| *first_page)
+    }
+
+    /// Takes a reference to the cache mode of every frame in the range, fails
+    /// if one is already mapped with another mode
+    fn use_frames(&mut self, first: PhysFrame, frames: u64, mode: CacheMode) -> Result<(), KError> {
+        for i in 0..frames {
+            let frame = first + i;
+            let (used_mode, refs) = self.frames.entry(frame).or_insert((mode, 0));
+
+            if *used_mode != mode {
+                log::error!(
+                    "Tried to map {:#X} as {:?} but it's already mapped as {:?}",
+                    frame.start_address(),
+                    mode,
+                    used_mode
+                );
+
+                self.unuse_frames(first, i);
+                return Err(KError::Fault);
+            }
+
+            *refs += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Drops the references taken by [`Mappings::use_frames`]
+    fn unuse_frames(&mut self, first: PhysFrame, frames: u64) {
+        for frame in PhysFrame::range(first, first + frames) {
+            if let Some((_, refs)) = self.frames.get_mut(&frame) {
+                *refs -= 1;
+
+                if *refs == 0 {
+                    self.frames.remove(&frame);
+                }
+            }
+        }
+    }
+}
+
+/// A mapped device region, it's unmapped when dropped
+#[derive(Debug)]
+pub struct MmioRegion {
+    start: PhysAddr,
+    len: usize,
+    base: VirtAddr,
+}
+
+impl MmioRegion {
+    /// The address of the start of the region
+    pub fn base(&self) -> VirtAddr { self.base }
+
+    /// The physical address of the start of the region
+    pub fn phys(&self) -> PhysAddr { self.start }
+
+    pub fn len(&self) -> usize { self.len }
+
+    pub fn is_empty(&self) -> bool { self.len == 0 }
+
+    pub fn as_mut_ptr<T>(&self) -> *mut T { self.base().as_mut_ptr() }
+
+    /// Keeps the region mapped forever
+    pub fn leak(self) -> VirtAddr {
+        let base = self.base();
+
+        core::mem::forget(self);
+
+        base
+    }
+}
+
+impl Drop for MmioRegion {
+    fn drop(&mut self) { release(self.base) }
+}
+
+/// How the cpu caches accesses to a mapping
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Cached like normal memory, for memory and device memory that behaves
+    /// like ram
+    WriteBack,
+    /// Reads are cached and writes go straight to the device
+    WriteThrough,
+    /// Uncached unless the MTRRs select write combining for the range
+    UncachedMinus,
+    /// Every access goes to the device, for registers
+    Uncached,
+    /// Writes are buffered and combined, for framebuffers
+    WriteCombining,
+}
+
+impl CacheMode {
+    /// The page table flags of a 2MiB mapping that select the mode, the PAT
+    /// bit is elsewhere in huge pages and can't be set with the flags so write
+    /// combining can't be used with them
+    pub fn huge_flags(self) -> Option<PageTableFlags> {
+        Some(self.flags())
+            .filter(|flags| !flags.contains(PAT))
+            .map(|flags| flags | PageTableFlags::HUGE_PAGE)
+    }
+
+    /// The page table flags of a 4KiB mapping that select the mode
+    pub fn flags(self) -> PageTableFlags {
+        match self {
+            CacheMode::WriteBack => PageTableFlags::empty(),
+            CacheMode::WriteThrough => PageTableFlags::WRITE_THROUGH,
+            CacheMode::UncachedMinus => PageTableFlags::NO_CACHE,
+            CacheMode::Uncached => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+            CacheMode::WriteCombining if pat_supported() => PAT,
+            // Without the PAT uncached is the closest safe mode
+            CacheMode::WriteCombining => CacheMode::Uncached.flags(),
+        }
+    }
+}
+
+/// Programs the PAT with [`PAT_LAYOUT`]
+pub(super) fn init_pat() {
+    if !pat_supported() {
+        log::warn!("PAT isn't supported, write combining is disabled");
+        return;
+    }
+
+    let pat = PAT_LAYOUT
+        .iter()
+        .enumerate()
+        .fold(0, |pat, (i, ty)| pat | ty << (i * 8));
+
+    unsafe {
+        Msr::new(IA32_PAT).write(pat);
+
+        // The manuals ask for the caches and the tlb to be flushed so no
+        // stale memory type is used
+        asm!("wbinvd", options(nostack));
+    }
+
+    tlb::flush_all();
+}
+
+fn pat_supported() -> bool { unsafe { __cpuid(1) }.edx & CPUID_PAT != 0 }
+
+/// Maps the `len` bytes of device memory at `start` with `mode`
+///
+/// # Safety
+///
+/// The range must be device memory
+pub unsafe fn map_mmio(start: PhysAddr, len: usize, mode: CacheMode) -> Result<MmioRegion, KError> {
+    let base = acquire(start, len, mode, false)?;
+
+    Ok(MmioRegion { start, len, base })
+}
+
+/// Maps the frames of the range in the window and returns the address of
+/// `start`, the mapping is released with [`release`]
+///
+/// Firmware tables can be in memory that isn't reserved, `firmware` allows
+/// mapping it.
+///
+/// # Safety
+///
+/// The range must be device memory or firmware tables
+pub(crate) unsafe fn acquire(
+    start: PhysAddr,
+    len: usize,
+    mode: CacheMode,
+    firmware: bool,
+) -> Result<VirtAddr, KError> {
+    let first = PhysFrame::containing_address(start);
+    let last = PhysFrame::containing_address(start + (len.max(1) - 1));
+    let frames = last + 1 - first;
+
+    let mut mappings = MAPPINGS.lock();
+
+    if let Some(first_page) = mappings.find(first, frames, mode) {
+        let mapping = mappings.regions.get_mut(&first_page).unwrap();
+        mapping.refs += 1;
+
+        let page = first_page + (first - mapping.first);
+
+        return Ok(page.start_address() + start.as_u64() % 0x1000);
+    }
+
+    mappings.use_frames(first, frames, mode)?;
+
+    // Aligning the pages like the frames lets the 2MiB parts use huge pages
+    let align = if frames >= HUGE_FRAMES {
+        HUGE_FRAMES
+    } else {
+        1
+    };
+    let offset = first.start_address().as_u64() / 0x1000 % align;
+
+    let area = match mappings.window.alloc(frames + offset, align) {
+        Some(area) => area,
+        None => {
+            mappings.unuse_frames(first, frames);
+            return Err(KError::NoMemory);
+        },
+    };
+
+    let first_page = Page::containing_address(VirtAddr::new((area + offset) * 0x1000));
+    let ctx = &mut *PAGING_CTX.get().unwrap().lock();
+
+    if let Err(e) = map_frames(ctx, first_page, first, frames, mode, firmware) {
+        // Part of the region might have been mapped
+        unmap_pages(ctx, first_page, frames);
+        mappings.window.free(area);
+        mappings.unuse_frames(first, frames);
+
+        return Err(e);
+    }
+
+    mappings.regions.insert(first_page, Mapping {
+        first,
+        frames,
+        mode,
+        area,
+        refs: 1,
+    });
+
+    Ok(first_page.start_address() + start.as_u64() % 0x1000)
+}
+
+/// Releases the region mapped by [`acquire`] that contains `addr`, the mapping
+/// is unmapped once no region uses it
+pub(crate) fn release(addr: VirtAddr) {
+    let page = Page::containing_address(addr);
+    let mut mappings = MAPPINGS.lock();
+
+    let first_page = match mappings.regions.range(..=page).next_back() {
+        Some((first_page, mapping)) if page < *first_page + mapping.frames => *first_page,
+        _ => {
+            log::error!("Tried to release {:#X} which isn't mapped", addr.as_u64());
+            return;
+        },
+    };
+
+    let mapping = mappings.regions.get_mut(&first_page).unwrap();
+    mapping.refs -= 1;
+
+    if mapping.refs != 0 {
+        return;
+    }
+
+    let mapping = mappings.regions.remove(&first_page).unwrap();
+
+    unmap_pages(
+        &mut PAGING_CTX.get().unwrap().lock(),
+        first_page,
+        mapping.frames,
+    );
+
+    mappings.window.free(mapping.area);
+    mappings.unuse_frames(mapping.first, mapping.frames);
+}
+
+/// Returns the cache mode `frame` is mapped with, if a region contains it
+pub(crate) fn mapped_mode(frame: PhysFrame) -> Option<CacheMode> {
+    MAPPINGS.lock().frames.get(&frame).map(|(mode, _)| *mode)
+}
+
+/// Maps `frames` frames from `first` at `first_page`, with 2MiB pages where
+/// both are aligned
+unsafe fn map_frames(
+    ctx: &mut PagingContext,
+    first_page: Page,
+    first: PhysFrame,
+    frames: u64,
+    mode: CacheMode,
+    firmware: bool,
+) -> Result<(), KError> {
+    let mut i = 0;
+
+    while i < frames {
+        let (page, frame) = (first_page + i, first + i);
+
+        if i + HUGE_FRAMES <= frames && map_huge(ctx, page, frame, mode, firmware)? {
+            i += HUGE_FRAMES;
+            continue;
+        }
+
+        let flags =
+            PageTableFlags::PRESENT | device_frame_flags(ctx, frame, firmware)? | mode.flags();
+
+        ctx.mapper
+            .map_to(page, frame, flags, &mut ctx.allocator)?
+            .flush();
+
+        i += 1;
+    }
+
+    Ok(())
+}
+
+/// Maps `frame` at `page` with a 2MiB page if both are aligned, all the frames
+/// need the same permissions and the mode can be used with huge pages, returns
+/// false if it must be mapped with 4KiB pages instead
+unsafe fn map_huge(
+    ctx: &mut PagingContext,
+    page: Page,
+    frame: PhysFrame,
+    mode: CacheMode,
+    firmware: bool,
+) -> Result<bool, KError> {
+    let huge_flags = match mode.huge_flags() {
+        Some(flags) => flags,
+        None => return Ok(false),
+    };
+
+    if !frame.start_address().is_aligned(Size2MiB::SIZE)
+        || !page.start_address().is_aligned(Size2MiB::SIZE)
+    {
+        return Ok(false);
+    }
+
+    let flags = device_frame_flags(ctx, frame, firmware)?;
+
+    for frame in PhysFrame::range(frame + 1, frame + HUGE_FRAMES) {
+        if device_frame_flags(ctx, frame, firmware)? != flags {
+            return Ok(false);
+        }
+    }
+
+    let result = ctx.mapper.map_to(
+        Page::<Size2MiB>::containing_address(page.start_address()),
+        PhysFrame::<Size2MiB>::containing_address(frame.start_address()),
+        PageTableFlags::PRESENT | flags | huge_flags,
+        &mut ctx.allocator,
+    );
+
+    match result {
+        Ok(flusher) => flusher.flush(),
+        Err(MapToError::FrameAllocationFailed) => return Err(KError::NoMemory),
+        // The upper tables are used with 4KiB pages
+        Err(_) => return Ok(false),
+    }
+
+    Ok(true)
+}
+
+/// Unmaps `pages` pages from `first` without freeing the frames, pages that
+/// aren't mapped are skipped
+fn unmap_pages(ctx: &mut PagingContext, first: Page, pages: u64) {
+    let end = first + pages;
+    let mut page = first;
+
+    while page < end {
+        match ctx.mapper.unmap(page) {
+            Ok((_, flusher)) => flusher.flush(),
+            Err(UnmapError::ParentEntryHugePage) => {
+                let huge_page = Page::<Size2MiB>::containing_address(page.start_address());
+
+                match ctx.mapper.unmap(huge_page) {
+                    Ok((_, flusher)) => flusher.flush(),
+                    Err(e) => log::error!("Failed to unmap a device page: {:?}", e),
+                }
+
+                page = Page::containing_address(huge_page.start_address()) + HUGE_FRAMES;
+                continue;
+            },
+            Err(UnmapError::PageNotMapped) => {},
+            Err(e) => log::error!("Failed to unmap a device page: {:?}", e),
+        }
+
+        page += 1;
+    }
+}
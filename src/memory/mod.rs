@@ -1,17 +1,32 @@
-pub use frame_allocator::GlobalFrameAllocator;
+pub use frame_allocator::{FrameOwner, GlobalFrameAllocator, Zone};
+pub use mmio::{map_mmio, CacheMode, MmioRegion};
+pub use valloc::{valloc, vfree, vmap, vunmap};
 
+use crate::error::KError;
+use alloc::vec::Vec;
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::{fmt, ops::Range};
 use spin::{Mutex, Once};
 use x86_64::{
     structures::paging::{
         mapper::{MapToError, UnmapError},
-        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
-        PhysFrame, Size4KiB,
+        page::PageRange,
+        FrameAllocator, Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags,
+        PhysFrame, Size2MiB, Size4KiB,
     },
-    VirtAddr,
+    PhysAddr, VirtAddr,
 };
 
+pub mod dma;
 mod frame_allocator;
+pub mod inspect;
+pub mod layout;
+pub mod mmio;
+pub mod shrinker;
+pub mod stack;
+pub mod trampoline;
+mod valloc;
+pub mod vmm;
 
 pub struct PagingContext {
     pub mapper: OffsetPageTable<'static>,
@@ -20,6 +35,9 @@ pub struct PagingContext {
 
 pub static PAGING_CTX: Once<Mutex<PagingContext>> = Once::new();
 
+/// The number of 4KiB pages in a 2MiB page
+const HUGE_PAGES: u64 = Size2MiB::SIZE / Size4KiB::SIZE;
+
 /// Initialize a new OffsetPageTable.
 ///
 /// # Safety
@@ -29,11 +47,39 @@ pub static PAGING_CTX: Once<Mutex<PagingContext>> = Once::new();
 /// `physical_memory_offset`. Also, this function must be only called once
 /// to avoid aliasing `&mut` references (which is undefined behavior).
 pub unsafe fn init(physical_memory_offset: VirtAddr, memory_map: &'static MemoryMap) {
+    let stack_marker = 0u8;
+    layout::check(
+        physical_memory_offset,
+        memory_map,
+        VirtAddr::from_ptr(&stack_marker),
+    );
+
     let level_4_table = active_level_4_table(physical_memory_offset);
     let mut mapper = OffsetPageTable::new(level_4_table, physical_memory_offset);
-    let allocator = GlobalFrameAllocator::init(memory_map, &mut mapper);
+    stack::init(&mut mapper, VirtAddr::from_ptr(&stack_marker));
+    let mut allocator = GlobalFrameAllocator::init(memory_map, &mut mapper);
+
+    // Low memory is scarce so it's reserved before anything else can use it
+    trampoline::reserve(&mut allocator);
 
     PAGING_CTX.call_once(|| Mutex::new(PagingContext { mapper, allocator }));
+
+    mmio::init_pat();
+}
+
+/// Gives the `AcpiReclaimable` memory to the frame allocator and returns the
+/// number of frames reclaimed
+///
+/// # Safety
+///
+/// The acpi tables must not be read after calling this since their memory
+/// might be reused
+pub unsafe fn reclaim_acpi() -> u64 { PAGING_CTX.get().unwrap().lock().allocator.reclaim_acpi() }
+
+/// Returns the address of a physical address in the complete physical memory
+/// mapping.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    PAGING_CTX.get().unwrap().lock().mapper.phys_offset() + addr.as_u64()
 }
 
 /// Returns a mutable reference to the active level 4 table.
@@ -56,89 +102,463 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
     &mut *page_table_ptr // unsafe
 }
 
-/// Identity maps a frame for a memory mapped device
-///
-/// # Safety
-///
-/// This function is unsafe because the caller must guarantee that the
-/// frame is free and is usable
-#[track_caller]
-pub unsafe fn mmap_dev(frame: PhysFrame, acpi: bool) -> Result<UnmapGuard, MapToError<Size4KiB>> {
-    let ctx = &mut *PAGING_CTX.get().unwrap().lock();
-    let ty = ctx
-        .allocator
-        .get_frame_ty(frame)
-        .ok_or(MapToError::FrameAllocationFailed)?;
-
-    let extra_flags = match ty {
-        MemoryRegionType::Reserved | MemoryRegionType::FrameZero => PageTableFlags::WRITABLE,
-        // Workaround acpi bios discovery
-        MemoryRegionType::KernelStack if acpi => PageTableFlags::empty(),
-        _ => panic!(
-            "Tried to mmap a device on a {:?} frame {:#X}",
-            ty,
-            frame.start_address()
-        ),
-    };
-
-    let page = Page::containing_address(VirtAddr::new(frame.start_address().as_u64()));
-
-    let flusher = ctx.mapper.identity_map(
-        frame,
-        PageTableFlags::PRESENT
-            | PageTableFlags::NO_CACHE
-            | PageTableFlags::WRITE_THROUGH
-            | extra_flags,
-        &mut ctx.allocator,
-    )?;
-
-    flusher.flush();
-
-    Ok(UnmapGuard {
-        page,
-        unmap_frame: !ctx.allocator.frame_in_use(frame),
-    })
-}
-
-/// Unmaps and if a guard is provided deallocates the frame
-pub fn unmap(guard: UnmapGuard) -> Result<(), UnmapError> {
-    let mut ctx = PAGING_CTX.get().unwrap().lock();
-    let (frame, flusher) = ctx.mapper.unmap(guard.page)?;
-
-    flusher.flush();
+/// Returns the flags a device mapping of `frame` needs, or an error if the
+/// frame is memory that can't belong to a device
+fn device_frame_flags(
+    ctx: &PagingContext,
+    frame: PhysFrame,
+    firmware: bool,
+) -> Result<PageTableFlags, KError> {
+    let ty = ctx.allocator.get_frame_ty(frame).ok_or(KError::Fault)?;
 
-    if guard.unmap_frame {
-        unsafe { ctx.allocator.deallocate_frame(frame) }
+    match ty {
+        MemoryRegionType::Reserved | MemoryRegionType::FrameZero => Ok(PageTableFlags::WRITABLE),
+        // Workaround acpi bios discovery
+        MemoryRegionType::KernelStack if firmware => Ok(PageTableFlags::empty()),
+        _ => {
+            log::error!(
+                "Tried to mmap a device on a {:?} frame {:#X}",
+                ty,
+                frame.start_address()
+            );
+            Err(KError::Fault)
+        },
     }
-
-    Ok(())
 }
 
-/// Maps a page range
+/// Maps a page range to newly allocated frames tagged with `owner`, `flags`
+/// must not contain caching flags since they are chosen by `mode`
+///
+/// The parts of the range that cover whole 2MiB pages are mapped with huge
+/// pages when there's contiguous memory for them.
 #[track_caller]
 pub fn map_range(
-    range: impl Iterator<Item = Page>,
+    range: PageRange,
+    flags: PageTableFlags,
+    mode: CacheMode,
+    owner: FrameOwner,
+) -> Result<(), MapToError<Size4KiB>> {
+    map_range_in(
+        &mut PAGING_CTX.get().unwrap().lock(),
+        range,
+        flags,
+        mode,
+        owner,
+    )
+}
+
+/// Like [`map_range`] but with the paging context already locked
+#[track_caller]
+pub fn map_range_in(
+    ctx: &mut PagingContext,
+    range: PageRange,
     flags: PageTableFlags,
+    mode: CacheMode,
+    owner: FrameOwner,
 ) -> Result<(), MapToError<Size4KiB>> {
-    let ctx = &mut *PAGING_CTX.get().unwrap().lock();
+    let mut page = range.start;
+
+    while page < range.end {
+        if map_huge_page(ctx, page, range.end, flags, mode, owner) {
+            page += HUGE_PAGES;
+            continue;
+        }
 
-    for page in range {
         let frame = ctx
             .allocator
-            .allocate_frame()
+            .allocate_frame_for(owner, Zone::Normal)
             .ok_or(MapToError::FrameAllocationFailed)?;
 
         unsafe {
             ctx.mapper
-                .map_to(page, frame, flags, &mut ctx.allocator)?
+                .map_to(page, frame, flags | mode.flags(), &mut ctx.allocator)?
                 .flush()
         };
+
+        page += 1;
     }
 
     Ok(())
 }
 
-pub struct UnmapGuard {
-    page: Page<Size4KiB>,
-    unmap_frame: bool,
+/// Maps a 2MiB page at `page` if it's aligned, the range ends after it and
+/// there's a free 2MiB frame, returns false if the page must be mapped with
+/// 4KiB pages instead
+fn map_huge_page(
+    ctx: &mut PagingContext,
+    page: Page,
+    end: Page,
+    flags: PageTableFlags,
+    mode: CacheMode,
+    owner: FrameOwner,
+) -> bool {
+    let huge_flags = match mode.huge_flags() {
+        Some(huge_flags) => huge_flags,
+        None => return false,
+    };
+
+    if !page.start_address().is_aligned(Size2MiB::SIZE) || end - page < HUGE_PAGES {
+        return false;
+    }
+
+    let frame = match ctx.allocator.allocate_huge_frame_for(owner, Zone::Normal) {
+        Some(frame) => frame,
+        None => return false,
+    };
+
+    let huge_page = Page::<Size2MiB>::containing_address(page.start_address());
+
+    match unsafe {
+        ctx.mapper
+            .map_to(huge_page, frame, flags | huge_flags, &mut ctx.allocator)
+    } {
+        Ok(flusher) => {
+            flusher.flush();
+            true
+        },
+        Err(_) => {
+            unsafe { ctx.allocator.deallocate_huge_frame(frame) };
+            false
+        },
+    }
+}
+
+/// Unmaps a page range mapped by [`map_range`] and frees it's frames, the
+/// pages that aren't mapped are skipped
+pub fn unmap_range(range: PageRange) {
+    unmap_range_in(&mut PAGING_CTX.get().unwrap().lock(), range)
+}
+
+/// Like [`unmap_range`] but with the paging context already locked
+pub fn unmap_range_in(ctx: &mut PagingContext, range: PageRange) {
+    let mut page = range.start;
+
+    while page < range.end {
+        match ctx.mapper.unmap(page) {
+            Ok((frame, flusher)) => {
+                flusher.flush();
+                unsafe { ctx.allocator.deallocate_frame(frame) };
+            },
+            Err(UnmapError::ParentEntryHugePage) => {
+                let huge_page = Page::<Size2MiB>::containing_address(page.start_address());
+
+                match ctx.mapper.unmap(huge_page) {
+                    Ok((frame, flusher)) => {
+                        flusher.flush();
+                        unsafe { ctx.allocator.deallocate_huge_frame(frame) };
+                    },
+                    Err(e) => log::error!("Failed to unmap {:?}: {:?}", huge_page, e),
+                }
+
+                page = Page::containing_address(huge_page.start_address()) + HUGE_PAGES;
+                continue;
+            },
+            Err(UnmapError::PageNotMapped) => {},
+            Err(e) => log::error!("Failed to unmap {:?}: {:?}", page, e),
+        }
+
+        page += 1;
+    }
+}
+
+/// Returns a report of the number of frames used by each owner
+pub fn frame_owner_report() -> impl fmt::Display {
+    struct Report([u64; FrameOwner::ALL.len()]);
+
+    impl fmt::Display for Report {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            for (owner, count) in FrameOwner::ALL.iter().zip(self.0.iter()) {
+                writeln!(f, "{:?}: {} frames ({} KiB)", owner, count, count * 4)?;
+            }
+
+            Ok(())
+        }
+    }
+
+    Report(
+        PAGING_CTX
+            .get()
+            .unwrap()
+            .lock()
+            .allocator
+            .frames_per_owner(),
+    )
+}
+
+/// Returns a table of the bootloader memory map with the occupancy of each
+/// region and of the kernel's virtual layout
+///
+/// The occupancy is read up front since formatting might allocate, which
+/// can't happen with the paging context locked
+pub fn memory_map_report() -> impl fmt::Display {
+    struct Report {
+        memory_map: &'static MemoryMap,
+        /// Used frames of each region of the memory map
+        used: Vec<u64>,
+        virtual_regions: [(&'static str, Range<u64>); 6],
+    }
+
+    impl fmt::Display for Report {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            writeln!(f, "Physical memory:")?;
+
+            for (region, used) in self.memory_map.iter().zip(self.used.iter()) {
+                let len = region.range.end_frame_number - region.range.start_frame_number;
+
+                write!(
+                    f,
+                    "  {:#012X}-{:#012X} {:>8} KiB {:?}",
+                    region.range.start_addr(),
+                    region.range.end_addr() - 1,
+                    len * 4,
+                    region.region_type
+                )?;
+
+                if let MemoryRegionType::Usable | MemoryRegionType::AcpiReclaimable =
+                    region.region_type
+                {
+                    write!(f, " ({}/{} used)", used, len)?;
+                }
+
+                writeln!(f)?;
+            }
+
+            writeln!(f, "Virtual layout:")?;
+
+            for (name, range) in self.virtual_regions.iter() {
+                writeln!(
+                    f,
+                    "  {:#018X}-{:#018X} {:>8} KiB {}",
+                    range.start,
+                    range.end - 1,
+                    (range.end - range.start + 1023) / 1024,
+                    name
+                )?;
+            }
+
+            write!(f, "  device memory is mapped in the mmio region")
+        }
+    }
+
+    let memory_map = PAGING_CTX.get().unwrap().lock().allocator.memory_map();
+    let mut used = Vec::with_capacity(memory_map.iter().count());
+
+    let ctx = PAGING_CTX.get().unwrap().lock();
+
+    for region in memory_map.iter() {
+        let frames = region.range.start_frame_number..region.range.end_frame_number;
+
+        used.push(ctx.allocator.used_frames(frames));
+    }
+
+    let [bitmap, owners, orders] = ctx.allocator.metadata_ranges();
+    let heap_start = crate::allocator::heap_start().as_u64();
+    let double_fault_stack = crate::gdt::double_fault_stack();
+    let phys_offset = ctx.mapper.phys_offset().as_u64();
+    let phys_end = memory_map
+        .iter()
+        .map(|region| region.range.end_addr())
+        .max()
+        .unwrap_or(0);
+
+    drop(ctx);
+
+    Report {
+        memory_map,
+        used,
+        virtual_regions: [
+            (
+                "heap",
+                heap_start..heap_start + crate::allocator::heap_size() as u64,
+            ),
+            ("frame bitmap", bitmap),
+            ("frame owners", owners),
+            ("frame orders", orders),
+            (
+                "double fault stack",
+                double_fault_stack.start.as_u64()..double_fault_stack.end.as_u64(),
+            ),
+            ("physical memory", phys_offset..phys_offset + phys_end),
+        ],
+    }
+}
+
+/// Randomly interleaves allocations, deallocations and device mappings and
+/// checks the allocator after each phase
+///
+/// The seed is printed so a failure can be reproduced by building with
+/// `STRESS_SEED` set to it
+#[test_case]
+fn frame_allocator_stress() {
+    use crate::serial_println;
+    use x86_64::structures::paging::Translate;
+
+    const PHASES: usize = 16;
+    const OPS_PER_PHASE: usize = 512;
+    const MAX_HELD: usize = 256;
+
+    let seed = option_env!("STRESS_SEED")
+        .and_then(|seed| seed.parse().ok())
+        .unwrap_or_else(|| unsafe { core::arch::x86_64::_rdtsc() } | 1);
+
+    serial_println!("seed {}", seed);
+
+    // xorshift64
+    let mut state: u64 = seed;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+
+    let ctx = PAGING_CTX.get().unwrap();
+
+    // Mapping devices can allocate page tables that are kept after the
+    // regions are unmapped, they're left out of the final comparison
+    let free_frames = |ctx: &PagingContext| -> u64 {
+        let page_tables = ctx.allocator.frames_per_owner()[FrameOwner::PageTables as usize];
+        let free: u64 = Zone::ALL
+            .iter()
+            .map(|zone| ctx.allocator.free_frames(*zone))
+            .sum();
+
+        free + page_tables
+    };
+
+    let initial_free = free_frames(&ctx.lock());
+
+    // A reserved frame that no driver has mapped to exercise `map_mmio`
+    let memory_map = ctx.lock().allocator.memory_map();
+    let device_frame = memory_map
+        .iter()
+        .filter(|region| region.region_type == MemoryRegionType::Reserved)
+        .map(|region| PhysFrame::containing_address(PhysAddr::new(region.range.start_addr())))
+        .find(|frame| mmio::mapped_mode(*frame).is_none());
+
+    let mut held: Vec<(PhysFrame, usize)> = Vec::with_capacity(MAX_HELD);
+
+    for phase in 0..PHASES {
+        for _ in 0..OPS_PER_PHASE {
+            match next() % 4 {
+                0 | 1 if held.len() < MAX_HELD => {
+                    let zone = Zone::ALL[next() as usize % Zone::ALL.len()];
+                    // Skip `Unknown`, it's reserved for frames not allocated
+                    let owner = FrameOwner::ALL[1 + next() as usize % (FrameOwner::ALL.len() - 1)];
+
+                    // Mostly single frames with some small blocks
+                    let order = if next() % 4 == 0 {
+                        next() as usize % 4
+                    } else {
+                        0
+                    };
+
+                    let frame = ctx.lock().allocator.allocate_block_for(owner, zone, order);
+
+                    if let Some(frame) = frame {
+                        let end = frame + (1 << order);
+
+                        assert_eq!(frame.start_address().as_u64() % (0x1000 << order), 0);
+                        assert!(PhysFrame::range(frame, end)
+                            .all(|frame| ctx.lock().allocator.frame_in_use(frame)));
+                        assert!(
+                            held.iter().all(|(start, held_order)| {
+                                *start + (1 << held_order) <= frame || end <= *start
+                            }),
+                            "frame allocated twice"
+                        );
+                        held.push((frame, order));
+                    }
+                },
+                2 if !held.is_empty() => {
+                    let (frame, order) = held.swap_remove(next() as usize % held.len());
+                    let mut ctx = ctx.lock();
+
+                    unsafe { ctx.allocator.deallocate_block(frame, order) };
+                    assert!(!ctx.allocator.frame_in_use(frame));
+                },
+                3 => {
+                    if let Some(frame) = device_frame {
+                        let start = frame.start_address();
+
+                        let first = unsafe { map_mmio(start, 0x1000, CacheMode::Uncached) }
+                            .expect("map_mmio failed");
+                        // A region inside another one shares it's mapping
+                        let second =
+                            unsafe { map_mmio(start + 0x800u64, 0x800, CacheMode::Uncached) }
+                                .expect("map_mmio failed");
+                        let second_base = second.base();
+
+                        assert_eq!(second_base, first.base() + 0x800u64);
+                        assert_eq!(
+                            ctx.lock().mapper.translate_addr(second_base),
+                            Some(start + 0x800u64)
+                        );
+
+                        drop(first);
+                        assert!(ctx.lock().mapper.translate_addr(second_base).is_some());
+
+                        drop(second);
+                        assert!(ctx.lock().mapper.translate_addr(second_base).is_none());
+                        assert_eq!(mmio::mapped_mode(frame), None);
+                    }
+                },
+                _ => {},
+            }
+        }
+
+        if let Err(e) = ctx.lock().allocator.check_invariants() {
+            panic!("phase {}: {}", phase, e);
+        }
+    }
+
+    let mut ctx = ctx.lock();
+
+    for (frame, order) in held {
+        unsafe { ctx.allocator.deallocate_block(frame, order) };
+    }
+
+    ctx.allocator.check_invariants().unwrap();
+    assert_eq!(free_frames(&ctx), initial_free);
+}
+
+#[test_case]
+fn contiguous_allocations() {
+    let mut ctx = PAGING_CTX.get().unwrap().lock();
+    let allocator = &mut ctx.allocator;
+
+    let initial_free: Vec<u64> = Zone::ALL
+        .iter()
+        .map(|zone| allocator.free_frames(*zone))
+        .collect();
+
+    // Cut from a block, the rest of it is given back
+    let small = allocator
+        .allocate_contiguous(3, 0x4000)
+        .expect("small contiguous allocation failed");
+
+    assert_eq!(small.start_address().as_u64() % 0x4000, 0);
+    assert!(PhysFrame::range(small, small + 3).all(|frame| allocator.frame_in_use(frame)));
+    assert!(!allocator.frame_in_use(small + 3));
+
+    // Bigger than the biggest block so it's searched in the bitmap
+    let frames = (1 << frame_allocator::MAX_ORDER) + 100;
+    let big = allocator.allocate_contiguous_for(FrameOwner::Driver, Zone::Normal, frames, 0x10000);
+
+    if let Some(big) = big {
+        assert_eq!(big.start_address().as_u64() % 0x10000, 0);
+        assert!(
+            PhysFrame::range(big, big + frames as u64).all(|frame| allocator.frame_in_use(frame))
+        );
+
+        unsafe { allocator.deallocate_contiguous(big, frames) };
+    }
+
+    unsafe { allocator.deallocate_contiguous(small, 3) };
+
+    allocator.check_invariants().unwrap();
+
+    for (zone, free) in Zone::ALL.iter().zip(initial_free) {
+        assert_eq!(allocator.free_frames(*zone), free);
+    }
 }
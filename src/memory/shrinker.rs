@@ -0,0 +1,83 @@
+//! Reclaimable caches
+//!
+//! Caches register a [`Shrinker`] that frees some of their memory when the
+//! kernel is running low on it, the heap calls the shrinkers when an
+//! allocation fails and when the free memory drops below the low watermark.
+use crate::interrupts::irqoff;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+/// A cache that can give back memory under pressure
+///
+/// The callbacks run from inside the allocator so they must not allocate and
+/// must only `try_lock` the cache since the allocating code might be holding
+/// it.
+pub trait Shrinker: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Returns roughly how many bytes could be freed
+    fn count(&self) -> usize;
+
+    /// Frees at least `target` bytes if possible and returns how many bytes
+    /// were freed
+    fn shrink(&self, target: usize) -> usize;
+}
+
+static SHRINKERS: Mutex<Vec<&'static dyn Shrinker>> = Mutex::new(Vec::new());
+
+/// Set while the shrinkers are running so that allocations made by them don't
+/// shrink again
+static SHRINKING: AtomicBool = AtomicBool::new(false);
+
+/// Registers a cache to be shrunk under memory pressure
+pub fn register(shrinker: &'static dyn Shrinker) {
+    log::debug!("Registering shrinker {}", shrinker.name());
+
+    irqoff::without_interrupts(|| SHRINKERS.lock().push(shrinker))
+}
+
+/// Asks the registered caches in order of registration to free `target` bytes
+///
+/// Returns how many bytes were freed, this might be less than `target` and is
+/// zero if the shrinkers are already running
+pub fn shrink(target: usize) -> usize {
+    if SHRINKING.swap(true, Ordering::Acquire) {
+        return 0;
+    }
+
+    let freed = irqoff::without_interrupts(|| {
+        // The registry might be locked by a `register` that ran out of memory
+        let shrinkers = match SHRINKERS.try_lock() {
+            Some(shrinkers) => shrinkers,
+            None => return 0,
+        };
+
+        let mut freed = 0;
+
+        for shrinker in shrinkers.iter() {
+            if freed >= target {
+                break;
+            }
+
+            freed += shrinker.shrink(target - freed);
+        }
+
+        freed
+    });
+
+    SHRINKING.store(false, Ordering::Release);
+
+    freed
+}
+
+/// Returns the number of bytes that could be freed by all the registered caches
+pub fn reclaimable() -> usize {
+    irqoff::without_interrupts(|| {
+        SHRINKERS
+            .lock()
+            .iter()
+            .map(|shrinker| shrinker.count())
+            .sum()
+    })
+}
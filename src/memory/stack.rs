@@ -0,0 +1,62 @@
+//! Guard pages below the kernel stacks
+//!
+//! The page right below each stack is kept unmapped so that an overflow faults
+//! instead of silently corrupting whatever lies below. The bootloader already
+//! leaves one below the boot stack, the interrupt stacks are statics inside
+//! the kernel image so their lowest page is unmapped here.
+use crate::gdt;
+use spin::Once;
+use x86_64::{
+    structures::paging::{Mapper, OffsetPageTable, Page, Translate},
+    VirtAddr,
+};
+
+/// The guard pages of the boot stack and of the interrupt stacks
+static GUARDS: Once<[Guard; 1 + gdt::IST_STACKS]> = Once::new();
+
+#[derive(Debug, Clone, Copy)]
+struct Guard {
+    /// Name of the stack above the guard
+    stack: &'static str,
+    page: Page,
+}
+
+/// Unmaps the guard pages of the interrupt stacks and finds the one of the
+/// boot stack, `stack` must be an address in the boot stack
+pub(super) fn init(mapper: &mut OffsetPageTable, stack: VirtAddr) {
+    // The boot stack is mapped from the top down to the guard
+    let mut boot: Page = Page::containing_address(stack);
+    while mapper.translate_addr(boot.start_address()).is_some() {
+        boot -= 1;
+    }
+
+    let mut guards = [Guard {
+        stack: "boot",
+        page: boot,
+    }; 1 + gdt::IST_STACKS];
+
+    for (guard, (name, range)) in guards[1..].iter_mut().zip(gdt::ist_stacks().iter()) {
+        let page: Page = Page::containing_address(range.start - gdt::GUARD_SIZE);
+
+        // The frame is part of the kernel image so it isn't given back
+        match mapper.unmap(page) {
+            Ok((_, flush)) => flush.flush(),
+            Err(e) => log::warn!("Couldn't unmap the guard of the {} stack: {:?}", name, e),
+        }
+
+        *guard = Guard { stack: *name, page };
+    }
+
+    GUARDS.call_once(|| guards);
+}
+
+/// Returns the name of the stack whose guard page contains `addr`
+pub fn overflowed(addr: VirtAddr) -> Option<&'static str> {
+    let page: Page = Page::containing_address(addr);
+
+    GUARDS
+        .get()?
+        .iter()
+        .find(|guard| guard.page == page)
+        .map(|guard| guard.stack)
+}
@@ -0,0 +1,68 @@
+//! The low memory used to start the application processors
+//!
+//! An application processor starts in real mode at the page selected by the
+//! startup IPI, so the code that brings it to long mode must be copied to a
+//! page below 1MiB. The page is reserved while the allocator is created and
+//! can be given back once all the processors are running.
+use super::{phys_to_virt, FrameOwner, GlobalFrameAllocator, PAGING_CTX};
+use crate::error::KError;
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{FrameDeallocator, PhysFrame},
+    PhysAddr,
+};
+
+/// The startup IPI can only address pages below 1MiB
+const LIMIT: u64 = 0x10_0000;
+
+static FRAME: Mutex<Option<PhysFrame>> = Mutex::new(None);
+
+pub(super) fn reserve(allocator: &mut GlobalFrameAllocator) {
+    let frame = allocator.allocate_frame_below(FrameOwner::Trampoline, PhysAddr::new(LIMIT));
+
+    match frame {
+        Some(frame) => log::debug!("Reserved {:#X} for the trampoline", frame.start_address()),
+        None => log::warn!("No low memory for the trampoline, the APs can't be started"),
+    }
+
+    *FRAME.lock() = frame;
+}
+
+/// Returns the reserved frame, if it wasn't released
+pub fn frame() -> Option<PhysFrame> { *FRAME.lock() }
+
+/// Copies `code` to the start of the reserved frame and returns the vector to
+/// send in the startup IPI
+///
+/// # Safety
+///
+/// No processor can be running the trampoline
+pub unsafe fn install(code: &[u8]) -> Result<u8, KError> {
+    let frame = frame().ok_or(KError::NoMemory)?;
+
+    if code.len() > frame.size() as usize {
+        return Err(KError::NotSupported);
+    }
+
+    let dst = phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+    core::ptr::copy_nonoverlapping(code.as_ptr(), dst, code.len());
+
+    Ok((frame.start_address().as_u64() / 0x1000) as u8)
+}
+
+/// Gives the reserved frame back to the allocator
+///
+/// # Safety
+///
+/// All the processors must be past the trampoline, they can't be restarted
+/// after this
+pub unsafe fn release() {
+    if let Some(frame) = FRAME.lock().take() {
+        PAGING_CTX
+            .get()
+            .unwrap()
+            .lock()
+            .allocator
+            .deallocate_frame(frame);
+    }
+}
@@ -0,0 +1,219 @@
+//! Virtually contiguous allocations
+//!
+//! Big buffers don't need to be physically contiguous, their frames are
+//! allocated one by one and mapped next to each other in a region reserved
+//! from the [`vmm`].
+//! Every allocation is followed by an unmapped guard page so overruns fault.
+//!
+//! Frames that were already allocated can be mapped in the same region with
+//! [`vmap`], for memory that needs a different cache mode than the physical
+//! memory mapping.
+use super::{
+    layout, map_range, unmap_range, vmm, CacheMode, FrameOwner, PagingContext, HUGE_PAGES,
+    PAGING_CTX,
+};
+use crate::error::KError;
+use alloc::collections::BTreeMap;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{Mapper, Page, PageTableFlags, PhysFrame, Size4KiB},
+    VirtAddr,
+};
+
+/// The address space used for the allocations
+const VALLOC_SIZE: u64 = layout::LEVEL_4_ENTRY;
+
+lazy_static! {
+    static ref AREAS: Mutex<Areas> = Mutex::new(Areas::new("valloc", VALLOC_SIZE));
+}
+
+/// The page ranges of a region, the starts and the sizes are in pages
+pub(super) struct Areas {
+    /// The free ranges keyed by their start, they are never adjacent
+    free: BTreeMap<u64, u64>,
+    /// The allocations keyed by their start, without the guard page
+    used: BTreeMap<u64, u64>,
+}
+
+impl Areas {
+    /// Reserves a region of `size` bytes for `name` from the [`vmm`]
+    pub(super) fn new(name: &'static str, size: u64) -> Self {
+        let start = vmm::alloc_region(name, size, layout::LEVEL_4_ENTRY)
+            .unwrap_or_else(|e| panic!("Failed to reserve the {} region: {}", name, e));
+
+        let mut free = BTreeMap::new();
+        free.insert(start.as_u64() / 0x1000, size / 0x1000);
+
+        Areas {
+            free,
+            used: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the first page of a free range of `pages` aligned to `align`
+    /// pages and followed by a guard page
+    pub(super) fn alloc(&mut self, pages: u64, align: u64) -> Option<u64> {
+        let (start, len, aligned) = self.free.iter().find_map(|(&start, &len)| {
+            let aligned = (start + align - 1) / align * align;
+
+            (aligned - start + pages < len).then(|| (start, len, aligned))
+        })?;
+
+        self.free.remove(&start);
+
+        if aligned > start {
+            self.free.insert(start, aligned - start);
+        }
+
+        let end = aligned + pages + 1;
+
+        if start + len > end {
+            self.free.insert(end, start + len - end);
+        }
+
+        self.used.insert(aligned, pages);
+
+        Some(aligned)
+    }
+
+    /// Frees the allocation at `start` and returns it's size
+    pub(super) fn free(&mut self, start: u64) -> Option<u64> {
+        let pages = self.used.remove(&start)?;
+        let mut free_start = start;
+        let mut free_len = pages + 1;
+
+        if let Some(len) = self.free.remove(&(start + free_len)) {
+            free_len += len;
+        }
+
+        let previous = self.free.range(..start).next_back().map(|(s, l)| (*s, *l));
+
+        if let Some((previous_start, previous_len)) = previous {
+            if previous_start + previous_len == start {
+                self.free.remove(&previous_start);
+                free_start = previous_start;
+                free_len += previous_len;
+            }
+        }
+
+        self.free.insert(free_start, free_len);
+
+        Some(pages)
+    }
+}
+
+/// Allocates `len` bytes of virtually contiguous memory mapped with `flags`
+///
+/// The memory is page aligned and isn't zeroed.
+pub fn valloc(len: usize, flags: PageTableFlags) -> Result<VirtAddr, KError> {
+    let pages = ((len as u64 + 0xFFF) / 0x1000).max(1);
+    // Big allocations are aligned so they can use huge pages
+    let align = if pages >= HUGE_PAGES { HUGE_PAGES } else { 1 };
+    let start = AREAS.lock().alloc(pages, align).ok_or(KError::NoMemory)?;
+
+    let first = Page::<Size4KiB>::containing_address(VirtAddr::new(start * 0x1000));
+
+    if let Err(e) = map_range(
+        Page::range(first, first + pages),
+        flags | PageTableFlags::PRESENT,
+        CacheMode::WriteBack,
+        FrameOwner::Valloc,
+    ) {
+        // Part of the range might have been mapped
+        unmap_range(Page::range(first, first + pages));
+        AREAS.lock().free(start);
+
+        return Err(e.into());
+    }
+
+    Ok(first.start_address())
+}
+
+/// Frees memory allocated by [`valloc`]
+///
+/// # Safety
+///
+/// The memory must not be used after this
+pub unsafe fn vfree(addr: VirtAddr) {
+    let first = Page::<Size4KiB>::containing_address(addr);
+    let pages = match AREAS.lock().used.get(&(addr.as_u64() / 0x1000)) {
+        Some(pages) => *pages,
+        None => {
+            log::error!("Tried to vfree {:#X} which wasn't allocated", addr.as_u64());
+            return;
+        },
+    };
+
+    // Unmap before the range can be reused by another allocation
+    unmap_range(Page::range(first, first + pages));
+    AREAS.lock().free(addr.as_u64() / 0x1000);
+}
+
+/// Maps `frames` frames starting at `first` next to each other with `flags`
+/// and `mode`, the frames stay owned by the caller
+pub fn vmap(
+    first: PhysFrame,
+    frames: u64,
+    flags: PageTableFlags,
+    mode: CacheMode,
+) -> Result<VirtAddr, KError> {
+    let pages = frames.max(1);
+    let start = AREAS.lock().alloc(pages, 1).ok_or(KError::NoMemory)?;
+    let first_page = Page::<Size4KiB>::containing_address(VirtAddr::new(start * 0x1000));
+
+    let mut ctx = PAGING_CTX.get().unwrap().lock();
+    let ctx = &mut *ctx;
+
+    for i in 0..pages {
+        let result = unsafe {
+            ctx.mapper.map_to(
+                first_page + i,
+                first + i,
+                flags | PageTableFlags::PRESENT | mode.flags(),
+                &mut ctx.allocator,
+            )
+        };
+
+        match result {
+            Ok(flusher) => flusher.flush(),
+            Err(e) => {
+                unmap_pages(ctx, first_page, i);
+                AREAS.lock().free(start);
+
+                return Err(e.into());
+            },
+        }
+    }
+
+    Ok(first_page.start_address())
+}
+
+/// Unmaps memory mapped by [`vmap`] without freeing the frames
+///
+/// # Safety
+///
+/// The memory must not be used after this
+pub unsafe fn vunmap(addr: VirtAddr) {
+    let first = Page::<Size4KiB>::containing_address(addr);
+    let pages = match AREAS.lock().used.get(&(addr.as_u64() / 0x1000)) {
+        Some(pages) => *pages,
+        None => {
+            log::error!("Tried to vunmap {:#X} which wasn't mapped", addr.as_u64());
+            return;
+        },
+    };
+
+    unmap_pages(&mut PAGING_CTX.get().unwrap().lock(), first, pages);
+    AREAS.lock().free(addr.as_u64() / 0x1000);
+}
+
+/// Unmaps `pages` pages from `first` keeping their frames
+fn unmap_pages(ctx: &mut PagingContext, first: Page, pages: u64) {
+    for page in Page::range(first, first + pages) {
+        match ctx.mapper.unmap(page) {
+            Ok((_, flusher)) => flusher.flush(),
+            Err(e) => log::error!("Failed to unmap {:?}: {:?}", page, e),
+        }
+    }
+}
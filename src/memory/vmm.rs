@@ -0,0 +1,134 @@
+//! The kernel virtual address space manager
+//!
+//! Every range of the higher half that is in use is recorded here. The fixed
+//! regions of [`layout`] are reserved from the start and everything else gets
+//! it's range from [`alloc_region`], so two users can never be given
+//! overlapping addresses.
+//!
+//! Only big and long lived ranges are tracked, users with many small
+//! allocations like [`super::valloc`] manage the inside of a region they got
+//! from here. The table has a fixed size so it works before the heap exists.
+use super::layout::{self, Region};
+use crate::error::KError;
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+/// How many regions can be tracked
+const MAX_REGIONS: usize = 32;
+
+static VMM: Mutex<Vmm> = Mutex::new(Vmm::new());
+
+struct Vmm {
+    regions: [Option<Region>; MAX_REGIONS],
+}
+
+impl Vmm {
+    /// Starts with the fixed regions of the layout reserved
+    const fn new() -> Self {
+        let mut regions = [None; MAX_REGIONS];
+        let mut i = 0;
+
+        while i < layout::REGIONS.len() {
+            regions[i] = Some(layout::REGIONS[i]);
+            i += 1;
+        }
+
+        Vmm { regions }
+    }
+
+    /// Returns the lowest start of a free range of `size` aligned to `align`
+    fn find_free(&self, size: u64, align: u64) -> Option<u64> {
+        // A free range either starts at the start of the higher half or right
+        // after a region
+        let after_regions = self
+            .regions
+            .iter()
+            .flatten()
+            .filter_map(|region| region.last().checked_add(1));
+
+        core::iter::once(layout::HIGHER_HALF)
+            .chain(after_regions)
+            .filter_map(|start| {
+                let start = start.checked_add(align - 1)? / align * align;
+                // The range can't wrap around the end of the address space
+                let _ = start.checked_add(size - 1)?;
+
+                let candidate = Region::new("", start, size);
+
+                (!self
+                    .regions
+                    .iter()
+                    .flatten()
+                    .any(|r| r.overlaps(&candidate)))
+                .then(|| start)
+            })
+            .min()
+    }
+}
+
+/// Reserves `size` bytes of the address space aligned to `align` for `name`
+///
+/// Nothing is mapped, the size is rounded up to a page and the alignment is at
+/// least a page.
+pub fn alloc_region(name: &'static str, size: u64, align: u64) -> Result<VirtAddr, KError> {
+    if size == 0 || !align.is_power_of_two() {
+        return Err(KError::Fault);
+    }
+
+    let size = (size + 0xFFF) & !0xFFF;
+    let align = align.max(0x1000);
+
+    let mut vmm = VMM.lock();
+
+    let start = vmm.find_free(size, align).ok_or(KError::NoMemory)?;
+    let slot = vmm
+        .regions
+        .iter_mut()
+        .find(|region| region.is_none())
+        .ok_or(KError::NoMemory)?;
+
+    *slot = Some(Region::new(name, start, size));
+
+    log::debug!(
+        "Reserved {:#X}-{:#X} for {}",
+        start,
+        start + (size - 1),
+        name
+    );
+
+    Ok(VirtAddr::new(start))
+}
+
+/// Gives back a range reserved by [`alloc_region`]
+///
+/// # Safety
+///
+/// Nothing may be mapped in the range anymore
+pub unsafe fn free_region(start: VirtAddr) {
+    let mut vmm = VMM.lock();
+
+    let slot = vmm.regions.iter_mut().find(|region| {
+        region.map_or(false, |region| {
+            region.start == start.as_u64() && !layout::REGIONS.contains(&region)
+        })
+    });
+
+    match slot {
+        Some(slot) => *slot = None,
+        None => log::error!(
+            "Tried to free the region at {:#X} which wasn't allocated",
+            start.as_u64()
+        ),
+    }
+}
+
+/// Returns the region that contains `addr`, doesn't wait for the lock since
+/// it's used by the fault handlers
+pub fn region_of(addr: VirtAddr) -> Option<Region> {
+    VMM.try_lock()?
+        .regions
+        .iter()
+        .flatten()
+        .find(|region| region.contains(addr))
+        .copied()
+}
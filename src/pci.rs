@@ -1,40 +1,450 @@
+use crate::{interrupts::irqoff, time};
 use alloc::vec::Vec;
+use bitflags::bitflags;
+use core::time::Duration;
 use pci_types::{ConfigRegionAccess, PciAddress, PciHeader};
+use spin::Mutex;
 use x86_64::instructions::port::{PortRead, PortWrite};
 
+pub mod ids;
+
 const CONFIG_ADDRESS: u16 = 0xCF8;
 const CONFIG_DATA: u16 = 0xCFC;
 
+const COMMAND_OFFSET: u16 = 0x04;
+const CAPABILITIES_POINTER_OFFSET: u16 = 0x34;
+
+const INTERRUPT_OFFSET: u16 = 0x3C;
+
+/// The size of the config space reachable through mechanism 1
+pub const CONFIG_SPACE_SIZE: u16 = 0x100;
+
+pub const CAPABILITY_POWER_MANAGEMENT: u8 = 0x01;
+pub const CAPABILITY_PCI_EXPRESS: u8 = 0x10;
+
+/// The number of dwords in the standard config space header
+const HEADER_DWORDS: usize = 16;
+
+/// How long a function has to respond to config reads after a reset
+const FLR_READY_TIMEOUT: Duration = Duration::from_secs(1);
+/// How often a function is checked after a reset
+const FLR_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+bitflags! {
+    /// The command register of a pci function
+    pub struct CommandRegister: u16 {
+        const IO_SPACE = 1;
+        const MEMORY_SPACE = 1 << 1;
+        const BUS_MASTER = 1 << 2;
+        const SPECIAL_CYCLES = 1 << 3;
+        const MEMORY_WRITE_AND_INVALIDATE = 1 << 4;
+        const VGA_PALETTE_SNOOP = 1 << 5;
+        const PARITY_ERROR_RESPONSE = 1 << 6;
+        const SERR_ENABLE = 1 << 8;
+        const FAST_BACK_TO_BACK = 1 << 9;
+        const INTERRUPT_DISABLE = 1 << 10;
+    }
+}
+
+bitflags! {
+    /// The status register of a pci function
+    pub struct StatusRegister: u16 {
+        const INTERRUPT_STATUS = 1 << 3;
+        const CAPABILITIES_LIST = 1 << 4;
+        const MHZ_66_CAPABLE = 1 << 5;
+        const FAST_BACK_TO_BACK_CAPABLE = 1 << 7;
+        const MASTER_DATA_PARITY_ERROR = 1 << 8;
+        const SIGNALED_TARGET_ABORT = 1 << 11;
+        const RECEIVED_TARGET_ABORT = 1 << 12;
+        const RECEIVED_MASTER_ABORT = 1 << 13;
+        const SIGNALED_SYSTEM_ERROR = 1 << 14;
+        const DETECTED_PARITY_ERROR = 1 << 15;
+    }
+}
+
+/// Serializes accesses to the `CONFIG_ADDRESS`/`CONFIG_DATA` pair, since an
+/// access is made of two port operations another cpu (or an interrupt handler
+/// running aml) could change the address in between
+static CONFIG_LOCK: Mutex<()> = Mutex::new(());
+
+/// Runs `f` with the config space lock held and interrupts disabled
+fn with_config_lock<R>(f: impl FnOnce() -> R) -> R {
+    irqoff::without_interrupts(|| {
+        let _guard = CONFIG_LOCK.lock();
+        f()
+    })
+}
+
+/// Selects the register to be accessed through `CONFIG_DATA`, must be called
+/// with the config lock held
+unsafe fn select(address: PciAddress, offset: u16) {
+    u32::write_to_port(CONFIG_ADDRESS, ConfigAddress::new(address, offset).0)
+}
+
 pub unsafe fn read(address: pci_types::PciAddress, offset: u16) -> u32 {
-    fn read_inner(address: pci_types::PciAddress, offset: u16) -> u32 {
-        if (offset & 0b11) != 0 {
-            panic!("Try to read pci with unaligned offset")
+    if (offset & 0b11) != 0 {
+        panic!("Try to read pci with unaligned offset")
+    }
+
+    with_config_lock(|| {
+        select(address, offset);
+        u32::read_from_port(CONFIG_DATA)
+    })
+}
+
+pub unsafe fn write(address: pci_types::PciAddress, offset: u16, value: u32) {
+    if (offset & 0b11) != 0 {
+        panic!("Try to write pci with unaligned offset")
+    }
+
+    with_config_lock(|| {
+        select(address, offset);
+        u32::write_to_port(CONFIG_DATA, value)
+    })
+}
+
+/// Atomically reads the dword at `offset`, passes it to `f` and writes back
+/// the returned value, no other access can happen in between.
+///
+/// `f` runs with the config lock held so it must not access the config space
+/// itself.
+pub unsafe fn modify(
+    address: pci_types::PciAddress,
+    offset: u16,
+    f: impl FnOnce(u32) -> u32,
+) -> u32 {
+    if (offset & 0b11) != 0 {
+        panic!("Try to modify pci with unaligned offset")
+    }
+
+    with_config_lock(|| {
+        select(address, offset);
+        let value = f(u32::read_from_port(CONFIG_DATA));
+        u32::write_to_port(CONFIG_DATA, value);
+        value
+    })
+}
+
+/// Reads the byte at `offset`
+pub unsafe fn read_u8(address: PciAddress, offset: u16) -> u8 {
+    (read(address, offset & !0b11) >> ((offset % 4) * 8)) as u8
+}
+
+/// Reads the word at `offset`, which must be 2 byte aligned
+pub unsafe fn read_u16(address: PciAddress, offset: u16) -> u16 {
+    if (offset & 0b1) != 0 {
+        panic!("Try to read pci with unaligned offset")
+    }
+
+    (read(address, offset & !0b11) >> ((offset % 4) * 8)) as u16
+}
+
+/// Writes the byte at `offset` leaving the rest of the dword untouched
+pub unsafe fn write_u8(address: PciAddress, offset: u16, value: u8) {
+    let shift = (offset % 4) * 8;
+
+    modify(address, offset & !0b11, |old| {
+        old & !(0xFF << shift) | (value as u32) << shift
+    });
+}
+
+/// Writes the word at `offset`, which must be 2 byte aligned, leaving the rest
+/// of the dword untouched
+pub unsafe fn write_u16(address: PciAddress, offset: u16, value: u16) {
+    if (offset & 0b1) != 0 {
+        panic!("Try to write pci with unaligned offset")
+    }
+
+    let shift = (offset % 4) * 8;
+
+    modify(address, offset & !0b11, |old| {
+        old & !(0xFFFF << shift) | (value as u32) << shift
+    });
+}
+
+/// Returns the command register of the function
+pub fn command(address: PciAddress) -> CommandRegister {
+    let res = unsafe { read(address, COMMAND_OFFSET) };
+
+    CommandRegister::from_bits_truncate(res as u16)
+}
+
+/// Sets (`enable = true`) or clears (`enable = false`) the passed bits of the
+/// command register leaving the others untouched
+///
+/// # Safety
+///
+/// The caller must guarantee that the function is ready to decode the enabled
+/// resources (BARs must be programmed before enabling memory/io space and the
+/// dma buffers must be valid before enabling bus mastering)
+pub unsafe fn set_command_flags(address: PciAddress, flags: CommandRegister, enable: bool) {
+    modify(address, COMMAND_OFFSET, |res| {
+        let mut command = CommandRegister::from_bits_truncate(res as u16);
+
+        command.set(flags, enable);
+
+        // Leave the status half zeroed since writing back it's error bits
+        // would clear them (they are write one to clear)
+        command.bits() as u32
+    });
+}
+
+/// Returns the status register of the function
+pub fn status(address: PciAddress) -> StatusRegister {
+    let res = unsafe { read(address, COMMAND_OFFSET) };
+
+    StatusRegister::from_bits_truncate((res >> 16) as u16)
+}
+
+/// Clears the passed error bits of the status register
+pub fn clear_status(address: PciAddress, flags: StatusRegister) {
+    // The status bits are write one to clear, so write back the current
+    // command register with the bits to clear in the upper half
+    unsafe {
+        modify(address, COMMAND_OFFSET, |res| {
+            (res & 0xFFFF) | (flags.bits() as u32) << 16
+        });
+    }
+}
+
+/// Returns the interrupt line of the function, this is the legacy pic irq
+/// the firmware routed the function to (0xFF means unknown or not connected)
+pub fn interrupt_line(address: PciAddress) -> u8 { unsafe { read_u8(address, INTERRUPT_OFFSET) } }
+
+/// Sets the interrupt line of the function, the register is only used by
+/// software so this doesn't change the routing
+pub fn set_interrupt_line(address: PciAddress, line: u8) {
+    unsafe { write_u8(address, INTERRUPT_OFFSET, line) }
+}
+
+/// Returns the interrupt pin used by the function, 1 through 4 map to INTA#
+/// through INTD# and 0 means the function doesn't use an interrupt pin
+pub fn interrupt_pin(address: PciAddress) -> u8 {
+    unsafe { read_u8(address, INTERRUPT_OFFSET + 1) }
+}
+
+/// Enables memory space decoding and bus mastering, which is what most dma
+/// capable drivers need
+///
+/// # Safety
+///
+/// See [`set_command_flags`]
+pub unsafe fn enable_bus_mastering(address: PciAddress) {
+    set_command_flags(
+        address,
+        CommandRegister::MEMORY_SPACE | CommandRegister::BUS_MASTER,
+        true,
+    )
+}
+
+/// Returns an iterator over the capabilities list of the function yielding
+/// `(id, offset)` pairs
+pub fn capabilities(address: PciAddress) -> impl Iterator<Item = (u8, u16)> {
+    let mut next = if status(address).contains(StatusRegister::CAPABILITIES_LIST) {
+        (unsafe { read_u8(address, CAPABILITIES_POINTER_OFFSET) } & 0xFC) as u16
+    } else {
+        0
+    };
+
+    // A malformed list might loop forever so limit the number of entries to
+    // the maximum that fit in the config space
+    let mut remaining = 48;
+
+    core::iter::from_fn(move || {
+        if next == 0 || remaining == 0 {
+            return None;
+        }
+
+        let offset = next;
+        let header = unsafe { read(address, offset) };
+
+        next = ((header >> 8) & 0xFC) as u16;
+        remaining -= 1;
+
+        Some((header as u8, offset))
+    })
+}
+
+/// Returns the offset of the first capability with the passed `id`
+pub fn find_capability(address: PciAddress, id: u8) -> Option<u16> {
+    capabilities(address).find_map(|(cap, offset)| Some(offset).filter(|_| cap == id))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerState {
+    D0,
+    D1,
+    D2,
+    D3Hot,
+}
+
+impl PowerState {
+    fn from_bits(bits: u32) -> Self {
+        match bits & 0b11 {
+            0 => PowerState::D0,
+            1 => PowerState::D1,
+            2 => PowerState::D2,
+            _ => PowerState::D3Hot,
         }
+    }
+}
+
+/// Returns the current power state of the function or `None` if it doesn't
+/// have the power management capability
+pub fn power_state(address: PciAddress) -> Option<PowerState> {
+    let cap = find_capability(address, CAPABILITY_POWER_MANAGEMENT)?;
+    let pmcsr = unsafe { read(address, cap + 4) };
+
+    Some(PowerState::from_bits(pmcsr))
+}
+
+/// Transitions the function to the passed power state, returns false if the
+/// function doesn't have the power management capability
+///
+/// # Safety
+///
+/// The driver of the function must be prepared for the transition, leaving D0
+/// stops the function from responding to anything but config space accesses
+/// and going from D3hot to D0 might reset the function
+pub unsafe fn set_power_state(address: PciAddress, state: PowerState) -> bool {
+    let cap = match find_capability(address, CAPABILITY_POWER_MANAGEMENT) {
+        Some(cap) => cap,
+        None => return false,
+    };
 
-        let config_address: ConfigAddress = address.into();
+    let bits = match state {
+        PowerState::D0 => 0,
+        PowerState::D1 => 1,
+        PowerState::D2 => 2,
+        PowerState::D3Hot => 3,
+    };
 
-        unsafe { u32::write_to_port(CONFIG_ADDRESS, config_address.0 | (offset as u32) & 0xff) };
+    let mut current = state;
 
-        unsafe { u32::read_from_port(CONFIG_DATA) }
+    modify(address, cap + 4, |pmcsr| {
+        current = PowerState::from_bits(pmcsr);
+
+        // Don't write back the PME status bit since it's write one to clear
+        (pmcsr & !0x8003) | bits
+    });
+
+    if current == state {
+        return true;
+    }
+
+    // The spec requires a 10ms recovery time for transitions from and to
+    // D3hot before the function can be accessed
+    if current == PowerState::D3Hot || state == PowerState::D3Hot {
+        crate::sleep(10);
     }
 
-    read_inner(address, offset)
+    true
 }
 
-pub unsafe fn write(address: pci_types::PciAddress, offset: u16, value: u32) {
-    fn write_inner(address: pci_types::PciAddress, offset: u16, value: u32) {
-        if (offset & 0b11) != 0 {
-            panic!("Try to write pci with unaligned offset")
+/// A copy of the standard header of a function's config space that can be
+/// written back after the function lost it's state (reset or D3 transition)
+#[derive(Debug, Clone)]
+pub struct ConfigSnapshot {
+    address: PciAddress,
+    header: [u32; HEADER_DWORDS],
+}
+
+impl ConfigSnapshot {
+    /// Saves the standard header of the function at `address`
+    pub fn save(address: PciAddress) -> Self {
+        let mut header = [0; HEADER_DWORDS];
+
+        for (i, dword) in header.iter_mut().enumerate() {
+            *dword = unsafe { read(address, i as u16 * 4) };
         }
 
-        let config_address: ConfigAddress = address.into();
+        ConfigSnapshot { address, header }
+    }
+
+    pub fn address(&self) -> PciAddress { self.address }
+
+    /// Writes back the saved header, only the dwords that differ from the
+    /// current values are written.
+    ///
+    /// The registers are restored from the end of the header to the start so
+    /// that the BARs are programmed before the command register enables
+    /// decoding.
+    ///
+    /// # Safety
+    ///
+    /// The function must still be the same device that was saved and the
+    /// resources described by the saved BARs must still be valid
+    pub unsafe fn restore(&self) {
+        // The first dword (vendor and device ids) is read only
+        for i in (1..HEADER_DWORDS).rev() {
+            let offset = i as u16 * 4;
+            let mut value = self.header[i];
 
-        unsafe { u32::write_to_port(CONFIG_ADDRESS, config_address.0 | (offset as u32) & 0xff) };
+            // Leave the status half zeroed since it's bits are write one to
+            // clear
+            if offset == COMMAND_OFFSET {
+                value &= 0xFFFF;
+            }
+
+            if read(self.address, offset) != value {
+                log::trace!(
+                    "Restoring {} config offset {:#X}: {:#X}",
+                    self.address,
+                    offset,
+                    value
+                );
 
-        unsafe { u32::write_to_port(CONFIG_DATA, value) }
+                write(self.address, offset, value)
+            }
+        }
     }
+}
+
+/// Performs a function level reset if the function supports it, returns false
+/// otherwise or if the function doesn't come back from the reset.
+///
+/// The reset clears the config space so callers will want to take a
+/// [`ConfigSnapshot`] before and restore it afterwards.
+///
+/// # Safety
+///
+/// The function must be quiesced by it's driver (no dma in flight) since the
+/// reset aborts all outstanding transactions
+pub unsafe fn function_level_reset(address: PciAddress) -> bool {
+    const FLR_CAPABLE: u32 = 1 << 28;
+    const INITIATE_FLR: u32 = 1 << 15;
+
+    let cap = match find_capability(address, CAPABILITY_PCI_EXPRESS) {
+        Some(cap) => cap,
+        None => return false,
+    };
+
+    if read(address, cap + 0x04) & FLR_CAPABLE == 0 {
+        return false;
+    }
+
+    // Only write the device control half, the status half is write one to
+    // clear
+    modify(address, cap + 0x08, |control| {
+        (control & 0xFFFF) | INITIATE_FLR
+    });
+
+    // The spec gives the function 100ms to complete the reset
+    crate::sleep(100);
 
-    write_inner(address, offset, value)
+    // The function might still be initializing, in which case it doesn't
+    // respond to config reads yet
+    let ready = time::wait_until(
+        || read(address, 0) & 0xFFFF != 0xFFFF,
+        FLR_READY_TIMEOUT,
+        FLR_POLL_INTERVAL,
+    );
+
+    if ready.is_err() {
+        log::warn!("{} didn't come back from the function level reset", address);
+    }
+
+    ready.is_ok()
 }
 
 pub struct ConfigSpaceMechanism1;
@@ -55,14 +465,38 @@ impl ConfigRegionAccess for ConfigSpaceMechanism1 {
     }
 }
 
+/// The value written to `CONFIG_ADDRESS` to select a register
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 struct ConfigAddress(u32);
 
-impl From<PciAddress> for ConfigAddress {
-    fn from(address: PciAddress) -> Self {
+impl ConfigAddress {
+    /// Encodes the address of the dword at `offset` in the config space of the
+    /// function at `address`
+    ///
+    /// The layout of the register is:
+    /// - bits 0-1: always 0 (accesses are dword aligned)
+    /// - bits 2-7: the dword index
+    /// - bits 8-10: the function number
+    /// - bits 11-15: the device number
+    /// - bits 16-23: the bus number
+    /// - bits 24-30: reserved
+    /// - bit 31: enable bit
+    fn new(address: PciAddress, offset: u16) -> Self {
+        assert_eq!(
+            address.segment(),
+            0,
+            "Mechanism 1 can only access the first segment"
+        );
+        assert!(
+            offset < CONFIG_SPACE_SIZE,
+            "Mechanism 1 can only access the first 256 bytes of the config space"
+        );
+
         let mut result = 0;
 
-        result |= (address.function() as u32) << 8;
-        result |= (address.device() as u32) << 11;
+        result |= offset as u32 & 0xFC;
+        result |= (address.function() as u32 & 0b111) << 8;
+        result |= (address.device() as u32 & 0b11111) << 11;
         result |= (address.bus() as u32) << 16;
         result |= 1 << 31;
 
@@ -87,3 +521,37 @@ pub fn brute_force_find(access: &impl ConfigRegionAccess) -> Vec<(PciAddress, Pc
 
     results
 }
+
+#[test_case]
+fn config_address_encoding() {
+    let address = PciAddress::new(0, 0x12, 0x1F, 0x7);
+
+    assert_eq!(ConfigAddress::new(address, 0x00).0, 0x8012_FF00);
+    assert_eq!(ConfigAddress::new(address, 0x3C).0, 0x8012_FF3C);
+    assert_eq!(ConfigAddress::new(address, 0xFC).0, 0x8012_FFFC);
+}
+
+#[test_case]
+fn qemu_host_bridge_config_space() {
+    // On the q35 machine the host bridge is always at 00:00.0
+    let address = PciAddress::new(0, 0, 0, 0);
+    let id = unsafe { read(address, 0) };
+
+    assert_eq!(id, 0x29C0_8086);
+    assert_eq!(unsafe { read_u16(address, 0) }, 0x8086);
+    assert_eq!(unsafe { read_u16(address, 2) }, 0x29C0);
+    assert_eq!(unsafe { read_u8(address, 0x0B) }, 0x06);
+
+    // Every dword of the config space must be reachable and the sized reads
+    // must agree with the full ones
+    for offset in (0..CONFIG_SPACE_SIZE).step_by(4) {
+        let dword = unsafe { read(address, offset) };
+
+        for byte in 0..4 {
+            assert_eq!(
+                unsafe { read_u8(address, offset + byte) },
+                (dword >> (byte * 8)) as u8
+            );
+        }
+    }
+}
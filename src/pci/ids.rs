@@ -0,0 +1,166 @@
+//! A small subset of the [pci.ids](https://pci-ids.ucw.cz/) database covering
+//! the vendors and devices commonly found in virtual machines and the class
+//! codes defined by the pci spec.
+//!
+//! The tables are sorted so that lookups can binary search them.
+
+const VENDORS: &[(u16, &str)] = &[
+    (0x1002, "Advanced Micro Devices, Inc. [AMD/ATI]"),
+    (0x1022, "Advanced Micro Devices, Inc. [AMD]"),
+    (0x102B, "Matrox Electronics Systems Ltd."),
+    (0x106B, "Apple Inc."),
+    (0x10DE, "NVIDIA Corporation"),
+    (0x10EC, "Realtek Semiconductor Co., Ltd."),
+    (0x1234, "QEMU"),
+    (0x1274, "Ensoniq"),
+    (0x14E4, "Broadcom Inc."),
+    (0x15AD, "VMware"),
+    (0x1AF4, "Red Hat, Inc. (virtio)"),
+    (0x1B36, "Red Hat, Inc. (QEMU)"),
+    (0x8086, "Intel Corporation"),
+    (0x80EE, "InnoTek Systemberatung GmbH (VirtualBox)"),
+];
+
+const DEVICES: &[(u16, u16, &str)] = &[
+    (0x1022, 0x2000, "79c970 [PCnet32 LANCE]"),
+    (0x10EC, 0x8029, "RTL-8029(AS)"),
+    (0x10EC, 0x8139, "RTL-8139 PCI Fast Ethernet Adapter"),
+    (0x1234, 0x1111, "QEMU Standard VGA"),
+    (0x1274, 0x5000, "ES1370 [AudioPCI]"),
+    (0x15AD, 0x0405, "SVGA II Adapter"),
+    (0x15AD, 0x07B0, "VMXNET3 Ethernet Controller"),
+    (0x1AF4, 0x1000, "Virtio network device"),
+    (0x1AF4, 0x1001, "Virtio block device"),
+    (0x1AF4, 0x1002, "Virtio memory balloon"),
+    (0x1AF4, 0x1003, "Virtio console"),
+    (0x1AF4, 0x1004, "Virtio SCSI"),
+    (0x1AF4, 0x1005, "Virtio RNG"),
+    (0x1AF4, 0x1041, "Virtio 1.0 network device"),
+    (0x1AF4, 0x1042, "Virtio 1.0 block device"),
+    (0x1AF4, 0x1050, "Virtio 1.0 GPU"),
+    (0x1B36, 0x0001, "QEMU PCI-PCI bridge"),
+    (0x1B36, 0x0008, "QEMU PCIe Host bridge"),
+    (0x1B36, 0x000C, "QEMU PCIe Root port"),
+    (0x1B36, 0x000D, "QEMU XHCI Host Controller"),
+    (0x1B36, 0x0100, "QXL paravirtual graphic card"),
+    (0x8086, 0x100E, "82540EM Gigabit Ethernet Controller"),
+    (0x8086, 0x10D3, "82574L Gigabit Network Connection"),
+    (0x8086, 0x1237, "440FX - 82441FX PMC [Natoma]"),
+    (0x8086, 0x2415, "82801AA AC'97 Audio Controller"),
+    (0x8086, 0x24CD, "82801DB (ICH4) USB2 EHCI Controller"),
+    (0x8086, 0x2668, "82801FB (ICH6) HD Audio Controller"),
+    (0x8086, 0x2918, "82801IB (ICH9) LPC Interface Controller"),
+    (0x8086, 0x2922, "82801IR (ICH9R) SATA Controller [AHCI]"),
+    (0x8086, 0x2930, "82801I (ICH9) SMBus Controller"),
+    (0x8086, 0x2934, "82801I (ICH9) USB UHCI Controller #1"),
+    (0x8086, 0x293A, "82801I (ICH9) USB2 EHCI Controller #1"),
+    (0x8086, 0x293E, "82801I (ICH9) HD Audio Controller"),
+    (0x8086, 0x29C0, "82G33/G31/P35/P31 Express DRAM Controller"),
+    (0x8086, 0x7000, "82371SB PIIX3 ISA [Natoma/Triton II]"),
+    (0x8086, 0x7010, "82371SB PIIX3 IDE [Natoma/Triton II]"),
+    (0x8086, 0x7020, "82371SB PIIX3 USB [Natoma/Triton II]"),
+    (0x8086, 0x7113, "82371AB/EB/MB PIIX4 ACPI"),
+    (0x80EE, 0xBEEF, "VirtualBox Graphics Adapter"),
+    (0x80EE, 0xCAFE, "VirtualBox Guest Service"),
+];
+
+const CLASSES: &[(u8, &str)] = &[
+    (0x00, "Unclassified device"),
+    (0x01, "Mass storage controller"),
+    (0x02, "Network controller"),
+    (0x03, "Display controller"),
+    (0x04, "Multimedia controller"),
+    (0x05, "Memory controller"),
+    (0x06, "Bridge"),
+    (0x07, "Communication controller"),
+    (0x08, "Generic system peripheral"),
+    (0x09, "Input device controller"),
+    (0x0A, "Docking station"),
+    (0x0B, "Processor"),
+    (0x0C, "Serial bus controller"),
+    (0x0D, "Wireless controller"),
+    (0x0E, "Intelligent controller"),
+    (0x0F, "Satellite communications controller"),
+    (0x10, "Encryption controller"),
+    (0x11, "Signal processing controller"),
+    (0x12, "Processing accelerators"),
+    (0x13, "Non-Essential Instrumentation"),
+    (0x40, "Coprocessor"),
+    (0xFF, "Unassigned class"),
+];
+
+const SUBCLASSES: &[((u8, u8), &str)] = &[
+    ((0x01, 0x00), "SCSI storage controller"),
+    ((0x01, 0x01), "IDE interface"),
+    ((0x01, 0x02), "Floppy disk controller"),
+    ((0x01, 0x04), "RAID bus controller"),
+    ((0x01, 0x05), "ATA controller"),
+    ((0x01, 0x06), "SATA controller"),
+    ((0x01, 0x07), "Serial Attached SCSI controller"),
+    ((0x01, 0x08), "Non-Volatile memory controller"),
+    ((0x02, 0x00), "Ethernet controller"),
+    ((0x02, 0x80), "Network controller"),
+    ((0x03, 0x00), "VGA compatible controller"),
+    ((0x03, 0x01), "XGA compatible controller"),
+    ((0x03, 0x02), "3D controller"),
+    ((0x04, 0x00), "Multimedia video controller"),
+    ((0x04, 0x01), "Multimedia audio controller"),
+    ((0x04, 0x03), "Audio device"),
+    ((0x05, 0x00), "RAM memory"),
+    ((0x06, 0x00), "Host bridge"),
+    ((0x06, 0x01), "ISA bridge"),
+    ((0x06, 0x04), "PCI bridge"),
+    ((0x06, 0x80), "Bridge"),
+    ((0x07, 0x00), "Serial controller"),
+    ((0x07, 0x01), "Parallel controller"),
+    ((0x08, 0x00), "PIC"),
+    ((0x08, 0x01), "DMA controller"),
+    ((0x08, 0x02), "Timer"),
+    ((0x08, 0x03), "RTC"),
+    ((0x08, 0x80), "System peripheral"),
+    ((0x09, 0x00), "Keyboard controller"),
+    ((0x09, 0x02), "Mouse controller"),
+    ((0x0C, 0x03), "USB controller"),
+    ((0x0C, 0x05), "SMBus"),
+];
+
+/// Returns the name of the vendor with the passed id
+pub fn vendor_name(vendor: u16) -> Option<&'static str> {
+    VENDORS
+        .binary_search_by_key(&vendor, |(id, _)| *id)
+        .ok()
+        .map(|idx| VENDORS[idx].1)
+}
+
+/// Returns the name of the device with the passed vendor and device ids
+pub fn device_name(vendor: u16, device: u16) -> Option<&'static str> {
+    DEVICES
+        .binary_search_by_key(&(vendor, device), |(vendor, device, _)| (*vendor, *device))
+        .ok()
+        .map(|idx| DEVICES[idx].2)
+}
+
+/// Returns the most specific name known for the passed class and subclass
+pub fn class_name(class: u8, subclass: u8) -> &'static str {
+    SUBCLASSES
+        .binary_search_by_key(&(class, subclass), |(id, _)| *id)
+        .ok()
+        .map(|idx| SUBCLASSES[idx].1)
+        .or_else(|| {
+            CLASSES
+                .binary_search_by_key(&class, |(id, _)| *id)
+                .ok()
+                .map(|idx| CLASSES[idx].1)
+        })
+        .unwrap_or("Unknown class")
+}
+
+#[test_case]
+fn tables_are_sorted() {
+    assert!(VENDORS.windows(2).all(|w| w[0].0 < w[1].0));
+    assert!(DEVICES
+        .windows(2)
+        .all(|w| (w[0].0, w[0].1) < (w[1].0, w[1].1)));
+    assert!(CLASSES.windows(2).all(|w| w[0].0 < w[1].0));
+    assert!(SUBCLASSES.windows(2).all(|w| w[0].0 < w[1].0));
+}
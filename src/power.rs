@@ -0,0 +1,111 @@
+//! System power management
+//!
+//! All the power transitions go through here so that the subsystems and the
+//! drivers get a chance to prepare, the acpi object installed with
+//! [`acpi::install`] is used to enter the sleep states.
+use crate::{
+    acpi::{self, SleepState},
+    device,
+    drivers::ps2,
+};
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+use x86_64::{instructions::tables, structures::DescriptorTablePointer};
+
+/// Called before shutting down or rebooting, in registration order
+static SHUTDOWN_HOOKS: Mutex<Vec<fn()>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerError {
+    /// Acpi isn't available
+    NoAcpi,
+    /// The transition isn't supported
+    Unsupported,
+    /// The firmware or a driver refused the transition
+    Failed,
+}
+
+impl fmt::Display for PowerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PowerError::NoAcpi => write!(f, "acpi isn't available"),
+            PowerError::Unsupported => write!(f, "not supported"),
+            PowerError::Failed => write!(f, "the transition failed"),
+        }
+    }
+}
+
+/// Registers a function to run before the system is shutdown or rebooted, for
+/// example to flush caches to disk
+pub fn on_shutdown(hook: fn()) { SHUTDOWN_HOOKS.lock().push(hook) }
+
+/// Turns the system off, if that fails the cpu is halted
+pub fn shutdown() -> ! {
+    log::info!("Shutting down");
+
+    prepare_shutdown();
+
+    // The firmware might take a moment to turn the power off, or never do it
+    // if the sleep type it gave is wrong
+    match enter(SleepState::S5) {
+        Ok(()) => log::warn!("The system is still running after shutting down"),
+        Err(e) => log::error!("Failed to shutdown: {}", e),
+    }
+
+    crate::hlt_loop()
+}
+
+/// Resets the system
+pub fn reboot() -> ! {
+    log::info!("Rebooting");
+
+    prepare_shutdown();
+
+    if let Err(e) = ps2::reset_cpu() {
+        log::warn!("Failed to reset through the PS/2 controller: {}", e);
+    }
+
+    // As a last resort cause a triple fault
+    unsafe {
+        tables::lidt(&DescriptorTablePointer { limit: 0, base: 0 });
+    }
+
+    x86_64::instructions::interrupts::int3();
+
+    crate::hlt_loop()
+}
+
+/// Suspends the system to ram
+///
+/// The cpu state is lost in S3 and the firmware resumes through the waking
+/// vector of the FACS, there's no resume trampoline to set it to yet so this
+/// isn't supported
+pub fn suspend() -> Result<(), PowerError> { Err(PowerError::Unsupported) }
+
+/// Suspends the system to disk
+///
+/// Saving the memory image needs a block layer so this isn't supported yet
+pub fn hibernate() -> Result<(), PowerError> { Err(PowerError::Unsupported) }
+
+/// Runs the shutdown hooks and stops the drivers
+fn prepare_shutdown() {
+    for hook in SHUTDOWN_HOOKS.lock().iter() {
+        hook()
+    }
+
+    device::shutdown_all();
+}
+
+fn enter(state: SleepState) -> Result<(), PowerError> {
+    acpi::with_acpi(|acpi| {
+        if !acpi.supports_sleep_state(state) {
+            Err(PowerError::Unsupported)
+        } else if acpi.set_sleep_state(state) {
+            Ok(())
+        } else {
+            Err(PowerError::Failed)
+        }
+    })
+    .unwrap_or(Err(PowerError::NoAcpi))
+}
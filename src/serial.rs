@@ -1,28 +1,74 @@
 use lazy_static::lazy_static;
 use spin::Mutex;
 use uart_16550::SerialPort;
+use x86_64::instructions::port::{PortRead, PortWrite};
+
+/// The io port of the first serial port
+const COM1: u16 = 0x3F8;
+/// Offset of the line status register
+const LINE_STATUS: u16 = 5;
+/// Line status bit set when the transmitter can take another byte
+const OUTPUT_EMPTY: u8 = 1 << 5;
 
 lazy_static! {
     pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+        let mut serial_port = unsafe { SerialPort::new(COM1) };
         serial_port.init();
         Mutex::new(serial_port)
     };
 }
 
+/// Prints through `SERIAL1`, if it's already locked this print interrupted
+/// another one and the port is written directly instead of deadlocking, like
+/// the vga buffer printing does
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
     use x86_64::instructions::interrupts;
 
+    interrupts::without_interrupts(|| match SERIAL1.try_lock() {
+        Some(mut serial) => serial.write_fmt(args).expect("Printing to serial failed"),
+        None => {
+            // The port was already initialized by the holder of the lock
+            let _ = unsafe { SerialPort::new(COM1) }.write_fmt(args);
+        },
+    });
+}
+
+/// Writes `bytes` to the serial port unchanged, `SerialPort::send` would
+/// expand the backspace and delete characters
+///
+/// Like [`_print`] it doesn't wait for the lock, it's only held so prints
+/// from elsewhere don't land in the middle of `bytes`.
+pub fn write_bytes(bytes: &[u8]) {
+    use x86_64::instructions::interrupts;
+
     interrupts::without_interrupts(|| {
-        SERIAL1
-            .lock()
-            .write_fmt(args)
-            .expect("Printing to serial failed");
+        let _serial = SERIAL1.try_lock();
+
+        for &byte in bytes {
+            unsafe {
+                while u8::read_from_port(COM1 + LINE_STATUS) & OUTPUT_EMPTY == 0 {
+                    core::hint::spin_loop();
+                }
+
+                u8::write_to_port(COM1, byte);
+            }
+        }
     });
 }
 
+/// Releases the lock of `SERIAL1` whoever holds it, for the panic handlers
+///
+/// # Safety
+///
+/// The code holding the lock must never resume.
+pub unsafe fn force_unlock() {
+    if SERIAL1.is_locked() {
+        SERIAL1.force_unlock()
+    }
+}
+
 /// Prints to the host through the serial interface.
 #[macro_export]
 macro_rules! serial_print {
@@ -0,0 +1,97 @@
+//! Kernel taint flags
+//!
+//! Abnormal events that the kernel recovered from are recorded here so that
+//! reports from a long running session show that the state might not be
+//! trustworthy anymore, the flags are never cleared.
+use bitflags::bitflags;
+use core::{
+    fmt,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+static TAINT: AtomicU32 = AtomicU32::new(0);
+
+bitflags! {
+    pub struct Taint: u32 {
+        /// A machine check error was reported
+        const MACHINE_CHECK = 1 << 0;
+        /// A non maskable interrupt with no known source was received
+        const UNKNOWN_NMI = 1 << 1;
+        /// The firmware tables or aml misbehaved and were worked around
+        const FIRMWARE_BUG = 1 << 2;
+    }
+}
+
+impl Taint {
+    const LETTERS: [(Taint, char); 3] = [
+        (Taint::MACHINE_CHECK, 'M'),
+        (Taint::UNKNOWN_NMI, 'N'),
+        (Taint::FIRMWARE_BUG, 'F'),
+    ];
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Taint::MACHINE_CHECK => "machine check",
+            Taint::UNKNOWN_NMI => "unknown nmi",
+            Taint::FIRMWARE_BUG => "firmware bug",
+            _ => "multiple",
+        }
+    }
+}
+
+/// Prints the flags as one letter each, `-` for the ones not set
+impl fmt::Display for Taint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (flag, letter) in Taint::LETTERS.iter() {
+            write!(f, "{}", if self.contains(*flag) { *letter } else { '-' })?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Records that `flag` happened, logging it the first time
+pub fn add(flag: Taint) {
+    let previous = Taint::from_bits_truncate(TAINT.fetch_or(flag.bits(), Ordering::Relaxed));
+
+    if !previous.contains(flag) {
+        log::warn!("Kernel tainted: {}", flag.description());
+    }
+}
+
+pub fn get() -> Taint { Taint::from_bits_truncate(TAINT.load(Ordering::Relaxed)) }
+
+pub fn is_tainted() -> bool { !get().is_empty() }
+
+/// The health status of the kernel, shown in panic reports
+pub fn status() -> impl fmt::Display {
+    struct Status(Taint);
+
+    impl fmt::Display for Status {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            if self.0.is_empty() {
+                return write!(f, "Not tainted");
+            }
+
+            write!(f, "Tainted: {} (", self.0)?;
+
+            let mut first = true;
+
+            for (flag, _) in Taint::LETTERS
+                .iter()
+                .filter(|(flag, _)| self.0.contains(*flag))
+            {
+                if !first {
+                    write!(f, ", ")?;
+                }
+
+                write!(f, "{}", flag.description())?;
+                first = false;
+            }
+
+            write!(f, ")")
+        }
+    }
+
+    Status(get())
+}
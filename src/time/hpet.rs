@@ -0,0 +1,110 @@
+//! The high precision event timer, used as a clock source when the tsc isn't
+//! invariant
+use super::ClockSource;
+use crate::memory::{map_mmio, CacheMode};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::PhysAddr;
+
+/// The size of the register block
+const REGISTERS_SIZE: usize = 0x400;
+
+const CAPABILITIES: u64 = 0x000;
+const CONFIGURATION: u64 = 0x010;
+const MAIN_COUNTER: u64 = 0x0F0;
+
+const CAPABILITIES_64_BIT: u64 = 1 << 13;
+const CONFIGURATION_ENABLE: u64 = 1;
+
+const FEMTOS_PER_SEC: u64 = 1_000_000_000_000_000;
+/// The spec limits the counter period to 100ns
+const MAX_PERIOD_FEMTOS: u64 = 100_000_000;
+
+pub static HPET: Hpet = Hpet {
+    base_address: AtomicU64::new(0),
+    frequency: AtomicU64::new(0),
+    wide: AtomicBool::new(false),
+};
+
+pub struct Hpet {
+    base_address: AtomicU64,
+    frequency: AtomicU64,
+    wide: AtomicBool,
+}
+
+impl Hpet {
+    unsafe fn read_reg(&self, reg: u64) -> u64 {
+        let ptr = (self.base_address.load(Ordering::Relaxed) + reg) as *const u64;
+        ptr.read_volatile()
+    }
+
+    unsafe fn write_reg(&self, reg: u64, val: u64) {
+        let ptr = (self.base_address.load(Ordering::Relaxed) + reg) as *mut u64;
+        ptr.write_volatile(val)
+    }
+}
+
+impl ClockSource for Hpet {
+    fn name(&self) -> &'static str { "hpet" }
+
+    fn rating(&self) -> u32 { 250 }
+
+    fn frequency(&self) -> u64 { self.frequency.load(Ordering::Relaxed) }
+
+    fn mask(&self) -> u64 {
+        if self.wide.load(Ordering::Relaxed) {
+            u64::MAX
+        } else {
+            u32::MAX as u64
+        }
+    }
+
+    fn read(&self) -> u64 {
+        if self.wide.load(Ordering::Relaxed) {
+            unsafe { self.read_reg(MAIN_COUNTER) }
+        } else {
+            let ptr = (self.base_address.load(Ordering::Relaxed) + MAIN_COUNTER) as *const u32;
+            unsafe { ptr.read_volatile() as u64 }
+        }
+    }
+}
+
+/// Maps, enables and registers the hpet at `base_address`
+///
+/// # Safety
+///
+/// `base_address` must be the address of the hpet registers as reported by
+/// the acpi tables
+pub unsafe fn init(base_address: u64) {
+    let registers = match map_mmio(
+        PhysAddr::new(base_address),
+        REGISTERS_SIZE,
+        CacheMode::Uncached,
+    ) {
+        Ok(region) => region.leak(),
+        Err(e) => {
+            log::warn!("Failed to map the HPET: {}", e);
+            return;
+        },
+    };
+
+    HPET.base_address
+        .store(registers.as_u64(), Ordering::Relaxed);
+
+    let capabilities = HPET.read_reg(CAPABILITIES);
+    let period = capabilities >> 32;
+
+    if period == 0 || period > MAX_PERIOD_FEMTOS {
+        log::warn!("HPET reports an invalid period of {} fs", period);
+        return;
+    }
+
+    HPET.frequency
+        .store(FEMTOS_PER_SEC / period, Ordering::Relaxed);
+    HPET.wide
+        .store(capabilities & CAPABILITIES_64_BIT != 0, Ordering::Relaxed);
+
+    let configuration = HPET.read_reg(CONFIGURATION);
+    HPET.write_reg(CONFIGURATION, configuration | CONFIGURATION_ENABLE);
+
+    super::register_clock_source(&HPET);
+}
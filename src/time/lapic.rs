@@ -0,0 +1,116 @@
+//! The local apic timer, a per cpu timer with good resolution that supports
+//! both periodic and one shot modes
+use super::{ClockEvent, ClockEventFeatures};
+use crate::interrupts::{read_apic_reg, write_apic_reg, InterruptIndex};
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+const LVT_TIMER: usize = 0x320;
+const INITIAL_COUNT: usize = 0x380;
+const CURRENT_COUNT: usize = 0x390;
+const DIVIDE_CONFIG: usize = 0x3E0;
+
+/// Divide the bus clock by 16
+const DIVIDE_BY_16: u32 = 0b0011;
+
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_PERIODIC: u32 = 1 << 17;
+
+/// How long to count for when calibrating
+const CALIBRATION_TIME: Duration = Duration::from_millis(10);
+
+pub static LAPIC_TIMER: LapicTimer = LapicTimer {
+    base_address: AtomicU64::new(0),
+    frequency: AtomicU64::new(0),
+};
+
+pub struct LapicTimer {
+    base_address: AtomicU64,
+    frequency: AtomicU64,
+}
+
+impl LapicTimer {
+    fn write(&self, reg: usize, val: u32) {
+        unsafe { write_apic_reg(self.base_address.load(Ordering::Relaxed), reg, val) }
+    }
+
+    fn read(&self, reg: usize) -> u32 {
+        unsafe { read_apic_reg(self.base_address.load(Ordering::Relaxed), reg) }
+    }
+
+    fn cycles(&self, duration: Duration) -> u32 {
+        let cycles = super::nanos_to_cycles(
+            duration.as_nanos() as u64,
+            self.frequency.load(Ordering::Relaxed),
+        );
+
+        // An initial count of 0 stops the timer
+        cycles.max(1).min(u32::MAX as u64) as u32
+    }
+}
+
+impl ClockEvent for LapicTimer {
+    fn name(&self) -> &'static str { "lapic" }
+
+    fn rating(&self) -> u32 { 200 }
+
+    fn features(&self) -> ClockEventFeatures {
+        ClockEventFeatures::PERIODIC | ClockEventFeatures::ONE_SHOT
+    }
+
+    fn max_delta(&self) -> Duration {
+        Duration::from_nanos(super::cycles_to_nanos(
+            u32::MAX as u64,
+            self.frequency.load(Ordering::Relaxed),
+        ))
+    }
+
+    fn set_periodic(&self, period: Duration) {
+        self.write(DIVIDE_CONFIG, DIVIDE_BY_16);
+        self.write(LVT_TIMER, InterruptIndex::LapicTimer as u32 | LVT_PERIODIC);
+        self.write(INITIAL_COUNT, self.cycles(period));
+    }
+
+    fn set_oneshot(&self, delta: Duration) {
+        self.write(DIVIDE_CONFIG, DIVIDE_BY_16);
+        self.write(LVT_TIMER, InterruptIndex::LapicTimer as u32);
+        self.write(INITIAL_COUNT, self.cycles(delta));
+    }
+
+    fn shutdown(&self) {
+        self.write(LVT_TIMER, LVT_MASKED);
+        self.write(INITIAL_COUNT, 0);
+    }
+}
+
+/// Calibrates the local apic timer against the current clock source and
+/// registers it
+///
+/// # Safety
+///
+/// The registers of the local apic must be mapped at `base_address`
+pub unsafe fn init(base_address: u64) {
+    LAPIC_TIMER
+        .base_address
+        .store(base_address, Ordering::Relaxed);
+
+    LAPIC_TIMER.write(DIVIDE_CONFIG, DIVIDE_BY_16);
+    LAPIC_TIMER.write(LVT_TIMER, LVT_MASKED);
+    LAPIC_TIMER.write(INITIAL_COUNT, u32::MAX);
+
+    super::busy_wait(CALIBRATION_TIME);
+
+    let counted = u32::MAX - LAPIC_TIMER.read(CURRENT_COUNT);
+    LAPIC_TIMER.write(INITIAL_COUNT, 0);
+
+    let frequency =
+        counted as u64 * super::NANOS_PER_SEC as u64 / CALIBRATION_TIME.as_nanos() as u64;
+
+    log::info!("LAPIC timer frequency: {} Hz", frequency);
+
+    LAPIC_TIMER.frequency.store(frequency, Ordering::Relaxed);
+
+    super::register_clock_event(&LAPIC_TIMER);
+}
@@ -0,0 +1,492 @@
+//! Timekeeping
+//!
+//! The hardware timers are split in two roles:
+//! - [`ClockSource`]s are free running counters used to read the monotonic
+//!   time.
+//! - [`ClockEvent`]s are devices that can be programmed to raise an interrupt
+//!   after some time.
+//!
+//! Drivers register the devices they find with a rating and the best one of
+//! each role is used, so the rest of the kernel doesn't need to know which
+//! hardware exists.
+//!
+//! When the clock event device runs in one shot mode the tick is stopped while
+//! the cpu is idle, the device is only programmed for the next timer and the
+//! skipped ticks are accounted when the cpu wakes up. This can be disabled at
+//! build time by setting `NOHZ` to `off`.
+use crate::interrupts::irqoff;
+use bitflags::bitflags;
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use spin::Mutex;
+
+pub mod hpet;
+pub mod lapic;
+mod pit;
+pub mod pm_timer;
+pub mod rtc;
+pub mod timer;
+pub mod tsc;
+
+/// The period of the timer interrupt
+pub const TICK_PERIOD: Duration = Duration::from_millis(1);
+
+/// How many ticks between rebasing the clock, this must be less than the
+/// time it takes for the counter with the smallest mask to wrap around
+const REBASE_TICKS: u64 = 1000;
+
+/// The longest the tick is stopped for, short enough for the clock to be
+/// rebased before the counter wraps around
+const MAX_IDLE_NANOS: u64 = TICK_PERIOD.as_nanos() as u64 * REBASE_TICKS / 2;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// A free running counter
+pub trait ClockSource: Sync {
+    fn name(&self) -> &'static str;
+
+    /// How good the source is, the highest rated source is used
+    fn rating(&self) -> u32;
+
+    /// The frequency of the counter in Hz
+    fn frequency(&self) -> u64;
+
+    /// Mask of the bits implemented by the counter, reads must be wrapped to
+    /// this
+    fn mask(&self) -> u64 { u64::MAX }
+
+    /// Whether the counter is incremented by the timer tick, with such a source
+    /// the tick can't be emulated with one shot events
+    fn tick_driven(&self) -> bool { false }
+
+    /// Reads the counter
+    fn read(&self) -> u64;
+}
+
+bitflags! {
+    pub struct ClockEventFeatures: u8 {
+        const PERIODIC = 1;
+        const ONE_SHOT = 1 << 1;
+    }
+}
+
+/// A device that can be programmed to raise interrupts
+///
+/// The interrupt handler of the device must call [`handle_event`]
+pub trait ClockEvent: Sync {
+    fn name(&self) -> &'static str;
+
+    /// How good the device is, the highest rated device is used
+    fn rating(&self) -> u32;
+
+    fn features(&self) -> ClockEventFeatures;
+
+    /// The longest delay supported by [`ClockEvent::set_oneshot`]
+    fn max_delta(&self) -> Duration;
+
+    /// Raises an interrupt every `period`
+    fn set_periodic(&self, period: Duration);
+
+    /// Raises a single interrupt after `delta`, deltas bigger than
+    /// [`ClockEvent::max_delta`] are clamped
+    fn set_oneshot(&self, delta: Duration);
+
+    /// Stops the device from raising interrupts
+    fn shutdown(&self);
+}
+
+/// The clock source in use and the point from where it's counting
+struct Clock {
+    source: &'static dyn ClockSource,
+    base_cycles: u64,
+    base_nanos: u64,
+}
+
+impl Clock {
+    fn nanos(&self) -> u64 {
+        let cycles = self.source.read().wrapping_sub(self.base_cycles) & self.source.mask();
+
+        self.base_nanos + cycles_to_nanos(cycles, self.source.frequency())
+    }
+
+    /// Moves the base to the current time so the elapsed cycles don't wrap
+    fn rebase(&mut self) {
+        let cycles = self.source.read();
+        let elapsed = cycles.wrapping_sub(self.base_cycles) & self.source.mask();
+
+        self.base_nanos += cycles_to_nanos(elapsed, self.source.frequency());
+        self.base_cycles = cycles;
+    }
+}
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+static CLOCK: Mutex<Option<Clock>> = Mutex::new(None);
+
+static EVENT: Mutex<Option<&'static dyn ClockEvent>> = Mutex::new(None);
+
+/// How the tick is generated
+struct TickState {
+    /// The clock event device runs in one shot mode and the tick is emulated
+    oneshot: bool,
+    /// When the next tick is due in one shot mode
+    next_tick: u64,
+    /// The tick is stopped because the cpu is idle
+    stopped: bool,
+}
+
+impl TickState {
+    /// When the clock event device must raise the next interrupt
+    fn next_wakeup(&self, now: u64) -> u64 {
+        let tick = if !self.oneshot {
+            // The next periodic tick is at most a period away
+            now + TICK_PERIOD.as_nanos() as u64
+        } else if self.stopped {
+            now + MAX_IDLE_NANOS
+        } else {
+            self.next_tick
+        };
+
+        timer::next_deadline().map_or(tick, |deadline| deadline.min(tick))
+    }
+
+    /// Accounts the ticks that are due by `now`
+    fn catch_up(&mut self, now: u64) {
+        if now < self.next_tick {
+            return;
+        }
+
+        let period = TICK_PERIOD.as_nanos() as u64;
+        let missed = (now - self.next_tick) / period + 1;
+
+        self.next_tick += missed * period;
+
+        let ticks = TICKS.fetch_add(missed, Ordering::Relaxed);
+
+        if (ticks + missed) / REBASE_TICKS != ticks / REBASE_TICKS {
+            rebase_clock()
+        }
+    }
+}
+
+static TICK_STATE: Mutex<TickState> = Mutex::new(TickState {
+    oneshot: false,
+    next_tick: 0,
+    stopped: false,
+});
+
+/// Registers the timers that are always available (the pit and the tick
+/// counter) and calibrates the tsc
+///
+/// Interrupts must be enabled since the calibration relies on the timer
+/// interrupt
+pub fn init() {
+    register_clock_source(&pit::TICK_SOURCE);
+    register_clock_event(&pit::PIT);
+
+    tsc::init();
+}
+
+/// Registers a clock source, it's used from now on if it's better than the
+/// current one
+pub fn register_clock_source(source: &'static dyn ClockSource) {
+    log::info!(
+        "Registering clock source {} ({} Hz, rating {})",
+        source.name(),
+        source.frequency(),
+        source.rating()
+    );
+
+    irqoff::without_interrupts(|| {
+        let mut clock = CLOCK.lock();
+
+        if let Some(ref current) = *clock {
+            if current.source.rating() >= source.rating() {
+                return;
+            }
+        }
+
+        // Keep counting from the current time so that the switch can't make
+        // the time go backwards
+        let base_nanos = clock.as_ref().map_or(0, Clock::nanos);
+
+        *clock = Some(Clock {
+            source,
+            base_cycles: source.read(),
+            base_nanos,
+        });
+    });
+
+    update_mode();
+}
+
+/// Registers a clock event device, if it's better than the current one the
+/// current one is shutdown and the new one takes over the tick
+pub fn register_clock_event(event: &'static dyn ClockEvent) {
+    log::info!(
+        "Registering clock event {} (rating {})",
+        event.name(),
+        event.rating()
+    );
+
+    irqoff::without_interrupts(|| {
+        let mut active = EVENT.lock();
+
+        if let Some(current) = *active {
+            if current.rating() >= event.rating() {
+                return;
+            }
+
+            current.shutdown();
+        }
+
+        *active = Some(event);
+    });
+
+    update_mode();
+}
+
+/// Chooses between running the clock event device in periodic or one shot mode
+/// and (re)programs it
+fn update_mode() {
+    irqoff::without_interrupts(|| {
+        let event = match *EVENT.lock() {
+            Some(event) => event,
+            None => return,
+        };
+
+        let continuous = CLOCK
+            .lock()
+            .as_ref()
+            .map_or(false, |clock| !clock.source.tick_driven());
+        let oneshot = continuous && event.features().contains(ClockEventFeatures::ONE_SHOT);
+
+        {
+            let mut state = TICK_STATE.lock();
+
+            if oneshot && !state.oneshot {
+                state.next_tick = now_nanos() + TICK_PERIOD.as_nanos() as u64;
+            }
+
+            state.oneshot = oneshot;
+        }
+
+        if oneshot {
+            reprogram()
+        } else {
+            event.set_periodic(TICK_PERIOD)
+        }
+    })
+}
+
+/// Programs the clock event device for the earliest of the next tick and the
+/// next timer, does nothing in periodic mode
+fn reprogram() {
+    irqoff::without_interrupts(|| {
+        let state = TICK_STATE.lock();
+
+        if !state.oneshot {
+            return;
+        }
+
+        if let Some(event) = *EVENT.lock() {
+            let now = now_nanos();
+            let next = state.next_wakeup(now);
+
+            event.set_oneshot(Duration::from_nanos(next.saturating_sub(now)));
+        }
+    })
+}
+
+/// Returns the name of the clock source in use
+pub fn clock_source_name() -> Option<&'static str> {
+    irqoff::without_interrupts(|| CLOCK.lock().as_ref().map(|clock| clock.source.name()))
+}
+
+/// Returns the name of the clock event device in use
+pub fn clock_event_name() -> Option<&'static str> {
+    irqoff::without_interrupts(|| EVENT.lock().map(|event| event.name()))
+}
+
+/// Returns the time elapsed since the first clock source was registered
+pub fn now() -> Duration { Duration::from_nanos(now_nanos()) }
+
+fn now_nanos() -> u64 {
+    irqoff::without_interrupts(|| CLOCK.lock().as_ref().map_or(0, Clock::nanos))
+}
+
+/// Returns how long until the next timer interrupt is expected, used to
+/// decide how deep the cpu can sleep
+pub fn next_event_in() -> Duration {
+    let now = now_nanos();
+
+    let next = irqoff::without_interrupts(|| TICK_STATE.lock().next_wakeup(now));
+
+    Duration::from_nanos(next.saturating_sub(now))
+}
+
+/// Stops the tick until [`tick_restart`] is called, used when the cpu goes
+/// idle
+///
+/// Does nothing in periodic mode or when tickless idle is disabled
+pub fn tick_stop() {
+    if option_env!("NOHZ") == Some("off") {
+        return;
+    }
+
+    irqoff::without_interrupts(|| {
+        {
+            // Don't wait for the lock since this might be called from a panic
+            let mut state = match TICK_STATE.try_lock() {
+                Some(state) => state,
+                None => return,
+            };
+
+            if !state.oneshot || state.stopped {
+                return;
+            }
+
+            state.stopped = true;
+        }
+
+        reprogram();
+    })
+}
+
+/// Restarts the tick stopped by [`tick_stop`] and accounts the ticks that
+/// were skipped
+pub fn tick_restart() {
+    irqoff::without_interrupts(|| {
+        {
+            let mut state = TICK_STATE.lock();
+
+            if !state.stopped {
+                return;
+            }
+
+            state.stopped = false;
+            state.catch_up(now_nanos());
+        }
+
+        reprogram();
+    })
+}
+
+/// Returns the number of timer ticks since boot
+pub fn ticks() -> u64 { TICKS.load(Ordering::Relaxed) }
+
+/// Spins until `duration` has passed
+pub fn busy_wait(duration: Duration) {
+    let deadline = now() + duration;
+
+    while now() < deadline {
+        core::hint::spin_loop();
+    }
+}
+
+/// A blocking operation didn't complete in time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimedOut;
+
+/// A poll interval of [`wait_until`] that spins between the polls
+pub const SPIN: Duration = Duration::from_secs(0);
+
+/// Polls `condition` every `interval` until it's true, gives up once `timeout`
+/// has passed
+///
+/// With an interval of [`SPIN`] the condition is polled continuously, which
+/// can be done with interrupts disabled, otherwise the cpu sleeps between the
+/// polls so slow hardware isn't hammered with accesses.
+///
+/// The condition is always checked one last time after the timeout so a slow
+/// poll can't cause a spurious error
+pub fn wait_until(
+    mut condition: impl FnMut() -> bool,
+    timeout: Duration,
+    interval: Duration,
+) -> Result<(), TimedOut> {
+    let deadline = now() + timeout;
+
+    loop {
+        let expired = now() >= deadline;
+
+        if condition() {
+            return Ok(());
+        }
+
+        if expired {
+            return Err(TimedOut);
+        }
+
+        if interval == SPIN {
+            core::hint::spin_loop();
+        } else {
+            sleep(interval);
+        }
+    }
+}
+
+/// Halts the cpu until `duration` has passed
+pub fn sleep(duration: Duration) {
+    irqoff::might_block();
+
+    let deadline = now() + duration;
+
+    // Make sure there's an interrupt to wake up from at the deadline
+    let wakeup = timer::at(deadline, || {});
+
+    while now() < deadline {
+        crate::cpuidle::idle()
+    }
+
+    wakeup.cancel();
+}
+
+/// Halts the cpu until `micros` microseconds have passed
+pub fn sleep_us(micros: u64) { sleep(Duration::from_micros(micros)) }
+
+/// Must be called by the interrupt handler of the active clock event device
+pub fn handle_event() {
+    let oneshot = {
+        let mut state = TICK_STATE.lock();
+
+        if state.oneshot {
+            // Catch up on all the ticks that should have happened
+            state.catch_up(now_nanos());
+        } else {
+            tick();
+        }
+
+        state.oneshot
+    };
+
+    timer::run_expired(now_nanos());
+
+    if oneshot {
+        reprogram();
+    }
+}
+
+fn tick() {
+    let ticks = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+
+    if ticks % REBASE_TICKS == 0 {
+        rebase_clock()
+    }
+}
+
+fn rebase_clock() {
+    if let Some(ref mut clock) = *CLOCK.lock() {
+        clock.rebase()
+    }
+}
+
+fn cycles_to_nanos(cycles: u64, frequency: u64) -> u64 {
+    (cycles as u128 * NANOS_PER_SEC / frequency as u128) as u64
+}
+
+fn nanos_to_cycles(nanos: u64, frequency: u64) -> u64 {
+    (nanos as u128 * frequency as u128 / NANOS_PER_SEC) as u64
+}
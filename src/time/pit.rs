@@ -0,0 +1,66 @@
+//! The legacy programmable interval timer, always present but slow to program
+//! and with a low resolution
+use super::{ClockEvent, ClockEventFeatures, ClockSource, TICK_PERIOD};
+use crate::drivers::pit::{self, Channel, Mode};
+use core::time::Duration;
+
+pub static PIT: Pit = Pit;
+
+pub static TICK_SOURCE: TickSource = TickSource;
+
+pub struct Pit;
+
+impl Pit {
+    fn divisor(duration: Duration) -> u16 {
+        let divisor = super::nanos_to_cycles(duration.as_nanos() as u64, pit::FREQUENCY);
+
+        // A divisor of 0 is interpreted as 65536
+        divisor.max(1).min(u16::MAX as u64) as u16
+    }
+}
+
+impl ClockEvent for Pit {
+    fn name(&self) -> &'static str { "pit" }
+
+    fn rating(&self) -> u32 { 100 }
+
+    fn features(&self) -> ClockEventFeatures {
+        ClockEventFeatures::PERIODIC | ClockEventFeatures::ONE_SHOT
+    }
+
+    fn max_delta(&self) -> Duration {
+        Duration::from_nanos(super::cycles_to_nanos(u16::MAX as u64, pit::FREQUENCY))
+    }
+
+    fn set_periodic(&self, period: Duration) {
+        unsafe { pit::configure(Channel::Zero, Mode::RateGenerator, Pit::divisor(period)) }
+    }
+
+    fn set_oneshot(&self, delta: Duration) {
+        unsafe {
+            pit::configure(
+                Channel::Zero,
+                Mode::InterruptOnTerminalCount,
+                Pit::divisor(delta),
+            )
+        }
+    }
+
+    fn shutdown(&self) { unsafe { pit::set_mode(Channel::Zero, Mode::InterruptOnTerminalCount) } }
+}
+
+/// Counts the timer interrupts, it's always available but has the resolution
+/// of a tick
+pub struct TickSource;
+
+impl ClockSource for TickSource {
+    fn name(&self) -> &'static str { "ticks" }
+
+    fn rating(&self) -> u32 { 1 }
+
+    fn frequency(&self) -> u64 { 1_000_000_000 / TICK_PERIOD.as_nanos() as u64 }
+
+    fn tick_driven(&self) -> bool { true }
+
+    fn read(&self) -> u64 { super::ticks() }
+}
@@ -0,0 +1,81 @@
+//! The acpi power management timer, a fixed frequency counter present on all
+//! acpi systems which makes it a good calibration reference in virtual
+//! machines where the pit is emulated poorly
+//!
+//! The counter is only 24 bits wide on some chipsets and wraps around every
+//! ~4.7 seconds, this is handled by the clock rebasing every second.
+use super::ClockSource;
+use crate::memory::{map_mmio, CacheMode};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::{instructions::port::PortRead, PhysAddr};
+
+/// The frequency is fixed by the spec
+pub const FREQUENCY: u64 = 3_579_545;
+
+pub static PM_TIMER: PmTimer = PmTimer {
+    address: AtomicU64::new(0),
+    mmio: AtomicBool::new(false),
+    wide: AtomicBool::new(false),
+};
+
+pub struct PmTimer {
+    address: AtomicU64,
+    /// The counter is memory mapped instead of in port space
+    mmio: AtomicBool,
+    /// The counter is 32 bits wide instead of 24
+    wide: AtomicBool,
+}
+
+impl ClockSource for PmTimer {
+    fn name(&self) -> &'static str { "acpi_pm" }
+
+    fn rating(&self) -> u32 { 200 }
+
+    fn frequency(&self) -> u64 { FREQUENCY }
+
+    fn mask(&self) -> u64 {
+        if self.wide.load(Ordering::Relaxed) {
+            u32::MAX as u64
+        } else {
+            0xFF_FFFF
+        }
+    }
+
+    fn read(&self) -> u64 {
+        let address = self.address.load(Ordering::Relaxed);
+
+        let value = if self.mmio.load(Ordering::Relaxed) {
+            unsafe { (address as *const u32).read_volatile() }
+        } else {
+            unsafe { u32::read_from_port(address as u16) }
+        };
+
+        value as u64 & self.mask()
+    }
+}
+
+/// Registers the pm timer at `address`, a port unless `mmio` is set
+///
+/// # Safety
+///
+/// `address` must be the address of the pm timer as reported by the FADT
+pub unsafe fn init(address: u64, mmio: bool, wide: bool) {
+    // Memory mapped timers are accessed through their mapping
+    let address = if mmio {
+        match map_mmio(PhysAddr::new(address), 4, CacheMode::Uncached) {
+            Ok(region) => region.leak().as_u64(),
+            Err(e) => {
+                log::warn!("Failed to map the PM timer: {}", e);
+                return;
+            },
+        }
+    } else {
+        address
+    };
+
+    PM_TIMER.address.store(address, Ordering::Relaxed);
+    PM_TIMER.mmio.store(mmio, Ordering::Relaxed);
+    PM_TIMER.wide.store(wide, Ordering::Relaxed);
+
+    super::register_clock_source(&PM_TIMER);
+}
@@ -0,0 +1,258 @@
+//! The cmos real time clock
+//!
+//! Besides keeping the wall clock time the rtc can raise an interrupt (irq 8)
+//! when an alarm time is reached or periodically, since it's clocked
+//! separately from the cpu it can wake it up when the other timers are
+//! stopped.
+use crate::interrupts::irqoff;
+use bitflags::bitflags;
+use core::{
+    fmt,
+    sync::atomic::{AtomicU8, Ordering},
+};
+use x86_64::instructions::port::{PortRead, PortWrite};
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+/// Keeps the nmi disabled while a register is selected, otherwise the rtc
+/// could be left in an undefined state
+const NMI_DISABLE: u8 = 0x80;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_SECONDS_ALARM: u8 = 0x01;
+const REG_MINUTES: u8 = 0x02;
+const REG_MINUTES_ALARM: u8 = 0x03;
+const REG_HOURS: u8 = 0x04;
+const REG_HOURS_ALARM: u8 = 0x05;
+const REG_A: u8 = 0x0A;
+const REG_B: u8 = 0x0B;
+const REG_C: u8 = 0x0C;
+
+/// Register A bit set while the time registers are being updated
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+
+const B_PERIODIC_ENABLE: u8 = 1 << 6;
+const B_ALARM_ENABLE: u8 = 1 << 5;
+const B_24_HOUR: u8 = 1 << 1;
+const B_BINARY: u8 = 1 << 2;
+
+/// Bit set in the hours of the 12 hour format for pm
+const HOURS_PM: u8 = 1 << 7;
+
+/// The frequency of the time base of the periodic interrupt
+const BASE_FREQUENCY: u32 = 32768;
+
+bitflags! {
+    /// The reasons for a rtc interrupt
+    pub struct WakeupEvents: u8 {
+        const PERIODIC = 1 << 6;
+        const ALARM = 1 << 5;
+    }
+}
+
+/// Events raised since the last call to [`take_events`]
+static PENDING: AtomicU8 = AtomicU8::new(0);
+
+/// A time of the day as kept by the rtc
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RtcTime {
+    pub hours: u8,
+    pub minutes: u8,
+    pub seconds: u8,
+}
+
+impl RtcTime {
+    /// Returns the time `seconds` after this one wrapping around midnight
+    pub fn add_seconds(self, seconds: u32) -> Self {
+        let total =
+            (self.hours as u32 * 3600 + self.minutes as u32 * 60 + self.seconds as u32 + seconds)
+                % (24 * 3600);
+
+        RtcTime {
+            hours: (total / 3600) as u8,
+            minutes: (total / 60 % 60) as u8,
+            seconds: (total % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for RtcTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}:{:02}",
+            self.hours, self.minutes, self.seconds
+        )
+    }
+}
+
+unsafe fn read_reg(reg: u8) -> u8 {
+    u8::write_to_port(CMOS_ADDRESS, NMI_DISABLE | reg);
+    let val = u8::read_from_port(CMOS_DATA);
+    enable_nmi(reg);
+
+    val
+}
+
+unsafe fn write_reg(reg: u8, val: u8) {
+    u8::write_to_port(CMOS_ADDRESS, NMI_DISABLE | reg);
+    u8::write_to_port(CMOS_DATA, val);
+    enable_nmi(reg)
+}
+
+/// The nmi disable bit is shared with the register index so it stays set
+/// until the index is written again without it
+unsafe fn enable_nmi(reg: u8) { u8::write_to_port(CMOS_ADDRESS, reg) }
+
+/// Clears and returns the interrupt flags, the rtc doesn't raise another
+/// interrupt until they are read
+unsafe fn ack() -> u8 { read_reg(REG_C) }
+
+/// Converts a value read from the rtc to binary
+fn decode(val: u8, format: u8) -> u8 {
+    if format & B_BINARY != 0 {
+        val
+    } else {
+        (val & 0x0F) + (val >> 4) * 10
+    }
+}
+
+/// Converts a binary value to the rtc format
+fn encode(val: u8, format: u8) -> u8 {
+    if format & B_BINARY != 0 {
+        val
+    } else {
+        (val / 10) << 4 | val % 10
+    }
+}
+
+fn decode_hours(val: u8, format: u8) -> u8 {
+    if format & B_24_HOUR != 0 {
+        return decode(val, format);
+    }
+
+    let hours = decode(val & !HOURS_PM, format) % 12;
+
+    if val & HOURS_PM != 0 {
+        hours + 12
+    } else {
+        hours
+    }
+}
+
+fn encode_hours(hours: u8, format: u8) -> u8 {
+    if format & B_24_HOUR != 0 {
+        return encode(hours, format);
+    }
+
+    let pm = if hours >= 12 { HOURS_PM } else { 0 };
+    let hours = match hours % 12 {
+        0 => 12,
+        hours => hours,
+    };
+
+    encode(hours, format) | pm
+}
+
+/// Reads the current time of the day
+pub fn read_time() -> RtcTime {
+    irqoff::without_interrupts(|| unsafe {
+        // Reading during an update might return a mix of the old and new time,
+        // so read until two consecutive reads match
+        let read = || {
+            while read_reg(REG_A) & UPDATE_IN_PROGRESS != 0 {
+                core::hint::spin_loop();
+            }
+
+            (
+                read_reg(REG_HOURS),
+                read_reg(REG_MINUTES),
+                read_reg(REG_SECONDS),
+            )
+        };
+
+        let mut last = read();
+
+        loop {
+            let current = read();
+
+            if current == last {
+                break;
+            }
+
+            last = current;
+        }
+
+        let format = read_reg(REG_B);
+
+        RtcTime {
+            hours: decode_hours(last.0, format),
+            minutes: decode(last.1, format),
+            seconds: decode(last.2, format),
+        }
+    })
+}
+
+/// Raises an alarm interrupt the next time the clock reaches `time`
+pub fn set_alarm(time: RtcTime) {
+    irqoff::without_interrupts(|| unsafe {
+        let format = read_reg(REG_B);
+
+        write_reg(REG_HOURS_ALARM, encode_hours(time.hours, format));
+        write_reg(REG_MINUTES_ALARM, encode(time.minutes, format));
+        write_reg(REG_SECONDS_ALARM, encode(time.seconds, format));
+
+        write_reg(REG_B, format | B_ALARM_ENABLE);
+        ack();
+    })
+}
+
+/// Raises an alarm interrupt in `seconds`, the alarm can be at most a day away
+pub fn alarm_after(seconds: u32) { set_alarm(read_time().add_seconds(seconds)) }
+
+pub fn clear_alarm() {
+    irqoff::without_interrupts(|| unsafe {
+        write_reg(REG_B, read_reg(REG_B) & !B_ALARM_ENABLE);
+    })
+}
+
+/// Raises an interrupt periodically at `32768 >> (rate - 1)` Hz and returns the
+/// resulting frequency
+///
+/// # Panics
+///
+/// If `rate` isn't in the range `3..=15`, lower rates aren't usable with the
+/// standard time base
+pub fn set_periodic(rate: u8) -> u32 {
+    assert!((3..=15).contains(&rate), "Invalid rtc rate {}", rate);
+
+    irqoff::without_interrupts(|| unsafe {
+        write_reg(REG_A, (read_reg(REG_A) & 0xF0) | rate);
+        write_reg(REG_B, read_reg(REG_B) | B_PERIODIC_ENABLE);
+        ack();
+    });
+
+    BASE_FREQUENCY >> (rate - 1)
+}
+
+pub fn stop_periodic() {
+    irqoff::without_interrupts(|| unsafe {
+        write_reg(REG_B, read_reg(REG_B) & !B_PERIODIC_ENABLE);
+    })
+}
+
+/// Returns and clears the events raised since the last call
+pub fn take_events() -> WakeupEvents {
+    WakeupEvents::from_bits_truncate(PENDING.swap(0, Ordering::Relaxed))
+}
+
+/// Must be called by the rtc interrupt handler
+pub fn handle_interrupt() {
+    let flags = unsafe { ack() };
+
+    PENDING.fetch_or(
+        WakeupEvents::from_bits_truncate(flags).bits(),
+        Ordering::Relaxed,
+    );
+}
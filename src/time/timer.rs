@@ -0,0 +1,125 @@
+//! One shot timers
+//!
+//! When the clock event device supports one shot mode it's programmed for the
+//! earliest timer deadline so timers fire with the resolution of the clock
+//! source, otherwise they're checked on every tick.
+//!
+//! The timers are kept in preallocated slots and the callbacks are plain
+//! functions so that timers can be set and run from interrupt handlers without
+//! allocating.
+use crate::interrupts::irqoff;
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use spin::Mutex;
+
+/// How many timers can be pending at once
+const MAX_TIMERS: usize = 32;
+
+/// Timers are keyed by their deadline in nanoseconds and an unique id so that
+/// timers with the same deadline can coexist
+type TimerKey = (u64, u64);
+
+#[derive(Clone, Copy)]
+struct Timer {
+    key: TimerKey,
+    callback: fn(),
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+static TIMERS: Mutex<[Option<Timer>; MAX_TIMERS]> = Mutex::new([None; MAX_TIMERS]);
+
+/// A pending timer, dropping the handle doesn't cancel the timer
+#[derive(Debug)]
+pub struct TimerHandle {
+    key: TimerKey,
+}
+
+impl TimerHandle {
+    pub fn deadline(&self) -> Duration { Duration::from_nanos(self.key.0) }
+
+    /// Cancels the timer, returns false if the timer already fired
+    pub fn cancel(self) -> bool {
+        irqoff::without_interrupts(|| {
+            TIMERS
+                .lock()
+                .iter_mut()
+                .find(|slot| slot.map_or(false, |timer| timer.key == self.key))
+                .and_then(Option::take)
+                .is_some()
+        })
+    }
+}
+
+/// Calls `callback` once `duration` has passed
+///
+/// The callback runs in interrupt context so it must be short and can't block
+/// or allocate
+pub fn after(duration: Duration, callback: fn()) -> TimerHandle {
+    at(super::now() + duration, callback)
+}
+
+/// Calls `callback` once the monotonic time reaches `deadline`
+///
+/// The callback runs in interrupt context so it must be short and can't block
+/// or allocate
+///
+/// # Panics
+///
+/// If [`MAX_TIMERS`] timers are already pending
+pub fn at(deadline: Duration, callback: fn()) -> TimerHandle {
+    let key = (
+        deadline.as_nanos() as u64,
+        NEXT_ID.fetch_add(1, Ordering::Relaxed),
+    );
+
+    irqoff::without_interrupts(|| {
+        let mut timers = TIMERS.lock();
+        let slot = timers
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .expect("Out of timer slots");
+
+        *slot = Some(Timer { key, callback });
+    });
+
+    // The new timer might expire before the event that is programmed
+    super::reprogram();
+
+    TimerHandle { key }
+}
+
+/// Returns the deadline of the next timer to expire in nanoseconds
+pub(super) fn next_deadline() -> Option<u64> {
+    irqoff::without_interrupts(|| {
+        TIMERS
+            .lock()
+            .iter()
+            .flatten()
+            .map(|timer| timer.key.0)
+            .min()
+    })
+}
+
+/// Runs the callbacks of all the timers that expired by `now`
+pub(super) fn run_expired(now: u64) {
+    loop {
+        // Don't hold the lock while running the callback so it can set new
+        // timers
+        let timer = irqoff::without_interrupts(|| {
+            TIMERS
+                .lock()
+                .iter_mut()
+                .filter(|slot| slot.map_or(false, |timer| timer.key.0 <= now))
+                .min_by_key(|slot| slot.map(|timer| timer.key))
+                .and_then(Option::take)
+        });
+
+        match timer {
+            Some(timer) => (timer.callback)(),
+            None => break,
+        }
+    }
+}
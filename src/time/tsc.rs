@@ -0,0 +1,93 @@
+//! The time stamp counter, the cheapest clock source to read but it's
+//! frequency must be calibrated and on older cpus it changes with the power
+//! state
+use super::ClockSource;
+use core::{
+    arch::x86_64::{__cpuid, _rdtsc},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Number of ticks used to calibrate the tsc
+const CALIBRATION_TICKS: u64 = 50;
+
+/// Cpuid 0x80000007 edx bit signaling that the tsc runs at a constant rate in
+/// all power states
+const CPUID_INVARIANT_TSC: u32 = 1 << 8;
+
+pub static TSC: Tsc = Tsc {
+    frequency: AtomicU64::new(0),
+    invariant: AtomicBool::new(false),
+};
+
+pub struct Tsc {
+    frequency: AtomicU64,
+    invariant: AtomicBool,
+}
+
+impl ClockSource for Tsc {
+    fn name(&self) -> &'static str { "tsc" }
+
+    fn rating(&self) -> u32 {
+        if self.invariant.load(Ordering::Relaxed) {
+            300
+        } else {
+            90
+        }
+    }
+
+    fn frequency(&self) -> u64 { self.frequency.load(Ordering::Relaxed) }
+
+    fn read(&self) -> u64 { unsafe { _rdtsc() } }
+}
+
+/// Calibrates the tsc against the timer tick and registers it
+pub fn init() {
+    let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    let invariant = max_extended_leaf >= 0x8000_0007
+        && unsafe { __cpuid(0x8000_0007) }.edx & CPUID_INVARIANT_TSC != 0;
+
+    let frequency = calibrate();
+
+    log::info!("TSC frequency: {} Hz (invariant: {})", frequency, invariant);
+
+    TSC.frequency.store(frequency, Ordering::Relaxed);
+    TSC.invariant.store(invariant, Ordering::Relaxed);
+
+    super::register_clock_source(&TSC);
+}
+
+/// Reads the tsc, usable even before it's calibrated
+pub fn read() -> u64 { unsafe { _rdtsc() } }
+
+/// Converts a number of cycles to a duration, returns None until the tsc is
+/// calibrated
+pub fn cycles_to_duration(cycles: u64) -> Option<Duration> {
+    match TSC.frequency.load(Ordering::Relaxed) {
+        0 => None,
+        frequency => Some(Duration::from_nanos(
+            (cycles as u128 * super::NANOS_PER_SEC / frequency as u128) as u64,
+        )),
+    }
+}
+
+/// Counts the tsc cycles between `CALIBRATION_TICKS` timer ticks
+fn calibrate() -> u64 {
+    // Start at a tick edge
+    let start_tick = super::ticks();
+    while super::ticks() == start_tick {
+        core::hint::spin_loop();
+    }
+
+    let start_tick = super::ticks();
+    let start = unsafe { _rdtsc() };
+
+    while super::ticks() < start_tick + CALIBRATION_TICKS {
+        core::hint::spin_loop();
+    }
+
+    let cycles = unsafe { _rdtsc() } - start;
+
+    (cycles as u128 * super::NANOS_PER_SEC
+        / (CALIBRATION_TICKS as u128 * super::TICK_PERIOD.as_nanos())) as u64
+}
@@ -1,7 +1,22 @@
-use core::fmt;
+use alloc::string::String;
+use core::{fmt, ops::Range};
 use lazy_static::lazy_static;
 use spin::Mutex;
 use volatile::Volatile;
+use x86_64::instructions::port::{PortRead, PortWrite};
+
+/// The crt controller index and data ports
+const CRTC_INDEX: u16 = 0x3D4;
+const CRTC_DATA: u16 = 0x3D5;
+
+const CRTC_CURSOR_START: u8 = 0x0A;
+const CRTC_CURSOR_LOCATION_HIGH: u8 = 0x0E;
+const CRTC_CURSOR_LOCATION_LOW: u8 = 0x0F;
+
+/// Bit of the cursor start register that hides the cursor
+const CURSOR_DISABLE: u8 = 1 << 5;
+
+const DEFAULT_COLOR: ColorCode = ColorCode::new(Color::Yellow, Color::Black);
 
 lazy_static! {
     /// A global `Writer` instance that can be used for printing to the VGA text buffer.
@@ -9,7 +24,7 @@ lazy_static! {
     /// Used by the `print!` and `println!` macros.
     pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
         column_position: 0,
-        color_code: ColorCode::new(Color::Yellow, Color::Black),
+        color_code: DEFAULT_COLOR,
         buffer: Volatile::new(unsafe { &mut *(0xb8000 as *mut Buffer) }),
     });
 }
@@ -40,14 +55,18 @@ pub enum Color {
 /// A combination of a foreground and a background color.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(transparent)]
-struct ColorCode(u8);
+pub struct ColorCode(u8);
 
 impl ColorCode {
     /// Create a new `ColorCode` with the given foreground and background
     /// colors.
-    fn new(foreground: Color, background: Color) -> ColorCode {
+    pub const fn new(foreground: Color, background: Color) -> ColorCode {
         ColorCode((background as u8) << 4 | (foreground as u8))
     }
+
+    /// Makes the character blink, the blink bit is shared with the high bit of
+    /// the background so only the first 8 colors can be used as background.
+    pub fn blinking(self) -> ColorCode { ColorCode(self.0 | 1 << 7) }
 }
 
 /// A screen character in the VGA text buffer, consisting of an ASCII character
@@ -67,6 +86,62 @@ const BUFFER_WIDTH: usize = 80;
 /// A structure representing the VGA text buffer.
 type Buffer = [[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT];
 
+/// A copy of the screen contents and the writer state
+pub struct SavedScreen {
+    chars: Buffer,
+    column_position: usize,
+    color_code: ColorCode,
+}
+
+/// A copy of the screen taken by [`capture`] to compare against the expected
+/// output in tests
+pub struct Snapshot {
+    chars: Buffer,
+}
+
+impl Snapshot {
+    /// The text of a row without the trailing blanks, characters that can't
+    /// be printed are shown as `■` like on the screen
+    pub fn row(&self, row: usize) -> String {
+        let mut text: String = self.chars[row]
+            .iter()
+            .map(|character| match character.ascii_character {
+                0xfe => '■',
+                byte => byte as char,
+            })
+            .collect();
+
+        text.truncate(text.trim_end().len());
+        text
+    }
+
+    pub fn color_at(&self, row: usize, col: usize) -> ColorCode { self.chars[row][col].color_code }
+}
+
+/// Shows the rows from the first to the last one that isn't blank, separated
+/// by newlines
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let blank = |row: &usize| self.chars[*row].iter().all(|c| c.ascii_character == b' ');
+
+        let first = match (0..BUFFER_HEIGHT).find(|row| !blank(row)) {
+            Some(first) => first,
+            None => return Ok(()),
+        };
+        let last = (0..BUFFER_HEIGHT).rev().find(|row| !blank(row)).unwrap();
+
+        for row in first..=last {
+            if row != first {
+                f.write_str("\n")?;
+            }
+
+            f.write_str(&self.row(row))?;
+        }
+
+        Ok(())
+    }
+}
+
 /// A writer type that allows writing ASCII bytes and strings to an underlying
 /// `Buffer`.
 ///
@@ -84,6 +159,129 @@ impl Writer {
         self.buffer.map_mut(|b| &mut b[row][col])
     }
 
+    /// Sets the color used for the next writes
+    pub fn set_color(&mut self, foreground: Color, background: Color) {
+        self.color_code = ColorCode::new(foreground, background)
+    }
+
+    pub fn color_code(&self) -> ColorCode { self.color_code }
+
+    pub fn set_color_code(&mut self, color_code: ColorCode) { self.color_code = color_code }
+
+    /// Moves the hardware cursor, the writer moves it back after the end of
+    /// the text when it writes.
+    pub fn set_cursor_position(&mut self, row: usize, col: usize) {
+        let position =
+            (row.min(BUFFER_HEIGHT - 1) * BUFFER_WIDTH + col.min(BUFFER_WIDTH - 1)) as u16;
+
+        unsafe {
+            crtc_write(CRTC_CURSOR_LOCATION_LOW, position as u8);
+            crtc_write(CRTC_CURSOR_LOCATION_HIGH, (position >> 8) as u8);
+        }
+    }
+
+    pub fn show_cursor(&mut self) {
+        unsafe {
+            crtc_write(
+                CRTC_CURSOR_START,
+                crtc_read(CRTC_CURSOR_START) & !CURSOR_DISABLE,
+            )
+        }
+    }
+
+    pub fn hide_cursor(&mut self) {
+        unsafe {
+            crtc_write(
+                CRTC_CURSOR_START,
+                crtc_read(CRTC_CURSOR_START) | CURSOR_DISABLE,
+            )
+        }
+    }
+
+    /// Writes a string at a position without moving the writer.
+    ///
+    /// The string is cut at the end of the row, nothing is written if the row
+    /// is past the end of the screen.
+    pub fn write_at(&mut self, row: usize, col: usize, s: &str) {
+        if row >= BUFFER_HEIGHT {
+            return;
+        }
+
+        let color_code = self.color_code;
+
+        for (col, byte) in (col..BUFFER_WIDTH).zip(s.bytes()) {
+            let ascii_character = match byte {
+                0x20..=0x7e => byte,
+                _ => 0xfe,
+            };
+
+            self.get_char_mut(row, col).write(ScreenChar {
+                ascii_character,
+                color_code,
+            });
+        }
+    }
+
+    /// Clears a rectangular region of the screen with the current color.
+    pub fn clear_region(&mut self, rows: Range<usize>, cols: Range<usize>) {
+        let blank = ScreenChar {
+            ascii_character: b' ',
+            color_code: self.color_code,
+        };
+
+        for row in rows.start..rows.end.min(BUFFER_HEIGHT) {
+            for col in cols.start..cols.end.min(BUFFER_WIDTH) {
+                self.get_char_mut(row, col).write(blank);
+            }
+        }
+    }
+
+    /// Clears the whole screen and moves the writer to the start of the last
+    /// row.
+    pub fn clear_screen(&mut self) {
+        self.clear_region(0..BUFFER_HEIGHT, 0..BUFFER_WIDTH);
+        self.column_position = 0;
+        self.update_cursor();
+    }
+
+    /// Copies the screen contents so they can be restored later.
+    pub fn save(&mut self) -> SavedScreen {
+        let mut chars = [[ScreenChar {
+            ascii_character: b' ',
+            color_code: self.color_code,
+        }; BUFFER_WIDTH]; BUFFER_HEIGHT];
+
+        for (row, line) in chars.iter_mut().enumerate() {
+            for (col, character) in line.iter_mut().enumerate() {
+                *character = self.get_char_mut(row, col).read();
+            }
+        }
+
+        SavedScreen {
+            chars,
+            column_position: self.column_position,
+            color_code: self.color_code,
+        }
+    }
+
+    /// Restores the screen contents and the writer state from a save.
+    pub fn restore(&mut self, saved: &SavedScreen) {
+        for (row, line) in saved.chars.iter().enumerate() {
+            for (col, character) in line.iter().enumerate() {
+                self.get_char_mut(row, col).write(*character);
+            }
+        }
+
+        self.column_position = saved.column_position;
+        self.color_code = saved.color_code;
+        self.update_cursor();
+    }
+
+    /// Moves the hardware cursor to after the last written character.
+    fn update_cursor(&mut self) {
+        self.set_cursor_position(BUFFER_HEIGHT - 1, self.column_position)
+    }
+
     /// Writes an ASCII byte to the buffer.
     ///
     /// Wraps lines at `BUFFER_WIDTH`. Supports the `\n` newline character.
@@ -151,10 +349,21 @@ impl Writer {
 impl fmt::Write for Writer {
     fn write_str(&mut self, s: &str) -> fmt::Result {
         self.write_string(s);
+        self.update_cursor();
         Ok(())
     }
 }
 
+unsafe fn crtc_read(reg: u8) -> u8 {
+    u8::write_to_port(CRTC_INDEX, reg);
+    u8::read_from_port(CRTC_DATA)
+}
+
+unsafe fn crtc_write(reg: u8, val: u8) {
+    u8::write_to_port(CRTC_INDEX, reg);
+    u8::write_to_port(CRTC_DATA, val)
+}
+
 /// Like the `print!` macro in the standard library, but prints to the VGA text
 /// buffer.
 #[macro_export]
@@ -173,11 +382,126 @@ macro_rules! println {
 /// Prints the given formatted string to the VGA text buffer
 /// through the global `WRITER` instance.
 #[doc(hidden)]
-pub fn _print(args: fmt::Arguments) {
+pub fn _print(args: fmt::Arguments) { print_colored(DEFAULT_COLOR, args) }
+
+/// Prints the given formatted string to the VGA text buffer with a color.
+///
+/// Interrupts are disabled while printing and only one cpu runs, so if the
+/// writer is already locked this print interrupted another one (an nmi or a
+/// fault while printing) and it bypasses the lock instead of deadlocking.
+pub fn print_colored(color_code: ColorCode, args: fmt::Arguments) {
     use core::fmt::Write;
     use x86_64::instructions::interrupts;
 
-    interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
+    interrupts::without_interrupts(|| match WRITER.try_lock() {
+        Some(mut writer) => {
+            let previous = writer.color_code();
+
+            writer.set_color_code(color_code);
+            writer.write_fmt(args).unwrap();
+            writer.set_color_code(previous);
+        },
+        None => {
+            let _ = emergency_writer(color_code).write_fmt(args);
+        },
     });
 }
+
+/// A writer that doesn't go through the lock of `WRITER`, it doesn't know
+/// where the locked writer is so it starts on a new row.
+fn emergency_writer(color_code: ColorCode) -> Writer {
+    Writer {
+        column_position: BUFFER_WIDTH,
+        color_code,
+        buffer: Volatile::new(unsafe { &mut *(0xb8000 as *mut Buffer) }),
+    }
+}
+
+/// Runs `f` on a cleared screen and returns what it drew, the previous screen
+/// contents are restored afterwards.
+///
+/// Anything printed while `f` runs is captured too, including by interrupt
+/// handlers, so it's meant for tests of the text output.
+pub fn capture<F: FnOnce()>(f: F) -> Snapshot {
+    let saved = {
+        let mut writer = WRITER.lock();
+        let saved = writer.save();
+
+        writer.set_color_code(DEFAULT_COLOR);
+        writer.clear_screen();
+        saved
+    };
+
+    f();
+
+    let mut writer = WRITER.lock();
+    let snapshot = Snapshot {
+        chars: writer.save().chars,
+    };
+
+    writer.restore(&saved);
+    snapshot
+}
+
+/// Releases the lock of `WRITER` whoever holds it, so the panic screen can be
+/// drawn even if the panic happened while printing.
+///
+/// # Safety
+///
+/// The code holding the lock must never resume.
+pub unsafe fn force_unlock() {
+    if WRITER.is_locked() {
+        WRITER.force_unlock()
+    }
+}
+
+#[test_case]
+fn console_wraps_and_scrolls() {
+    use crate::{print, println};
+    use alloc::string::ToString;
+
+    let snapshot = capture(|| {
+        println!("first");
+        print!("{}", "x".repeat(BUFFER_WIDTH + 3));
+        print!("\tend");
+    });
+
+    let expected = ["first", "x".repeat(BUFFER_WIDTH).as_str(), "xxx■end"].join("\n");
+    assert_eq!(snapshot.to_string(), expected);
+    // The writer starts on the last row so the output scrolled up
+    assert_eq!(snapshot.row(BUFFER_HEIGHT - 1), "xxx■end");
+    assert_eq!(snapshot.row(BUFFER_HEIGHT - 3), "first");
+}
+
+#[test_case]
+fn emergency_writer_starts_on_a_new_row() {
+    use alloc::string::ToString;
+    use core::fmt::Write;
+
+    let color_code = ColorCode::new(Color::White, Color::Red);
+    let snapshot = capture(|| {
+        crate::print!("interrupted");
+
+        let _writer = WRITER.lock();
+        write!(emergency_writer(color_code), "nmi").unwrap();
+    });
+
+    assert_eq!(snapshot.to_string(), "interrupted\nnmi");
+    assert_eq!(snapshot.color_at(BUFFER_HEIGHT - 1, 0), color_code);
+    assert_eq!(snapshot.color_at(BUFFER_HEIGHT - 2, 0), DEFAULT_COLOR);
+}
+
+#[test_case]
+fn write_at_ignores_rows_past_the_screen() {
+    use alloc::string::ToString;
+
+    let snapshot = capture(|| {
+        let mut writer = WRITER.lock();
+
+        writer.write_at(BUFFER_HEIGHT, 0, "hidden");
+        writer.write_at(0, BUFFER_WIDTH - 2, "cut");
+    });
+
+    assert_eq!(snapshot.to_string().trim_start(), "cu");
+    assert_eq!(snapshot.row(0).len(), BUFFER_WIDTH);
+}
@@ -0,0 +1,248 @@
+//! VGA mode 13h (320x200, 256 colors)
+//!
+//! The mode is set by programming the VGA registers directly so it works
+//! without going back to real mode for the bios, the values are the standard
+//! ones for mode 13h.
+use crate::memory;
+use x86_64::{
+    instructions::port::{PortRead, PortWrite},
+    PhysAddr, VirtAddr,
+};
+
+const MISC_WRITE: u16 = 0x3C2;
+const SEQ_INDEX: u16 = 0x3C4;
+const SEQ_DATA: u16 = 0x3C5;
+const CRTC_INDEX: u16 = 0x3D4;
+const CRTC_DATA: u16 = 0x3D5;
+const GC_INDEX: u16 = 0x3CE;
+const GC_DATA: u16 = 0x3CF;
+const AC_INDEX: u16 = 0x3C0;
+/// Reading this resets the attribute controller flip flop to the index state
+const INPUT_STATUS: u16 = 0x3DA;
+const DAC_READ_INDEX: u16 = 0x3C7;
+const DAC_WRITE_INDEX: u16 = 0x3C8;
+const DAC_DATA: u16 = 0x3C9;
+
+/// Enables the display after the attribute controller is programmed
+const AC_PALETTE_ENABLE: u8 = 0x20;
+
+const MODE_13H_MISC: u8 = 0x63;
+const MODE_13H_SEQ: [u8; 5] = [0x03, 0x01, 0x0F, 0x00, 0x0E];
+const MODE_13H_CRTC: [u8; 25] = [
+    0x5F, 0x4F, 0x50, 0x82, 0x54, 0x80, 0xBF, 0x1F, 0x00, 0x41, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x9C, 0x0E, 0x8F, 0x28, 0x40, 0x96, 0xB9, 0xA3, 0xFF,
+];
+const MODE_13H_GC: [u8; 9] = [0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x05, 0x0F, 0xFF];
+const MODE_13H_AC: [u8; 21] = [
+    0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E, 0x0F,
+    0x41, 0x00, 0x0F, 0x00, 0x00,
+];
+
+/// Bit of the vertical retrace end register that write protects the crtc
+/// registers 0-7
+const CRTC_PROTECT: u8 = 0x80;
+
+const FRAMEBUFFER_ADDRESS: u64 = 0xA0000;
+
+/// The 16 text mode colors, the first entries of the default palette
+const TEXT_COLORS: [(u8, u8, u8); 16] = [
+    (0x00, 0x00, 0x00),
+    (0x00, 0x00, 0x2A),
+    (0x00, 0x2A, 0x00),
+    (0x00, 0x2A, 0x2A),
+    (0x2A, 0x00, 0x00),
+    (0x2A, 0x00, 0x2A),
+    (0x2A, 0x15, 0x00),
+    (0x2A, 0x2A, 0x2A),
+    (0x15, 0x15, 0x15),
+    (0x15, 0x15, 0x3F),
+    (0x15, 0x3F, 0x15),
+    (0x15, 0x3F, 0x3F),
+    (0x3F, 0x15, 0x15),
+    (0x3F, 0x15, 0x3F),
+    (0x3F, 0x3F, 0x15),
+    (0x3F, 0x3F, 0x3F),
+];
+
+/// A linear 8 bit per pixel framebuffer
+pub struct Framebuffer {
+    base: VirtAddr,
+    width: usize,
+    height: usize,
+}
+
+impl Framebuffer {
+    pub fn width(&self) -> usize { self.width }
+
+    pub fn height(&self) -> usize { self.height }
+
+    /// Sets a pixel to a palette index, pixels outside the screen are ignored
+    pub fn put_pixel(&mut self, x: usize, y: usize, color: u8) {
+        if x >= self.width || y >= self.height {
+            return;
+        }
+
+        let ptr = (self.base + y * self.width + x).as_mut_ptr::<u8>();
+        unsafe { ptr.write_volatile(color) }
+    }
+
+    /// Fills a rectangle clipped to the screen
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: u8) {
+        for y in y..(y + height).min(self.height) {
+            for x in x..(x + width).min(self.width) {
+                self.put_pixel(x, y, color)
+            }
+        }
+    }
+
+    pub fn clear(&mut self, color: u8) { self.fill_rect(0, 0, self.width, self.height, color) }
+}
+
+/// Sets a palette entry, the components are 6 bits
+pub fn set_palette_entry(index: u8, red: u8, green: u8, blue: u8) {
+    unsafe {
+        u8::write_to_port(DAC_WRITE_INDEX, index);
+        u8::write_to_port(DAC_DATA, red & 0x3F);
+        u8::write_to_port(DAC_DATA, green & 0x3F);
+        u8::write_to_port(DAC_DATA, blue & 0x3F);
+    }
+}
+
+/// Reads a palette entry
+pub fn palette_entry(index: u8) -> (u8, u8, u8) {
+    unsafe {
+        u8::write_to_port(DAC_READ_INDEX, index);
+
+        (
+            u8::read_from_port(DAC_DATA),
+            u8::read_from_port(DAC_DATA),
+            u8::read_from_port(DAC_DATA),
+        )
+    }
+}
+
+/// Loads the default palette: the 16 text mode colors, a 6x6x6 color cube and
+/// a grayscale ramp
+pub fn load_default_palette() {
+    for index in 0..=u8::MAX {
+        let (red, green, blue) = default_palette_entry(index);
+
+        set_palette_entry(index, red, green, blue);
+    }
+}
+
+/// The components of an entry of the default palette
+fn default_palette_entry(index: u8) -> (u8, u8, u8) {
+    // Done in u16 since the products don't fit in a u8
+    let level = |step: u8, steps: u16| (step as u16 * 0x3F / steps) as u8;
+
+    match index {
+        0..=15 => TEXT_COLORS[index as usize],
+        16..=231 => {
+            let cube = index - 16;
+
+            (
+                level(cube / 36, 5),
+                level(cube / 6 % 6, 5),
+                level(cube % 6, 5),
+            )
+        },
+        _ => {
+            let gray = level(index - 232, 23);
+
+            (gray, gray, gray)
+        },
+    }
+}
+
+/// Switches the display to mode 13h and returns the framebuffer
+///
+/// There's no way back to text mode since drawing overwrites the font, so the
+/// vga text writer output isn't visible anymore after this.
+///
+/// # Safety
+///
+/// The display must be a vga compatible adapter and nothing else must be
+/// using the vga registers
+pub unsafe fn set_mode_13h() -> Framebuffer {
+    crate::interrupts::irqoff::without_interrupts(|| {
+        u8::write_to_port(MISC_WRITE, MODE_13H_MISC);
+
+        for (index, val) in MODE_13H_SEQ.iter().enumerate() {
+            u8::write_to_port(SEQ_INDEX, index as u8);
+            u8::write_to_port(SEQ_DATA, *val);
+        }
+
+        // Unlock the crtc registers and make sure they stay unlocked when
+        // written below
+        u8::write_to_port(CRTC_INDEX, 0x11);
+        let retrace_end = u8::read_from_port(CRTC_DATA);
+        u8::write_to_port(CRTC_DATA, retrace_end & !CRTC_PROTECT);
+
+        for (index, val) in MODE_13H_CRTC.iter().enumerate() {
+            let val = if index == 0x11 {
+                val & !CRTC_PROTECT
+            } else {
+                *val
+            };
+
+            u8::write_to_port(CRTC_INDEX, index as u8);
+            u8::write_to_port(CRTC_DATA, val);
+        }
+
+        for (index, val) in MODE_13H_GC.iter().enumerate() {
+            u8::write_to_port(GC_INDEX, index as u8);
+            u8::write_to_port(GC_DATA, *val);
+        }
+
+        for (index, val) in MODE_13H_AC.iter().enumerate() {
+            u8::read_from_port(INPUT_STATUS);
+            u8::write_to_port(AC_INDEX, index as u8);
+            u8::write_to_port(AC_INDEX, *val);
+        }
+
+        u8::read_from_port(INPUT_STATUS);
+        u8::write_to_port(AC_INDEX, AC_PALETTE_ENABLE);
+    });
+
+    load_default_palette();
+
+    let mut framebuffer = Framebuffer {
+        base: memory::phys_to_virt(PhysAddr::new(FRAMEBUFFER_ADDRESS)),
+        width: 320,
+        height: 200,
+    };
+
+    framebuffer.clear(0);
+
+    framebuffer
+}
+
+#[test_case]
+fn default_palette() {
+    // The text mode colors come from the palette too so it's put back after
+    let mut saved = [(0, 0, 0); 256];
+    for (index, entry) in saved.iter_mut().enumerate() {
+        *entry = palette_entry(index as u8);
+    }
+
+    load_default_palette();
+
+    let loaded = [
+        palette_entry(16),
+        palette_entry(231),
+        palette_entry(232),
+        palette_entry(255),
+    ];
+
+    for (index, (red, green, blue)) in saved.iter().enumerate() {
+        set_palette_entry(index as u8, *red, *green, *blue);
+    }
+
+    assert_eq!(loaded, [
+        (0, 0, 0),
+        (0x3F, 0x3F, 0x3F),
+        (0, 0, 0),
+        (0x3F, 0x3F, 0x3F)
+    ]);
+}
@@ -0,0 +1,90 @@
+#!/usr/bin/env python3
+"""Decodes the binary log records of a kernel built with LOG_FORMAT=binary
+
+Reads the serial output from stdin and writes it as text to stdout, anything
+that isn't a log frame is passed through unchanged. The format is described in
+src/logger/binary.rs.
+"""
+import codecs
+import struct
+import sys
+
+MAGIC = b"\xfe\xca"
+VERSION = 1
+LEVELS = {1: "ERROR", 2: "WARN", 3: "INFO", 4: "DEBUG", 5: "TRACE"}
+
+
+def read_short_str(payload, offset):
+    length = payload[offset]
+    end = offset + 1 + length
+    return payload[offset + 1 : end].decode("utf-8", "replace"), end
+
+
+def decode_record(payload):
+    version, level, ticks, line = struct.unpack_from("<BBQI", payload)
+
+    if version != VERSION:
+        return "[logdecode] unknown frame version {}\n".format(version)
+
+    target, offset = read_short_str(payload, 14)
+    file, offset = read_short_str(payload, offset)
+    message = payload[offset:].decode("utf-8", "replace")
+
+    location = ""
+    if file:
+        location = "[{}:{}]".format(file, line) if line else "[{}]".format(file)
+
+    return "[{:>8}ms][{}][{}]{}{}\n".format(
+        ticks, LEVELS.get(level, level), target, location, message
+    )
+
+
+def decode(stream, out):
+    buffer = b""
+    # Text might be split in the middle of a character
+    text = codecs.getincrementaldecoder("utf-8")("replace")
+
+    while True:
+        chunk = stream.read1(4096)
+        if not chunk:
+            break
+        buffer += chunk
+
+        while True:
+            start = buffer.find(MAGIC)
+
+            if start == -1:
+                # The first byte of the magic might be at the end
+                keep = 1 if buffer.endswith(MAGIC[:1]) else 0
+                out.write(text.decode(buffer[: len(buffer) - keep]))
+                buffer = buffer[len(buffer) - keep :]
+                break
+
+            out.write(text.decode(buffer[:start]))
+            buffer = buffer[start:]
+
+            if len(buffer) < 4:
+                break
+
+            (length,) = struct.unpack_from("<H", buffer, 2)
+            if len(buffer) < 4 + length:
+                break
+
+            try:
+                out.write(decode_record(buffer[4 : 4 + length]))
+            except (struct.error, IndexError):
+                out.write("[logdecode] malformed frame\n")
+
+            buffer = buffer[4 + length :]
+
+        out.flush()
+
+    out.write(text.decode(buffer, final=True))
+    out.flush()
+
+
+if __name__ == "__main__":
+    try:
+        decode(sys.stdin.buffer, sys.stdout)
+    except KeyboardInterrupt:
+        pass
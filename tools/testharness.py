@@ -0,0 +1,206 @@
+#!/usr/bin/env python3
+"""Reports the results of the kernel tests
+
+Reads the serial output of `cargo test` from stdin and prints a line per test,
+then the output and panic message of the ones that failed and a summary. The
+exit status is 1 if any test failed or a run didn't finish. The protocol is
+described in `test_runner` in src/lib.rs, the output of a kernel built with
+LOG_FORMAT=binary must go through tools/logdecode.py first.
+"""
+import argparse
+import sys
+
+MARKER = "[test] "
+
+
+class Test:
+    def __init__(self, name):
+        self.name = name
+        self.output = []
+        self.status = None
+        self.duration = None
+        self.message = ""
+
+
+class Run:
+    def __init__(self, count):
+        self.count = count
+        self.tests = []
+        self.current = None
+        # Printed outside of a test, only shown if the run broke
+        self.output = []
+        self.done = False
+        self.error = None
+
+
+def unescape(message):
+    out = []
+    chars = iter(message)
+
+    for c in chars:
+        if c == "\\":
+            c = next(chars, "\\")
+            out.append("\n" if c == "n" else c)
+        else:
+            out.append(c)
+
+    return "".join(out)
+
+
+def duration(micros):
+    return None if micros == "-" else int(micros)
+
+
+def format_duration(micros):
+    if micros is None:
+        return ""
+    if micros >= 1000000:
+        return " ({:.2f} s)".format(micros / 1000000)
+    return " ({:.2f} ms)".format(micros / 1000)
+
+
+class Harness:
+    def __init__(self, verbose):
+        self.verbose = verbose
+        self.runs = []
+        # Printed before the first run, like the build output
+        self.preamble = []
+
+    def output(self, text):
+        if self.verbose:
+            print("    " + text)
+
+        run = self.runs[-1] if self.runs else None
+        if run is None:
+            self.preamble.append(text)
+        elif run.current is not None:
+            run.current.output.append(text)
+        else:
+            run.output.append(text)
+
+    def finish(self, test, status, micros=None, message=""):
+        test.status = status
+        test.duration = micros
+        test.message = message
+
+        label = "ok" if status == "ok" else status.upper()
+        print("test {} ... {}{}".format(test.name, label, format_duration(micros)))
+
+    def record(self, line):
+        kind, _, rest = line.partition(" ")
+        run = self.runs[-1] if self.runs else None
+
+        if kind == "run":
+            self.runs.append(Run(int(rest)))
+            print("\nrunning {} tests".format(rest))
+            return
+
+        if run is None:
+            self.output(MARKER + line)
+        elif kind == "start":
+            run.current = Test(rest)
+            run.tests.append(run.current)
+        elif kind in ("ok", "failed") and run.current is not None:
+            fields = rest.split(" ", 2)
+            message = unescape(fields[2]) if len(fields) > 2 else ""
+            self.finish(run.current, kind, duration(fields[1]), message)
+            run.current = None
+        elif kind == "timeout":
+            if run.current is not None:
+                self.finish(
+                    run.current, "timeout", message="The watchdog stopped the run"
+                )
+                run.current = None
+            run.error = "the watchdog stopped the run"
+        elif kind == "panic":
+            run.error = unescape(rest)
+        elif kind == "done":
+            run.done = True
+        else:
+            self.output(MARKER + line)
+
+    def feed(self, line):
+        start = line.find(MARKER)
+
+        if start == -1:
+            self.output(line)
+            return
+
+        # Output that didn't end with a newline
+        if start > 0:
+            self.output(line[:start])
+
+        self.record(line[start + len(MARKER) :])
+
+    def report(self):
+        failed = []
+        passed = 0
+        broken = False
+
+        for run in self.runs:
+            if run.current is not None:
+                self.finish(
+                    run.current, "crashed", message="The run ended during the test"
+                )
+                run.current = None
+
+            for test in run.tests:
+                if test.status == "ok":
+                    passed += 1
+                else:
+                    failed.append(test)
+
+            if not run.done and run.error is None:
+                run.error = "the run ended after {} of {} tests".format(
+                    len(run.tests), run.count
+                )
+
+            if run.error is not None:
+                broken = True
+                print("\nerror: {}".format(run.error))
+                for text in run.output:
+                    print("    " + text)
+
+        if not self.runs:
+            broken = True
+            print("error: no test run found")
+            for text in self.preamble:
+                print(text)
+
+        for test in failed:
+            print("\n---- {} ----".format(test.name))
+            for text in test.output:
+                print(text)
+            if test.message:
+                print(test.message)
+
+        print(
+            "\ntest result: {}. {} passed; {} failed".format(
+                "ok" if not failed and not broken else "FAILED", passed, len(failed)
+            )
+        )
+
+        return 1 if failed or broken else 0
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.splitlines()[0])
+    parser.add_argument(
+        "-v", "--verbose", action="store_true", help="show the output of every test"
+    )
+    args = parser.parse_args()
+
+    harness = Harness(args.verbose)
+    stdin = open(sys.stdin.fileno(), encoding="utf-8", errors="replace", newline="")
+
+    try:
+        for line in stdin:
+            harness.feed(line.rstrip("\r\n"))
+    except KeyboardInterrupt:
+        pass
+
+    return harness.report()
+
+
+if __name__ == "__main__":
+    sys.exit(main())